@@ -0,0 +1,110 @@
+use amplicon_tk::{
+    primers::{AmpliconScheme, PossiblePrimers},
+    record::{FindAmplicons, SchemeKind},
+};
+use biotest::{values::Nucleotides, Format};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use noodles::fastq::{record::Definition, Record as FastqRecord};
+
+/// Generate random uppercase DNA sequences of the requested length.
+fn random_seqs(count: usize, len: usize, seed: u64) -> Vec<Vec<u8>> {
+    let mut rng = biotest::seeded_rand(seed);
+    let generator = biotest::Sequence::builder()
+        .sequence(Nucleotides::DnaUpper)
+        .sequence_len(len)
+        .build()
+        .expect("sequence generator");
+    (0..count)
+        .map(|_| {
+            let mut seq = Vec::new();
+            generator
+                .record(&mut seq, &mut rng)
+                .expect("random sequence");
+            seq
+        })
+        .collect()
+}
+
+fn revcomp(seq: &[u8]) -> String {
+    String::from_utf8(amplicon_tk::primers::reverse_complement_bytes(seq)).unwrap()
+}
+
+/// A 50-amplicon scheme with 22-base primers, along with one 400-base read per amplicon.
+fn scheme_and_reads() -> (AmpliconScheme, Vec<FastqRecord>) {
+    let primers = random_seqs(100, 22, 1);
+    let scheme = AmpliconScheme {
+        scheme: primers
+            .chunks(2)
+            .enumerate()
+            .map(|(i, pair)| {
+                PossiblePrimers::new(
+                    format!("amplicon_{i}"),
+                    String::from_utf8(pair[0].clone()).unwrap(),
+                    revcomp(&pair[0]),
+                    String::from_utf8(pair[1].clone()).unwrap(),
+                    revcomp(&pair[1]),
+                )
+            })
+            .collect(),
+    };
+
+    let inserts = random_seqs(50, 356, 2);
+    let reads = scheme
+        .scheme
+        .iter()
+        .zip(inserts)
+        .map(|(pair, insert)| {
+            let seq = [pair.fwd.as_bytes(), &insert, pair.rev_rc.as_bytes()].concat();
+            let qual = vec![b'I'; seq.len()];
+            FastqRecord::new(Definition::new("read", ""), seq, qual)
+        })
+        .collect();
+
+    (scheme, reads)
+}
+
+/// The window-by-window scan used before primer finders were compiled onto the scheme.
+fn window_scan(seq: &[u8], primer: &str) -> bool {
+    seq.windows(primer.len())
+        .any(|window| window.eq(primer.as_bytes()))
+}
+
+fn bench_matching(c: &mut Criterion) {
+    let (scheme, reads) = scheme_and_reads();
+
+    c.bench_function("find_amplicon with compiled finders", |b| {
+        b.iter(|| {
+            for read in &reads {
+                black_box(block_on(read.find_amplicon(&scheme.scheme)));
+            }
+        })
+    });
+
+    c.bench_function("find_amplicon_in a non-overlapping scheme", |b| {
+        b.iter(|| {
+            for read in &reads {
+                black_box(block_on(
+                    read.find_amplicon_in(&scheme.scheme, SchemeKind::NonOverlapping),
+                ));
+            }
+        })
+    });
+
+    c.bench_function("primer search with window scans", |b| {
+        b.iter(|| {
+            for read in &reads {
+                for pair in &scheme.scheme {
+                    let fwd = window_scan(read.sequence(), &pair.fwd)
+                        || window_scan(read.sequence(), &pair.fwd_rc);
+                    let rev = window_scan(read.sequence(), &pair.rev)
+                        || window_scan(read.sequence(), &pair.rev_rc);
+                    black_box((fwd, rev));
+                }
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_matching);
+criterion_main!(benches);
@@ -1,6 +1,15 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+use regex::Regex;
+
+use crate::{
+    io::OutputType,
+    reads::{AmpliconsPerRead, DedupKey, LengthBins, OnError, OutputMode},
+    record::{MatchAlgorithm, QualityOffset, SchemeKind},
+};
 
 pub const INFO: &str = r"
 
@@ -29,12 +38,172 @@ chimeras and other artifacts will be removed.
 #[clap(version = "v0.1.0")]
 pub struct Cli {
     #[command(flatten)]
-    pub verbose: clap_verbosity_flag::Verbosity,
+    pub verbose: Verbosity<InfoLevel>,
 
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+/// Translate the `-v`/`-q` flags into a tracing filter directive. Logging defaults to `info`,
+/// with each `-v` making it more verbose and each `-q` quieter. When neither flag is given,
+/// `RUST_LOG` is honored if it is set.
+pub fn log_directive(verbose: &Verbosity<InfoLevel>, rust_log: Option<&str>) -> String {
+    match (verbose.is_present(), rust_log) {
+        (false, Some(directive)) => directive.to_string(),
+        _ => verbose.log_level_filter().to_string().to_lowercase(),
+    }
+}
+
+/// Parse a reporting interval such as `5s`, `500ms`, or `2m`. A bare number is taken as seconds.
+///
+/// # Errors
+///
+/// This function will return an error if the interval is not a number with an optional `ms`,
+/// `s`, or `m` unit, or if it is zero.
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let interval = interval.trim();
+    let (value, millis_per_unit) = if let Some(value) = interval.strip_suffix("ms") {
+        (value, 1)
+    } else if let Some(value) = interval.strip_suffix('s') {
+        (value, 1_000)
+    } else if let Some(value) = interval.strip_suffix('m') {
+        (value, 60_000)
+    } else {
+        (interval, 1_000)
+    };
+    let value: u64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{interval:?} is not an interval like 5s, 500ms, or 2m"))?;
+    if value == 0 {
+        return Err("The interval must be longer than zero".to_string());
+    }
+
+    Ok(Duration::from_millis(value * millis_per_unit))
+}
+
+/// Parse a memory size such as `512M`, `2G`, or `64KB`, in powers of 1024. A bare number is
+/// taken as bytes.
+///
+/// # Errors
+///
+/// This function will return an error if the size is not a number with an optional `K`, `M`,
+/// or `G` unit, or if it is zero.
+pub fn parse_memory_size(size: &str) -> Result<u64, String> {
+    let trimmed = size.trim().to_ascii_uppercase();
+    let trimmed = trimmed.strip_suffix('B').unwrap_or(&trimmed);
+    let (value, bytes_per_unit) = if let Some(value) = trimmed.strip_suffix('K') {
+        (value, 1 << 10)
+    } else if let Some(value) = trimmed.strip_suffix('M') {
+        (value, 1 << 20)
+    } else if let Some(value) = trimmed.strip_suffix('G') {
+        (value, 1 << 30)
+    } else {
+        (trimmed, 1)
+    };
+    let value: u64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{size:?} is not a memory size like 512M, 2G, or 64K"))?;
+    if value == 0 {
+        return Err("The memory size must be larger than zero".to_string());
+    }
+
+    value
+        .checked_mul(bytes_per_unit)
+        .ok_or_else(|| format!("{size:?} is too large a memory size"))
+}
+
+/// Parse comma-separated length boundaries, e.g. `200,400,600`, into [`LengthBins`].
+///
+/// # Errors
+///
+/// This function will return an error if a boundary is not a whole number or if the boundaries
+/// don't make at least one bin.
+pub fn parse_length_bins(bounds: &str) -> Result<LengthBins, String> {
+    let bounds = bounds
+        .split(',')
+        .map(|bound| {
+            bound
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("{bound:?} is not a read length"))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+
+    LengthBins::new(bounds).map_err(|err| err.to_string())
+}
+
+/// Parse the number of complete amplicons a kept read may hold, given as a count, e.g. `1`, an
+/// open range, e.g. `2..`, or an inclusive range, e.g. `1..3`, into [`AmpliconsPerRead`].
+///
+/// # Errors
+///
+/// This function will return an error if either end of the range is not a whole number or if
+/// the range is empty.
+pub fn parse_amplicons_per_read(range: &str) -> Result<AmpliconsPerRead, String> {
+    let parse_count = |count: &str| {
+        count
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("{count:?} is not a number of amplicons"))
+    };
+    let (min, max) = match range.split_once("..") {
+        Some((min, max)) if max.trim().is_empty() => (parse_count(min)?, None),
+        Some((min, max)) => (parse_count(min)?, Some(parse_count(max)?)),
+        None => {
+            let count = parse_count(range)?;
+            (count, Some(count))
+        }
+    };
+    if max.is_some_and(|max| max < min) {
+        return Err(format!(
+            "{range:?} does not include any number of amplicons"
+        ));
+    }
+
+    Ok(AmpliconsPerRead { min, max })
+}
+
+/// Parse a regular expression that finds barcodes in read names, e.g. `barcode=(\S+)`.
+///
+/// # Errors
+///
+/// This function will return an error if the pattern is not a valid regular expression.
+pub fn parse_barcode_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("{pattern:?} is not a valid barcode pattern: {err}"))
+}
+
+/// Parse the number of reads to write to each output file, e.g. `1000000`.
+///
+/// # Errors
+///
+/// This function will return an error if the number is not a whole number larger than zero.
+pub fn parse_reads_per_file(count: &str) -> Result<usize, String> {
+    match count.trim().parse() {
+        Ok(0) => Err("Each output file must hold at least one read".to_string()),
+        Ok(count) => Ok(count),
+        Err(_) => Err(format!("{count:?} is not a whole number of reads")),
+    }
+}
+
+/// Parse the short name of an output format, e.g. `fastq.gz`.
+///
+/// # Errors
+///
+/// This function will return an error if no output format has that name.
+pub fn parse_output_format(name: &str) -> Result<OutputType, String> {
+    OutputType::from_name(name.trim()).ok_or_else(|| {
+        let names: Vec<&str> = OutputType::all().iter().map(OutputType::name).collect();
+        format!(
+            "{name:?} is not an output format; expected one of {}",
+            names.join(", ")
+        )
+    })
+}
+
+// subcommands are parsed once per run, so the size of the largest one doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     #[clap(
@@ -49,9 +218,20 @@ pub enum Commands {
         #[arg(short, long, required = true)]
         bed_file: PathBuf,
 
-        /// Reference sequence in FASTA format
-        #[arg(short, long, required = true)]
-        fasta_ref: PathBuf,
+        /// Reference sequence(s) in FASTA format. Repeat the flag or separate paths with commas
+        /// for references split across files
+        #[arg(short, long, required = true, num_args = 1.., value_delimiter = ',')]
+        fasta_ref: Vec<PathBuf>,
+
+        /// Load only the stretches of the reference around each primer in the BED file, rather
+        /// than every whole reference sequence, to save memory with large references
+        #[arg(long, required = false, default_value_t = false)]
+        lazy_ref: bool,
+
+        /// With --lazy-ref, build a `.fai` index for any reference FASTA without one, so primer
+        /// windows are fetched by coordinate rather than by streaming every reference
+        #[arg(long, required = false, default_value_t = false, requires = "lazy_ref")]
+        create_fai: bool,
 
         /// The suffix used to identify forward primers in the provided BED file
         #[arg(short, long, required = false, default_value = "_LEFT")]
@@ -60,29 +240,166 @@ pub enum Commands {
         /// The suffix used to identify reverse primers in the provided BED file
         #[arg(short, long, required = false, default_value = "_RIGHT")]
         right_suffix: String,
+
+        /// Primer sequences in FASTA format, named as in the BED file, that take precedence over
+        /// the sequences at the BED coordinates in the reference
+        #[arg(long, required = false)]
+        primer_fasta: Option<PathBuf>,
+
+        /// Write the most frequent trimmed sequence for each amplicon to this FASTA file
+        #[arg(long, required = false)]
+        representatives: Option<PathBuf>,
+
+        /// Compress the index with gzip, which shrinks it considerably for high-diversity data.
+        /// Compressed indices are detected and read like any other
+        #[arg(long, required = false, default_value_t = false)]
+        compress_index: bool,
+
+        /// Stop with an error, rather than being killed for running out of memory, once the
+        /// index is estimated to use more than this much memory, e.g. 512M or 4G
+        #[arg(long, required = false, value_parser = parse_memory_size)]
+        max_memory: Option<u64>,
+
+        /// Run even if the primer scheme has no amplicons, rather than stopping with an error
+        #[arg(long, required = false, default_value_t = false)]
+        allow_empty_scheme: bool,
+
+        /// Treat each reverse primer sequence as it appears in reads of the forward strand,
+        /// i.e., already reverse complemented, rather than written 5' to 3'
+        #[arg(long, required = false, default_value_t = false)]
+        rev_as_appears: bool,
+
+        /// Stop with an error, rather than a warning, when a primer's region of the reference
+        /// contains hard-masked N bases, since its sequence can't be derived from the reference,
+        /// or when primers from two amplicons in the scheme are reverse complements of each other
+        #[arg(long, required = false, default_value_t = false)]
+        strict: bool,
+
+        /// Count the input reads in a quick first pass, without parsing them, so that the index
+        /// can be sized for them up front rather than growing as reads are counted
+        #[arg(long, required = false, default_value_t = false)]
+        dry_count: bool,
+    },
+
+    #[clap(
+        about = "Merge per-sample indices built with the same primer scheme into one cohort-level index by summing their counts.",
+        aliases = &["im", "idx-merge"])]
+    IndexMerge {
+        /// Index files (.ampidx) to merge
+        #[arg(required = true, num_args = 2..)]
+        indices: Vec<PathBuf>,
+
+        /// Path to write the merged index to
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+
+        /// Compress the merged index with gzip
+        #[arg(long, required = false, default_value_t = false)]
+        compress_index: bool,
     },
 
     #[clap(
             about = "Trim a set of reads down to only those reads that contain a complete amplicon.",
             aliases = &["tr", "tirm", "trm", "tri", "tm"])]
     Trim {
-        /// Input FASTQ file (optionally compressed with gzip or bgzip)
-        #[arg(short, long, required = true)]
-        input_file: PathBuf,
+        /// Input FASTQ file(s) (optionally compressed with gzip or bgzip), or '-' to read from
+        /// standard input. Multiple inputs are merged into a single output unless
+        /// `--output-mode per-input` is set
+        #[arg(short, long, num_args = 1.., required_unless_present = "input_glob")]
+        input_file: Vec<PathBuf>,
+
+        /// A glob pattern, e.g. 'data/*.fastq.gz', matching input files to trim together
+        #[arg(long, required = false, conflicts_with = "input_file")]
+        input_glob: Option<String>,
 
         /// Input BED file of primer coordinates
-        #[arg(short, long, required = false)]
-        bed_file: PathBuf,
+        #[arg(short, long, required_unless_present = "primer_table")]
+        bed_file: Option<PathBuf>,
 
-        /// Reference sequence in FASTA format
-        #[arg(short, long, required = false)]
-        fasta_ref: PathBuf,
+        /// Reference sequence(s) in FASTA format. Repeat the flag or separate paths with commas
+        /// for references split across files
+        #[arg(
+            short,
+            long,
+            num_args = 1..,
+            value_delimiter = ',',
+            required_unless_present = "primer_table"
+        )]
+        fasta_ref: Vec<PathBuf>,
+
+        /// Load only the stretches of the reference around each primer in the BED file, rather
+        /// than every whole reference sequence, to save memory with large references
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            conflicts_with = "primer_table"
+        )]
+        lazy_ref: bool,
+
+        /// With --lazy-ref, build a `.fai` index for any reference FASTA without one, so primer
+        /// windows are fetched by coordinate rather than by streaming every reference
+        #[arg(long, required = false, default_value_t = false, requires = "lazy_ref")]
+        create_fai: bool,
+
+        /// Tab-separated table pairing forward and reverse primer names from --primer-fasta, with
+        /// optional further columns giving each amplicon's name and expected maximum length
+        #[arg(
+            long,
+            required = false,
+            requires = "primer_fasta",
+            conflicts_with = "bed_file"
+        )]
+        primer_table: Option<PathBuf>,
+
+        /// Primer sequences in FASTA format, named as in --primer-table. Alongside a BED file,
+        /// these sequences take precedence over the sequences at the BED coordinates in the
+        /// reference
+        #[arg(long, required = false)]
+        primer_fasta: Option<PathBuf>,
+
+        /// Check that the reference sequence at each primer's BED coordinates matches its
+        /// sequence in --primer-fasta, suggesting corrected coordinates for any that don't
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            requires = "primer_fasta",
+            conflicts_with = "primer_table"
+        )]
+        check_bed_coords: bool,
 
         /// Whether to keep reads that contain multiple pairs of primers
         #[arg(short, long, required = false, default_value_t = false)]
         keep_multi: bool,
 
-        /// The suffix used to identify forward primers in the provided BED file
+        /// Restrict the scheme to this one amplicon, e.g., to quickly re-trim the reads for one
+        /// problematic amplicon. Only reads from this amplicon are written
+        #[arg(long, required = false)]
+        only_amplicon: Option<String>,
+
+        /// Run even if the primer scheme has no amplicons, rather than stopping with an error
+        #[arg(long, required = false, default_value_t = false)]
+        allow_empty_scheme: bool,
+
+        /// Treat each reverse primer sequence from the reference at its BED coordinates as it
+        /// appears in reads of the forward strand, i.e., already reverse complemented, rather
+        /// than written 5' to 3'
+        #[arg(long, required = false, default_value_t = false)]
+        rev_as_appears: bool,
+
+        /// Treat each reverse primer sequence in --primer-fasta for a primer table as it appears
+        /// in reads of the forward strand, reverse complementing it before building the scheme
+        #[arg(long, required = false, default_value_t = false)]
+        reverse_complement_primers_in_table: bool,
+
+        /// Stop with an error, rather than a warning, when a primer's region of the reference
+        /// contains hard-masked N bases, since its sequence can't be derived from the reference,
+        /// or when primers from two amplicons in the scheme are reverse complements of each other
+        #[arg(long, required = false, default_value_t = false)]
+        strict: bool,
+
+        /// The suffix used to identify forward primers in the provided BED file or primer table
         #[arg(short, long, required = false, default_value = "_LEFT")]
         left_suffix: String,
 
@@ -94,13 +411,402 @@ pub enum Commands {
         #[arg(short, long, required = false)]
         min_freq: Option<f64>,
 
+        /// The minimum number of reads that must carry an amplicon variant for it to be kept
+        #[arg(long, required = false)]
+        min_count: Option<u64>,
+
+        /// Filter with this index, e.g., one produced by `index-merge`, instead of the index
+        /// alongside the input file
+        #[arg(long, required = false)]
+        index: Option<PathBuf>,
+
+        /// Compute the frequencies filtered on by --min-freq and --min-count from the inputs
+        /// themselves, in a first pass over them, and then trim and filter in a second pass,
+        /// rather than relying on a separately built index that may be stale or missing. Reads
+        /// from standard input are first copied to a scratch file in --temp-dir, since standard
+        /// input cannot be read twice
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            conflicts_with = "index"
+        )]
+        two_pass_freq: bool,
+
+        /// The directory to write intermediate files to, e.g., fast local scratch space when the
+        /// outputs are on networked storage. Defaults to $TMPDIR, or the system's temporary
+        /// directory. Intermediates are removed once the run finishes
+        #[arg(long, required = false)]
+        temp_dir: Option<PathBuf>,
+
+        /// With --two-pass-freq, stop with an error once the frequencies counted in the first
+        /// pass are estimated to use more than this much memory, e.g. 512M or 4G
+        #[arg(
+            long,
+            required = false,
+            value_parser = parse_memory_size,
+            requires = "two_pass_freq"
+        )]
+        max_memory: Option<u64>,
+
+        /// Only trim primers, without loading any index or applying any frequency, count, or
+        /// length filters, so that every read holding an amplicon is written
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            conflicts_with_all = ["index", "two_pass_freq", "min_freq", "min_count", "expected_len"]
+        )]
+        no_index: bool,
+
         /// Whether to filter by an expected maximum length for amplicons in this scheme
         #[arg(short, long, required = false)]
         expected_len: Option<usize>,
 
-        /// Output file name
+        /// Output file name, without an extension, or the output directory when writing one
+        /// output per input
         #[arg(short, long, required = false, default_value = "trimmed")]
         output: String,
+
+        /// Whether to merge the reads from every input into one output or to write each input's
+        /// reads to a file named after it in the output directory
+        #[arg(long, required = false, value_enum, default_value_t = OutputMode::Merge)]
+        output_mode: OutputMode,
+
+        /// The formats to write trimmed reads in, e.g. `fastq,fastq.gz`, each to its own output
+        /// with the matching extension. Defaults to the format of the first input
+        #[arg(
+            long,
+            required = false,
+            value_delimiter = ',',
+            value_parser = parse_output_format
+        )]
+        output_format: Vec<OutputType>,
+
+        /// Write trimmed reads uncompressed if there are no more than this many of them, and
+        /// gzip-compressed otherwise, choosing the output's extension to match. Reads are held in
+        /// memory until the threshold is passed, so small outputs skip the cost of compression
+        #[arg(
+            long,
+            required = false,
+            conflicts_with_all = ["output_format", "split_by_length", "barcode_from_name", "count_only"]
+        )]
+        output_uncompressed_threshold: Option<usize>,
+
+        /// Split trimmed reads across numbered outputs of at most this many reads each, e.g.
+        /// trimmed.000.fastq.gz and trimmed.001.fastq.gz, for tools that limit the size of each
+        /// file they take
+        #[arg(
+            long,
+            required = false,
+            value_parser = parse_reads_per_file,
+            conflicts_with_all = ["output_format", "split_by_length", "barcode_from_name", "count_only", "output_uncompressed_threshold"]
+        )]
+        reads_per_file: Option<usize>,
+
+        /// Run all matching and filtering but only report counts, without writing any reads
+        #[arg(long, required = false, default_value_t = false)]
+        count_only: bool,
+
+        /// Skip trimming and instead scan the reads for each primer on its own, in either
+        /// orientation, writing a TSV to stdout of how many reads carry each one. Primers found in
+        /// few or no reads usually have an error in their sequence or coordinates
+        #[arg(long, required = false, default_value_t = false)]
+        count_primers_only: bool,
+
+        /// Write the run's read counts to this path as JSON
+        #[arg(long, required = false)]
+        stats_json: Option<PathBuf>,
+
+        /// The ASCII offset of the input quality scores (64 for legacy Illumina data)
+        #[arg(long, required = false, value_enum, default_value_t = QualityOffset::Phred33)]
+        quality_offset: QualityOffset,
+
+        /// Re-encode output quality scores as Phred+33 instead of writing them back unchanged
+        #[arg(long, required = false, default_value_t = false)]
+        rescale_quality: bool,
+
+        /// Reverse complement every trimmed read, and reverse its quality scores, before writing
+        #[arg(
+            long,
+            alias = "reverse-complement-output",
+            required = false,
+            default_value_t = false
+        )]
+        rc_output: bool,
+
+        /// Uppercase the bases of every trimmed read before writing, rather than keeping any
+        /// lowercase bases from the input
+        #[arg(long, required = false, default_value_t = false)]
+        uppercase_output: bool,
+
+        /// Append a running index to the name of every written read, e.g. `read_1`, so that read
+        /// names stay unique when inputs are merged
+        #[arg(long, required = false, default_value_t = false)]
+        uniquify_names: bool,
+
+        /// The name of the sample being trimmed, recorded in the read counts and stats JSON.
+        /// Defaults to the file name of the first input without its extensions
+        #[arg(long, required = false)]
+        sample_name: Option<String>,
+
+        /// Prepend the sample name and a colon to the name of every written read, e.g.
+        /// `sample1:read_1`, so that reads pooled from several samples stay traceable
+        #[arg(long, required = false, default_value_t = false)]
+        prefix_sample_name: bool,
+
+        /// Move each end of a trimmed read up to two bases inward to the nearest base with a
+        /// quality of at least --refine-min-quality, dropping low-quality primer junctions
+        #[arg(long, required = false, default_value_t = false)]
+        refine_boundary: bool,
+
+        /// The lowest Phred score allowed at a refined trim boundary
+        #[arg(
+            long,
+            required = false,
+            default_value_t = 10,
+            requires = "refine_boundary"
+        )]
+        refine_min_quality: u8,
+
+        /// Append the length and GC content of each trimmed read to its header, e.g.
+        /// `len=19 gc=0.42`, after any existing comment
+        #[arg(long, required = false, default_value_t = false)]
+        annotate_metrics: bool,
+
+        /// Append the untrimmed length of each read and the 0-based, half-open coordinates of its
+        /// insert within the untrimmed read to its description, e.g. "orig_len=80 trim_start=12
+        /// trim_end=24", so that trimmed positions can be mapped back to the original read
+        #[arg(long, required = false, default_value_t = false)]
+        keep_original_in_header: bool,
+
+        /// Write one read for each unique trimmed sequence, named after the first read with that
+        /// sequence plus ";size=N" for the N reads that share it, with the highest quality seen at
+        /// each position
+        #[arg(long, required = false, default_value_t = false)]
+        collapse_output: bool,
+
+        /// With --collapse-output, what makes two reads share a sequence: "seq" for identical
+        /// sequences, "seq+len" for identical sequences and lengths, or "canonical" to also
+        /// collapse each sequence with its reverse complement when orientation isn't normalized
+        #[arg(
+            long,
+            required = false,
+            value_enum,
+            default_value_t = DedupKey::Seq,
+            requires = "collapse_output"
+        )]
+        dedup_key: DedupKey,
+
+        /// Write all reads of each amplicon contiguously, one amplicon after another in the order of
+        /// the primer scheme, rather than in the order they were trimmed. Reads are held in memory
+        /// until every input has been trimmed
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            conflicts_with_all = ["collapse_output", "count_only"]
+        )]
+        group_by_amplicon: bool,
+
+        /// Split retained reads by trimmed length into one output per bin, given as boundaries
+        /// such as "200,400,600" for bins of 200 to 399 and 400 to 599 bases. Each output is
+        /// named after the output file plus its bin, e.g. trimmed.200-400.fastq, and reads
+        /// outside every bin are written to trimmed.other.fastq
+        #[arg(
+            long,
+            required = false,
+            value_parser = parse_length_bins,
+            conflicts_with_all = ["output_format", "collapse_output", "group_by_amplicon"]
+        )]
+        split_by_length: Option<LengthBins>,
+
+        /// Split retained reads into one output per barcode, found in each read's name and
+        /// description with this regular expression, e.g. "barcode=(\S+)". The barcode is the
+        /// pattern's first capture group, or its whole match if it has none. Each output is an
+        /// uncompressed FASTQ named after the output file plus its barcode, e.g.
+        /// trimmed.BC07.fastq, and reads without a barcode are written to
+        /// trimmed.unclassified.fastq
+        #[arg(
+            long,
+            required = false,
+            value_parser = parse_barcode_pattern,
+            conflicts_with_all = ["output_format", "collapse_output", "group_by_amplicon", "split_by_length", "count_only"]
+        )]
+        barcode_from_name: Option<Regex>,
+
+        /// With --barcode-from-name, split each barcode's reads further by amplicon, e.g. into
+        /// trimmed.BC07.amplicon_1.fastq
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            requires = "barcode_from_name"
+        )]
+        demux_by_amplicon: bool,
+
+        /// Drop reads whose insert, excluding both primers, is shorter than this many bases, as in
+        /// primer dimers. Reads whose primers abut or overlap are always dropped [default: 10]
+        #[arg(long, required = false)]
+        min_insert_len: Option<usize>,
+
+        /// Count reads whose insert differs by more than this many bases from the length the BED
+        /// coordinates give its amplicon, which usually means a primer was matched in the wrong
+        /// place
+        #[arg(long, required = false, conflicts_with = "primer_table")]
+        insert_len_tolerance: Option<usize>,
+
+        /// With --insert-len-tolerance, drop the reads whose inserts are too far from their
+        /// expected length rather than only counting them
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            requires = "insert_len_tolerance"
+        )]
+        drop_implausible_inserts: bool,
+
+        /// How to find primers in reads: exactly, allowing substituted bases (hamming), or
+        /// allowing substitutions, insertions, and deletions (align), which suits nanopore reads.
+        /// Partial amplicons from --min-extension are always found exactly
+        #[arg(long, required = false, value_enum, default_value_t = MatchAlgorithm::Exact)]
+        match_algo: MatchAlgorithm,
+
+        /// Whether the scheme's amplicons may overlap (tiled) or never do (non-overlapping). For
+        /// non-overlapping schemes, primer searches stop at the first complete amplicon found,
+        /// which is faster for large schemes but takes reads joining several complete amplicons
+        /// to be the first of them rather than rejecting them as multiple matches
+        #[arg(long, required = false, value_enum, default_value_t = SchemeKind::Tiled)]
+        scheme_kind: SchemeKind,
+
+        /// The most mismatches (hamming) or edits (align) allowed in each primer [default: 2]
+        #[arg(long, required = false)]
+        max_primer_errors: Option<usize>,
+
+        /// Reject reads whose forward or reverse primer was matched over bases with a mean
+        /// quality score below this, since a primer found among low-quality bases is more likely
+        /// to be a false positive
+        #[arg(long, required = false)]
+        min_primer_qual: Option<u8>,
+
+        /// Keep only reads holding this many complete amplicons, e.g. 1 to drop the concatemers
+        /// common in nanopore data, or 2.. to keep only those concatemers, which are trimmed to
+        /// their outermost primers. Ranges such as 1..3 include both ends
+        #[arg(long, required = false, value_parser = parse_amplicons_per_read)]
+        amplicons_per_read: Option<AmpliconsPerRead>,
+
+        /// Split reads holding several complete amplicons, as in nanopore concatemers, into one
+        /// read per amplicon before trimming, named after the read with their place in it, e.g.
+        /// read1_1 and read1_2. Each piece is then trimmed and counted as a read of its own
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            conflicts_with = "amplicons_per_read"
+        )]
+        split_concatemers: bool,
+
+        /// Keep reads too short to reach the reverse primer if they hold exactly one amplicon's
+        /// forward primer and read at least this many bases past it toward the reverse primer
+        #[arg(long, required = false)]
+        min_extension: Option<usize>,
+
+        /// Check that every read is no longer after trimming than before and has one quality
+        /// score per base, dropping and reporting any that fail instead of writing them
+        #[arg(long, required = false, default_value_t = false)]
+        validate_output: bool,
+
+        /// What to do with input reads that have a different number of bases and quality scores:
+        /// "stop" with an error naming the read, or "skip-warn" to skip each with a warning and
+        /// count it as malformed
+        #[arg(long, required = false, value_enum, default_value_t = OnError::Stop)]
+        on_error: OnError,
+
+        /// Time the primer search for each read and report the total time for each amplicon
+        #[arg(long, required = false, default_value_t = false)]
+        time_matching: bool,
+
+        /// Remember how up to this many distinct read sequences matched, so that identical reads
+        /// are only searched for primers once
+        #[arg(long, required = false)]
+        match_cache: Option<usize>,
+
+        /// Report progress to stderr at this interval, e.g. 5s, 500ms, or 2m
+        #[arg(long, required = false, value_parser = parse_interval)]
+        progress_interval: Option<Duration>,
+
+        /// Count the input reads in a first pass so that progress reports include an ETA. Reads
+        /// from standard input cannot be counted ahead of time, so only throughput is reported
+        #[arg(
+            long,
+            required = false,
+            default_value_t = false,
+            requires = "progress_interval"
+        )]
+        eta: bool,
+
+        /// Write every dropped read, untrimmed and with the reason it was dropped appended to its
+        /// header, to this FASTQ file
+        #[arg(long, required = false)]
+        emit_rejected: Option<PathBuf>,
+
+        /// Write the regions trimmed off of every retained read to this FASTQ file, as two
+        /// records per read: the 5' end up to and including the first primer, then the 3' end
+        /// from the second primer onward, e.g., for adapter, UMI, or barcode analysis
+        #[arg(long, required = false)]
+        primer_regions_out: Option<PathBuf>,
+
+        /// Write one line of JSON per input read to this file, or to stdout with "-", giving its
+        /// read_id, whether it matched, its amplicon, where its insert starts and stops, its
+        /// trimmed length, whether it passed the filters, and why it was rejected if it was
+        #[arg(long, required = false)]
+        report_per_read: Option<PathBuf>,
+
+        /// Write a TSV with the amplicon, read_id, insert_len, and gc_fraction of every retained
+        /// read to this file, e.g., for plotting length against GC content per amplicon to spot
+        /// off-target products
+        #[arg(long, required = false)]
+        report_gc_length_scatter: Option<PathBuf>,
+
+        /// With --report-gc-length-scatter, write rows for a sample of at most this many retained
+        /// reads, chosen by a hash of their names so that the same reads are sampled each run
+        #[arg(long, required = false, requires = "report_gc_length_scatter")]
+        qc_sample: Option<usize>,
+    },
+
+    #[clap(
+            about = "Merge overlapping paired-end reads into single, full-length amplicon reads, keeping the higher-quality base where mates disagree. Merged reads can then be trimmed as usual.",
+            aliases = &["mg", "mrg", "stitch"])]
+    Merge {
+        /// FASTQ file of first mates (optionally compressed with gzip or bgzip)
+        #[arg(short = '1', long, required = true)]
+        r1: PathBuf,
+
+        /// FASTQ file of second mates, in the same order as the first mates
+        #[arg(short = '2', long, required = true)]
+        r2: PathBuf,
+
+        /// The fewest bases mates must overlap by to be merged
+        #[arg(long, required = false, default_value_t = 10)]
+        min_overlap: usize,
+
+        /// The largest fraction of overlapping bases allowed to disagree between mates
+        #[arg(long, required = false, default_value_t = 0.1)]
+        max_mismatch_rate: f64,
+
+        /// Write mates that cannot be merged as separate reads instead of dropping them
+        #[arg(long, required = false, default_value_t = false)]
+        keep_unmerged: bool,
+
+        /// What to do with pairs where a mate has a different number of bases and quality
+        /// scores: "stop" with an error naming the read, or "skip-warn" to skip each pair with a
+        /// warning and count it as malformed
+        #[arg(long, required = false, value_enum, default_value_t = OnError::Stop)]
+        on_error: OnError,
+
+        /// Output file name
+        #[arg(short, long, required = false, default_value = "merged.fastq")]
+        output: PathBuf,
     },
 
     #[clap(
@@ -112,17 +818,26 @@ pub enum Commands {
         input_file: PathBuf,
 
         /// Input BED file of primer coordinates
-        #[arg(short, long, required = false)]
+        #[arg(short, long, required = true)]
         bed_file: PathBuf,
 
-        /// Primer sequences in FASTA format
-        #[arg(short, long, required = true)]
-        primer_file: PathBuf,
+        /// Primer sequences in FASTA format, named as in the BED file, that take precedence over
+        /// the sequences at the BED coordinates in the reference
+        #[arg(short, long, required = false)]
+        primer_file: Option<PathBuf>,
 
         /// Reference sequence in FASTA format
-        #[arg(short, long, required = false)]
+        #[arg(short, long, required = true)]
         ref_file: PathBuf,
 
+        /// The suffix used to identify forward primers in the provided BED file
+        #[arg(long, required = false, default_value = "_LEFT")]
+        left_suffix: String,
+
+        /// The suffix used to identify reverse primers in the provided BED file
+        #[arg(long, required = false, default_value = "_RIGHT")]
+        right_suffix: String,
+
         /// Minimum frequency for variations of the same amplicon
         #[arg(short, long, required = false, default_value_t = 0.0)]
         min_freq: f32,
@@ -130,6 +845,20 @@ pub enum Commands {
         /// Whether to keep reads that contain multiple pairs of primers
         #[arg(short, long, required = false, default_value_t = false)]
         keep_multi: bool,
+
+        /// Write the reads in each amplicon's file in the order they appear in the input, rather
+        /// than in the order they finish trimming, so that output is identical across runs
+        #[arg(long, required = false, default_value_t = false)]
+        keep_order_within_amplicon: bool,
+
+        /// How many reads to search for primers at once, defaulting to the number of CPUs
+        /// available
+        #[arg(long, required = false)]
+        concurrency: Option<usize>,
+
+        /// The directory to write each amplicon's FASTQ file to, which is created if needed
+        #[arg(short, long, required = false, default_value = "sorted")]
+        output_dir: PathBuf,
     },
 
     #[clap(
@@ -141,17 +870,26 @@ pub enum Commands {
         input_file: PathBuf,
 
         /// Input BED file of primer coordinates
-        #[arg(short, long, required = false)]
+        #[arg(short, long, required = true)]
         bed_file: PathBuf,
 
-        /// Primer sequences in FASTA format
-        #[arg(short, long, required = true)]
-        primer_file: PathBuf,
+        /// Primer sequences in FASTA format, named as in the BED file, that take precedence over
+        /// the sequences at the BED coordinates in the reference
+        #[arg(short, long, required = false)]
+        primer_file: Option<PathBuf>,
 
         /// Reference sequence in FASTA format
-        #[arg(short, long, required = false)]
+        #[arg(short, long, required = true)]
         ref_file: PathBuf,
 
+        /// The suffix used to identify forward primers in the provided BED file
+        #[arg(long, required = false, default_value = "_LEFT")]
+        left_suffix: String,
+
+        /// The suffix used to identify reverse primers in the provided BED file
+        #[arg(long, required = false, default_value = "_RIGHT")]
+        right_suffix: String,
+
         /// Minimum frequency for variations of the same amplicon
         #[arg(short, long, required = false, default_value_t = 0.0)]
         min_freq: f32,
@@ -160,8 +898,130 @@ pub enum Commands {
         #[arg(short, long, required = false, default_value_t = false)]
         keep_multi: bool,
 
+        /// Align each amplicon's consensus to its region of the reference and trim or pad it to
+        /// span exactly that region, so that consensus sequences can be concatenated into a
+        /// whole-genome consensus
+        #[arg(long, required = false, default_value_t = false)]
+        trim_to_reference: bool,
+
+        /// Stitch the reference-trimmed consensus of every amplicon into one consensus as long as
+        /// the reference, preferring deeper amplicons where they overlap and filling gaps with N,
+        /// and write it to this FASTA file
+        #[arg(long, required = false, requires = "trim_to_reference")]
+        assemble_genome: Option<PathBuf>,
+
+        /// Also write the trimmed reads each consensus was called from into this directory, one
+        /// FASTQ file per amplicon as with `sort`, for manual review
+        #[arg(long, required = false)]
+        emit_reads_dir: Option<PathBuf>,
+
+        /// Stop with an error once the reads grouped for consensus calling are estimated to use
+        /// more than this much memory, e.g. 512M or 4G
+        #[arg(long, required = false, value_parser = parse_memory_size)]
+        max_memory: Option<u64>,
+
+        /// Weight each read's base at every position by the probability, from its quality score,
+        /// that it was called correctly, rather than counting every read the same
+        #[arg(long, required = false, default_value_t = false)]
+        quality_weighted: bool,
+
+        /// The ASCII offset of the input quality scores (64 for legacy Illumina data)
+        #[arg(long, required = false, value_enum, default_value_t = QualityOffset::Phred33)]
+        quality_offset: QualityOffset,
+
         /// Output file name
         #[arg(short, long, required = false, default_value = "amplicons.fasta")]
         output: String,
     },
+
+    #[clap(
+            about = "Tag each record of an aligned BAM file with the amplicon it came from, e.g. am:Z:nCoV_42, without trimming or otherwise changing any record.",
+            aliases = &["annotate", "tag-bam"])]
+    AnnotateBam {
+        /// Input BAM file of aligned reads
+        #[arg(short, long, required = true)]
+        input_file: PathBuf,
+
+        /// Input BED file of primer coordinates
+        #[arg(short, long, required = true)]
+        bed_file: PathBuf,
+
+        /// Reference sequence(s) in FASTA format. Repeat the flag or separate paths with commas
+        /// for references split across files
+        #[arg(short, long, required = true, num_args = 1.., value_delimiter = ',')]
+        fasta_ref: Vec<PathBuf>,
+
+        /// Load only the stretches of the reference around each primer in the BED file, rather
+        /// than every whole reference sequence, to save memory with large references
+        #[arg(long, required = false, default_value_t = false)]
+        lazy_ref: bool,
+
+        /// With --lazy-ref, build a `.fai` index for any reference FASTA without one, so primer
+        /// windows are fetched by coordinate rather than by streaming every reference
+        #[arg(long, required = false, default_value_t = false, requires = "lazy_ref")]
+        create_fai: bool,
+
+        /// The suffix used to identify forward primers in the provided BED file
+        #[arg(short, long, required = false, default_value = "_LEFT")]
+        left_suffix: String,
+
+        /// The suffix used to identify reverse primers in the provided BED file
+        #[arg(short, long, required = false, default_value = "_RIGHT")]
+        right_suffix: String,
+
+        /// Primer sequences in FASTA format, named as in the BED file, that take precedence over
+        /// the sequences at the BED coordinates in the reference
+        #[arg(long, required = false)]
+        primer_fasta: Option<PathBuf>,
+
+        /// Run even if the primer scheme has no amplicons, rather than stopping with an error
+        #[arg(long, required = false, default_value_t = false)]
+        allow_empty_scheme: bool,
+
+        /// Treat each reverse primer sequence as it appears in reads of the forward strand,
+        /// i.e., already reverse complemented, rather than written 5' to 3'
+        #[arg(long, required = false, default_value_t = false)]
+        rev_as_appears: bool,
+
+        /// Stop with an error, rather than a warning, when a primer's region of the reference
+        /// contains hard-masked N bases, since its sequence can't be derived from the reference,
+        /// or when primers from two amplicons in the scheme are reverse complements of each other
+        #[arg(long, required = false, default_value_t = false)]
+        strict: bool,
+
+        /// Path to write the annotated BAM file to
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+    },
+
+    #[clap(
+            about = "Check a primer scheme for design errors before any reads are trimmed: duplicate primer names, primers that are reverse complements of each other, primers whose 3' end also occurs elsewhere in the reference, amplicons with no insert, and primer pairs with mismatched melting temperatures.",
+            aliases = &["lint", "check-scheme"])]
+    LintScheme {
+        /// Input BED file of primer coordinates
+        #[arg(short, long, alias = "bed", required = true)]
+        bed_file: PathBuf,
+
+        /// Reference sequence(s) in FASTA format that the scheme was designed against. Repeat the
+        /// flag or separate paths with commas for references split across files
+        #[arg(short, long, required = true, num_args = 1.., value_delimiter = ',')]
+        fasta_ref: Vec<PathBuf>,
+
+        /// The suffix used to identify forward primers in the provided BED file
+        #[arg(short, long, required = false, default_value = "_LEFT")]
+        left_suffix: String,
+
+        /// The suffix used to identify reverse primers in the provided BED file
+        #[arg(short, long, required = false, default_value = "_RIGHT")]
+        right_suffix: String,
+
+        /// Write every issue found to this file as JSON, e.g., for scheme design pipelines
+        #[arg(long, required = false)]
+        json: Option<PathBuf>,
+    },
+
+    #[clap(
+            about = "List the read formats that can be read and written, along with the file extensions they are recognized by and written with.",
+            aliases = &["formats", "lf"])]
+    ListFormats,
 }
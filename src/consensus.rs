@@ -0,0 +1,338 @@
+// #![warn(missing_docs)]
+
+//! Module `consensus` holds the pieces of amplicon consensus calling, starting with placing a
+//! consensus sequence onto the coordinates of the reference region it was amplified from, so
+//! that per-amplicon consensus sequences can be concatenated into a whole-genome consensus.
+
+/// The base written at reference positions that a consensus sequence does not cover.
+pub const REFERENCE_PAD: u8 = b'N';
+
+/// The symbol for a reference base that an aligned read skips over.
+pub const DELETION: u8 = b'*';
+
+/// The fraction of the reads covering a position that must skip over it for the position to be
+/// left out of a consensus, unless another threshold is requested.
+pub const DEFAULT_MIN_DELETION_FREQ: f64 = 0.5;
+
+/// How far, beyond the difference in their lengths, an alignment between a consensus and its
+/// reference region may stray from the main diagonal.
+pub const DEFAULT_BAND_WIDTH: usize = 16;
+
+/// A step in the traceback through an alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// A consensus base aligned to a reference base, whether or not they match
+    Diagonal,
+
+    /// A consensus base with no reference base, i.e., an insertion in the consensus
+    Insertion,
+
+    /// A reference base with no consensus base, i.e., a deletion in the consensus
+    Deletion,
+}
+
+/// Trim and pad a consensus sequence so that it spans exactly the provided reference region,
+/// base for base. The consensus is globally aligned to the reference within a band of
+/// [`DEFAULT_BAND_WIDTH`] diagonals around the difference in their lengths. Bases inserted in
+/// the consensus relative to the reference are dropped, reference bases missing from the
+/// consensus are filled with [`REFERENCE_PAD`], and every other position keeps the consensus
+/// base, so the result is always as long as the reference.
+pub fn trim_to_reference(consensus: &[u8], reference: &[u8]) -> Vec<u8> {
+    trim_to_reference_banded(consensus, reference, DEFAULT_BAND_WIDTH)
+}
+
+/// Trim and pad a consensus sequence to a reference region as in [`trim_to_reference`], with
+/// a band of `band_width` diagonals beyond the difference in their lengths.
+pub fn trim_to_reference_banded(consensus: &[u8], reference: &[u8], band_width: usize) -> Vec<u8> {
+    place_on_reference(consensus, reference, band_width, REFERENCE_PAD)
+}
+
+/// Align a read to a reference region as in [`trim_to_reference`], but mark reference bases
+/// that the read skips over with [`DELETION`] rather than [`REFERENCE_PAD`], ready to be added
+/// to a [`ConsensusAccumulator`]. Reference bases before the read starts or after it ends are
+/// not deletions, since the read simply doesn't cover them, so they are still padded.
+pub fn align_to_reference(read: &[u8], reference: &[u8]) -> Vec<u8> {
+    let placed = trace_alignment(read, reference, DEFAULT_BAND_WIDTH);
+    mark_deletions(read, &placed)
+}
+
+/// The probability that a base call with this Phred score is correct, e.g., 0.99 for a score of
+/// 20.
+pub fn call_accuracy(phred: u8) -> f64 {
+    1.0 - 10_f64.powf(-f64::from(phred) / 10.0)
+}
+
+/// Align a read to a reference region as in [`align_to_reference`], also returning how much
+/// weight the read's symbol at each reference position should carry in a quality-weighted
+/// consensus, given the read's decoded Phred scores. Each base weighs the probability that it
+/// was called correctly, per [`call_accuracy`], while each deletion, which has no quality of its
+/// own, weighs the mean of that probability across the read.
+pub fn align_to_reference_weighted(
+    read: &[u8],
+    phred_scores: &[u8],
+    reference: &[u8],
+) -> (Vec<u8>, Vec<f64>) {
+    let placed = trace_alignment(read, reference, DEFAULT_BAND_WIDTH);
+    let aligned = mark_deletions(read, &placed);
+    let accuracies: Vec<f64> = (0..read.len())
+        .map(|index| phred_scores.get(index).copied().map_or(0.0, call_accuracy))
+        .collect();
+    let mean_accuracy = if accuracies.is_empty() {
+        0.0
+    } else {
+        accuracies.iter().sum::<f64>() / accuracies.len() as f64
+    };
+    let weights = placed
+        .iter()
+        .zip(&aligned)
+        .map(|(index, symbol)| match (index, *symbol) {
+            (Some(index), _) => accuracies[*index],
+            (None, DELETION) => mean_accuracy,
+            (None, _) => 0.0,
+        })
+        .collect();
+
+    (aligned, weights)
+}
+
+/// Write out the bases of `read` placed onto reference positions by [`trace_alignment`],
+/// marking positions without a base as [`DELETION`] between the first and last positions the
+/// read covers and as [`REFERENCE_PAD`] outside them.
+fn mark_deletions(read: &[u8], placed: &[Option<usize>]) -> Vec<u8> {
+    let first = placed
+        .iter()
+        .position(Option::is_some)
+        .unwrap_or(placed.len());
+    let last = placed.iter().rposition(Option::is_some).unwrap_or(0);
+    placed
+        .iter()
+        .enumerate()
+        .map(|(position, index)| match index {
+            Some(index) => read[*index],
+            None if (first..=last).contains(&position) => DELETION,
+            None => REFERENCE_PAD,
+        })
+        .collect()
+}
+
+/// Globally align `seq` to `reference` within a band of `band_width` diagonals beyond the
+/// difference in their lengths, returning one base of `seq` for each reference position, with
+/// `gap` wherever `seq` has no base for the reference.
+fn place_on_reference(seq: &[u8], reference: &[u8], band_width: usize, gap: u8) -> Vec<u8> {
+    trace_alignment(seq, reference, band_width)
+        .into_iter()
+        .map(|index| index.map_or(gap, |index| seq[index]))
+        .collect()
+}
+
+/// Globally align `seq` to `reference` within a band of `band_width` diagonals beyond the
+/// difference in their lengths, returning for each reference position the index of the base of
+/// `seq` aligned to it, if any.
+fn trace_alignment(seq: &[u8], reference: &[u8], band_width: usize) -> Vec<Option<usize>> {
+    let (rows, cols) = (seq.len(), reference.len());
+
+    // the band always covers both the start and the end of the alignment, so that a global
+    // alignment exists whatever the sequences are
+    let lowest_diagonal = (cols as isize - rows as isize).min(0) - band_width as isize;
+    let highest_diagonal = (cols as isize - rows as isize).max(0) + band_width as isize;
+    let in_band = |row: usize, col: usize| {
+        let diagonal = col as isize - row as isize;
+        (lowest_diagonal..=highest_diagonal).contains(&diagonal)
+    };
+
+    // score alignments by edit distance, where every mismatch and gap costs the same
+    let width = cols + 1;
+    let mut costs = vec![u32::MAX; (rows + 1) * width];
+    let mut steps = vec![Step::Diagonal; (rows + 1) * width];
+    for row in 0..=rows {
+        for col in 0..=cols {
+            if !in_band(row, col) {
+                continue;
+            }
+            let cell = row * width + col;
+            if row == 0 && col == 0 {
+                costs[cell] = 0;
+                continue;
+            }
+
+            let mut best = (u32::MAX, Step::Diagonal);
+            if row > 0 && col > 0 && costs[cell - width - 1] != u32::MAX {
+                let mismatch = !seq[row - 1].eq_ignore_ascii_case(&reference[col - 1]);
+                best = (
+                    costs[cell - width - 1] + u32::from(mismatch),
+                    Step::Diagonal,
+                );
+            }
+            if row > 0 && costs[cell - width] != u32::MAX && costs[cell - width] + 1 < best.0 {
+                best = (costs[cell - width] + 1, Step::Insertion);
+            }
+            if col > 0 && costs[cell - 1] != u32::MAX && costs[cell - 1] + 1 < best.0 {
+                best = (costs[cell - 1] + 1, Step::Deletion);
+            }
+            (costs[cell], steps[cell]) = best;
+        }
+    }
+
+    // walk back from the end of both sequences, placing one base per reference position
+    let mut placed = Vec::with_capacity(cols);
+    let (mut row, mut col) = (rows, cols);
+    while row > 0 || col > 0 {
+        let step = match (row, col) {
+            (0, _) => Step::Deletion,
+            (_, 0) => Step::Insertion,
+            _ => steps[row * width + col],
+        };
+        match step {
+            Step::Diagonal => {
+                placed.push(Some(row - 1));
+                row -= 1;
+                col -= 1;
+            }
+            Step::Insertion => row -= 1,
+            Step::Deletion => {
+                placed.push(None);
+                col -= 1;
+            }
+        }
+    }
+    placed.reverse();
+
+    placed
+}
+
+/// A consensus sequence placed onto reference coordinates, e.g., by [`trim_to_reference`],
+/// along with the depth of reads it was called from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlacedConsensus {
+    /// The 0-based reference position that the consensus starts at
+    pub start: usize,
+
+    /// The consensus bases, one for each reference position from `start` onward
+    pub sequence: Vec<u8>,
+
+    /// The number of reads the consensus was called from
+    pub depth: u64,
+}
+
+/// Stitch consensus sequences placed onto a reference of `ref_len` bases into one consensus as
+/// long as the reference, e.g., for the tiled amplicons of a viral genome. Where amplicons
+/// overlap, each position takes its base from the deepest amplicon that covers it with a base
+/// other than [`REFERENCE_PAD`], with ties going to the amplicon listed first. Positions that
+/// no amplicon covers are filled with [`REFERENCE_PAD`], as is anything past the end of the
+/// reference.
+pub fn assemble_genome(ref_len: usize, amplicons: &[PlacedConsensus]) -> Vec<u8> {
+    let mut genome = vec![REFERENCE_PAD; ref_len];
+    let mut filled = vec![false; ref_len];
+
+    // fill in the deepest amplicons first, so that shallower ones only cover what is left
+    let mut by_depth: Vec<&PlacedConsensus> = amplicons.iter().collect();
+    by_depth.sort_by_key(|amplicon| std::cmp::Reverse(amplicon.depth));
+    for amplicon in by_depth {
+        for (offset, base) in amplicon.sequence.iter().enumerate() {
+            let position = amplicon.start + offset;
+            if position >= ref_len {
+                break;
+            }
+            if filled[position] || base.eq_ignore_ascii_case(&REFERENCE_PAD) {
+                continue;
+            }
+            genome[position] = *base;
+            filled[position] = true;
+        }
+    }
+
+    genome
+}
+
+/// The symbols tallied at each position, in the order ties between them are broken.
+const SYMBOLS: [u8; 6] = [b'A', b'C', b'G', b'T', b'N', DELETION];
+
+/// Tallies of the symbols that reads aligned to a reference region carry at each of its
+/// positions, including [`DELETION`] for reads that skip over a position, from which a
+/// consensus for the region can be called. Each read's symbol counts once, or, for a
+/// quality-weighted consensus, by how likely it is to be correct.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsensusAccumulator {
+    /// For each reference position, the summed weight of each symbol in [`SYMBOLS`]
+    scores: Vec<[f64; SYMBOLS.len()]>,
+}
+
+impl ConsensusAccumulator {
+    /// Start tallying symbols for a reference region of `ref_len` bases.
+    pub fn new(ref_len: usize) -> Self {
+        ConsensusAccumulator {
+            scores: vec![[0.0; SYMBOLS.len()]; ref_len],
+        }
+    }
+
+    /// Tally a read already aligned to the reference region, with one symbol per reference
+    /// position, e.g., from [`align_to_reference`]. Positions holding [`REFERENCE_PAD`], whether
+    /// the read doesn't cover them or its base there is uncalled, are not tallied, and any other
+    /// ambiguous base is tallied as N.
+    pub fn add(&mut self, aligned: &[u8]) {
+        for (scores, symbol) in self.scores.iter_mut().zip(aligned) {
+            if let Some(slot) = symbol_slot(*symbol) {
+                scores[slot] += 1.0;
+            }
+        }
+    }
+
+    /// Tally a read already aligned to the reference region as in [`ConsensusAccumulator::add`],
+    /// but with each symbol counting for its weight at the same position, e.g., from
+    /// [`align_to_reference_weighted`], so that likely miscalls sway the consensus less.
+    pub fn add_weighted(&mut self, aligned: &[u8], weights: &[f64]) {
+        for ((scores, symbol), weight) in self.scores.iter_mut().zip(aligned).zip(weights) {
+            if let Some(slot) = symbol_slot(*symbol) {
+                scores[slot] += weight;
+            }
+        }
+    }
+
+    /// Call a consensus from the tallied reads. Positions where at least `min_deletion_freq` of
+    /// the tallied weight skips over them are left out, so that real deletions shorten the
+    /// consensus. Every other position takes the base with the most weight, with ties going to
+    /// the first of A, C, G, T, and N, or [`REFERENCE_PAD`] if no read covers it.
+    pub fn consensus(&self, min_deletion_freq: f64) -> Vec<u8> {
+        self.scores
+            .iter()
+            .filter_map(|scores| {
+                let depth: f64 = scores.iter().sum();
+                if depth <= 0.0 {
+                    return Some(REFERENCE_PAD);
+                }
+                let deletions = scores[SYMBOLS.len() - 1];
+                if deletions / depth >= min_deletion_freq {
+                    return None;
+                }
+
+                // the first symbol with the highest score wins
+                let (slot, _) = scores[..SYMBOLS.len() - 1].iter().enumerate().fold(
+                    (4, 0.0),
+                    |(best, most), (slot, score)| {
+                        if *score > most {
+                            (slot, *score)
+                        } else {
+                            (best, most)
+                        }
+                    },
+                );
+                Some(SYMBOLS[slot])
+            })
+            .collect()
+    }
+}
+
+/// The slot in [`SYMBOLS`] that a symbol is tallied in, or `None` for [`REFERENCE_PAD`], whether
+/// the read doesn't cover the position or its base there is uncalled. Any other ambiguous base
+/// is tallied as N.
+fn symbol_slot(symbol: u8) -> Option<usize> {
+    match symbol.to_ascii_uppercase() {
+        REFERENCE_PAD => None,
+        DELETION => Some(5),
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => Some(4),
+    }
+}
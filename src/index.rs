@@ -1,152 +1,552 @@
-use flate2::bufread::GzDecoder;
+use flate2::bufread::{GzDecoder, MultiGzDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use noodles::fasta::record::{Definition, Sequence};
 use noodles::fastq::Reader as FastqReader;
+use noodles::fastq::Record as FastqRecord;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{collections::HashMap, fs::File, io::BufReader};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 
 use crate::io::FastqGz;
-use crate::io::{Fastq, SupportedFormat};
+use crate::io::{io_selector, is_stdin, Fastq, InputType, SeqReader, SupportedFormat, GZIP_MAGIC};
 use crate::primers::AmpliconScheme;
-use crate::reads::sync_trimming;
+use crate::reads::{index_trimmed_records, FilterSettings, TrimSettings};
+use crate::record::{FindAmplicons, QualityOffset};
+
+/// A rough count of the bytes a hash map spends on each entry beyond the bytes of its key,
+/// covering the key's own header, its value, and the table's slack.
+pub const MAP_ENTRY_OVERHEAD: u64 = 48;
+
+/// The most unique sequences an index is pre-sized for, however many records are expected,
+/// since low-diversity inputs may carry millions of reads but only a handful of sequences.
+pub const MAX_PRESIZED_SEQS: usize = 1 << 20;
+
+/// A running estimate of the memory held by a growing structure, such as an index or the reads
+/// grouped for consensus calling, that stops a run with a clear error once it passes a limit,
+/// rather than letting the process be killed for running out of memory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryGuard {
+    /// The most bytes allowed, if there is a limit
+    limit: Option<u64>,
+
+    /// The bytes estimated to be in use so far
+    used: u64,
+}
+
+impl MemoryGuard {
+    /// Start tracking memory against an optional limit in bytes.
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryGuard { limit, used: 0 }
+    }
+
+    /// The bytes estimated to be in use so far.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Record that `bytes` more are in use by the named structure.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the total now exceeds the limit.
+    pub fn charge(&mut self, bytes: u64, structure: &str) -> Result<()> {
+        self.used += bytes;
+        match self.limit {
+            Some(limit) if self.used > limit => Err(eyre!(
+                "The {} grew to about {} bytes, past the --max-memory limit of {} bytes. The input is too diverse to hold in that much memory; raise --max-memory or split the input into smaller batches.",
+                structure,
+                self.used,
+                limit
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Options for writing an index and bounding the memory used to build it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IndexOptions {
+    /// Whether to gzip-compress the `.ampidx` file
+    pub compress: bool,
+
+    /// The most memory, in bytes, the index may be estimated to use while it is built
+    pub max_memory: Option<u64>,
+
+    /// The number of records in the input, if it was counted ahead of time, used to pre-size
+    /// the index's map of unique sequences
+    pub expected_records: Option<u64>,
+}
+
+/// Trim each record and count it into an index, one record at a time, so that only the index
+/// itself grows with the input, and stop with an error if it grows past the guard's limit.
+///
+/// # Errors
+///
+/// This function will return an error if the index grows past the guard's limit.
+pub async fn index_records<I>(
+    records: I,
+    scheme: &AmpliconScheme,
+    index: &mut IndexFormat,
+    guard: &mut MemoryGuard,
+) -> Result<()>
+where
+    I: IntoIterator<Item = FastqRecord>,
+{
+    for record in records {
+        let Some(hit) = record.find_amplicon(&scheme.scheme).await else {
+            continue;
+        };
+        let amplicon = hit.amplicon.clone();
+        if let Ok(Some(trimmed)) = record.trim_to_amplicon(hit).await {
+            guard.charge(index.add(&amplicon, &trimmed), "index")?;
+        }
+    }
+
+    Ok(())
+}
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct IndexFormat {
     hash: String,
-    pub unique_seqs: HashMap<Vec<u8>, f64>,
+
+    /// The number of trimmed reads carrying each unique sequence
+    pub unique_seqs: HashMap<Vec<u8>, u64>,
+
+    /// The total number of trimmed reads the index was built from
+    pub total: u64,
+
+    /// The number of trimmed reads carrying each unique sequence, grouped by the amplicon it
+    /// was trimmed from
+    pub amplicon_seqs: BTreeMap<String, HashMap<Vec<u8>, u64>>,
+
+    /// The total number of trimmed reads for each amplicon
+    pub amplicon_totals: BTreeMap<String, u64>,
+
+    /// The summed Phred quality of every read carrying each unique sequence, grouped by
+    /// amplicon, used to break ties between equally frequent sequences
+    pub amplicon_quals: BTreeMap<String, HashMap<Vec<u8>, u64>>,
+}
+
+impl IndexFormat {
+    /// An index built with the primer scheme hashed to `hash` that has not counted any reads.
+    pub fn empty(hash: String) -> Self {
+        IndexFormat {
+            hash,
+            unique_seqs: HashMap::new(),
+            total: 0,
+            amplicon_seqs: BTreeMap::new(),
+            amplicon_totals: BTreeMap::new(),
+            amplicon_quals: BTreeMap::new(),
+        }
+    }
+
+    /// An empty index, as in [`IndexFormat::empty`], whose map of unique sequences has room
+    /// for as many as `expected_records` entries, up to [`MAX_PRESIZED_SEQS`], so that it isn't
+    /// rehashed over and over as it grows.
+    pub fn presized(hash: String, expected_records: u64) -> Self {
+        let capacity = usize::try_from(expected_records)
+            .unwrap_or(usize::MAX)
+            .min(MAX_PRESIZED_SEQS);
+        IndexFormat {
+            unique_seqs: HashMap::with_capacity(capacity),
+            ..IndexFormat::empty(hash)
+        }
+    }
+
+    /// Count the unique sequences among a set of trimmed reads, both overall and per amplicon.
+    pub fn from_trimmed(hash: String, reads: &[(String, FastqRecord)]) -> Self {
+        let mut index = IndexFormat::presized(hash, reads.len() as u64);
+        for (amplicon, read) in reads {
+            index.add(amplicon, read);
+        }
+        index
+    }
+
+    /// Count one read trimmed to the named amplicon, returning roughly how many bytes the index
+    /// grew by, which is nothing unless the read's sequence is new to the amplicon.
+    pub fn add(&mut self, amplicon: &str, read: &FastqRecord) -> u64 {
+        let read_qual: u64 = read
+            .quality_scores()
+            .iter()
+            .map(|score| score.saturating_sub(QualityOffset::Phred33.value()) as u64)
+            .sum();
+        let seq = read.sequence();
+
+        // a sequence new to the amplicon is stored once per map, and is new overall unless
+        // another amplicon already carries it
+        let amplicon_seqs = self.amplicon_seqs.entry(amplicon.to_owned()).or_default();
+        let grown = match amplicon_seqs.get_mut(seq) {
+            Some(count) => {
+                *count += 1;
+                0
+            }
+            None => {
+                amplicon_seqs.insert(seq.to_owned(), 1);
+                let copies = if self.unique_seqs.contains_key(seq) {
+                    2
+                } else {
+                    3
+                };
+                copies * (seq.len() as u64 + MAP_ENTRY_OVERHEAD)
+            }
+        };
+        *self
+            .amplicon_quals
+            .entry(amplicon.to_owned())
+            .or_default()
+            .entry(seq.to_owned())
+            .or_insert(0) += read_qual;
+        *self.unique_seqs.entry(seq.to_owned()).or_insert(0) += 1;
+        *self.amplicon_totals.entry(amplicon.to_owned()).or_insert(0) += 1;
+        self.total += 1;
+
+        grown
+    }
+
+    /// The number of trimmed reads carrying `seq`, if it was seen at all.
+    pub fn count(&self, seq: &[u8]) -> Option<u64> {
+        self.unique_seqs.get(seq).copied()
+    }
+
+    /// The fraction of all trimmed reads carrying `seq`, if it was seen at all.
+    pub fn frequency(&self, seq: &[u8]) -> Option<f64> {
+        self.count(seq)
+            .map(|count| (count as f64) / (self.total as f64))
+    }
+
+    /// The fraction of the named amplicon's trimmed reads carrying `seq`, if it was seen in
+    /// that amplicon at all.
+    pub fn amplicon_frequency(&self, amplicon: &str, seq: &[u8]) -> Option<f64> {
+        let count = self.amplicon_seqs.get(amplicon)?.get(seq)?;
+        let total = self.amplicon_totals.get(amplicon)?;
+        Some((*count as f64) / (*total as f64))
+    }
+
+    /// The hash of the primer scheme the index was built with.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Serialize the index to a `.ampidx` file alongside the input it was built from,
+    /// gzip-compressed if requested.
+    fn write(&self, input_file: &Path, compress: bool) -> Result<()> {
+        let index_filename = format!("{}.ampidx", input_file.to_string_lossy());
+        match compress {
+            true => self.write_compressed_to(Path::new(&index_filename)),
+            false => self.write_to(Path::new(&index_filename)),
+        }
+    }
+
+    /// Serialize the index to the provided path.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index cannot be serialized or written.
+    pub fn write_to(&self, index_path: &Path) -> Result<()> {
+        let serialized_index = serde_cbor::to_vec(self)?;
+        let mut file = File::create(index_path)?;
+        file.write_all(&serialized_index)?;
+
+        Ok(())
+    }
+
+    /// Serialize the index to the provided path, compressed with gzip. Compressed indices are
+    /// read back by [`IndexFormat::read`] just like uncompressed ones.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index cannot be serialized or written.
+    pub fn write_compressed_to(&self, index_path: &Path) -> Result<()> {
+        let serialized_index = serde_cbor::to_vec(self)?;
+        let mut encoder = GzEncoder::new(File::create(index_path)?, Compression::default());
+        encoder.write_all(&serialized_index)?;
+        encoder.finish()?;
+
+        Ok(())
+    }
+
+    /// Deserialize an index from the provided path, decompressing it first if it starts with
+    /// the gzip magic bytes, which can never start an uncompressed index.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be read or decompressed or is not a
+    /// valid index.
+    pub fn read(index_path: &Path) -> Result<Self> {
+        let mut buffer = Vec::new();
+        File::open(index_path)?.read_to_end(&mut buffer)?;
+        if buffer.starts_with(&GZIP_MAGIC) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(buffer.as_slice()).read_to_end(&mut decompressed)?;
+            buffer = decompressed;
+        }
+        let index: IndexFormat = serde_cbor::from_slice(&buffer)?;
+        Ok(index)
+    }
+
+    /// Add the counts from another index into this one, e.g., to build a cohort-level index
+    /// from per-sample indices.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the two indices were built with different primer
+    /// schemes, as their counts would not be comparable.
+    pub fn merge(&mut self, other: IndexFormat) -> Result<()> {
+        if self.hash != other.hash {
+            return Err(eyre!(
+                "Indices built with different primer schemes cannot be merged."
+            ));
+        }
+
+        let add_counts = |into: &mut HashMap<Vec<u8>, u64>, from: HashMap<Vec<u8>, u64>| {
+            for (seq, count) in from {
+                *into.entry(seq).or_insert(0) += count;
+            }
+        };
+        add_counts(&mut self.unique_seqs, other.unique_seqs);
+        for (amplicon, seqs) in other.amplicon_seqs {
+            add_counts(self.amplicon_seqs.entry(amplicon).or_default(), seqs);
+        }
+        for (amplicon, quals) in other.amplicon_quals {
+            add_counts(self.amplicon_quals.entry(amplicon).or_default(), quals);
+        }
+        for (amplicon, total) in other.amplicon_totals {
+            *self.amplicon_totals.entry(amplicon).or_insert(0) += total;
+        }
+        self.total += other.total;
+
+        Ok(())
+    }
+
+    /// Return the most frequent unique sequence for each amplicon, a fast, alignment-free
+    /// stand-in for a full consensus. Because equal frequencies are common in small datasets,
+    /// ties are broken deterministically: first by the highest summed quality across the reads
+    /// carrying each sequence, and then by choosing the lexicographically smallest sequence.
+    pub fn representatives(&self) -> BTreeMap<String, Vec<u8>> {
+        self.amplicon_seqs
+            .iter()
+            .filter_map(|(amplicon, seqs)| {
+                let quals = self.amplicon_quals.get(amplicon);
+                let total_qual =
+                    |seq: &Vec<u8>| quals.and_then(|quals| quals.get(seq)).copied().unwrap_or(0);
+                seqs.iter()
+                    .max_by(|(seq_a, count_a), (seq_b, count_b)| {
+                        count_a
+                            .cmp(count_b)
+                            .then_with(|| total_qual(seq_a).cmp(&total_qual(seq_b)))
+                            .then_with(|| seq_b.cmp(seq_a))
+                    })
+                    .map(|(seq, _)| (amplicon.to_owned(), seq.to_owned()))
+            })
+            .collect()
+    }
+
+    /// Write the representative sequence for each amplicon to a FASTA file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the output FASTA cannot be written.
+    pub fn write_representatives(&self, output_path: &Path) -> Result<()> {
+        let mut writer = File::create(output_path)
+            .map(std::io::BufWriter::new)
+            .map(noodles::fasta::io::Writer::new)?;
+        for (amplicon, seq) in self.representatives() {
+            let record =
+                noodles::fasta::Record::new(Definition::new(amplicon, None), Sequence::from(seq));
+            writer.write_record(&record)?;
+        }
+
+        Ok(())
+    }
 }
 
 pub trait Index: SupportedFormat {
     type Reader: Unpin + Send;
+
+    /// Count the unique trimmed sequences in the reads and write them to an uncompressed
+    /// `.ampidx` file alongside the input.
     fn index(
         self,
         reader: Self::Reader,
         scheme: AmpliconScheme,
         input_file: &Path,
-    ) -> impl futures::Future<Output = Result<()>>;
-    fn load_index(
-        &self,
+    ) -> impl futures::Future<Output = Result<IndexFormat>>
+    where
+        Self: Sized,
+    {
+        self.index_with(reader, scheme, input_file, IndexOptions::default())
+    }
+
+    /// Index the reads as in [`Index::index`], compressing the `.ampidx` file and bounding
+    /// the memory used to build it as requested in `options`.
+    fn index_with(
+        self,
+        reader: Self::Reader,
+        scheme: AmpliconScheme,
         input_file: &Path,
-        current_hash: &str,
-    ) -> Result<Option<HashMap<Vec<u8>, f64>>> {
+        options: IndexOptions,
+    ) -> impl futures::Future<Output = Result<IndexFormat>>;
+
+    fn load_index(&self, input_file: &Path, current_hash: &str) -> Result<Option<IndexFormat>> {
         let index_filename = format!("{}.ampidx", input_file.to_string_lossy());
-        let index_file = File::open(&index_filename);
-        let potential_index = match index_file {
-            Err(_) => None,
-            Ok(mut file) => {
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                let index: IndexFormat = serde_cbor::from_slice(&buffer)?;
-                match index.hash.eq(current_hash) {
-                    true => Some(index),
-                    false => {
-                        eprintln!(
-                            "An index for the current sample, {}, was found, but it was built with a different primer scheme. As such, filtering cannot be performed. Please rerun indexing before attempting to filter.",
-                            &index_filename
-                        );
-                        None
-                    }
-                }
-            }
-        };
-        let unique_seqs = if let Some(index) = potential_index {
-            Some(index.unique_seqs)
-        } else {
-            None
-        };
+        match Path::new(&index_filename).exists() {
+            true => load_index_file(Path::new(&index_filename), current_hash),
+            false => Ok(None),
+        }
+    }
+}
 
-        Ok(unique_seqs)
+/// Load an index from an arbitrary path, such as a merged cohort index, returning `None` if it
+/// was built with a different primer scheme than the one in use.
+///
+/// # Errors
+///
+/// This function will return an error if the index file cannot be read.
+pub fn load_index_file(index_path: &Path, current_hash: &str) -> Result<Option<IndexFormat>> {
+    let index = IndexFormat::read(index_path)?;
+    match index.hash.eq(current_hash) {
+        true => Ok(Some(index)),
+        false => {
+            eprintln!(
+                "An index for the current sample, {}, was found, but it was built with a different primer scheme. As such, filtering cannot be performed. Please rerun indexing before attempting to filter.",
+                index_path.display()
+            );
+            Ok(None)
+        }
     }
 }
 
+/// Merge several indices built with the same primer scheme by summing their counts.
+///
+/// # Errors
+///
+/// This function will return an error if no indices are provided, if any cannot be read, or if
+/// they were built with different primer schemes.
+pub fn merge_indices(index_paths: &[PathBuf]) -> Result<IndexFormat> {
+    let mut paths = index_paths.iter();
+    let first = paths
+        .next()
+        .ok_or_else(|| eyre!("At least one index must be provided for merging."))?;
+    let mut merged = IndexFormat::read(first)?;
+    for path in paths {
+        merged
+            .merge(IndexFormat::read(path)?)
+            .map_err(|err| eyre!("Could not merge {}: {}", path.display(), err))?;
+    }
+
+    Ok(merged)
+}
+
+/// Count the unique trimmed sequences across several inputs without writing an index file,
+/// trimming them with the same `settings` and length filters as the trimming pass that
+/// follows. This is the first pass of trimming with frequencies computed from the inputs
+/// themselves, so the filter in the second pass always matches exactly the reads being
+/// trimmed. With `max_memory`, counting stops with an error once the counts are estimated to
+/// use more than that many bytes.
+///
+/// # Errors
+///
+/// This function will return an error if an input is standard input, which cannot be read a
+/// second time, is in an unsupported format, or cannot be read, or if the counts grow past
+/// `max_memory`.
+pub async fn index_inputs(
+    inputs: &[PathBuf],
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    max_memory: Option<u64>,
+) -> Result<IndexFormat> {
+    let mut index = IndexFormat::empty(scheme.hash_amplicon_scheme()?);
+    let mut guard = MemoryGuard::new(max_memory);
+    for input in inputs {
+        if is_stdin(input) {
+            return Err(eyre!(
+                "Frequencies cannot be computed in a separate pass over standard input, since it can only be read once. Save the reads to a file, or build an index with `amplicon-tk index` and filter with it instead."
+            ));
+        }
+
+        match io_selector(input).await? {
+            InputType::FASTQGZ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                let records = reader.records();
+                index_trimmed_records(records, scheme, filters, settings, &mut index, &mut guard)
+                    .await?;
+            }
+            InputType::FASTQ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                let records = reader.records();
+                index_trimmed_records(records, scheme, filters, settings, &mut index, &mut guard)
+                    .await?;
+            }
+            InputType::BAM(_) => {
+                return Err(eyre!(
+                    "Unaligned BAM inputs are not yet supported but will be soon! Found {:?}.",
+                    input
+                ))
+            }
+        }
+    }
+
+    Ok(index)
+}
+
 impl Index for Fastq {
     type Reader = FastqReader<BufReader<File>>;
-    async fn index(
+    async fn index_with(
         self,
         mut reader: Self::Reader,
         scheme: AmpliconScheme,
         input_file: &Path,
-    ) -> Result<()> {
+        options: IndexOptions,
+    ) -> Result<IndexFormat> {
         // hash the amplicon scheme
         let hash = scheme.hash_amplicon_scheme()?;
 
-        // collect the reads into an eager vector
+        // lazily read the records, trimming and counting the unique sequences as they come
         let reads = reader.records().filter_map(|record| record.ok());
+        let mut format = match options.expected_records {
+            Some(expected_records) => IndexFormat::presized(hash, expected_records),
+            None => IndexFormat::empty(hash),
+        };
+        let mut guard = MemoryGuard::new(options.max_memory);
+        index_records(reads, &scheme, &mut format, &mut guard).await?;
 
-        // trim them down based on the amplicon scheme
-        let reads = sync_trimming(reads, &scheme).await?;
-
-        // use the trimmed sequences to find and count unique amplicon sequences
-        let (seq_counts, total_count) =
-            reads
-                .iter()
-                .fold((HashMap::new(), 0), |(mut counts, read_count), read| {
-                    *counts.entry(read.sequence().to_owned()).or_insert(0) += 1;
-                    (counts, read_count + 1)
-                });
-
-        // compute the prevalence for each sequence
-        let unique_seqs: HashMap<Vec<u8>, f64> = seq_counts
-            .into_iter()
-            .map(|(seq, count)| (seq, (count as f64) / (total_count as f64)))
-            .collect();
-        let format = IndexFormat { hash, unique_seqs };
-
-        let serialized_index = serde_cbor::to_vec(&format)?;
+        // write the counts to the index file
+        format.write(input_file, options.compress)?;
 
-        let index_filename = format!("{}.ampidx", input_file.to_string_lossy());
-        let mut file = File::create(index_filename)?;
-        file.write_all(&serialized_index)?;
-
-        Ok(())
+        Ok(format)
     }
 }
 
 impl Index for FastqGz {
-    type Reader = FastqReader<BufReader<GzDecoder<BufReader<File>>>>;
-    async fn index(
+    type Reader = FastqReader<BufReader<MultiGzDecoder<BufReader<File>>>>;
+    async fn index_with(
         self,
         mut reader: Self::Reader,
         scheme: AmpliconScheme,
         input_file: &Path,
-    ) -> Result<()> {
+        options: IndexOptions,
+    ) -> Result<IndexFormat> {
         // hash the amplicon scheme
-        let encoded_scheme: Vec<u8> = bincode::serialize(&scheme)?;
-        let mut hasher = Sha256::new();
-        hasher.update(&encoded_scheme);
-        let hash = format!("{:?}", hasher.finalize());
+        let hash = scheme.hash_amplicon_scheme()?;
 
-        // collect the reads into a lazy iterator
+        // lazily read the records, trimming and counting the unique sequences as they come
         let reads = reader.records().filter_map(|record| record.ok());
+        let mut format = match options.expected_records {
+            Some(expected_records) => IndexFormat::presized(hash, expected_records),
+            None => IndexFormat::empty(hash),
+        };
+        let mut guard = MemoryGuard::new(options.max_memory);
+        index_records(reads, &scheme, &mut format, &mut guard).await?;
 
-        // trim them down based on the amplicon scheme
-        let reads = sync_trimming(reads, &scheme).await?;
-
-        // use the trimmed sequences to find and count unique amplicon sequences
-        let (seq_counts, total_count) =
-            reads
-                .iter()
-                .fold((HashMap::new(), 0), |(mut counts, read_count), read| {
-                    *counts.entry(read.sequence().to_owned()).or_insert(0) += 1;
-                    (counts, read_count + 1)
-                });
-
-        // compute the prevalence for each sequence
-        let unique_seqs: HashMap<Vec<u8>, f64> = seq_counts
-            .into_iter()
-            .map(|(seq, count)| (seq, (count as f64) / (total_count as f64)))
-            .collect();
-        let format = IndexFormat { hash, unique_seqs };
-
-        let serialized = serde_cbor::to_vec(&format)?;
-
-        let index_filename = format!("{}.ampidx", input_file.to_string_lossy());
-        let mut file = File::create(index_filename)?;
-        file.write_all(&serialized)?;
+        // write the counts to the index file
+        format.write(input_file, options.compress)?;
 
-        Ok(())
+        Ok(format)
     }
 }
@@ -7,7 +7,11 @@
 
 // #![warn(missing_docs)]
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::task::{ready, Context, Poll};
 
 use async_compression::tokio::bufread::GzipDecoder;
 use async_compression::tokio::write::GzipEncoder;
@@ -18,17 +22,35 @@ use noodles::bam::AsyncWriter as BamWriter;
 use noodles::bed::io::Reader as BedReader;
 use noodles::bgzf::AsyncReader as BgzfReader;
 use noodles::bgzf::AsyncWriter as BgzfWriter;
+use noodles::fasta::io::IndexedReader as IndexedFastaReader;
 use noodles::fasta::io::Reader as FastaReader;
 use noodles::fastq::AsyncReader as FastqReader;
 use noodles::fastq::AsyncWriter as FastqWriter;
-use tokio::io::AsyncWriteExt;
+use noodles::fastq::Record as FastqRecord;
+use noodles::sam;
+use noodles::sam::alignment::record::{data::field::Tag, Flags};
+use noodles::sam::alignment::record_buf::{
+    data::field::Value, Name as SamName, QualityScores as SamQualityScores, Sequence as SamSequence,
+};
+use noodles::sam::alignment::RecordBuf;
+use noodles::sam::AsyncWriter as SamAsyncWriter;
 use tokio::io::BufWriter;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
 use tokio::{fs::File, io::BufReader};
 
+use crate::record::QualityOffset;
+
 // supported sequencing read formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FastqGz;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Fastq;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Bam;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sam;
 
 pub enum InputType {
     FASTQGZ(FastqGz),
@@ -37,6 +59,34 @@ pub enum InputType {
 }
 
 impl InputType {
+    /// Every supported read input format, in the order they are listed to users.
+    pub fn all() -> [InputType; 3] {
+        [
+            InputType::FASTQGZ(FastqGz),
+            InputType::FASTQ(Fastq),
+            InputType::BAM(Bam),
+        ]
+    }
+
+    /// The short name of the format, e.g. `fastq.gz`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputType::FASTQGZ(_) => "fastq.gz",
+            InputType::FASTQ(_) => "fastq",
+            InputType::BAM(_) => "bam",
+        }
+    }
+
+    /// The final file extensions, without the leading dot, that inputs in this format are
+    /// recognized by.
+    pub fn detected_extensions(&self) -> &'static [&'static str] {
+        match self {
+            InputType::FASTQGZ(_) => &["gz"],
+            InputType::FASTQ(_) => &["fastq"],
+            InputType::BAM(_) => &["bam"],
+        }
+    }
+
     pub fn extension(&self) -> String {
         match self {
             InputType::FASTQGZ(_) => String::from(".fastq.gz"),
@@ -46,10 +96,561 @@ impl InputType {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OutputType {
     FASTQGZ(FastqGz),
     FASTQ(Fastq),
     BAM(Bam),
+    SAM(Sam),
+}
+
+impl OutputType {
+    /// Every supported read output format, in the order they are listed to users.
+    pub fn all() -> [OutputType; 4] {
+        [
+            OutputType::FASTQGZ(FastqGz),
+            OutputType::FASTQ(Fastq),
+            OutputType::BAM(Bam),
+            OutputType::SAM(Sam),
+        ]
+    }
+
+    /// The short name of the format, e.g. `fastq.gz`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputType::FASTQGZ(_) => "fastq.gz",
+            OutputType::FASTQ(_) => "fastq",
+            OutputType::BAM(_) => "bam",
+            OutputType::SAM(_) => "sam",
+        }
+    }
+
+    /// The extension given to outputs written in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputType::FASTQGZ(_) => ".fastq.gz",
+            OutputType::FASTQ(_) => ".fastq",
+            OutputType::BAM(_) => ".bam",
+            OutputType::SAM(_) => ".sam",
+        }
+    }
+
+    /// Look up an output format by its short name, e.g. `fastq.gz`.
+    pub fn from_name(name: &str) -> Option<OutputType> {
+        OutputType::all()
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+
+    /// Create a writer for an output in this format at `output_path`, for records whose quality
+    /// scores are encoded with `offset`. Outputs in different formats can be written side by
+    /// side, and each must be closed with [`finalize_output`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the format cannot hold trimmed reads or if the
+    /// output file cannot be created.
+    pub async fn output_writer(
+        &self,
+        output_path: &Path,
+        offset: QualityOffset,
+    ) -> Result<ReadOutput> {
+        let writer = BufWriter::new(File::create(output_path).await?);
+        let output = match self {
+            OutputType::FASTQGZ(_) => {
+                ReadOutput::Fastq(FastqWriter::new(Box::new(GzipEncoder::new(writer))))
+            }
+            OutputType::FASTQ(_) => ReadOutput::Fastq(FastqWriter::new(Box::new(writer))),
+            OutputType::SAM(_) => ReadOutput::Sam(Box::new(SamWriter::new(writer, offset)?)),
+            OutputType::BAM(_) => {
+                return Err(eyre!(
+                "Unaligned BAM outputs are not yet supported but will be soon! Requested for {:?}.",
+                output_path
+            ))
+            }
+        };
+
+        Ok(output)
+    }
+}
+
+/// A writer for an output in any format that trimmed reads can be written in.
+pub enum ReadOutput {
+    /// An output in a FASTQ-based format, compressed or not
+    Fastq(FastqWriter<Box<dyn AsyncWrite + Unpin + Send>>),
+
+    /// An output of unaligned SAM records, boxed since its writer carries the SAM header
+    Sam(Box<SamWriter<BufWriter<File>>>),
+}
+
+impl ReadSink for ReadOutput {
+    async fn write_read(&mut self, record: &FastqRecord) -> Result<()> {
+        match self {
+            ReadOutput::Fastq(writer) => writer.write_read(record).await,
+            ReadOutput::Sam(writer) => writer.write_read(record).await,
+        }
+    }
+}
+
+/// Flush and close a writer from [`OutputType::output_writer`], writing the end of the stream
+/// for compressed formats so that the output is not corrupted.
+///
+/// # Errors
+///
+/// This function will return an error if the remaining contents cannot be written.
+pub async fn finalize_output(writer: ReadOutput) -> Result<()> {
+    match writer {
+        ReadOutput::Fastq(writer) => {
+            let mut final_contents = writer.into_inner();
+            final_contents.shutdown().await?;
+        }
+        ReadOutput::Sam(writer) => writer.finish().await?,
+    }
+    Ok(())
+}
+
+/// Where an [`AutoCompressWriter`] is in deciding how to write its output.
+#[derive(Debug)]
+enum AutoCompressState {
+    /// Everything written so far is held in memory, along with how many lines it holds, since it
+    /// still fits under the threshold
+    Buffering { buffered: Vec<u8>, lines: usize },
+
+    /// The output outgrew the threshold and is being compressed, starting with whatever was
+    /// buffered, of which the first `flushed` bytes have been handed to the encoder. The
+    /// encoder is boxed, since it is much larger than the buffer it replaces
+    Compressing {
+        encoder: Box<GzipEncoder<BufWriter<File>>>,
+        pending: Vec<u8>,
+        flushed: usize,
+    },
+
+    /// The output has been written out and closed
+    Done,
+}
+
+/// The number of lines each FASTQ record is written on.
+const FASTQ_RECORD_LINES: usize = 4;
+
+/// A FASTQ output that is only compressed if it turns out to be large: up to `threshold`
+/// records are held in memory, and if the output is shut down before outgrowing them, they are
+/// written uncompressed to the FASTQ path. Otherwise, everything is gzip-compressed into the
+/// FASTQ.gz path from then on. Either way, exactly one of the two files is written, and it is
+/// only complete once the writer is shut down.
+#[derive(Debug)]
+pub struct AutoCompressWriter {
+    /// The most lines held in memory before switching to compression
+    max_lines: usize,
+
+    /// Where the output is written if it stays under the threshold
+    uncompressed_path: PathBuf,
+
+    /// Where the output is written if it outgrows the threshold
+    compressed_path: PathBuf,
+
+    /// Whether the output is still buffered, being compressed, or done
+    state: AutoCompressState,
+}
+
+impl AutoCompressWriter {
+    /// Prepare an output at `stem` plus `.fastq` or `.fastq.gz`, depending on whether more
+    /// than `threshold` records are written to it.
+    pub fn new(stem: &Path, threshold: usize) -> Self {
+        let with_extension = |extension: &str| {
+            let mut path = stem.as_os_str().to_owned();
+            path.push(extension);
+            PathBuf::from(path)
+        };
+        AutoCompressWriter {
+            max_lines: threshold.saturating_mul(FASTQ_RECORD_LINES),
+            uncompressed_path: with_extension(OutputType::FASTQ(Fastq).extension()),
+            compressed_path: with_extension(OutputType::FASTQGZ(FastqGz).extension()),
+            state: AutoCompressState::Buffering {
+                buffered: Vec::new(),
+                lines: 0,
+            },
+        }
+    }
+
+    /// The path the output will be written to, given what has been written so far.
+    pub fn path(&self) -> &Path {
+        match self.state {
+            AutoCompressState::Compressing { .. } => &self.compressed_path,
+            _ => &self.uncompressed_path,
+        }
+    }
+
+    /// Hand any bytes buffered before the switch to compression on to the encoder.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let AutoCompressState::Compressing {
+            encoder,
+            pending,
+            flushed,
+        } = &mut self.state
+        else {
+            return Poll::Ready(Ok(()));
+        };
+        while *flushed < pending.len() {
+            let written = ready!(Pin::new(&mut *encoder).poll_write(cx, &pending[*flushed..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            *flushed += written;
+        }
+        pending.clear();
+        *flushed = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AutoCompressWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let AutoCompressState::Buffering { buffered, lines } = &mut this.state {
+            let new_lines = memchr::memchr_iter(b'\n', buf).count();
+            if *lines + new_lines <= this.max_lines {
+                buffered.extend_from_slice(buf);
+                *lines += new_lines;
+                return Poll::Ready(Ok(buf.len()));
+            }
+
+            // the output has outgrown the threshold, so compress everything from here on,
+            // starting with what was buffered. The file is created synchronously, since
+            // creating it is quick and happens only once
+            let file = std::fs::File::create(&this.compressed_path)?;
+            this.state = AutoCompressState::Compressing {
+                encoder: Box::new(GzipEncoder::new(BufWriter::new(File::from_std(file)))),
+                pending: std::mem::take(buffered),
+                flushed: 0,
+            };
+        }
+
+        ready!(this.poll_pending(cx))?;
+        match &mut this.state {
+            AutoCompressState::Compressing { encoder, .. } => Pin::new(encoder).poll_write(cx, buf),
+            _ => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "The output was written to after it was shut down.",
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // buffered output isn't written anywhere until it is known to stay small
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        match &mut this.state {
+            AutoCompressState::Compressing { encoder, .. } => Pin::new(encoder).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        match &mut this.state {
+            AutoCompressState::Buffering { buffered, .. } => {
+                // the output stayed under the threshold, so it is written as it is
+                std::fs::write(&this.uncompressed_path, buffered)?;
+                this.state = AutoCompressState::Done;
+                Poll::Ready(Ok(()))
+            }
+            AutoCompressState::Compressing { encoder, .. } => Pin::new(encoder).poll_shutdown(cx),
+            AutoCompressState::Done => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// A FASTQ output split across numbered files of at most `reads_per_file` records each, e.g.
+/// `trimmed.000.fastq.gz`, `trimmed.001.fastq.gz`, and so on. Each file is closed, including
+/// writing the end of the stream for compressed formats, before the next one is started, and a
+/// new file is only started once there is a record to write to it.
+pub struct ChunkedWriter {
+    /// The path of every file, without its number or extension
+    stem: PathBuf,
+
+    /// The format each file is written in
+    format: OutputType,
+
+    /// The most lines written to each file before moving on to the next
+    max_lines: usize,
+
+    /// The number of lines written to the current file
+    lines: usize,
+
+    /// The number of files started so far
+    chunks: usize,
+
+    /// The file being written, if one has been started
+    current: Option<Box<dyn AsyncWrite + Unpin + Send>>,
+
+    /// A full file that is still being closed
+    closing: Option<Box<dyn AsyncWrite + Unpin + Send>>,
+}
+
+impl ChunkedWriter {
+    /// Prepare an output split across files at `stem`, plus their number and the extension of
+    /// `format`, each holding at most `reads_per_file` records.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `format` cannot hold FASTQ records or if
+    /// `reads_per_file` is zero.
+    pub fn new(stem: &Path, format: OutputType, reads_per_file: usize) -> Result<Self> {
+        match format {
+            OutputType::BAM(_) => {
+                return Err(eyre!(
+                "Unaligned BAM outputs are not yet supported but will be soon! Requested for {:?}.",
+                stem
+            ))
+            }
+            OutputType::SAM(_) => {
+                return Err(eyre!(
+                    "SAM outputs can't be split across files. Requested for {:?}.",
+                    stem
+                ))
+            }
+            OutputType::FASTQGZ(_) | OutputType::FASTQ(_) => {}
+        }
+        if reads_per_file == 0 {
+            return Err(eyre!("Each output file must hold at least one read."));
+        }
+        Ok(ChunkedWriter {
+            stem: stem.to_path_buf(),
+            format,
+            max_lines: reads_per_file.saturating_mul(FASTQ_RECORD_LINES),
+            lines: 0,
+            chunks: 0,
+            current: None,
+            closing: None,
+        })
+    }
+
+    /// The path of the file numbered `chunk`, counting from zero.
+    pub fn path_for(&self, chunk: usize) -> PathBuf {
+        let mut path = self.stem.as_os_str().to_owned();
+        path.push(format!(".{chunk:03}{}", self.format.extension()));
+        PathBuf::from(path)
+    }
+
+    /// The number of files started so far.
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// Start the next file. It is created synchronously, since creating it is quick and happens
+    /// only once per file.
+    fn start_chunk(&mut self) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        let file = std::fs::File::create(self.path_for(self.chunks))?;
+        let writer = BufWriter::new(File::from_std(file));
+        self.chunks += 1;
+        self.lines = 0;
+        Ok(match self.format {
+            OutputType::FASTQGZ(_) => Box::new(GzipEncoder::new(writer)),
+            _ => Box::new(writer),
+        })
+    }
+
+    /// Finish closing the last full file, if there is one.
+    fn poll_closing(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(closing) = self.closing.as_mut() {
+            ready!(Pin::new(closing).poll_shutdown(cx))?;
+            self.closing = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ChunkedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_closing(cx))?;
+        if this.current.is_none() {
+            this.current = Some(this.start_chunk()?);
+        }
+
+        // write no further than the line that fills the current file, so that the rest goes to
+        // the next one
+        let remaining = this.max_lines - this.lines;
+        let end = memchr::memchr_iter(b'\n', buf)
+            .nth(remaining - 1)
+            .map_or(buf.len(), |position| position + 1);
+        let Some(current) = this.current.as_mut() else {
+            return Poll::Ready(Ok(0));
+        };
+        let written = ready!(Pin::new(current).poll_write(cx, &buf[..end]))?;
+        this.lines += memchr::memchr_iter(b'\n', &buf[..written]).count();
+        if this.lines >= this.max_lines {
+            this.closing = this.current.take();
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_closing(cx))?;
+        match this.current.as_mut() {
+            Some(current) => Pin::new(current).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_closing(cx))?;
+        if let Some(current) = this.current.as_mut() {
+            ready!(Pin::new(current).poll_shutdown(cx))?;
+            this.current = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The header written at the top of every SAM output, which declares no reference sequences
+/// since every record in it is unaligned.
+pub const UNALIGNED_SAM_HEADER: &str = "@HD\tVN:1.6\tSO:unknown\n";
+
+/// A destination for trimmed reads, written one FASTQ record at a time whether they are kept
+/// as FASTQ or converted into another format.
+pub trait ReadSink {
+    /// Write one record.
+    fn write_read(&mut self, record: &FastqRecord) -> impl futures::Future<Output = Result<()>>;
+}
+
+impl<W: AsyncWrite + Unpin> ReadSink for FastqWriter<W> {
+    async fn write_read(&mut self, record: &FastqRecord) -> Result<()> {
+        self.write_record(record).await?;
+        Ok(())
+    }
+}
+
+/// A writer of FASTQ records as unaligned SAM records, after a minimal header, e.g., for
+/// debugging or piping into `samtools`. Each record's name becomes its QNAME and any
+/// description is kept under the `CO` tag, as `samtools import` does, and its quality scores are
+/// decoded with the offset they were encoded with, since SAM holds raw Phred scores.
+pub struct SamWriter<W: AsyncWrite> {
+    /// Where the SAM records are written
+    inner: SamAsyncWriter<W>,
+
+    /// The header of the output, from [`UNALIGNED_SAM_HEADER`]
+    header: sam::Header,
+
+    /// The offset that the quality scores of records to write are encoded with
+    offset: QualityOffset,
+
+    /// Whether the header has been written yet
+    started: bool,
+}
+
+impl<W: AsyncWrite + Unpin> SamWriter<W> {
+    /// Write SAM to `inner`, starting with [`UNALIGNED_SAM_HEADER`], decoding the quality scores
+    /// of records with `offset`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the header cannot be parsed.
+    pub fn new(inner: W, offset: QualityOffset) -> Result<Self> {
+        Ok(SamWriter {
+            inner: SamAsyncWriter::new(inner),
+            header: UNALIGNED_SAM_HEADER.parse()?,
+            offset,
+            started: false,
+        })
+    }
+
+    /// Write the header, unless it has already been written.
+    async fn start(&mut self) -> Result<()> {
+        if !self.started {
+            self.inner.write_header(&self.header).await?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    /// Write a FASTQ record as an unaligned SAM record, with only its name, the unmapped flag,
+    /// its bases and quality scores, and its description, if it has one, and every alignment
+    /// field left unset.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the record's quality scores cannot be decoded or if
+    /// it cannot be written.
+    pub async fn write_record(&mut self, record: &FastqRecord) -> Result<()> {
+        self.start().await?;
+        let mut sam_record = RecordBuf::builder()
+            .set_name(SamName::from(record.name()))
+            .set_flags(Flags::UNMAPPED)
+            .set_sequence(SamSequence::from(record.sequence().to_vec()))
+            .set_quality_scores(SamQualityScores::from(
+                self.offset.decode(record.quality_scores())?,
+            ))
+            .build();
+        if !record.description().is_empty() {
+            sam_record.data_mut().insert(
+                Tag::COMMENT,
+                Value::String(record.description().to_vec().into()),
+            );
+        }
+        self.inner
+            .write_alignment_record(&self.header, &sam_record)
+            .await?;
+        Ok(())
+    }
+
+    /// Write the header, if no records were written, and close the output.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the remaining contents cannot be written.
+    pub async fn finish(mut self) -> Result<()> {
+        self.start().await?;
+        self.inner.into_inner().shutdown().await?;
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ReadSink for SamWriter<W> {
+    async fn write_read(&mut self, record: &FastqRecord) -> Result<()> {
+        self.write_record(record).await
+    }
+}
+
+/// Describe every read format that can be read or written, along with the extensions inputs
+/// are recognized by and the extensions outputs are given.
+pub fn list_formats() -> String {
+    let mut listing = String::from("Input formats:\n");
+    for format in InputType::all() {
+        let extensions = format
+            .detected_extensions()
+            .iter()
+            .map(|ext| format!(".{ext}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        listing.push_str(&format!(
+            "  {:<10} detected from {}\n",
+            format.name(),
+            extensions
+        ));
+    }
+    listing.push_str("Output formats:\n");
+    for format in OutputType::all() {
+        listing.push_str(&format!(
+            "  {:<10} written as {}\n",
+            format.name(),
+            format.extension()
+        ));
+    }
+
+    listing
 }
 
 // supported input primer and reference formats
@@ -66,6 +667,7 @@ pub trait SupportedFormat {}
 impl SupportedFormat for FastqGz {}
 impl SupportedFormat for Fastq {}
 impl SupportedFormat for Bam {}
+impl SupportedFormat for Sam {}
 
 pub trait PrimerFormat {}
 impl PrimerFormat for Bed {}
@@ -81,18 +683,73 @@ pub trait SeqReader {
 
 impl SeqReader for FastqGz {
     type Format = FastqGz;
-    type Reader = FastqReader<BufReader<GzipDecoder<BufReader<File>>>>;
+    type Reader = FastqReader<GzipReader<BufReader<File>>>;
     async fn read_reads(&self, input_path: &Path) -> Result<Self::Reader> {
         let input_file = File::open(input_path).await?;
         let reader = BufReader::new(input_file);
-        let decoder = GzipDecoder::new(reader);
-        let decode_reader = BufReader::new(decoder);
-        let fastq = FastqReader::new(decode_reader);
+        let fastq = FastqReader::new(gzip_reader(reader));
 
         Ok(fastq)
     }
 }
 
+/// The decompressed bytes of a gzip stream, as read by [`gzip_reader`].
+pub type GzipReader<R> = BufReader<TruncationCheck<GzipDecoder<R>>>;
+
+/// Decompress a gzip stream. Files concatenated with e.g. `cat a.fastq.gz b.fastq.gz` hold one
+/// gzip member per original file, so decoding carries on past the end of the first, and a
+/// stream that ends partway through a member is reported as truncated by [`TruncationCheck`].
+pub fn gzip_reader<R: AsyncBufRead + Unpin>(reader: R) -> GzipReader<R> {
+    let mut decoder = GzipDecoder::new(reader);
+    decoder.multiple_members(true);
+    BufReader::new(TruncationCheck { inner: decoder })
+}
+
+/// Passes reads through to a decompressing reader, but reports the stream ending partway
+/// through as [`TruncatedInput`] rather than as an unexpected end of file. FASTQ parsers take
+/// an unexpected end of file before a record to be the end of the input, so a stream cut off
+/// between two records would otherwise pass for a complete one.
+pub struct TruncationCheck<R> {
+    inner: R,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TruncationCheck<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)) {
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Poll::Ready(Err(
+                std::io::Error::new(std::io::ErrorKind::InvalidData, TruncatedInput(error)),
+            )),
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+/// The error behind a compressed input that ends partway through, as reported by
+/// [`TruncationCheck`].
+#[derive(Debug)]
+pub struct TruncatedInput(std::io::Error);
+
+impl std::fmt::Display for TruncatedInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TruncatedInput {}
+
+/// Whether reading an input failed because it ended partway through a record or a compressed
+/// stream.
+pub fn is_truncation(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::UnexpectedEof
+        || error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<TruncatedInput>())
+}
+
 impl SeqReader for Fastq {
     type Format = Fastq;
     type Reader = FastqReader<BufReader<File>>;
@@ -105,6 +762,28 @@ impl SeqReader for Fastq {
     }
 }
 
+/// Readers that parse their input into a stream of records, which is what the [`Init`] readers
+/// hand to trimming in [`crate::reads::Trimming`].
+pub trait RecordParser {
+    /// The type of record parsed from the input
+    type Record;
+
+    /// Parse the input into a stream of records, or of the errors met reading them, in the
+    /// order they appear.
+    fn parse_records(
+        &mut self,
+    ) -> impl futures::Stream<Item = std::io::Result<Self::Record>> + Unpin + '_;
+}
+
+impl<R: AsyncBufRead + Unpin> RecordParser for FastqReader<R> {
+    type Record = FastqRecord;
+    fn parse_records(
+        &mut self,
+    ) -> impl futures::Stream<Item = std::io::Result<FastqRecord>> + Unpin + '_ {
+        self.records()
+    }
+}
+
 impl SeqReader for Bam {
     type Format = Bam;
     type Reader = BamReader<BgzfReader<File>>;
@@ -136,11 +815,22 @@ impl PrimerReader for Bed {
 
 pub trait RefReader: RefFormat {
     type Reader;
+    type IndexedReader;
     fn read_ref(&self, input_path: &Path) -> Result<Self::Reader>;
+
+    /// Open a reference for random access by coordinate through its index, or `None` if it
+    /// has no index and `create_index` is not set. With `create_index`, a missing index is
+    /// built and written next to the reference first.
+    fn read_indexed_ref(
+        &self,
+        input_path: &Path,
+        create_index: bool,
+    ) -> Result<Option<Self::IndexedReader>>;
 }
 
 impl RefReader for Fasta {
     type Reader = FastaReader<std::io::BufReader<std::fs::File>>;
+    type IndexedReader = IndexedFastaReader<std::io::BufReader<std::fs::File>>;
     fn read_ref(&self, input_path: &Path) -> Result<Self::Reader> {
         let reader = std::fs::File::open(input_path)
             .map(std::io::BufReader::new)
@@ -148,6 +838,30 @@ impl RefReader for Fasta {
 
         Ok(reader)
     }
+
+    fn read_indexed_ref(
+        &self,
+        input_path: &Path,
+        create_index: bool,
+    ) -> Result<Option<Self::IndexedReader>> {
+        let mut index_path = input_path.as_os_str().to_owned();
+        index_path.push(".fai");
+        let index_path = PathBuf::from(index_path);
+
+        let index = if index_path.exists() {
+            noodles::fasta::fai::read(&index_path)?
+        } else if create_index {
+            let index = noodles::fasta::index(input_path)?;
+            let mut writer = noodles::fasta::fai::Writer::new(std::fs::File::create(&index_path)?);
+            writer.write_index(&index)?;
+            index
+        } else {
+            return Ok(None);
+        };
+
+        let reader = std::fs::File::open(input_path).map(std::io::BufReader::new)?;
+        Ok(Some(IndexedFastaReader::new(reader, index)))
+    }
 }
 
 pub trait SeqWriter: SupportedFormat {
@@ -207,37 +921,272 @@ impl SeqWriter for Bam {
     }
 }
 
+impl SeqWriter for Sam {
+    type Writer = SamWriter<BufWriter<File>>;
+    async fn read_writer(&self, output_path: &Path) -> Result<Self::Writer> {
+        let output_file = File::create(output_path).await?;
+        let writer = BufWriter::new(output_file);
+        let sam_writer = SamWriter::new(writer, QualityOffset::Phred33)?;
+
+        Ok(sam_writer)
+    }
+    async fn finalize_write(&self, writer: Self::Writer) -> Result<()> {
+        writer.finish().await
+    }
+}
+
+/// The input path that stands in for standard input, e.g. `amplicon-tk trim -i -`.
+pub const STDIN_PATH: &str = "-";
+
+/// Whether the provided input path refers to standard input rather than a file.
+pub fn is_stdin(input_path: &Path) -> bool {
+    input_path == Path::new(STDIN_PATH)
+}
+
+/// The bytes that every gzip stream, including BGZF, begins with.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Inputs named as gzip-compressed that have already been reported to be plain text, so that
+/// each is only warned about once however many times its format is looked up.
+static PLAIN_TEXT_GZ: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Whether the file at `input_path` begins with [`GZIP_MAGIC`].
+///
+/// # Errors
+///
+/// This function will return an error if the file cannot be opened or read.
+pub async fn has_gzip_magic(input_path: &Path) -> Result<bool> {
+    let mut file = File::open(input_path).await?;
+    let mut leading = Vec::with_capacity(GZIP_MAGIC.len());
+    (&mut file)
+        .take(GZIP_MAGIC.len() as u64)
+        .read_to_end(&mut leading)
+        .await?;
+    Ok(leading == GZIP_MAGIC)
+}
+
+/// Count the lines in a stream without parsing them, counting a final line that lacks a
+/// trailing newline.
+async fn count_lines<R: AsyncBufRead + Unpin>(mut reader: R) -> Result<u64> {
+    let mut lines = 0;
+    let mut last = b'\n';
+    loop {
+        let buf = reader.fill_buf().await?;
+        let Some(&final_byte) = buf.last() else {
+            break;
+        };
+        lines += memchr::memchr_iter(b'\n', buf).count() as u64;
+        last = final_byte;
+        let consumed = buf.len();
+        reader.consume(consumed);
+    }
+    if last != b'\n' {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Quickly count the records across all of the provided FASTQ inputs by counting their lines
+/// rather than parsing each record, e.g., to pre-size the maps built in a second pass over the
+/// same inputs. Standard input can only be read once, so `None` is returned if any input is
+/// standard input.
+///
+/// # Errors
+///
+/// This function will return an error if any input has an unsupported format or cannot be
+/// read or decompressed.
+pub async fn dry_count_records(inputs: &[PathBuf]) -> Result<Option<u64>> {
+    let mut lines = 0;
+    for input in inputs {
+        if is_stdin(input) {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(input).await?);
+        lines += match io_selector(input).await? {
+            InputType::FASTQGZ(_) => count_lines(gzip_reader(reader)).await?,
+            InputType::FASTQ(_) => count_lines(reader).await?,
+            InputType::BAM(_) => {
+                return Err(eyre!(
+                    "Unaligned BAM inputs are not yet supported but will be soon! Found {:?}.",
+                    input
+                ))
+            }
+        };
+    }
+
+    Ok(Some(lines / FASTQ_RECORD_LINES as u64))
+}
+
+/// A FASTQ reader over standard input, which may or may not be gzip-compressed.
+pub type StdinReader = FastqReader<Box<dyn AsyncBufRead + Unpin + Send>>;
+
+/// Open a FASTQ reader over standard input. Because there is no file extension to go on,
+/// gzip compression is detected from the stream's leading magic bytes.
+///
+/// # Errors
+///
+/// This function will return an error if standard input cannot be read.
+pub async fn read_stdin_reads() -> Result<StdinReader> {
+    Ok(FastqReader::new(decoded_stdin().await?))
+}
+
+/// The bytes of standard input, decompressed if they begin with the gzip magic bytes.
+async fn decoded_stdin() -> Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let is_gzipped = stdin.fill_buf().await?.starts_with(&GZIP_MAGIC);
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = if is_gzipped {
+        Box::new(gzip_reader(stdin))
+    } else {
+        Box::new(stdin)
+    };
+
+    Ok(reader)
+}
+
+/// The number of scratch directories created so far by this process, which keeps their names
+/// unique.
+static SCRATCH_DIRS: AtomicU64 = AtomicU64::new(0);
+
+/// A directory for intermediate files, e.g., a copy of standard input that must be read more
+/// than once. It is created inside a chosen temporary directory, which can be fast local
+/// storage even when outputs go to slow networked storage, and it is removed along with
+/// everything in it when dropped.
+#[derive(Debug)]
+pub struct ScratchDir {
+    /// The path of the scratch directory itself
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Create a new, uniquely named scratch directory inside `temp_dir`, or when none is given,
+    /// inside the system's temporary directory, which honors `$TMPDIR`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the scratch directory cannot be created.
+    pub fn create(temp_dir: Option<&Path>) -> Result<Self> {
+        let root = temp_dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        std::fs::create_dir_all(&root).map_err(|error| {
+            eyre!(
+                "Could not create the temporary directory {:?}: {error}",
+                root
+            )
+        })?;
+
+        loop {
+            let count = SCRATCH_DIRS.fetch_add(1, Ordering::Relaxed);
+            let path = root.join(format!("amplicon-tk-{}-{count}", std::process::id()));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(ScratchDir { path }),
+                Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(error) => {
+                    return Err(eyre!(
+                        "Could not create a scratch directory in {:?}: {error}",
+                        root
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The path of the scratch directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Copy the reads on standard input, decompressed if need be, to an uncompressed FASTQ file in
+/// `scratch`, so that they can be read more than once, e.g., to compute frequencies in a first
+/// pass. The copy is named so that outputs named after it match those named after standard
+/// input.
+///
+/// # Errors
+///
+/// This function will return an error if standard input cannot be read or the copy cannot be
+/// written.
+pub async fn spool_stdin(scratch: &ScratchDir) -> Result<PathBuf> {
+    let path = scratch.path().join("stdin.fastq");
+    let mut reader = decoded_stdin().await?;
+    let mut file = File::create(&path).await?;
+    tokio::io::copy_buf(&mut reader, &mut file).await?;
+    file.flush().await?;
+
+    Ok(path)
+}
+
 pub async fn io_selector(input_path: &Path) -> Result<InputType> {
+    // reads on standard input are written back out as uncompressed FASTQ
+    if is_stdin(input_path) {
+        return Ok(InputType::FASTQ(Fastq));
+    }
+
     match input_path.try_exists() {
         Ok(_) => (),
         Err(_) => return Err(eyre!("The provided file {:?} does not exist.", input_path)),
     }
 
     let extension = input_path.extension();
-    if let Some(ext) = extension {
-        match ext.to_str().unwrap_or("") {
-            "gz" => Ok(InputType::FASTQGZ(FastqGz)),
-            "fastq" => Ok(InputType::FASTQ(Fastq)),
-            "bam" => Ok(InputType::BAM(Bam)),
-            _ => Err(eyre!("Unsupported file type provided: {:?}", input_path)),
-        }
+    let input_type = if let Some(ext) = extension {
+        let ext = ext.to_str().unwrap_or("");
+        InputType::all()
+            .into_iter()
+            .find(|format| format.detected_extensions().contains(&ext))
+            .ok_or_else(|| eyre!("Unsupported file type provided: {:?}", input_path))?
     } else {
-        Err(eyre!(
+        return Err(eyre!(
             "Could not determine an extension from the provided file name: {:?}.",
             input_path
-        ))
+        ));
+    };
+
+    // files named as gzip-compressed are sometimes plain text, which is read as such rather
+    // than failing in the decoder
+    if matches!(input_type, InputType::FASTQGZ(_)) && !has_gzip_magic(input_path).await? {
+        let mut warned = PLAIN_TEXT_GZ.lock().unwrap_or_else(PoisonError::into_inner);
+        if !warned.iter().any(|path| path == input_path) {
+            eprintln!(
+                "Warning: {:?} is named as gzip-compressed but is not, so it will be read as uncompressed FASTQ.",
+                input_path
+            );
+            warned.push(input_path.to_path_buf());
+        }
+        return Ok(InputType::FASTQ(Fastq));
     }
+
+    Ok(input_type)
+}
+
+/// Expand a glob pattern into a deterministically sorted list of input files.
+///
+/// # Errors
+///
+/// This function will return an error if the pattern is invalid or matches no files.
+pub fn expand_input_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut inputs = glob::glob(pattern)?.collect::<Result<Vec<PathBuf>, _>>()?;
+    inputs.sort();
+
+    if inputs.is_empty() {
+        return Err(eyre!("No input files matched the pattern {:?}.", pattern));
+    }
+
+    Ok(inputs)
 }
 
 pub trait Init: SupportedFormat {
-    type Reader;
+    type Reader: RecordParser;
     fn init(self, input_path: &Path) -> impl futures::Future<Output = Result<(Self::Reader, Self)>>
     where
         Self: std::marker::Sized;
 }
 
 impl Init for FastqGz {
-    type Reader = FastqReader<BufReader<GzipDecoder<BufReader<File>>>>;
+    type Reader = FastqReader<GzipReader<BufReader<File>>>;
     async fn init(self, input_path: &Path) -> Result<(Self::Reader, Self)>
     where
         Self: std::marker::Sized,
@@ -1,8 +1,11 @@
 // #![warn(missing_docs)]
 
 pub mod cli;
+pub mod consensus;
 pub mod index;
 pub mod io;
+pub mod lint;
+pub mod pipeline;
 pub mod primers;
 pub mod py_api;
 pub mod r_api;
@@ -0,0 +1,333 @@
+// #![warn(missing_docs)]
+
+//! Module `lint` checks a primer scheme for design errors using only its BED file and
+//! reference, before any reads are trimmed, e.g., as a pre-flight check for scheme authors.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+
+use color_eyre::eyre::{eyre, Result};
+use memchr::memmem::Finder;
+use noodles::bed::Reader as BedReader;
+use serde::{Deserialize, Serialize};
+
+use crate::primers::{reverse_complement_bytes, RefLookup};
+
+/// How many bases at the 3' end of each primer are searched for elsewhere in the reference.
+/// Polymerases extend from the 3' end, so a match there is enough for a primer to prime
+/// somewhere it shouldn't.
+pub const SECONDARY_SITE_LEN: usize = 12;
+
+/// The largest difference, in degrees Celsius, allowed between the estimated melting
+/// temperatures of an amplicon's two primers.
+pub const MAX_TM_DIFFERENCE: u32 = 5;
+
+/// A design error found in a primer scheme.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LintIssue {
+    /// More than one primer in the BED file has the same name
+    DuplicateName {
+        /// The repeated name
+        primer_name: String,
+
+        /// The number of primers with that name
+        count: usize,
+    },
+
+    /// Two primers are reverse complements of each other, so they anneal to one another
+    ComplementaryPrimers {
+        /// The first of the two primers in the BED file
+        first: String,
+
+        /// The second of the two primers in the BED file
+        second: String,
+    },
+
+    /// The 3' end of a primer also occurs, in either orientation, somewhere else in the
+    /// reference, where the primer may prime as well
+    SecondaryBindingSite {
+        /// The name of the primer
+        primer_name: String,
+
+        /// The name of the reference sequence holding the other site
+        ref_name: String,
+
+        /// The 0-based position where the other site starts
+        position: usize,
+    },
+
+    /// An amplicon's reverse primer starts before its forward primer ends, leaving no insert
+    /// between them
+    EmptySpan {
+        /// The name of the amplicon
+        amplicon: String,
+
+        /// The number of bases between the end of the forward primer and the start of the
+        /// reverse primer, which is zero or negative
+        span: i64,
+    },
+
+    /// An amplicon's primers have estimated melting temperatures more than
+    /// [`MAX_TM_DIFFERENCE`] degrees apart, so no one annealing temperature suits both
+    TmMismatch {
+        /// The name of the amplicon
+        amplicon: String,
+
+        /// The estimated melting temperature of the forward primer
+        fwd_tm: u32,
+
+        /// The estimated melting temperature of the reverse primer
+        rev_tm: u32,
+    },
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintIssue::DuplicateName { primer_name, count } => write!(
+                f,
+                "The primer name {primer_name} is used by {count} primers in the BED file."
+            ),
+            LintIssue::ComplementaryPrimers { first, second } => write!(
+                f,
+                "The primers {first} and {second} are reverse complements of each other."
+            ),
+            LintIssue::SecondaryBindingSite {
+                primer_name,
+                ref_name,
+                position,
+            } => write!(
+                f,
+                "The last {SECONDARY_SITE_LEN} bases of {primer_name} also occur at position {position} of {ref_name}, where the primer may bind as well."
+            ),
+            LintIssue::EmptySpan { amplicon, span } => write!(
+                f,
+                "The primers of {amplicon} leave {span} bases between them, so the amplicon has no insert."
+            ),
+            LintIssue::TmMismatch {
+                amplicon,
+                fwd_tm,
+                rev_tm,
+            } => write!(
+                f,
+                "The primers of {amplicon} have estimated melting temperatures of {fwd_tm}°C and {rev_tm}°C, more than {MAX_TM_DIFFERENCE}°C apart."
+            ),
+        }
+    }
+}
+
+/// Estimate the melting temperature of a primer, in degrees Celsius, with the Wallace rule:
+/// two degrees for each A or T and four for each G or C. It is only a rough proxy, but enough
+/// to catch primers whose GC content or length is far from their partner's.
+pub fn wallace_tm(primer: &[u8]) -> u32 {
+    primer
+        .iter()
+        .map(|base| match base.to_ascii_uppercase() {
+            b'G' | b'C' => 4,
+            b'A' | b'T' => 2,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// A primer from the BED file along with its bases in the reference.
+struct LintPrimer {
+    /// The name of the primer
+    name: String,
+
+    /// The name of the reference sequence the primer lies on
+    ref_name: String,
+
+    /// The 0-based start of the primer in the reference
+    start: usize,
+
+    /// The end of the primer in the reference, exclusive
+    stop: usize,
+
+    /// The primer's sequence, 5' to 3', which for reverse primers is the reverse complement of
+    /// the reference bases they lie on
+    oligo: Vec<u8>,
+}
+
+/// Check a primer scheme for design errors using its BED file and the reference it was
+/// designed against: duplicate primer names, pairs of primers that are reverse complements of
+/// each other, primers whose 3' end also occurs elsewhere in the reference, amplicons whose
+/// primers leave no insert, and amplicons whose primers differ in estimated melting temperature
+/// by more than [`MAX_TM_DIFFERENCE`] degrees. Primers are paired into amplicons by their
+/// `fwd_suffix` and `rev_suffix`, as when trimming.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read or if a primer's
+/// coordinates are not in the reference.
+pub fn lint_scheme(
+    mut bed: BedReader<BufReader<File>>,
+    reference: &HashMap<Vec<u8>, Vec<u8>>,
+    fwd_suffix: &str,
+    rev_suffix: &str,
+) -> Result<Vec<LintIssue>> {
+    let mut primers = Vec::new();
+    for record in bed.records::<4>() {
+        let record = record?;
+        let name = record
+            .name()
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+        let ref_name = record.reference_sequence_name().to_string();
+        let start = record.start_position().get() - 1;
+        let stop = record.end_position().get();
+        let bases = reference
+            .lookup(ref_name.as_bytes(), start, stop)
+            .ok_or_else(|| {
+                eyre!(
+                    "Positions {} and {} for {} are not present in the reference sequence, {}.",
+                    start,
+                    stop,
+                    name,
+                    ref_name
+                )
+            })?
+            .to_ascii_uppercase();
+        let is_reverse = !name.contains(fwd_suffix) && name.contains(rev_suffix);
+        let oligo = if is_reverse {
+            reverse_complement_bytes(&bases)
+        } else {
+            bases
+        };
+        primers.push(LintPrimer {
+            name,
+            ref_name,
+            start,
+            stop,
+            oligo,
+        });
+    }
+
+    let mut issues = duplicate_names(&primers);
+    issues.extend(complementary_primers(&primers));
+    issues.extend(secondary_sites(&primers, reference));
+    issues.extend(amplicon_issues(&primers, fwd_suffix, rev_suffix));
+
+    Ok(issues)
+}
+
+/// Report each primer name used more than once.
+fn duplicate_names(primers: &[LintPrimer]) -> Vec<LintIssue> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for primer in primers {
+        *counts.entry(primer.name.as_str()).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(primer_name, count)| LintIssue::DuplicateName {
+            primer_name: primer_name.to_string(),
+            count,
+        })
+        .collect()
+}
+
+/// Report each pair of primers whose sequences are reverse complements of each other.
+fn complementary_primers(primers: &[LintPrimer]) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+    for (i, first) in primers.iter().enumerate() {
+        let first_rc = reverse_complement_bytes(&first.oligo);
+        for second in &primers[i + 1..] {
+            if second.oligo == first_rc {
+                issues.push(LintIssue::ComplementaryPrimers {
+                    first: first.name.clone(),
+                    second: second.name.clone(),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/// Report each place, other than a primer's own site, where the last [`SECONDARY_SITE_LEN`]
+/// bases of the primer occur in the reference in either orientation.
+fn secondary_sites(
+    primers: &[LintPrimer],
+    reference: &HashMap<Vec<u8>, Vec<u8>>,
+) -> Vec<LintIssue> {
+    // search the reference in a fixed order so that the report is the same from run to run
+    let mut ref_names: Vec<&Vec<u8>> = reference.keys().collect();
+    ref_names.sort();
+
+    let mut issues = Vec::new();
+    for primer in primers.iter().filter(|primer| !primer.oligo.is_empty()) {
+        let site_len = SECONDARY_SITE_LEN.min(primer.oligo.len());
+        let three_prime = &primer.oligo[primer.oligo.len() - site_len..];
+        let three_prime_rc = reverse_complement_bytes(three_prime);
+
+        for ref_name in &ref_names {
+            let sequence = &reference[*ref_name];
+            let ref_name = String::from_utf8_lossy(ref_name).into_owned();
+            let mut positions: Vec<usize> = Finder::new(three_prime)
+                .find_iter(sequence)
+                .chain(Finder::new(&three_prime_rc).find_iter(sequence))
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+            for position in positions {
+                let own_site = ref_name == primer.ref_name
+                    && position >= primer.start
+                    && position + site_len <= primer.stop;
+                if !own_site {
+                    issues.push(LintIssue::SecondaryBindingSite {
+                        primer_name: primer.name.clone(),
+                        ref_name: ref_name.clone(),
+                        position,
+                    });
+                }
+            }
+        }
+    }
+    issues
+}
+
+/// Pair primers into amplicons by their suffixes and report amplicons whose primers leave no
+/// insert or differ too much in estimated melting temperature.
+fn amplicon_issues(primers: &[LintPrimer], fwd_suffix: &str, rev_suffix: &str) -> Vec<LintIssue> {
+    let mut amplicons: BTreeMap<String, (Option<&LintPrimer>, Option<&LintPrimer>)> =
+        BTreeMap::new();
+    for primer in primers {
+        let amplicon = primer.name.replace(fwd_suffix, "").replace(rev_suffix, "");
+        let pair = amplicons.entry(amplicon).or_default();
+        if primer.name.contains(fwd_suffix) {
+            pair.0.get_or_insert(primer);
+        } else if primer.name.contains(rev_suffix) {
+            pair.1.get_or_insert(primer);
+        }
+    }
+
+    let mut issues = Vec::new();
+    for (amplicon, pair) in amplicons {
+        let (Some(fwd), Some(rev)) = pair else {
+            continue;
+        };
+
+        if fwd.ref_name == rev.ref_name {
+            let span = rev.start as i64 - fwd.stop as i64;
+            if span <= 0 {
+                issues.push(LintIssue::EmptySpan {
+                    amplicon: amplicon.clone(),
+                    span,
+                });
+            }
+        }
+
+        let (fwd_tm, rev_tm) = (wallace_tm(&fwd.oligo), wallace_tm(&rev.oligo));
+        if fwd_tm.abs_diff(rev_tm) > MAX_TM_DIFFERENCE {
+            issues.push(LintIssue::TmMismatch {
+                amplicon,
+                fwd_tm,
+                rev_tm,
+            });
+        }
+    }
+    issues
+}
@@ -8,156 +8,740 @@
 //! handling (`reads`), individual record-handling `record`, consensus sequence-calling
 //! (`consensus`), the command-line interface (`cli`), and a work-in-progress Python interface.
 
-use std::{fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 
 use amplicon_tk::{
     cli::{self, Commands},
-    index::Index,
-    io::{io_selector, Bed, Fasta, InputType, PrimerReader, RefReader},
-    primers::{define_amplicons, ref_to_dict},
-    reads::{FilterSettings, Trimming},
+    index::{index_inputs, load_index_file, merge_indices, Index, IndexOptions},
+    io::{
+        dry_count_records, expand_input_glob, io_selector, is_stdin, list_formats, spool_stdin,
+        Bed, Fasta, InputType, PrimerReader, RefReader, ScratchDir,
+    },
+    lint::lint_scheme,
+    primers::{
+        check_bed_coordinates, define_amplicons_checked, define_amplicons_from_table,
+        expected_insert_lens, indexed_refs_to_windows, insert_regions, read_primer_fasta,
+        ref_to_dict, refs_to_dict, refs_to_windows, AmpliconScheme, RefLookup,
+    },
+    reads::{
+        annotate_bam, consensus_input, count_primers, count_records, merge_paired_inputs,
+        sample_name_for_input, sort_input, trim_inputs, trim_inputs_per_input, BarcodeDemux,
+        CollapsedReads, ConsensusReference, ConsensusSettings, FilterSettings, GcLengthReport,
+        GroupedReads, InsertLenCheck, OutputMode, PrimerRegions, Progress, ReadReport,
+        TrimSettings,
+    },
+    record::{
+        BoundaryRefinement, IdentityTransform, MatchCache, MatcherOptions, MergeSettings,
+        DEFAULT_MAX_PRIMER_ERRORS,
+    },
 };
 use clap::Parser;
-use color_eyre::eyre::Result;
-use flate2::bufread::GzDecoder;
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+use color_eyre::eyre::{eyre, Result};
+use flate2::bufread::MultiGzDecoder;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // parse command line arguments, which determine how chatty the tracer should be
+    let cli = cli::Cli::parse();
+
     // set up the color-eyre display and tracer
-    setup()?;
+    setup(&cli.verbose)?;
 
-    // parse command line arguments and use a match statement to determine behavior based on the
-    // provided subcommand
-    let cli = cli::Cli::parse();
+    // use a match statement to determine behavior based on the provided subcommand
     match &cli.command {
         Some(Commands::Index {
             input_file,
             bed_file,
             fasta_ref,
+            lazy_ref,
+            create_fai,
             left_suffix,
             right_suffix,
+            primer_fasta,
+            representatives,
+            compress_index,
+            max_memory,
+            allow_empty_scheme,
+            rev_as_appears,
+            strict,
+            dry_count,
         }) => {
             // defining input and output types for the reads
             let input_type = io_selector(input_file).await?;
 
-            // pulling in the primers
-            let primer_type = Bed;
-            let bed = primer_type.read_primers(bed_file)?;
-
-            // pulling in the reference
-            let ref_type = Fasta;
-            let mut fasta = ref_type.read_ref(fasta_ref)?;
-
-            // convert the reference to a hashmap and use it to pull in the primer pairs for each
-            // amplicon
-            let ref_dict = ref_to_dict(&mut fasta).await?;
-            let scheme = define_amplicons(bed, &ref_dict, left_suffix, right_suffix).await?;
+            // pull in the primer scheme from BED coordinates in the reference
+            let (scheme, _) = load_scheme(SchemeArgs {
+                bed_file: Some(bed_file),
+                fasta_ref,
+                primer_table: None,
+                primer_fasta: primer_fasta.as_ref(),
+                left_suffix,
+                right_suffix,
+                lazy_ref: *lazy_ref,
+                create_fai: *create_fai,
+                check_bed_coords: false,
+                strict: *strict,
+                allow_empty_scheme: *allow_empty_scheme,
+                rev_as_appears: *rev_as_appears,
+                rc_table_primers: false,
+            })
+            .await?;
 
             // based on the input filetype, open, decode, and parse the sequence read records
             // lazily and use them to create an index
-            match input_type {
+            let expected_records = if *dry_count {
+                dry_count_records(std::slice::from_ref(input_file)).await?
+            } else {
+                None
+            };
+            let options = IndexOptions {
+                compress: *compress_index,
+                max_memory: *max_memory,
+                expected_records,
+            };
+            let index = match input_type {
                 InputType::FASTQGZ(supported_type) => {
                     let opened_file = File::open(input_file)?;
                     let buffer_raw = std::io::BufReader::new(opened_file);
-                    let decoded = GzDecoder::new(buffer_raw);
+                    let decoded = MultiGzDecoder::new(buffer_raw);
                     let decoded_buffer = std::io::BufReader::new(decoded);
                     let reader = noodles::fastq::Reader::new(decoded_buffer);
-                    supported_type.index(reader, scheme, input_file).await?;
+                    supported_type
+                        .index_with(reader, scheme, input_file, options)
+                        .await?
                 }
                 InputType::FASTQ(supported_type) => {
                     let opened_file = File::open(input_file)?;
                     let buffer = std::io::BufReader::new(opened_file);
                     let reader = noodles::fastq::Reader::new(buffer);
-                    supported_type.index(reader, scheme, input_file).await?;
+                    supported_type
+                        .index_with(reader, scheme, input_file, options)
+                        .await?
                 }
                 InputType::BAM(_supported_type) => {
-                    eprintln!("Unaligned BAM inputs are not yet supported but will be soon!")
+                    eprintln!("Unaligned BAM inputs are not yet supported but will be soon!");
+                    return Ok(());
                 }
             };
+
+            // optionally write out the most frequent sequence for each amplicon
+            if let Some(representatives_path) = representatives {
+                index.write_representatives(representatives_path)?;
+            }
+        }
+        Some(Commands::IndexMerge {
+            indices,
+            output,
+            compress_index,
+        }) => {
+            // sum the counts across all provided indices and write out the result
+            let merged = merge_indices(indices)?;
+            match compress_index {
+                true => merged.write_compressed_to(output)?,
+                false => merged.write_to(output)?,
+            }
         }
         Some(Commands::Trim {
             input_file,
+            input_glob,
             bed_file,
             fasta_ref,
             keep_multi: _,
+            only_amplicon,
+            allow_empty_scheme,
+            rev_as_appears,
+            reverse_complement_primers_in_table,
+            strict,
             left_suffix,
             right_suffix,
             min_freq,
+            min_count,
+            index: index_path,
+            two_pass_freq,
+            temp_dir,
+            max_memory,
+            no_index,
             expected_len,
             output,
+            output_mode,
+            output_format,
+            count_only,
+            count_primers_only,
+            stats_json,
+            quality_offset,
+            rescale_quality,
+            emit_rejected,
+            primer_regions_out,
+            report_per_read,
+            report_gc_length_scatter,
+            qc_sample,
+            rc_output,
+            uppercase_output,
+            uniquify_names,
+            sample_name,
+            prefix_sample_name,
+            refine_boundary,
+            refine_min_quality,
+            annotate_metrics,
+            keep_original_in_header,
+            collapse_output,
+            dedup_key,
+            group_by_amplicon,
+            split_by_length,
+            barcode_from_name,
+            demux_by_amplicon,
+            output_uncompressed_threshold,
+            reads_per_file,
+            min_insert_len,
+            insert_len_tolerance,
+            drop_implausible_inserts,
+            match_algo,
+            scheme_kind,
+            max_primer_errors,
+            min_primer_qual,
+            amplicons_per_read,
+            split_concatemers,
+            min_extension,
+            validate_output,
+            on_error,
+            time_matching,
+            match_cache,
+            progress_interval,
+            eta,
+            primer_table,
+            primer_fasta,
+            check_bed_coords,
+            lazy_ref,
+            create_fai,
         }) => {
-            // pull in the primers
-            let primer_type = Bed;
-            let bed = primer_type.read_primers(bed_file)?;
-
-            // pull in the reference
-            let ref_type = Fasta;
-            let mut fasta = ref_type.read_ref(fasta_ref)?;
-
-            // convert the reference to a hashmap and use it to pull in the primer pairs for each
-            // amplicon
-            let ref_dict = ref_to_dict(&mut fasta).await?;
-            let scheme = define_amplicons(bed, &ref_dict, left_suffix, right_suffix).await?;
+            // pull in the primer scheme, either from a primer table or from BED coordinates in
+            // the reference, along with any per-amplicon expected lengths
+            let (scheme, amplicon_max_lens) = load_scheme(SchemeArgs {
+                bed_file: bed_file.as_ref(),
+                fasta_ref,
+                primer_table: primer_table.as_ref(),
+                primer_fasta: primer_fasta.as_ref(),
+                left_suffix,
+                right_suffix,
+                lazy_ref: *lazy_ref,
+                create_fai: *create_fai,
+                check_bed_coords: *check_bed_coords,
+                strict: *strict,
+                allow_empty_scheme: *allow_empty_scheme,
+                rev_as_appears: *rev_as_appears,
+                rc_table_primers: *reverse_complement_primers_in_table,
+            })
+            .await?;
 
             // hash the current primer scheme to compare with a potential index
             let current_hash = scheme.hash_amplicon_scheme()?;
 
-            // define input and output types for the reads
-            let input_type = io_selector(input_file).await?;
-            let output_name = format!("{}{}", output, input_type.extension());
-            let output_path = PathBuf::from(output_name);
+            // optionally narrow the scheme to one amplicon, after hashing so that an index built
+            // with the full scheme can still be used
+            let scheme = match only_amplicon {
+                Some(amplicon) => scheme.restrict_to_amplicon(amplicon)?,
+                None => scheme,
+            };
+
+            // collect the input files, either as provided or by expanding a glob pattern
+            let inputs = match input_glob {
+                Some(pattern) => expand_input_glob(pattern)?,
+                None => input_file.clone(),
+            };
+
+            // optionally just report how often each primer turns up, before any pairing
+            if *count_primers_only {
+                let matcher =
+                    match_algo.matcher(max_primer_errors.unwrap_or(DEFAULT_MAX_PRIMER_ERRORS));
+                let scan = count_primers(&inputs, &scheme, matcher.as_ref()).await?;
+                scan.write_tsv(std::io::stdout().lock())?;
+                return Ok(());
+            }
+
+            // standard input can only be read once, so when frequencies are computed in a first
+            // pass, copy it to a scratch file that both passes can read
+            let scratch = match (*two_pass_freq, inputs.iter().any(|input| is_stdin(input))) {
+                (true, true) => Some(ScratchDir::create(temp_dir.as_deref())?),
+                _ => None,
+            };
+            let inputs = match &scratch {
+                Some(scratch) => {
+                    let spooled = spool_stdin(scratch).await?;
+                    inputs
+                        .into_iter()
+                        .map(|input| match is_stdin(&input) {
+                            true => spooled.clone(),
+                            false => input,
+                        })
+                        .collect()
+                }
+                None => inputs,
+            };
+
+            // define input and output types for the reads. Multiple inputs are merged into one
+            // output with the same type as the first input, unless each is written on its own
+            // into an output directory
+            let input_type = io_selector(&inputs[0]).await?;
+            let output_path = match output_mode {
+                OutputMode::Merge => PathBuf::from(format!("{}{}", output, input_type.extension())),
+                OutputMode::PerInput => PathBuf::from(output),
+            };
+            if barcode_from_name.is_some() && matches!(output_mode, OutputMode::PerInput) {
+                return Err(eyre!(
+                    "Reads can't be demultiplexed by barcode when each input is written on its own, since every barcode's reads are gathered into one output across inputs."
+                ));
+            }
             // still need to work out how to select different input and output types
 
-            // based on the file type, run lazy, asynchronous trimming with the appropriate record type
-            match input_type {
-                InputType::FASTQGZ(supported_type) => {
-                    // attempt to retrieve a set of unique sequences from an index to use with filtering
-                    let unique_seqs = supported_type.load_index(input_file, &current_hash)?;
+            // optionally report progress as reads are processed, counting them first when an ETA
+            // is requested
+            let progress = match progress_interval {
+                Some(interval) => {
+                    let total = if *eta {
+                        count_records(&inputs).await?
+                    } else {
+                        None
+                    };
+                    Some(Arc::new(Mutex::new(Progress::new(
+                        *interval,
+                        total,
+                        Instant::now(),
+                    ))))
+                }
+                None => None,
+            };
 
-                    // bundle the requested filter settings. These settings will be None if no unique sequences
-                    // could be retrieved from the index
-                    let filters = FilterSettings::new(min_freq, expected_len, &unique_seqs);
+            // optionally check trimmed inserts against the lengths the BED coordinates give them
+            let insert_len_check = match (insert_len_tolerance, bed_file) {
+                (Some(tolerance), Some(bed_file)) => Some(InsertLenCheck {
+                    expected: expected_insert_lens(
+                        Bed.read_primers(bed_file)?,
+                        left_suffix,
+                        right_suffix,
+                    )?,
+                    tolerance: *tolerance,
+                    drop: *drop_implausible_inserts,
+                }),
+                _ => None,
+            };
 
-                    // perform trimming based on the supported type
-                    supported_type
-                        .trim(input_file, &output_path, scheme, filters)
-                        .await?
+            // write each requested output format once, however many times it was requested
+            let mut output_formats = Vec::with_capacity(output_format.len());
+            for format in output_format {
+                if !output_formats.contains(format) {
+                    output_formats.push(*format);
                 }
-                InputType::FASTQ(supported_type) => {
-                    let unique_seqs = supported_type.load_index(input_file, &current_hash)?;
-                    let filters = FilterSettings::new(min_freq, expected_len, &unique_seqs);
-                    supported_type
-                        .trim(input_file, &output_path, scheme, filters)
-                        .await?
+            }
+
+            let settings = TrimSettings {
+                count_only: *count_only,
+                output_formats,
+                quality_offset: *quality_offset,
+                rescale_quality: *rescale_quality,
+                emit_rejected: emit_rejected.clone(),
+                rc_output: *rc_output,
+                uppercase_output: *uppercase_output,
+                uniquify_names: *uniquify_names,
+                name_counter: Arc::new(AtomicU64::new(0)),
+                refine_boundary: refine_boundary.then_some(BoundaryRefinement {
+                    min_quality: *refine_min_quality,
+                    ..BoundaryRefinement::default()
+                }),
+                annotate_metrics: *annotate_metrics,
+                keep_original_in_header: *keep_original_in_header,
+                min_insert_len: *min_insert_len,
+                match_algorithm: *match_algo,
+                scheme_kind: *scheme_kind,
+                max_primer_errors: *max_primer_errors,
+                min_primer_quality: *min_primer_qual,
+                amplicons_per_read: *amplicons_per_read,
+                split_concatemers: *split_concatemers,
+                min_extension: *min_extension,
+                validate_output: *validate_output,
+                on_error: *on_error,
+                time_matching: *time_matching,
+                match_cache: match_cache.map(|capacity| Arc::new(MatchCache::new(capacity))),
+                progress,
+                collapse_output: collapse_output
+                    .then(|| Arc::new(Mutex::new(CollapsedReads::with_key(*dedup_key)))),
+                group_by_amplicon: group_by_amplicon
+                    .then(|| Arc::new(Mutex::new(GroupedReads::default()))),
+                primer_regions: match primer_regions_out {
+                    Some(path) => Some(Arc::new(Mutex::new(PrimerRegions::create(path)?))),
+                    None => None,
+                },
+                gc_length_report: match report_gc_length_scatter {
+                    Some(path) => Some(Arc::new(Mutex::new(GcLengthReport::create(
+                        path, *qc_sample,
+                    )?))),
+                    None => None,
+                },
+                read_report: match report_per_read {
+                    Some(path) => Some(Arc::new(Mutex::new(ReadReport::create(path)?))),
+                    None => None,
+                },
+                length_bins: split_by_length.clone(),
+                barcode_demux: barcode_from_name.as_ref().map(|pattern| {
+                    Arc::new(Mutex::new(BarcodeDemux::new(
+                        pattern.clone(),
+                        *demux_by_amplicon,
+                        &output_path,
+                    )))
+                }),
+                uncompressed_threshold: *output_uncompressed_threshold,
+                reads_per_file: *reads_per_file,
+                insert_len_check,
+                sample_name: Some(
+                    sample_name
+                        .clone()
+                        .unwrap_or_else(|| sample_name_for_input(&inputs[0])),
+                ),
+                prefix_sample_name: *prefix_sample_name,
+                interrupt: Arc::new(AtomicBool::new(false)),
+            };
+
+            // on Ctrl-C, stop consuming records but let the outputs be finalized
+            let interrupt = Arc::clone(&settings.interrupt);
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupt.store(true, Ordering::Relaxed);
                 }
-                InputType::BAM(_supported_type) => {
-                    eprintln!("Unaligned BAM inputs are not yet supported but will be soon!")
+            });
+
+            // attempt to retrieve a set of unique sequences from an index to use with filtering.
+            // Indices are built per sample, so unless an index is provided explicitly, they are
+            // only used when trimming a single input. With two passes, the unique sequences are
+            // instead counted across exactly the inputs being trimmed. Pure trimming never
+            // looks for an index at all
+            let index = match (index_path, inputs.as_slice(), input_type) {
+                (_, _, InputType::BAM(_)) => {
+                    eprintln!("Unaligned BAM inputs are not yet supported but will be soon!");
+                    return Ok(());
+                }
+                _ if *no_index => None,
+                _ if *two_pass_freq => {
+                    // the first pass only needs the expected lengths, which gate reads before
+                    // they are matched, since there are no frequencies to filter on yet
+                    let len_filters =
+                        FilterSettings::new(&None, &None, expected_len, &None, &amplicon_max_lens);
+                    Some(
+                        index_inputs(&inputs, &scheme, &len_filters, &settings, *max_memory)
+                            .await?,
+                    )
+                }
+                (Some(index_path), _, _) => load_index_file(index_path, &current_hash)?,
+                (None, [input_file], InputType::FASTQGZ(supported_type)) => {
+                    supported_type.load_index(input_file, &current_hash)?
+                }
+                (None, [input_file], InputType::FASTQ(supported_type)) => {
+                    supported_type.load_index(input_file, &current_hash)?
+                }
+                _ => None,
+            };
+
+            // bundle the requested filter settings. These settings will be None for pure trimming,
+            // if no filters were requested, or if only index-based filters were requested and no
+            // index could be loaded
+            let filters = match no_index {
+                true => None,
+                false => FilterSettings::new(
+                    min_freq,
+                    min_count,
+                    expected_len,
+                    &index,
+                    &amplicon_max_lens,
+                ),
+            };
+
+            // run lazy, asynchronous trimming over each input in turn, writing to one output or
+            // to one output per input
+            let stats = match output_mode {
+                OutputMode::Merge => {
+                    trim_inputs(
+                        &inputs,
+                        &output_path,
+                        &scheme,
+                        &filters,
+                        &settings,
+                        &IdentityTransform,
+                    )
+                    .await?
+                }
+                OutputMode::PerInput => {
+                    trim_inputs_per_input(
+                        &inputs,
+                        &output_path,
+                        &scheme,
+                        &filters,
+                        &settings,
+                        &IdentityTransform,
+                    )
+                    .await?
                 }
             };
+
+            // sampled GC and length rows can only be chosen once every read has been seen
+            if let Some(report) = &settings.gc_length_report {
+                report
+                    .lock()
+                    .map_err(|_| eyre!("GC and length reporting was poisoned by a panic."))?
+                    .finish()?;
+            }
+
+            let interrupted = settings.interrupt.load(Ordering::Relaxed);
+            if interrupted {
+                eprintln!(
+                    "Interrupted after processing {} reads. Outputs were finalized but only contain the reads processed before the interruption.",
+                    stats.total
+                );
+            }
+
+            // report the counts from the run to stderr and, if requested, as JSON
+            if *count_only || *time_matching {
+                eprintln!("{stats}");
+                if let Some(cache) = &settings.match_cache {
+                    eprintln!(
+                        "Match cache: {} hits, {} misses",
+                        cache.hits(),
+                        cache.misses()
+                    );
+                }
+            }
+            if let Some(json_path) = stats_json {
+                let json_file = File::create(json_path)?;
+                serde_json::to_writer_pretty(json_file, &stats)?;
+            }
+
+            // exit with the conventional status for SIGINT, removing any intermediates first
+            // since exiting skips their cleanup
+            if interrupted {
+                drop(scratch);
+                std::process::exit(130);
+            }
+        }
+        Some(Commands::Merge {
+            r1,
+            r2,
+            min_overlap,
+            max_mismatch_rate,
+            keep_unmerged,
+            on_error,
+            output,
+        }) => {
+            let settings = MergeSettings {
+                min_overlap: *min_overlap,
+                max_mismatch_rate: *max_mismatch_rate,
+                keep_unmerged: *keep_unmerged,
+                on_error: *on_error,
+            };
+            let stats = merge_paired_inputs(r1, r2, output, &settings).await?;
+            eprintln!("{stats}");
         }
         Some(Commands::Sort {
-            input_file: _,
-            bed_file: _,
-            primer_file: _,
-            ref_file: _,
+            input_file,
+            bed_file,
+            primer_file,
+            ref_file,
+            left_suffix,
+            right_suffix,
             min_freq: _,
             keep_multi: _,
+            keep_order_within_amplicon,
+            concurrency,
+            output_dir,
         }) => {
-            eprintln!("{}\n", cli::INFO);
-            eprintln!("\nSorting is not yet ready for use, but it will be available soon!")
+            // pull in the primer scheme from BED coordinates in the reference
+            let (scheme, _) = load_scheme(SchemeArgs {
+                bed_file: Some(bed_file),
+                fasta_ref: std::slice::from_ref(ref_file),
+                primer_table: None,
+                primer_fasta: primer_file.as_ref(),
+                left_suffix,
+                right_suffix,
+                lazy_ref: false,
+                create_fai: false,
+                check_bed_coords: false,
+                strict: false,
+                allow_empty_scheme: false,
+                rev_as_appears: false,
+                rc_table_primers: false,
+            })
+            .await?;
+
+            // sort the trimmed reads into one file per amplicon and report how many each got
+            std::fs::create_dir_all(output_dir)?;
+            let counts = sort_input(
+                input_file,
+                output_dir,
+                &scheme,
+                *concurrency,
+                *keep_order_within_amplicon,
+            )
+            .await?;
+            for (amplicon, count) in &counts {
+                eprintln!("{amplicon}: {count} reads");
+            }
         }
         Some(Commands::Consensus {
-            input_file: _,
-            bed_file: _,
-            primer_file: _,
-            ref_file: _,
+            input_file,
+            bed_file,
+            primer_file,
+            ref_file,
+            left_suffix,
+            right_suffix,
             min_freq: _,
             keep_multi: _,
-            output: _,
+            trim_to_reference,
+            assemble_genome,
+            emit_reads_dir,
+            max_memory,
+            quality_weighted,
+            quality_offset,
+            output,
         }) => {
-            eprintln!("{}\n", cli::INFO);
-            eprintln!("\nAmplicon consensus calling is not yet ready for use, but it will be available soon!")
+            // pull in the primer scheme from BED coordinates in the reference
+            let (scheme, _) = load_scheme(SchemeArgs {
+                bed_file: Some(bed_file),
+                fasta_ref: std::slice::from_ref(ref_file),
+                primer_table: None,
+                primer_fasta: primer_file.as_ref(),
+                left_suffix,
+                right_suffix,
+                lazy_ref: false,
+                create_fai: false,
+                check_bed_coords: false,
+                strict: false,
+                allow_empty_scheme: false,
+                rev_as_appears: false,
+                rc_table_primers: false,
+            })
+            .await?;
+
+            // optionally place each consensus onto its amplicon's insert in the reference
+            let reference = if *trim_to_reference {
+                let mut fasta = Fasta.read_ref(ref_file)?;
+                Some(ConsensusReference {
+                    sequences: ref_to_dict(&mut fasta).await?,
+                    regions: insert_regions(
+                        Bed.read_primers(bed_file)?,
+                        left_suffix,
+                        right_suffix,
+                    )?,
+                })
+            } else {
+                None
+            };
+
+            // the trimmed reads behind each consensus are written into a directory of their own
+            if let Some(reads_dir) = emit_reads_dir {
+                std::fs::create_dir_all(reads_dir)?;
+            }
+
+            // call a consensus for each amplicon and report how many reads went into each
+            let settings = ConsensusSettings {
+                reads_dir: emit_reads_dir.clone(),
+                max_memory: *max_memory,
+                quality_weighted: *quality_weighted,
+                quality_offset: *quality_offset,
+                reference,
+                genome_output: assemble_genome.clone(),
+            };
+            let depths =
+                consensus_input(input_file, &PathBuf::from(output), &scheme, &settings).await?;
+            for (amplicon, depth) in &depths {
+                eprintln!("{amplicon}: {depth} reads");
+            }
+        }
+        Some(Commands::AnnotateBam {
+            input_file,
+            bed_file,
+            fasta_ref,
+            lazy_ref,
+            create_fai,
+            left_suffix,
+            right_suffix,
+            primer_fasta,
+            allow_empty_scheme,
+            rev_as_appears,
+            strict,
+            output,
+        }) => {
+            // pull in the primer scheme from BED coordinates in the reference
+            let (scheme, _) = load_scheme(SchemeArgs {
+                bed_file: Some(bed_file),
+                fasta_ref,
+                primer_table: None,
+                primer_fasta: primer_fasta.as_ref(),
+                left_suffix,
+                right_suffix,
+                lazy_ref: *lazy_ref,
+                create_fai: *create_fai,
+                check_bed_coords: false,
+                strict: *strict,
+                allow_empty_scheme: *allow_empty_scheme,
+                rev_as_appears: *rev_as_appears,
+                rc_table_primers: false,
+            })
+            .await?;
+
+            // tag each record with its amplicon, leaving the alignments as they are
+            let stats =
+                annotate_bam(input_file, output, &scheme, MatcherOptions::default()).await?;
+            eprintln!("{stats}");
+        }
+        Some(Commands::LintScheme {
+            bed_file,
+            fasta_ref,
+            left_suffix,
+            right_suffix,
+            json,
+        }) => {
+            // lint against whole reference sequences, since secondary binding sites can be
+            // anywhere in them
+            let mut fastas = fasta_ref
+                .iter()
+                .map(|path| Fasta.read_ref(path))
+                .collect::<Result<Vec<_>>>()?;
+            let reference = refs_to_dict(&mut fastas).await?;
+            let issues = lint_scheme(
+                Bed.read_primers(bed_file)?,
+                &reference,
+                left_suffix,
+                right_suffix,
+            )?;
+
+            if let Some(json_path) = json {
+                let json_file = File::create(json_path)?;
+                serde_json::to_writer_pretty(json_file, &issues)?;
+            }
+            if !issues.is_empty() {
+                let report = issues
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                return Err(eyre!(
+                    "{} issue(s) found in the primer scheme {:?}:\n{}",
+                    issues.len(),
+                    bed_file,
+                    report
+                ));
+            }
+            eprintln!("No issues found in the primer scheme {:?}.", bed_file);
+        }
+        Some(Commands::ListFormats) => {
+            print!("{}", list_formats());
         }
         None => {
             eprintln!("{}\n", cli::INFO);
@@ -167,17 +751,181 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn setup() -> Result<()> {
+/// Where a subcommand's primer scheme comes from and how to read it.
+struct SchemeArgs<'a> {
+    /// BED file of primer coordinates in the reference
+    bed_file: Option<&'a PathBuf>,
+
+    /// Reference FASTA(s) that the BED coordinates refer to
+    fasta_ref: &'a [PathBuf],
+
+    /// Primer table pairing primers from the primer FASTA, used instead of a BED file
+    primer_table: Option<&'a PathBuf>,
+
+    /// Primer sequences, either for the primer table or to override the reference's
+    primer_fasta: Option<&'a PathBuf>,
+
+    /// The suffix that identifies forward primers
+    left_suffix: &'a str,
+
+    /// The suffix that identifies reverse primers
+    right_suffix: &'a str,
+
+    /// Whether to load only the reference around each primer
+    lazy_ref: bool,
+
+    /// Whether to build `.fai` indices for lazily loaded references without one
+    create_fai: bool,
+
+    /// Whether to check the BED coordinates against the primer FASTA
+    check_bed_coords: bool,
+
+    /// Whether masked primers and colliding amplicons are errors rather than warnings
+    strict: bool,
+
+    /// Whether to carry on with a scheme that has no amplicons
+    allow_empty_scheme: bool,
+
+    /// Whether reverse primers at BED coordinates are given as they appear in forward-strand
+    /// reads
+    rev_as_appears: bool,
+
+    /// Whether reverse primers in a primer table's FASTA are given as they appear in
+    /// forward-strand reads
+    rc_table_primers: bool,
+}
+
+/// Pull in a primer scheme, either from a primer table and primer FASTA or from BED
+/// coordinates in the reference, along with any per-amplicon expected lengths the primer table
+/// gives. Reverse primers are put in 5' to 3' orientation before the scheme is returned, so
+/// any hash of it matches an index built with the same convention.
+///
+/// # Errors
+///
+/// This function will return an error if neither a primer table nor a BED file and reference
+/// are given, if any of them cannot be read, if the BED coordinates disagree with the primer
+/// FASTA when they are checked, or if the scheme has no amplicons and that isn't allowed.
+async fn load_scheme(
+    args: SchemeArgs<'_>,
+) -> Result<(AmpliconScheme, Option<HashMap<String, usize>>)> {
+    let (scheme, expected_lens) = match (args.primer_table, args.primer_fasta) {
+        (Some(table_path), Some(primer_fasta)) => {
+            let mut primer_fasta = Fasta.read_ref(primer_fasta)?;
+            let primer_seqs = read_primer_fasta(&mut primer_fasta)?;
+            let table = define_amplicons_from_table(
+                table_path,
+                &primer_seqs,
+                args.left_suffix,
+                args.rc_table_primers,
+            )?;
+            (table.scheme, Some(table.expected_lens))
+        }
+        _ => {
+            let (Some(bed_file), false) = (args.bed_file, args.fasta_ref.is_empty()) else {
+                return Err(eyre!(
+                    "Either a BED file and reference FASTA or a primer table and primer FASTA must be provided."
+                ));
+            };
+
+            // pull in the primers
+            let primer_type = Bed;
+            let bed = primer_type.read_primers(bed_file)?;
+
+            // pull in the reference, which may be split across files
+            let mut fastas = args
+                .fasta_ref
+                .iter()
+                .map(|path| Fasta.read_ref(path))
+                .collect::<Result<Vec<_>>>()?;
+
+            // optionally pull in primer sequences that override those in the reference
+            let overrides = match args.primer_fasta {
+                Some(primer_fasta) => read_primer_fasta(&mut Fasta.read_ref(primer_fasta)?)?,
+                None => HashMap::new(),
+            };
+
+            // convert the reference to a hashmap, or just the windows around each primer, and use
+            // it to pull in the primer pairs for each amplicon. Windows are fetched through `.fai`
+            // indices when every reference has one, and streamed otherwise
+            let indexed = if args.lazy_ref {
+                args.fasta_ref
+                    .iter()
+                    .map(|path| Fasta.read_indexed_ref(path, args.create_fai))
+                    .collect::<Result<Option<Vec<_>>>>()?
+            } else {
+                None
+            };
+            let ref_dict: Box<dyn RefLookup> = match (args.lazy_ref, indexed) {
+                (false, _) => Box::new(refs_to_dict(&mut fastas).await?),
+                (true, Some(mut indexed)) => Box::new(indexed_refs_to_windows(
+                    &mut indexed,
+                    primer_type.read_primers(bed_file)?,
+                )?),
+                (true, None) => Box::new(
+                    refs_to_windows(&mut fastas, primer_type.read_primers(bed_file)?).await?,
+                ),
+            };
+
+            // optionally make sure the BED coordinates agree with the primer FASTA before its
+            // sequences silently paper over a shifted BED file
+            if args.check_bed_coords {
+                let mismatches =
+                    check_bed_coordinates(Bed.read_primers(bed_file)?, &*ref_dict, &overrides)?;
+                if !mismatches.is_empty() {
+                    let report = mismatches
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    return Err(eyre!(
+                        "{} primer(s) in {:?} do not match the primer FASTA:\n{}",
+                        mismatches.len(),
+                        bed_file,
+                        report
+                    ));
+                }
+            }
+
+            let scheme = define_amplicons_checked(
+                bed,
+                &*ref_dict,
+                &overrides,
+                args.left_suffix,
+                args.right_suffix,
+                args.strict,
+            )
+            .await?;
+
+            // put reverse primers in 5' to 3' orientation before any hashing, so that the hash
+            // matches an index built with the same convention
+            let scheme = if args.rev_as_appears {
+                scheme.with_rev_as_appears()
+            } else {
+                scheme
+            };
+            (scheme, None)
+        }
+    };
+
+    // stop early if the scheme is broken, since no reads could ever match it
+    if !args.allow_empty_scheme {
+        scheme.ensure_not_empty()?;
+    }
+
+    Ok((scheme, expected_lens))
+}
+
+fn setup(verbose: &Verbosity<InfoLevel>) -> Result<()> {
     if std::env::var("RUST_LIB_BACKTRACE").is_err() {
         std::env::set_var("RUST_LIB_BACKTRACE", "1")
     }
     color_eyre::install()?;
 
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info")
-    }
+    // verbosity flags take precedence over RUST_LOG, which takes precedence over the default
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let filter = EnvFilter::new(cli::log_directive(verbose, rust_log.as_deref()));
     tracing_subscriber::fmt::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(filter)
         .init();
 
     Ok(())
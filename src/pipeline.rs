@@ -0,0 +1,240 @@
+// #![warn(missing_docs)]
+
+//! Module `pipeline` offers the steps of trimming as stages of a stream of records, so that
+//! embedders can compose exactly the pipeline they need, e.g., trimming, filtering, and
+//! deduplicating, before writing whatever is left.
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use color_eyre::eyre::{Report, Result};
+use futures::{future, Stream, TryStreamExt};
+use noodles::fastq::AsyncWriter as FastqWriter;
+use noodles::fastq::Record as FastqRecord;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    primers::AmpliconScheme,
+    reads::{DedupKey, FilterSettings},
+    record::{AmpliconMatcher, FindAmplicons, MatcherOptions},
+};
+
+/// A record trimmed to the insert of the amplicon it was found to hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrimmedRecord {
+    /// The name of the amplicon the record was trimmed to
+    pub amplicon: String,
+
+    /// The trimmed record
+    pub record: FastqRecord,
+}
+
+/// The items that flow through a [`RecordStream`], each of which carries a FASTQ record.
+pub trait StreamRecord {
+    /// The FASTQ record carried by the item.
+    fn record(&self) -> &FastqRecord;
+}
+
+impl StreamRecord for FastqRecord {
+    fn record(&self) -> &FastqRecord {
+        self
+    }
+}
+
+impl StreamRecord for TrimmedRecord {
+    fn record(&self) -> &FastqRecord {
+        &self.record
+    }
+}
+
+/// A stream of records, or of the errors met reading them, with a combinator for each stage of
+/// trimming. Every stage returns another `RecordStream`, and nothing is read until the stream
+/// is consumed, e.g., by [`RecordStream::write_records`]:
+///
+/// ```
+/// use amplicon_tk::{
+///     pipeline::RecordStream,
+///     primers::{AmpliconScheme, PossiblePrimers},
+///     reads::FilterSettings,
+/// };
+/// use noodles::fastq::{AsyncReader, AsyncWriter};
+///
+/// # #[tokio::main]
+/// # async fn main() -> color_eyre::eyre::Result<()> {
+/// let scheme = AmpliconScheme {
+///     scheme: vec![PossiblePrimers::new(
+///         "amplicon_1".to_string(),
+///         "TGGAGGAT".to_string(),
+///         "ATCCTCCA".to_string(),
+///         "CCATAGTA".to_string(),
+///         "TACTATGG".to_string(),
+///     )],
+/// };
+///
+/// // two copies of one amplicon, another with a longer insert, and a read with no primers
+/// let reads = [
+///     ("read_1", "GGGGTGGAGGATACGTACGTACGTACTACTATGGCCCC".to_string()),
+///     ("read_2", "GGGGTGGAGGATACGTACGTACGTACTACTATGGCCCC".to_string()),
+///     ("read_3", format!("GGGGTGGAGGAT{}TACTATGGCCCC", "ACGT".repeat(10))),
+///     ("read_4", "A".repeat(40)),
+/// ];
+/// let fastq: String = reads
+///     .iter()
+///     .map(|(name, seq)| format!("@{name}\n{seq}\n+\n{}\n", "I".repeat(seq.len())))
+///     .collect();
+///
+/// // keep inserts of up to 20 bases, with no index to filter frequencies against
+/// let (min_freq, min_count, max_len) = (None, None, Some(20));
+/// let (index, amplicon_max_lens) = (None, None);
+/// let filters = FilterSettings::new(&min_freq, &min_count, &max_len, &index, &amplicon_max_lens);
+///
+/// let mut reader = AsyncReader::new(fastq.as_bytes());
+/// let mut writer = AsyncWriter::new(Vec::new());
+/// let written = RecordStream::from_records(reader.records())
+///     .trim_to_amplicons(&scheme)
+///     .filter_by(&filters)
+///     .dedup()
+///     .write_records(&mut writer)
+///     .await?;
+///
+/// // read_2 duplicates read_1, read_3's insert is too long, and read_4 holds no amplicon
+/// assert_eq!(written, 1);
+/// assert_eq!(
+///     writer.get_ref().as_slice(),
+///     format!("@read_1\nACGTACGTACGTAC\n+\n{}\n", "I".repeat(14)).as_bytes()
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub struct RecordStream<'a, R> {
+    /// The records, or errors, from every stage so far
+    inner: Pin<Box<dyn Stream<Item = Result<R>> + 'a>>,
+}
+
+impl<'a, R: 'a> RecordStream<'a, R> {
+    /// Wrap a stream of records, or of errors met reading them.
+    pub fn new(stream: impl Stream<Item = Result<R>> + 'a) -> Self {
+        RecordStream {
+            inner: Box::pin(stream),
+        }
+    }
+}
+
+impl<'a> RecordStream<'a, FastqRecord> {
+    /// Wrap a stream of FASTQ records as they are read, e.g., from
+    /// [`noodles::fastq::AsyncReader::records`].
+    pub fn from_records(records: impl Stream<Item = std::io::Result<FastqRecord>> + 'a) -> Self {
+        Self::new(records.map_err(Report::from))
+    }
+
+    /// Trim each record to the insert of the one amplicon in `scheme` it holds, as in
+    /// [`AmpliconMatcher::trim`], dropping records without exactly one complete amplicon or
+    /// whose insert is too short. Primers are matched exactly.
+    pub fn trim_to_amplicons(self, scheme: &AmpliconScheme) -> RecordStream<'a, TrimmedRecord> {
+        self.trim_to_amplicons_with(scheme, MatcherOptions::default())
+    }
+
+    /// Trim each record as in [`RecordStream::trim_to_amplicons`], but match primers with the
+    /// provided options, e.g., to tolerate sequencing errors in them. The scheme is compiled
+    /// once, up front, for every record in the stream.
+    pub fn trim_to_amplicons_with(
+        self,
+        scheme: &AmpliconScheme,
+        options: MatcherOptions,
+    ) -> RecordStream<'a, TrimmedRecord> {
+        let matcher = AmpliconMatcher::from_scheme(scheme, options);
+        RecordStream::new(self.inner.try_filter_map(move |record| {
+            let trimmed = matcher.match_read(record.sequence()).and_then(|hit| {
+                matcher
+                    .trim_to_hit(record, &hit)
+                    .map(|record| TrimmedRecord {
+                        amplicon: hit.amplicon,
+                        record,
+                    })
+            });
+            future::ready(Ok::<_, Report>(trimmed))
+        }))
+    }
+
+    /// Drop records that fail the provided filters, i.e., those longer than the maximum length
+    /// and those whose sequence is below the minimum frequency or count in the index, or is
+    /// missing from it. The records are expected to be trimmed already. Since the amplicon each
+    /// was trimmed to isn't known, amplicon-specific maximum lengths can't be applied, so use
+    /// [`RecordStream::filter_by`] on the output of [`RecordStream::trim_to_amplicons`] for
+    /// those. Without any filters, every record is kept.
+    pub fn run_filters(self, filters: &'a Option<FilterSettings<'a, 'a>>) -> Self {
+        RecordStream::new(self.inner.try_filter_map(move |record| async move {
+            // no amplicon is named by an empty string, so only the global maximum length applies
+            let rejected = record.rejection_reason("", filters).await.is_some();
+            Ok::<_, Report>((!rejected).then_some(record))
+        }))
+    }
+}
+
+impl<'a> RecordStream<'a, TrimmedRecord> {
+    /// Drop trimmed records that fail the provided filters, as when trimming from the command
+    /// line. Without any filters, every record is kept.
+    pub fn filter_by(self, filters: &'a Option<FilterSettings<'a, 'a>>) -> Self {
+        RecordStream::new(self.inner.try_filter_map(move |trimmed| async move {
+            let rejected = trimmed
+                .record
+                .rejection_reason(&trimmed.amplicon, filters)
+                .await
+                .is_some();
+            Ok::<_, Report>((!rejected).then_some(trimmed))
+        }))
+    }
+
+    /// Set aside the amplicon each record was trimmed to, leaving only the records.
+    pub fn into_records(self) -> RecordStream<'a, FastqRecord> {
+        RecordStream::new(self.inner.map_ok(|trimmed| trimmed.record))
+    }
+}
+
+impl<'a, R: StreamRecord + 'a> RecordStream<'a, R> {
+    /// Drop every record whose sequence has already been seen in the stream, whatever the case
+    /// of its bases, keeping only the first record with each sequence. Every unique sequence is
+    /// remembered until the stream is dropped.
+    pub fn dedup(self) -> Self {
+        self.dedup_by(DedupKey::Seq)
+    }
+
+    /// Drop every record that duplicates one already seen in the stream by `key`, e.g., to
+    /// treat a sequence and its reverse complement as one, keeping only the first record with
+    /// each key. Every unique key is remembered until the stream is dropped.
+    pub fn dedup_by(self, key: DedupKey) -> Self {
+        let mut seen = HashSet::new();
+        RecordStream::new(
+            self.inner.try_filter(move |item| {
+                future::ready(seen.insert(key.key(item.record().sequence())))
+            }),
+        )
+    }
+
+    /// Write every record left in the stream to `writer`, returning how many were written.
+    /// The writer is left open, so that more records can be written to it afterward.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a record cannot be read or written.
+    pub async fn write_records<W: AsyncWrite + Unpin>(
+        mut self,
+        writer: &mut FastqWriter<W>,
+    ) -> Result<u64> {
+        let mut written = 0;
+        while let Some(item) = self.inner.try_next().await? {
+            writer.write_record(item.record()).await?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+impl<R> Stream for RecordStream<'_, R> {
+    type Item = Result<R>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().inner.as_mut().poll_next(cx)
+    }
+}
@@ -1,23 +1,71 @@
 // #![warn(missing_docs)]
 
-//!
+//! Module `primers` pulls primer sequences out of a reference using the coordinates in a BED
+//! file and pairs them into the amplicons of a primer scheme.
 
+use std::hash::{Hash, Hasher};
 use std::io::BufReader;
-use std::{collections::HashMap, fs::File};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+};
 
 use color_eyre::eyre::{eyre, Result};
 use derive_new::new;
+use memchr::memmem::Finder;
 use noodles::bed::Reader as BedReader;
+use noodles::core::{Position, Region};
+use noodles::fasta::fai;
+use noodles::fasta::io::IndexedReader as IndexedFastaReader;
 use noodles::fasta::io::Reader as FastaReader;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::record::subseq;
+
 struct PrimerSeq<'a> {
     primer_name: String,
     primer_seq: &'a str,
 }
 
-///
+/// Substring finders for each orientation of an amplicon's primers, compiled once and reused
+/// for every read.
+#[derive(Debug)]
+pub struct PrimerFinders {
+    /// Finds the forward primer
+    pub fwd: Finder<'static>,
+
+    /// Finds the reverse complement of the forward primer
+    pub fwd_rc: Finder<'static>,
+
+    /// Finds the reverse primer
+    pub rev: Finder<'static>,
+
+    /// Finds the reverse complement of the reverse primer
+    pub rev_rc: Finder<'static>,
+}
+
+/// A lazily filled cache of [`PrimerFinders`]. It is invisible to serialization, hashing, and
+/// comparisons, so caching finders never changes a scheme's identity.
+#[derive(Debug, Default)]
+pub struct FinderCache(OnceLock<PrimerFinders>);
+
+impl Hash for FinderCache {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl PartialEq for FinderCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FinderCache {}
+
+/// The forward and reverse primers for one amplicon, in each orientation they may appear in a
+/// read.
 #[derive(Debug, new, Hash, Serialize, Deserialize, Eq, PartialEq)]
 pub struct PossiblePrimers {
     /// The name or label of the amplicon
@@ -34,19 +82,72 @@ pub struct PossiblePrimers {
 
     /// The reverse complement of the reverse primer sequence
     pub rev_rc: String,
+
+    /// Finders for each primer sequence, compiled on first use
+    #[new(default)]
+    #[serde(skip)]
+    finders: FinderCache,
 }
 
-///
+impl PossiblePrimers {
+    /// Whether the forward and reverse primers are identical or reverse complements of each
+    /// other, e.g., for palindromic targets, so that they can only be told apart by where they
+    /// lie in a read.
+    pub fn primers_coincide(&self) -> bool {
+        self.fwd.eq_ignore_ascii_case(&self.rev) || self.fwd.eq_ignore_ascii_case(&self.rev_rc)
+    }
+
+    /// The compiled finders for each primer sequence, which are built the first time they are
+    /// needed and then reused for every read.
+    pub fn finders(&self) -> &PrimerFinders {
+        self.finders.0.get_or_init(|| PrimerFinders {
+            fwd: Finder::new(self.fwd.as_bytes()).into_owned(),
+            fwd_rc: Finder::new(self.fwd_rc.as_bytes()).into_owned(),
+            rev: Finder::new(self.rev.as_bytes()).into_owned(),
+            rev_rc: Finder::new(self.rev_rc.as_bytes()).into_owned(),
+        })
+    }
+}
+
+/// The primer sequences actually found in a read, along with the amplicon they belong to.
 #[derive(Debug, new, Hash, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct PrimerPair {
+    /// The name or label of the matched amplicon
+    pub amplicon: String,
+
+    /// The forward primer sequence as it was found in the read
     pub fwd: String,
+
+    /// The reverse primer sequence as it was found in the read
     pub rev: String,
 }
 
-///
+/// Two primers in a scheme whose sequences are reverse complements of each other. Primers are
+/// searched for in both orientations, so each is found wherever the other is, and reads may be
+/// dropped as chimeras, multiple matches, or too-short inserts rather than trimmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimerCollision {
+    /// The first primer, named by its amplicon and orientation, e.g. `amplicon_1 forward`
+    pub first: String,
+
+    /// The second primer, named in the same way
+    pub second: String,
+}
+
+impl std::fmt::Display for PrimerCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the {} primer is the reverse complement of the {} primer",
+            self.first, self.second
+        )
+    }
+}
+
+/// All amplicons that could be present in a set of reads.
 #[derive(Debug, Hash, Serialize, Deserialize, PartialEq)]
 pub struct AmpliconScheme {
-    ///
+    /// The primers for each amplicon in the scheme
     pub scheme: Vec<PossiblePrimers>,
 }
 
@@ -59,6 +160,105 @@ impl AmpliconScheme {
 
         Ok(hash)
     }
+
+    /// Make sure the scheme has at least one amplicon, so that a BED file or primer table whose
+    /// primers could not be paired is reported as a broken scheme rather than as reads that all
+    /// failed to match.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the scheme has no amplicons.
+    pub fn ensure_not_empty(&self) -> Result<()> {
+        if self.scheme.is_empty() {
+            return Err(eyre!(
+                "The primer scheme produced no amplicons, so no reads could match it. Check that the primer names end in the expected suffixes and that their coordinates fall within the reference. Pass --allow-empty-scheme to run anyway."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keep only the named amplicon in the scheme, e.g., to quickly re-trim the reads for one
+    /// problematic amplicon. Any hash used to match an index should be taken from the full
+    /// scheme beforehand.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the scheme has no amplicon with that name.
+    pub fn restrict_to_amplicon(self, amplicon: &str) -> Result<AmpliconScheme> {
+        let scheme: Vec<PossiblePrimers> = self
+            .scheme
+            .into_iter()
+            .filter(|pair| pair.amplicon == amplicon)
+            .collect();
+        if scheme.is_empty() {
+            return Err(eyre!(
+                "The amplicon {} is not in the primer scheme.",
+                amplicon
+            ));
+        }
+
+        Ok(AmpliconScheme { scheme })
+    }
+
+    /// Find every pair of primers from two different amplicons whose sequences are reverse
+    /// complements of each other, regardless of case. Each pair is reported once, even if its
+    /// amplicons are listed more than once. An amplicon's own primers may coincide, since they
+    /// are then told apart by position, as in [`PossiblePrimers::primers_coincide`].
+    pub fn reverse_complement_collisions(&self) -> Vec<PrimerCollision> {
+        let primers: Vec<(&str, String, &str, &str)> = self
+            .scheme
+            .iter()
+            .flat_map(|pair| {
+                [
+                    (pair, "forward", &pair.fwd, &pair.fwd_rc),
+                    (pair, "reverse", &pair.rev, &pair.rev_rc),
+                ]
+            })
+            .map(|(pair, orientation, seq, seq_rc)| {
+                (
+                    pair.amplicon.as_str(),
+                    format!("{} {orientation}", pair.amplicon),
+                    seq.as_str(),
+                    seq_rc.as_str(),
+                )
+            })
+            .collect();
+
+        let mut collisions = Vec::new();
+        for (i, (amplicon, first, seq, _)) in primers.iter().enumerate() {
+            for (other_amplicon, second, _, other_rc) in &primers[i + 1..] {
+                if amplicon == other_amplicon || !seq.eq_ignore_ascii_case(other_rc) {
+                    continue;
+                }
+                let collision = PrimerCollision {
+                    first: first.clone(),
+                    second: second.clone(),
+                };
+                if !collisions.contains(&collision) {
+                    collisions.push(collision);
+                }
+            }
+        }
+        collisions
+    }
+
+    /// Read each amplicon's reverse primer as the sequence it appears as in reads of the
+    /// forward strand, i.e., already reverse complemented, for schemes whose BED files or primer
+    /// sequences encode reverse primers in read orientation. The reverse primer and its reverse
+    /// complement trade places, so that the rest of the scheme's conventions, e.g. which end of
+    /// a read each primer is anchored to, hold as they would for a scheme written 5' to 3'. Any
+    /// hash used to match an index should be taken afterward.
+    pub fn with_rev_as_appears(self) -> AmpliconScheme {
+        let scheme = self
+            .scheme
+            .into_iter()
+            .map(|pair| {
+                PossiblePrimers::new(pair.amplicon, pair.fwd, pair.fwd_rc, pair.rev_rc, pair.rev)
+            })
+            .collect();
+
+        AmpliconScheme { scheme }
+    }
 }
 
 /// .
@@ -73,35 +273,443 @@ pub async fn ref_to_dict(
         .records()
         .filter_map(|record| record.ok())
         .map(|record| {
-            let name = record.name().to_owned();
-            let sequence = record.sequence().as_ref().to_owned();
+            let name = ref_name_key(record.name()).to_owned();
+            // uppercase soft-masked regions so primers extracted from them match reads
+            let sequence = record.sequence().as_ref().to_ascii_uppercase();
             (name, sequence)
         })
         .collect();
     Ok(ref_dict)
 }
 
+/// Merge the sequences from several reference FASTAs, e.g., for a segmented genome shipped as
+/// one file per segment, into a single map as in [`ref_to_dict`].
 ///
-fn get_reverse_complement(sequence: &str) -> String {
-    sequence
-        .chars()
-        .flat_map(|base| match base {
-            'A' => Some('T'),
-            'T' => Some('A'),
-            'G' => Some('C'),
-            'C' => Some('G'),
-            'U' => Some('A'),
-            _ => None,
+/// # Errors
+///
+/// This function will return an error if any reference cannot be read or if two references
+/// contain sequences with the same name.
+pub async fn refs_to_dict(
+    ref_files: &mut [FastaReader<BufReader<File>>],
+) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut merged = HashMap::new();
+    for ref_file in ref_files.iter_mut() {
+        for (name, sequence) in ref_to_dict(ref_file).await? {
+            if merged.contains_key(&name) {
+                return Err(eyre!(
+                    "The reference sequence {} appears in more than one reference FASTA.",
+                    String::from_utf8_lossy(&name)
+                ));
+            }
+            merged.insert(name, sequence);
+        }
+    }
+    Ok(merged)
+}
+
+/// The part of a reference sequence name that BED and FASTA names are matched on, which is its
+/// first whitespace-delimited token. FASTA headers often carry a description after the name,
+/// e.g. `chr1 Homo sapiens chromosome 1`, while BED files only ever use the bare name.
+pub fn ref_name_key(name: &[u8]) -> &[u8] {
+    name.split(|byte| byte.is_ascii_whitespace())
+        .find(|token| !token.is_empty())
+        .unwrap_or(name)
+}
+
+/// A reference that primer sequences can be looked up in by their coordinates. Names are
+/// matched on [`ref_name_key`], so any description after a name is ignored.
+pub trait RefLookup {
+    /// Whether the reference holds any sequence with this name.
+    fn has_sequence(&self, name: &[u8]) -> bool;
+
+    /// The bases of the named reference sequence from `start` up to, but not including,
+    /// `stop`, if they are available.
+    fn lookup(&self, name: &[u8], start: usize, stop: usize) -> Option<&[u8]>;
+}
+
+impl RefLookup for HashMap<Vec<u8>, Vec<u8>> {
+    fn has_sequence(&self, name: &[u8]) -> bool {
+        self.contains_key(ref_name_key(name))
+    }
+
+    fn lookup(&self, name: &[u8], start: usize, stop: usize) -> Option<&[u8]> {
+        subseq(self.get(ref_name_key(name))?, start, stop).ok()
+    }
+}
+
+/// How many bases on either side of each primer are kept by [`refs_to_windows`], which leaves
+/// room to check shifted BED coordinates with [`check_bed_coordinates`].
+pub const REF_WINDOW_MARGIN: usize = 2 * MAX_BED_SHIFT;
+
+/// Only the windows of a reference that a BED file's primers fall in, rather than whole
+/// reference sequences, for references too large to hold in memory as a whole.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RefWindows {
+    /// The windows in each reference sequence, as the position each starts at and its bases
+    windows: HashMap<Vec<u8>, Vec<(usize, Vec<u8>)>>,
+}
+
+impl RefLookup for RefWindows {
+    fn has_sequence(&self, name: &[u8]) -> bool {
+        self.windows.contains_key(ref_name_key(name))
+    }
+
+    fn lookup(&self, name: &[u8], start: usize, stop: usize) -> Option<&[u8]> {
+        if start > stop {
+            return None;
+        }
+        self.windows
+            .get(ref_name_key(name))?
+            .iter()
+            .find(|(window_start, bases)| {
+                start >= *window_start && stop <= window_start + bases.len()
+            })
+            .map(|(window_start, bases)| &bases[start - window_start..stop - window_start])
+    }
+}
+
+/// The 0-based, half-open spans to load from each reference sequence, keyed by its name.
+type RefRegions = HashMap<Vec<u8>, Vec<(usize, usize)>>;
+
+/// Collect the span around each primer in the BED file, padded by [`REF_WINDOW_MARGIN`] bases on
+/// either side, for each reference sequence, merging spans that overlap so each base is only
+/// kept once.
+fn primer_regions(mut bed: BedReader<BufReader<File>>) -> Result<RefRegions> {
+    let mut regions = RefRegions::new();
+    for record in bed.records::<4>() {
+        let record = record?;
+        let start = (record.start_position().get() - 1).saturating_sub(REF_WINDOW_MARGIN);
+        let stop = record.end_position().get() + REF_WINDOW_MARGIN;
+        regions
+            .entry(ref_name_key(record.reference_sequence_name().as_bytes()).to_owned())
+            .or_default()
+            .push((start, stop));
+    }
+
+    for spans in regions.values_mut() {
+        spans.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+        for &(start, stop) in spans.iter() {
+            match merged.last_mut() {
+                Some((_, last_stop)) if start <= *last_stop => *last_stop = stop.max(*last_stop),
+                _ => merged.push((start, stop)),
+            }
+        }
+        *spans = merged;
+    }
+
+    Ok(regions)
+}
+
+/// Where an amplicon's insert, between the end of its forward primer and the start of its
+/// reverse primer, lies in the reference according to the coordinates in a BED file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertRegion {
+    /// The name of the reference sequence the amplicon lies on
+    pub ref_name: String,
+
+    /// The 0-based position right after the forward primer, where the insert starts
+    pub start: usize,
+
+    /// The 0-based position where the reverse primer starts, which is where the insert ends,
+    /// exclusive, or before `start` if the primers overlap
+    pub stop: usize,
+}
+
+impl InsertRegion {
+    /// The number of bases in the insert, which is zero if the primers abut or overlap.
+    pub fn len(&self) -> usize {
+        self.stop.saturating_sub(self.start)
+    }
+
+    /// Whether the primers abut or overlap, leaving no insert.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Where the insert between each amplicon's primers lies in the reference according to the
+/// coordinates in the BED file, keyed by amplicon name as in [`define_amplicons`]. Amplicons
+/// missing a primer, or whose primers lie on different reference sequences, are left out.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read.
+pub fn insert_regions(
+    mut bed: BedReader<BufReader<File>>,
+    fwd_suffix: &str,
+    rev_suffix: &str,
+) -> Result<HashMap<String, InsertRegion>> {
+    let mut fwd_ends: HashMap<String, (String, usize)> = HashMap::new();
+    let mut rev_starts: HashMap<String, (String, usize)> = HashMap::new();
+    for record in bed.records::<4>() {
+        let record = record?;
+        let name = record
+            .name()
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+        let amplicon = name.replace(fwd_suffix, "").replace(rev_suffix, "");
+        let ref_name = record.reference_sequence_name().to_string();
+        if name.contains(fwd_suffix) {
+            fwd_ends.insert(amplicon, (ref_name, record.end_position().get()));
+        } else if name.contains(rev_suffix) {
+            rev_starts.insert(amplicon, (ref_name, record.start_position().get() - 1));
+        }
+    }
+
+    Ok(fwd_ends
+        .into_iter()
+        .filter_map(|(amplicon, (ref_name, start))| {
+            let (rev_ref, stop) = rev_starts.remove(&amplicon)?;
+            (rev_ref == ref_name).then_some((
+                amplicon,
+                InsertRegion {
+                    ref_name,
+                    start,
+                    stop,
+                },
+            ))
         })
-        .rev()
-        .collect::<String>()
+        .collect())
 }
 
+/// The length of the insert between each amplicon's primers according to the coordinates in
+/// the BED file, keyed by amplicon name as in [`define_amplicons`]. Amplicons missing a primer,
+/// or whose primers lie on different reference sequences, are left out, and primers that
+/// overlap give an insert length of zero.
 ///
-async fn collect_primer_seqs(
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read.
+pub fn expected_insert_lens(
+    bed: BedReader<BufReader<File>>,
+    fwd_suffix: &str,
+    rev_suffix: &str,
+) -> Result<HashMap<String, usize>> {
+    Ok(insert_regions(bed, fwd_suffix, rev_suffix)?
+        .into_iter()
+        .map(|(amplicon, region)| (amplicon, region.len()))
+        .collect())
+}
+
+/// Pull just the windows around each primer in the BED file, plus [`REF_WINDOW_MARGIN`] bases
+/// on either side, out of one or more reference FASTAs. References are streamed one sequence at
+/// a time, so at most one whole sequence is held in memory at once, and only the windows are
+/// kept. Primer sequences looked up in the result are identical to those looked up in the
+/// output of [`refs_to_dict`].
+///
+/// # Errors
+///
+/// This function will return an error if the BED file or any reference cannot be read, or if
+/// two references contain sequences with the same name.
+pub async fn refs_to_windows(
+    ref_files: &mut [FastaReader<BufReader<File>>],
+    bed: BedReader<BufReader<File>>,
+) -> Result<RefWindows> {
+    let regions = primer_regions(bed)?;
+
+    let mut windows: HashMap<Vec<u8>, Vec<(usize, Vec<u8>)>> = HashMap::new();
+    let mut seen = HashSet::new();
+    for ref_file in ref_files.iter_mut() {
+        for record in ref_file.records() {
+            let record = record?;
+            let name = ref_name_key(record.name()).to_owned();
+            if !seen.insert(name.clone()) {
+                return Err(eyre!(
+                    "The reference sequence {} appears in more than one reference FASTA.",
+                    String::from_utf8_lossy(&name)
+                ));
+            }
+            let Some(spans) = regions.get(&name) else {
+                continue;
+            };
+
+            let sequence = record.sequence().as_ref();
+            let kept = spans
+                .iter()
+                .filter(|(start, _)| *start < sequence.len())
+                .map(|&(start, stop)| {
+                    // uppercase soft-masked regions so primers extracted from them match reads
+                    let bases = sequence[start..stop.min(sequence.len())].to_ascii_uppercase();
+                    (start, bases)
+                })
+                .collect();
+            windows.insert(name, kept);
+        }
+    }
+
+    Ok(RefWindows { windows })
+}
+
+/// Fetch the same windows as [`refs_to_windows`], but by coordinate through each reference's
+/// `.fai` index, so that nothing outside the windows is ever read from disk.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file or any reference cannot be read, or if
+/// two references contain sequences with the same name.
+pub fn indexed_refs_to_windows(
+    ref_files: &mut [IndexedFastaReader<BufReader<File>>],
+    bed: BedReader<BufReader<File>>,
+) -> Result<RefWindows> {
+    let regions = primer_regions(bed)?;
+
+    // work out which reference holds each sequence, how long it is, and the name its index
+    // knows it by, from the indices alone
+    let mut locations: HashMap<Vec<u8>, (usize, usize, Vec<u8>)> = HashMap::new();
+    for (i, ref_file) in ref_files.iter().enumerate() {
+        let records: &[fai::Record] = ref_file.index().as_ref();
+        for record in records {
+            let indexed_name = AsRef::<[u8]>::as_ref(record.name()).to_owned();
+            let name = ref_name_key(&indexed_name).to_owned();
+            if locations.contains_key(&name) {
+                return Err(eyre!(
+                    "The reference sequence {} appears in more than one reference FASTA.",
+                    String::from_utf8_lossy(&name)
+                ));
+            }
+            locations.insert(name, (i, usize::try_from(record.length())?, indexed_name));
+        }
+    }
+
+    let mut windows: HashMap<Vec<u8>, Vec<(usize, Vec<u8>)>> = HashMap::new();
+    for (name, spans) in regions {
+        let Some((i, length, indexed_name)) = locations.get(&name) else {
+            continue;
+        };
+        let (i, length) = (*i, *length);
+
+        let mut kept = Vec::with_capacity(spans.len());
+        for (start, stop) in spans.into_iter().filter(|(start, _)| *start < length) {
+            let stop = stop.min(length);
+            let (Some(first), Some(last)) = (Position::new(start + 1), Position::new(stop)) else {
+                continue;
+            };
+            let region = Region::new(indexed_name.clone(), first..=last);
+            let record = ref_files[i].query(&region)?;
+
+            // uppercase soft-masked regions so primers extracted from them match reads
+            kept.push((start, record.sequence().as_ref().to_ascii_uppercase()));
+        }
+        windows.insert(name, kept);
+    }
+
+    Ok(RefWindows { windows })
+}
+
+/// A lookup table from each byte to its complement. A, C, G, and T (and U, for RNA) are
+/// complemented in either case, as are the IUPAC degenerate bases, so that degenerate primers
+/// round-trip: R and Y, K and M, and B and V swap, D and H swap, and S, W, and N are their own
+/// complements. Every other byte maps to itself.
+const COMPLEMENT: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = byte as u8;
+        byte += 1;
+    }
+    let pairs: [(u8, u8); 13] = [
+        (b'A', b'T'),
+        (b'T', b'A'),
+        (b'G', b'C'),
+        (b'C', b'G'),
+        (b'U', b'A'),
+        (b'R', b'Y'),
+        (b'Y', b'R'),
+        (b'K', b'M'),
+        (b'M', b'K'),
+        (b'B', b'V'),
+        (b'V', b'B'),
+        (b'D', b'H'),
+        (b'H', b'D'),
+    ];
+    let mut i = 0;
+    while i < pairs.len() {
+        let (base, complement) = pairs[i];
+        table[base as usize] = complement;
+        table[base.to_ascii_lowercase() as usize] = complement.to_ascii_lowercase();
+        i += 1;
+    }
+    table
+};
+
+/// What to do with bytes that aren't nucleotides or IUPAC codes, e.g. gaps or stray
+/// punctuation, when reverse complementing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownBases {
+    /// Keep each unknown byte as it is
+    #[default]
+    Keep,
+
+    /// Replace each unknown byte with `N`
+    MaskAsN,
+}
+
+/// Whether a byte is a nucleotide, in either case, or an IUPAC degenerate base.
+fn is_known_base(base: u8) -> bool {
+    matches!(
+        base.to_ascii_uppercase(),
+        b'A' | b'C'
+            | b'G'
+            | b'T'
+            | b'U'
+            | b'R'
+            | b'Y'
+            | b'S'
+            | b'W'
+            | b'K'
+            | b'M'
+            | b'B'
+            | b'D'
+            | b'H'
+            | b'V'
+            | b'N'
+    )
+}
+
+/// Reverse complement a nucleotide sequence held as bytes, as in sequencing records, without
+/// any UTF-8 conversion. IUPAC degenerate bases are complemented along with A, C, G, T, and U,
+/// and any other byte is kept as-is, so the output is always the same length as the input and
+/// stays in step with any quality scores.
+pub fn reverse_complement_bytes(seq: &[u8]) -> Vec<u8> {
+    reverse_complement_bytes_with(seq, UnknownBases::Keep)
+}
+
+/// Reverse complement a sequence held as bytes as in [`reverse_complement_bytes`], handling
+/// bytes that aren't bases as `unknown` says. Either way, the output is the same length as the
+/// input.
+pub fn reverse_complement_bytes_with(seq: &[u8], unknown: UnknownBases) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match unknown {
+            UnknownBases::MaskAsN if !is_known_base(*base) => b'N',
+            _ => COMPLEMENT[*base as usize],
+        })
+        .collect()
+}
+
+/// Reverse complement a nucleotide sequence held as a string. This is a thin wrapper around
+/// [`reverse_complement_bytes`].
+pub fn get_reverse_complement(sequence: &str) -> String {
+    get_reverse_complement_with(sequence, UnknownBases::Keep)
+}
+
+/// Reverse complement a sequence held as a string, handling bytes that aren't bases as
+/// `unknown` says. This is a thin wrapper around [`reverse_complement_bytes_with`].
+pub fn get_reverse_complement_with(sequence: &str, unknown: UnknownBases) -> String {
+    String::from_utf8_lossy(&reverse_complement_bytes_with(sequence.as_bytes(), unknown))
+        .into_owned()
+}
+
+/// Look up the sequence of each primer in the BED file within the reference, unless the primer
+/// has an override sequence, which is used as-is.
+async fn collect_primer_seqs<'a, R: RefLookup + ?Sized>(
     mut bed: BedReader<BufReader<File>>,
-    ref_dict: &HashMap<Vec<u8>, Vec<u8>>,
-) -> Result<Vec<PrimerSeq>> {
+    ref_dict: &'a R,
+    overrides: &'a HashMap<String, String>,
+    strict: bool,
+) -> Result<Vec<PrimerSeq<'a>>> {
+    let mut masked = Vec::new();
     let all_primer_seqs: Vec<PrimerSeq> = bed
         .records()
         .filter_map(|record| record.ok())
@@ -109,32 +717,49 @@ async fn collect_primer_seqs(
             // define the primer name and amplicon name
             let primer_name = record.name().unwrap().to_string();
 
-            // define the ref name and start and stop positions
+            // sequences provided for a primer by name take precedence over the reference
+            if let Some(primer_seq) = overrides.get(&primer_name) {
+                return Ok(PrimerSeq {
+                    primer_name,
+                    primer_seq,
+                });
+            }
+
+            // define the ref name and the 0-based, half-open start and stop positions, as they
+            // are written in the BED file
             let ref_name = record.reference_sequence_name().as_bytes().to_owned();
-            let start_pos = record.start_position().get();
+            let start_pos = record.start_position().get() - 1;
             let stop_pos = record.end_position().get();
 
-            // pull in the sequence from the ref hashmap
-            let seq = ref_dict.get(&ref_name).unwrap();
-            match stop_pos <= seq.len() {
-                true => {
-                    let primer_seq_bytes = &seq[start_pos..stop_pos];
+            // pull in the sequence from the reference
+            match ref_dict.lookup(&ref_name, start_pos, stop_pos) {
+                Some(primer_seq_bytes) => {
                     let primer_seq = std::str::from_utf8(primer_seq_bytes)?;
 
+                    // hard-masked assembly gaps leave a primer that can never match a read
+                    if primer_seq_bytes.contains(&b'N') {
+                        masked.push(format!(
+                            "{} (positions {} to {} on {}) is {}",
+                            primer_name,
+                            start_pos,
+                            stop_pos,
+                            String::from_utf8_lossy(&ref_name),
+                            primer_seq
+                        ));
+                    }
+
                     Ok(PrimerSeq {
                         primer_name,
                         primer_seq,
                     })
                 }
-                false => {
-                    eprintln!("{}", seq.len());
+                None => {
                     let message = format!(
-                        "Positions {} and {} for {} are not present in the reference sequence, {}. The reference sequence is:\n\n{}\n",
+                        "Positions {} and {} for {} are not present in the reference sequence, {}.",
                         &start_pos,
                         &stop_pos,
                         &primer_name,
-                        String::from_utf8(ref_name)?,
-                        String::from_utf8(seq.clone())?
+                        String::from_utf8(ref_name)?
                     );
                     eprintln!("{}", &message);
                     Err(eyre!(message))
@@ -143,6 +768,19 @@ async fn collect_primer_seqs(
         })
         .filter_map(|primer_seq| primer_seq.ok())
         .collect();
+
+    if !masked.is_empty() {
+        let message = format!(
+            "{} primer(s) overlap hard-masked N bases in the reference, so their sequences can't be derived from it and they will never match a read:\n{}\nProvide their sequences with --primer-fasta instead.",
+            masked.len(),
+            masked.join("\n")
+        );
+        if strict {
+            return Err(eyre!(message));
+        }
+        eprintln!("Warning: {message}");
+    }
+
     Ok(all_primer_seqs)
 }
 
@@ -155,14 +793,57 @@ async fn collect_primer_seqs(
 /// # Errors
 ///
 /// This function will return an error if .
-pub async fn define_amplicons<'a>(
+pub async fn define_amplicons<'a, R: RefLookup + ?Sized>(
+    bed: BedReader<BufReader<File>>,
+    ref_dict: &'a R,
+    fwd_suffix: &'a str,
+    rev_suffix: &'a str,
+) -> Result<AmpliconScheme> {
+    define_amplicons_with_overrides(bed, ref_dict, &HashMap::new(), fwd_suffix, rev_suffix).await
+}
+
+/// Define amplicons from BED coordinates as in [`define_amplicons`], but take the sequence of
+/// any primer named in `overrides` from there rather than from the reference. This way, the BED
+/// file can still provide coordinates while, e.g., a primer FASTA provides the authoritative
+/// sequences when the BED was designed against a slightly different reference. Because reads
+/// are searched for both orientations of each primer, override sequences may be given in
+/// either orientation.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read.
+pub async fn define_amplicons_with_overrides<'a, R: RefLookup + ?Sized>(
+    bed: BedReader<BufReader<File>>,
+    ref_dict: &'a R,
+    overrides: &'a HashMap<String, String>,
+    fwd_suffix: &'a str,
+    rev_suffix: &'a str,
+) -> Result<AmpliconScheme> {
+    define_amplicons_checked(bed, ref_dict, overrides, fwd_suffix, rev_suffix, false).await
+}
+
+/// Define amplicons as in [`define_amplicons_with_overrides`], choosing what happens when a
+/// primer's region of the reference contains hard-masked `N` bases. Such primers can never
+/// match a read exactly, so they are reported with a warning, or when `strict` is set, with an
+/// error.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read, or when `strict` is set,
+/// if any primer taken from the reference contains `N` bases.
+pub async fn define_amplicons_checked<'a, R: RefLookup + ?Sized>(
     bed: BedReader<BufReader<File>>,
-    ref_dict: &'a HashMap<Vec<u8>, Vec<u8>>,
+    ref_dict: &'a R,
+    overrides: &'a HashMap<String, String>,
     fwd_suffix: &'a str,
     rev_suffix: &'a str,
+    strict: bool,
 ) -> Result<AmpliconScheme> {
-    let all_primer_seqs = collect_primer_seqs(bed, ref_dict).await?;
+    let all_primer_seqs = collect_primer_seqs(bed, ref_dict, overrides, strict).await?;
 
+    // both primers of an amplicon name it, so each amplicon is only kept the first time it is
+    // named, in the order of the BED file
+    let mut seen = HashSet::new();
     let amplicons = all_primer_seqs
         .iter()
         .map(|primer_seq| {
@@ -171,6 +852,7 @@ pub async fn define_amplicons<'a>(
                 .replace(fwd_suffix, "")
                 .replace(rev_suffix, "")
         })
+        .filter(|amplicon| seen.insert(amplicon.clone()))
         .collect::<Vec<String>>();
 
     let scheme = amplicons
@@ -200,13 +882,13 @@ pub async fn define_amplicons<'a>(
             if let (Some(fwd), Some(rev)) = (fwd, rev) {
                 let fwd_rc = get_reverse_complement(fwd.primer_seq);
                 let rev_rc = get_reverse_complement(rev.primer_seq);
-                let pair = PossiblePrimers {
+                let pair = PossiblePrimers::new(
                     amplicon,
-                    fwd: fwd.primer_seq.to_owned(),
+                    fwd.primer_seq.to_owned(),
                     fwd_rc,
-                    rev: rev.primer_seq.to_owned(),
+                    rev.primer_seq.to_owned(),
                     rev_rc,
-                };
+                );
                 Some(pair)
             } else {
                 None
@@ -214,5 +896,302 @@ pub async fn define_amplicons<'a>(
         })
         .collect::<Vec<PossiblePrimers>>();
 
-    Ok(AmpliconScheme { scheme })
+    let scheme = AmpliconScheme { scheme };
+    check_collisions(&scheme, strict)?;
+
+    Ok(scheme)
+}
+
+/// Warn about, or with `strict`, stop on, any primers in the scheme that are reverse
+/// complements of each other, since the matcher will behave poorly with them.
+///
+/// # Errors
+///
+/// This function will return an error if `strict` is set and any primers collide.
+fn check_collisions(scheme: &AmpliconScheme, strict: bool) -> Result<()> {
+    let collisions = scheme.reverse_complement_collisions();
+    if collisions.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{} pair(s) of primers are reverse complements of each other, so each will be found wherever the other is and reads may be dropped rather than trimmed:\n{}\nCheck the primer sequences in the scheme.",
+        collisions.len(),
+        collisions
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    if strict {
+        return Err(eyre!(message));
+    }
+    eprintln!("Warning: {message}");
+
+    Ok(())
+}
+
+/// The furthest, in bases, that BED coordinates are shifted in either direction when looking
+/// for where a mismatched primer really sits in the reference.
+pub const MAX_BED_SHIFT: usize = 5;
+
+/// A primer whose sequence at its BED coordinates in the reference differs from its sequence in
+/// the primer FASTA, e.g. because the BED file uses 1-based coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoordinateMismatch {
+    /// The name of the primer
+    pub primer_name: String,
+
+    /// The 0-based start of the primer, as written in the BED file
+    pub start: usize,
+
+    /// The end of the primer, as written in the BED file
+    pub stop: usize,
+
+    /// The shift, in bases, that would make the BED coordinates match the primer sequence, if
+    /// any shift of up to [`MAX_BED_SHIFT`] bases does
+    pub shift: Option<isize>,
+}
+
+impl std::fmt::Display for CoordinateMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The reference sequence at positions {} and {} for {} does not match its sequence in the primer FASTA",
+            self.start, self.stop, self.primer_name
+        )?;
+        match self.shift {
+            Some(shift) => write!(
+                f,
+                ", but it does {} base(s) {}. The BED coordinates are likely off by {}; try positions {} and {} instead.",
+                shift.unsigned_abs(),
+                if shift < 0 { "upstream" } else { "downstream" },
+                shift,
+                self.start.saturating_add_signed(shift),
+                self.stop.saturating_add_signed(shift)
+            ),
+            None => write!(
+                f,
+                ", even when shifted up to {} bases in either direction. The BED file may have been designed against a different reference.",
+                MAX_BED_SHIFT
+            ),
+        }
+    }
+}
+
+/// Check that the sequence at each primer's BED coordinates in the reference matches the
+/// sequence given for it in a primer FASTA, in either orientation. Primers missing from the
+/// FASTA are skipped. For each primer that doesn't match, the nearest shift of up to
+/// [`MAX_BED_SHIFT`] bases that would make it match, if any, is reported so that the BED file
+/// can be corrected.
+///
+/// # Errors
+///
+/// This function will return an error if the BED file cannot be read or names a reference
+/// sequence that is not in the reference FASTA.
+pub fn check_bed_coordinates<R: RefLookup + ?Sized>(
+    mut bed: BedReader<BufReader<File>>,
+    ref_dict: &R,
+    primer_seqs: &HashMap<String, String>,
+) -> Result<Vec<CoordinateMismatch>> {
+    let mut mismatches = Vec::new();
+    for record in bed.records::<4>() {
+        let record = record?;
+        let Some(primer_name) = record.name().map(|name| name.to_string()) else {
+            continue;
+        };
+        let Some(primer_seq) = primer_seqs.get(&primer_name) else {
+            continue;
+        };
+        let primer = primer_seq.to_ascii_uppercase().into_bytes();
+        let primer_rc = reverse_complement_bytes(&primer);
+
+        let ref_name = record.reference_sequence_name();
+        if !ref_dict.has_sequence(ref_name.as_bytes()) {
+            return Err(eyre!(
+                "The reference sequence {} for {} is not in the reference FASTA.",
+                ref_name,
+                primer_name
+            ));
+        }
+        // noodles reports the 0-based BED start as a 1-based position, so step back a base to
+        // compare against, and report, the coordinates as they are written in the BED file
+        let start = record.start_position().get() - 1;
+        let stop = record.end_position().get();
+
+        let matches_at = |shift: isize| {
+            let (Some(start), Some(stop)) = (
+                start.checked_add_signed(shift),
+                stop.checked_add_signed(shift),
+            ) else {
+                return false;
+            };
+            ref_dict
+                .lookup(ref_name.as_bytes(), start, stop)
+                .is_some_and(|window| window == primer || window == primer_rc)
+        };
+        if matches_at(0) {
+            continue;
+        }
+
+        // try the smallest shifts first, since off-by-one errors are by far the most common
+        let shift = (1..=MAX_BED_SHIFT as isize)
+            .flat_map(|distance| [-distance, distance])
+            .find(|shift| matches_at(*shift));
+        mismatches.push(CoordinateMismatch {
+            primer_name,
+            start,
+            stop,
+            shift,
+        });
+    }
+
+    Ok(mismatches)
+}
+
+/// A primer scheme assembled from a primer table, along with any per-amplicon expected
+/// lengths the table provides.
+#[derive(Debug)]
+pub struct TableScheme {
+    /// The amplicons defined by the table
+    pub scheme: AmpliconScheme,
+
+    /// The expected maximum insert length for each amplicon that has one, keyed by amplicon name
+    pub expected_lens: HashMap<String, usize>,
+}
+
+/// Read primer sequences from a FASTA file into a map of primer names to sequences. Sequences
+/// are uppercased, as primers taken from a reference are, since reads are matched against them
+/// in uppercase whatever the case of their own bases.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed or is not valid UTF-8.
+pub fn read_primer_fasta(
+    primer_fasta: &mut FastaReader<BufReader<File>>,
+) -> Result<HashMap<String, String>> {
+    primer_fasta
+        .records()
+        .map(|record| {
+            let record = record?;
+            let name = String::from_utf8(record.name().to_owned())?;
+            let seq = String::from_utf8(record.sequence().as_ref().to_ascii_uppercase())?;
+            Ok((name, seq))
+        })
+        .collect()
+}
+
+/// Define amplicons from a tab-separated primer table. Each row names a forward and a reverse
+/// primer from the primer FASTA, optionally followed by an explicit amplicon name and then by
+/// the expected maximum length of the amplicon's trimmed insert. A third column that is a whole
+/// number is read as the expected length, as in tables without amplicon names; to name an
+/// amplicon with a number, give both columns. Without an explicit name, amplicon names are the
+/// forward primer names with `fwd_suffix` removed. Blank lines and lines starting with `#` are
+/// skipped. Reverse primers are taken to be written 5' to 3' in the FASTA unless
+/// `rev_as_appears` is set, in which case they are taken as they appear in reads of the forward
+/// strand and reverse complemented before each amplicon's primers are assembled.
+///
+/// # Errors
+///
+/// This function will return an error if the table cannot be read, if a row has the wrong
+/// number of columns, an empty amplicon name, or an invalid length, or if it names a primer
+/// missing from the FASTA.
+pub fn define_amplicons_from_table(
+    table_path: &Path,
+    primer_seqs: &HashMap<String, String>,
+    fwd_suffix: &str,
+    rev_as_appears: bool,
+) -> Result<TableScheme> {
+    let table = std::fs::read_to_string(table_path)?;
+
+    let mut scheme = Vec::new();
+    let mut expected_lens = HashMap::new();
+    for (line_number, line) in table.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parse_len = |expected_len: &str| {
+            expected_len.parse::<usize>().map_err(|_| {
+                eyre!(
+                    "Line {} of {:?} has an invalid expected length: {:?}",
+                    line_number + 1,
+                    table_path,
+                    expected_len
+                )
+            })
+        };
+
+        let columns: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let (fwd_name, rev_name, label, expected_len) = match columns.as_slice() {
+            [fwd_name, rev_name] => (*fwd_name, *rev_name, None, None),
+            [fwd_name, rev_name, expected_len]
+                if expected_len.bytes().all(|byte| byte.is_ascii_digit()) =>
+            {
+                (*fwd_name, *rev_name, None, Some(parse_len(expected_len)?))
+            }
+            [fwd_name, rev_name, label] => (*fwd_name, *rev_name, Some(*label), None),
+            [fwd_name, rev_name, label, expected_len] => (
+                *fwd_name,
+                *rev_name,
+                Some(*label),
+                Some(parse_len(expected_len)?),
+            ),
+            _ => {
+                return Err(eyre!(
+                    "Line {} of {:?} has {} columns, but primer tables must have a forward primer name, a reverse primer name, and optionally an amplicon name and an expected length.",
+                    line_number + 1,
+                    table_path,
+                    columns.len()
+                ))
+            }
+        };
+        if label.is_some_and(str::is_empty) {
+            return Err(eyre!(
+                "Line {} of {:?} has an empty amplicon name.",
+                line_number + 1,
+                table_path
+            ));
+        }
+
+        let lookup = |name: &str| {
+            primer_seqs.get(name).ok_or_else(|| {
+                eyre!(
+                    "Primer {} from line {} of {:?} is not in the primer FASTA.",
+                    name,
+                    line_number + 1,
+                    table_path
+                )
+            })
+        };
+        let fwd = lookup(fwd_name)?;
+        let rev = match rev_as_appears {
+            true => get_reverse_complement(lookup(rev_name)?),
+            false => lookup(rev_name)?.to_owned(),
+        };
+        let rev_rc = get_reverse_complement(&rev);
+
+        let amplicon = match label {
+            Some(label) => label.to_string(),
+            None => fwd_name.replace(fwd_suffix, ""),
+        };
+        if let Some(expected_len) = expected_len {
+            expected_lens.insert(amplicon.clone(), expected_len);
+        }
+        scheme.push(PossiblePrimers::new(
+            amplicon,
+            fwd.to_owned(),
+            get_reverse_complement(fwd),
+            rev,
+            rev_rc,
+        ));
+    }
+
+    let scheme = AmpliconScheme { scheme };
+    check_collisions(&scheme, false)?;
+
+    Ok(TableScheme {
+        scheme,
+        expected_lens,
+    })
 }
@@ -1,154 +1,3445 @@
 // #![warn(missing_docs)]
 
-//!
+//! Module `reads` drives whole-file operations over sequencing reads, most importantly the
+//! lazy, asynchronous trimming loop that finds amplicons, trims them, filters them, and
+//! writes them out.
 
-use futures::TryStreamExt;
+use clap::ValueEnum;
 use futures::{future::join_all, Future};
+use futures::{Stream, StreamExt, TryStreamExt};
+use noodles::fasta::record::{Definition, Sequence};
+use noodles::fastq::AsyncWriter as FastqWriter;
 use noodles::fastq::Record as FastqRecord;
-use std::{collections::HashMap, path::Path};
+use noodles::sam::alignment::record::data::field::Tag;
+use noodles::sam::alignment::record_buf::data::field::Value;
+use noodles::sam::alignment::RecordBuf;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{btree_map::Entry, BTreeMap, BinaryHeap};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    consensus::{
+        align_to_reference, align_to_reference_weighted, assemble_genome, trim_to_reference,
+        ConsensusAccumulator, PlacedConsensus, DEFAULT_MIN_DELETION_FREQ,
+    },
+    index::{IndexFormat, MemoryGuard, MAP_ENTRY_OVERHEAD},
+    io::{
+        finalize_output, io_selector, is_stdin, is_truncation, read_stdin_reads,
+        AutoCompressWriter, Bam, ChunkedWriter, Fastq, FastqGz, Init, InputType, OutputType,
+        ReadSink, RecordParser, SeqReader, SeqWriter, SupportedFormat, STDIN_PATH,
+    },
+    primers::{
+        reverse_complement_bytes, AmpliconScheme, InsertRegion, PossiblePrimers, PrimerPair,
+    },
+    record::{
+        annotate_metrics, annotate_origin, find_primer_match, gc_content, is_confident_span,
+        merge_pair, primer_regions, refine_boundaries, AmpliconHit, AmpliconMatch, AmpliconMatcher,
+        BoundaryRefinement, FindAmplicons, MatchAlgorithm, MatchCache, MatcherOptions,
+        MergeSettings, PartialAmplicon, PrimerMatcher, QualityOffset, RecordTransform,
+        RejectReason, SchemeKind,
+    },
+};
+use color_eyre::eyre::{eyre, Result};
+
+pub struct FilterSettings<'a, 'b> {
+    pub min_freq: &'a f64,
+
+    /// The minimum number of reads that must carry a sequence for it to be kept
+    pub min_count: &'a u64,
+
+    pub max_len: &'a usize,
+
+    /// The index of unique sequences that frequencies and counts are looked up in
+    pub index: Option<&'b IndexFormat>,
+
+    /// Maximum lengths for individual amplicons, keyed by amplicon name, which take precedence
+    /// over `max_len`
+    pub amplicon_max_lens: Option<&'a HashMap<String, usize>>,
+}
+
+impl<'a, 'b> FilterSettings<'a, 'b> {
+    pub fn new(
+        min_freq: &'a Option<f64>,
+        min_count: &'a Option<u64>,
+        max_len: &'a Option<usize>,
+        index: &'b Option<IndexFormat>,
+        amplicon_max_lens: &'a Option<HashMap<String, usize>>,
+    ) -> Option<FilterSettings<'a, 'b>> {
+        let wants_index = min_freq.is_some() || min_count.is_some();
+        let wants_len = max_len.is_some() || amplicon_max_lens.is_some();
+        match (wants_index, wants_len, index) {
+            // no filters were requested
+            (false, false, _) => None,
+
+            // frequencies and counts can only be filtered on when an index could be loaded
+            (true, false, None) => None,
+
+            (_, _, index) => Some(FilterSettings {
+                min_freq: min_freq.as_ref().unwrap_or(&0.0),
+                min_count: min_count.as_ref().unwrap_or(&0),
+                max_len: max_len.as_ref().unwrap_or(&usize::MAX),
+                index: index.as_ref(),
+                amplicon_max_lens: amplicon_max_lens.as_ref(),
+            }),
+        }
+    }
+}
+
+/// The most sequence allowed outside the primers, e.g. adapters or barcodes, before a read is
+/// considered too long to hold a plausible amplicon.
+pub const LENGTH_GATE_FLANK: usize = 100;
+
+/// A cheap check on read length, applied before searching reads for primers, that rejects
+/// reads too short to hold both primers of any amplicon around at least one base, or, when
+/// expected lengths are known, too long to hold any plausible amplicon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthGate {
+    /// The shortest read that could contain an amplicon
+    pub min_len: usize,
+
+    /// The longest read that could contain an amplicon, if it is bounded
+    pub max_len: Option<usize>,
+}
+
+impl LengthGate {
+    /// Compute the gate from the primer footprints in the scheme and any expected lengths in the
+    /// filters.
+    pub fn new(scheme: &AmpliconScheme, filters: &Option<FilterSettings<'_, '_>>) -> Self {
+        let footprint = |pair: &PossiblePrimers| pair.fwd.len() + pair.rev.len();
+        let min_len = scheme
+            .scheme
+            .iter()
+            .map(|pair| footprint(pair) + 1)
+            .min()
+            .unwrap_or(0);
+
+        let max_len = filters.as_ref().and_then(|filters| {
+            scheme
+                .scheme
+                .iter()
+                .map(|pair| {
+                    let max_insert = filters
+                        .amplicon_max_lens
+                        .and_then(|lens| lens.get(&pair.amplicon))
+                        .unwrap_or(filters.max_len);
+                    max_insert
+                        .saturating_add(footprint(pair))
+                        .saturating_add(LENGTH_GATE_FLANK)
+                })
+                .max()
+                .filter(|max_len| *max_len < usize::MAX)
+        });
+
+        LengthGate { min_len, max_len }
+    }
+
+    /// Lower the minimum length to admit reads that could hold a partial amplicon: a forward
+    /// primer followed by at least `min_extension` bases, and always at least one.
+    pub fn allow_partial(self, scheme: &AmpliconScheme, min_extension: usize) -> Self {
+        let min_len = scheme
+            .scheme
+            .iter()
+            .map(|pair| pair.fwd.len() + min_extension.max(1))
+            .min()
+            .unwrap_or(0)
+            .min(self.min_len);
+
+        LengthGate { min_len, ..self }
+    }
+
+    /// Whether a read of this length could hold an amplicon.
+    pub fn admits(&self, read_len: usize) -> bool {
+        read_len >= self.min_len && self.max_len.is_none_or(|max_len| read_len <= max_len)
+    }
+}
+
+/// The number of complete amplicons, as found by [`AmpliconMatcher::find_all_amplicons`], that a
+/// read must hold to be kept, e.g. exactly one to keep only single amplicons, or two or more to
+/// keep only the concatemers common in nanopore data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmpliconsPerRead {
+    /// The fewest amplicons a kept read may hold
+    pub min: usize,
+
+    /// The most amplicons a kept read may hold, if there is a limit
+    pub max: Option<usize>,
+}
+
+impl AmpliconsPerRead {
+    /// Whether a read holding `count` complete amplicons is kept.
+    pub fn contains(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+/// Boundaries that divide trimmed records into bins by length, e.g., to separate the alleles
+/// of an amplicon with a length polymorphism. Each pair of neighboring boundaries makes a bin
+/// from the first boundary up to but not including the second, and records outside every bin
+/// fall into a final `other` bin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LengthBins {
+    /// The boundaries between bins, in increasing order
+    bounds: Vec<usize>,
+}
+
+impl LengthBins {
+    /// Make bins from their boundaries, e.g., `[200, 400, 600]` for one bin from 200 to 400
+    /// bases and another from 400 to 600.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there are fewer than two boundaries or if they
+    /// are not strictly increasing.
+    pub fn new(bounds: Vec<usize>) -> Result<Self> {
+        if bounds.len() < 2 {
+            return Err(eyre!(
+                "At least two length boundaries are needed to make a bin, but {} were provided.",
+                bounds.len()
+            ));
+        }
+        if !bounds.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(eyre!(
+                "Length boundaries must be strictly increasing, but found {:?}.",
+                bounds
+            ));
+        }
+        Ok(LengthBins { bounds })
+    }
+
+    /// The position, among [`LengthBins::labels`], of the bin a record of `len` bases falls in.
+    pub fn bin(&self, len: usize) -> usize {
+        self.bounds
+            .windows(2)
+            .position(|pair| (pair[0]..pair[1]).contains(&len))
+            .unwrap_or(self.bounds.len() - 1)
+    }
+
+    /// The label of each bin, e.g. `200-400`, followed by `other` for records outside every bin.
+    pub fn labels(&self) -> Vec<String> {
+        self.bounds
+            .windows(2)
+            .map(|pair| format!("{}-{}", pair[0], pair[1]))
+            .chain(std::iter::once("other".to_string()))
+            .collect()
+    }
+}
+
+/// Settings that change how the trimming loop handles records, as opposed to which records
+/// pass the frequency and length filters in [`FilterSettings`].
+#[derive(Debug, Default, Clone)]
+pub struct TrimSettings {
+    /// Run all matching and filtering logic but skip creating and writing the output file
+    pub count_only: bool,
+
+    /// The formats to write trimmed records in, each to its own output. When empty, records are
+    /// written in the same format as the first input
+    pub output_formats: Vec<OutputType>,
+
+    /// The offset used to interpret the quality scores of input records
+    pub quality_offset: QualityOffset,
+
+    /// Whether to re-encode written quality scores as Phred+33 rather than leaving them as-is
+    pub rescale_quality: bool,
+
+    /// Where to write dropped records, tagged with the reason they were dropped
+    pub emit_rejected: Option<PathBuf>,
+
+    /// Whether to reverse complement every written record, whatever its orientation
+    pub rc_output: bool,
+
+    /// Whether to uppercase the bases of every written record. Otherwise, lowercase bases are
+    /// written as they were read, even though primers are matched regardless of case
+    pub uppercase_output: bool,
+
+    /// Whether to append a running count of written records to each record's name, so that
+    /// names stay unique even when inputs share read names
+    pub uniquify_names: bool,
+
+    /// The number of records named so far when uniquifying names. It is shared across every
+    /// input written to the same output so that the count never restarts
+    pub name_counter: Arc<AtomicU64>,
+
+    /// When set, the ends of each trimmed record are moved inward past low-quality bases
+    pub refine_boundary: Option<BoundaryRefinement>,
+
+    /// Whether to append the length and GC content of each written record to its description
+    pub annotate_metrics: bool,
+
+    /// Whether to append the length of each written record's untrimmed input and where its
+    /// insert lay within it to its description
+    pub keep_original_in_header: bool,
+
+    /// The fewest bases that must separate a record's primers for it to be kept, which defaults
+    /// to [`DEFAULT_MIN_INSERT_LEN`]
+    pub min_insert_len: Option<usize>,
+
+    /// When set, records without a complete amplicon are kept if they hold the forward primer
+    /// of exactly one amplicon and read at least this many bases toward its reverse primer
+    pub min_extension: Option<usize>,
+
+    /// Whether to check each record just before it is written, dropping and counting any that
+    /// are longer than the input record or have a different number of bases and quality scores
+    pub validate_output: bool,
+
+    /// How primers are found in each record
+    pub match_algorithm: MatchAlgorithm,
+
+    /// Whether the scheme's amplicons may overlap, and so whether primer searches can stop at
+    /// the first complete amplicon
+    pub scheme_kind: SchemeKind,
+
+    /// The most mismatches or edits allowed in each primer when matching is not exact, which
+    /// defaults to [`crate::record::DEFAULT_MAX_PRIMER_ERRORS`]
+    pub max_primer_errors: Option<usize>,
+
+    /// When set, reads are rejected if either of their primers was matched over bases with a
+    /// mean Phred score below this
+    pub min_primer_quality: Option<u8>,
+
+    /// When set, reads are rejected unless they hold a number of complete amplicons in this
+    /// range, and kept reads holding several are trimmed to their outermost primers
+    pub amplicons_per_read: Option<AmpliconsPerRead>,
+
+    /// Whether to split reads holding several complete amplicons into one read per amplicon,
+    /// named after the read with their place in it, before trimming
+    pub split_concatemers: bool,
+
+    /// Whether to time the primer search for each record, bucketed by the amplicon it matched
+    pub time_matching: bool,
+
+    /// When set, the classification of each read sequence is remembered here, so that identical
+    /// reads are only searched for primers once. It is shared across every input, so it must
+    /// only be used with one scheme and one set of matching settings
+    pub match_cache: Option<Arc<MatchCache>>,
+
+    /// When set, progress is reported to stderr as records are read. It is shared across every
+    /// input so that the count never restarts
+    pub progress: Option<Arc<Mutex<Progress>>>,
+
+    /// When set, retained records are gathered here rather than written as they are trimmed,
+    /// and one record is written for each unique sequence once every input has been trimmed
+    pub collapse_output: Option<Arc<Mutex<CollapsedReads>>>,
+
+    /// When set, retained records are gathered here rather than written as they are trimmed,
+    /// and written one amplicon after another once every input has been trimmed
+    pub group_by_amplicon: Option<Arc<Mutex<GroupedReads>>>,
+
+    /// When set, the regions trimmed off either side of each retained record's insert are
+    /// written here. It is shared across every input so that all regions land in one file
+    pub primer_regions: Option<Arc<Mutex<PrimerRegions>>>,
+
+    /// When set, the amplicon, name, insert length, and GC fraction of every retained record are
+    /// written here as a row of a TSV. It is shared across every input so that all rows land
+    /// in one report
+    pub gc_length_report: Option<Arc<Mutex<GcLengthReport>>>,
+
+    /// When set, the outcome of every record is written here as a line of JSON. It is shared
+    /// across every input so that all outcomes land in one report
+    pub read_report: Option<Arc<Mutex<ReadReport>>>,
+
+    /// When set, retained records are split by length across one output per bin rather than
+    /// written to a single output
+    pub length_bins: Option<LengthBins>,
+
+    /// When set, retained records are split across one output per barcode in their names
+    /// rather than written to a single output. It is shared across every input so that each
+    /// barcode's records land in one file
+    pub barcode_demux: Option<Arc<Mutex<BarcodeDemux>>>,
+
+    /// When set, retained records are written uncompressed if there are no more than this many
+    /// of them, and gzip-compressed otherwise, in place of the format of the first input
+    pub uncompressed_threshold: Option<usize>,
+
+    /// When set, retained records are split across numbered outputs of at most this many
+    /// records each rather than written to a single output
+    pub reads_per_file: Option<usize>,
+
+    /// When set, trimmed inserts are checked against their amplicon's expected length, and
+    /// those too far from it are counted, or dropped
+    pub insert_len_check: Option<InsertLenCheck>,
+
+    /// The name of the sample being trimmed, which is recorded in the run's [`TrimStats`]
+    pub sample_name: Option<String>,
+
+    /// Whether to prepend the sample name and a colon to each written record's name, so that
+    /// records pooled from several samples can be traced back to their source
+    pub prefix_sample_name: bool,
+
+    /// What to do with input records that are malformed, e.g., with a different number of bases
+    /// and quality scores, which are checked as soon as they are read
+    pub on_error: OnError,
+
+    /// Set, e.g. on Ctrl-C, to stop consuming new records. Outputs are still finalized, so they
+    /// are valid but truncated
+    pub interrupt: Arc<AtomicBool>,
+}
+
+/// The insert length each amplicon is expected to have, e.g., from [`expected_insert_lens`],
+/// and how far a trimmed insert may stray from it before the trim is treated as suspicious.
+/// Inserts this far off usually mean that a primer was matched somewhere other than its true
+/// site, even though the insert is long enough to pass the other length checks.
+///
+/// [`expected_insert_lens`]: crate::primers::expected_insert_lens
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InsertLenCheck {
+    /// The expected insert length for each amplicon, keyed by amplicon name
+    pub expected: HashMap<String, usize>,
+
+    /// The most bases an insert may differ from its amplicon's expected length
+    pub tolerance: usize,
+
+    /// Whether to drop suspicious records rather than only counting them
+    pub drop: bool,
+}
+
+impl InsertLenCheck {
+    /// Whether an insert of `len` bases is plausible for `amplicon`. Amplicons without an
+    /// expected length are always plausible.
+    pub fn is_plausible(&self, amplicon: &str, len: usize) -> bool {
+        self.expected
+            .get(amplicon)
+            .is_none_or(|expected| expected.abs_diff(len) <= self.tolerance)
+    }
+}
+
+/// What makes two trimmed records duplicates of one another when collapsing or deduplicating
+/// them. Quality scores and headers never count, and neither does the case of bases, so that a
+/// soft-masked read is a duplicate of the same read in uppercase under every key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupKey {
+    /// Records are duplicates if their sequences are identical
+    #[default]
+    Seq,
+
+    /// Records are duplicates if their sequences and lengths are identical
+    #[value(name = "seq+len")]
+    SeqLen,
+
+    /// Records are duplicates if their sequences are identical or reverse complements of each
+    /// other, so that both strands of one molecule count as one sequence when orientation
+    /// hasn't been normalized
+    Canonical,
+}
+
+impl DedupKey {
+    /// The key that `sequence` is deduplicated by, taken from its bases in uppercase.
+    pub fn key(&self, sequence: &[u8]) -> Vec<u8> {
+        let sequence = sequence.to_ascii_uppercase();
+        match self {
+            DedupKey::Seq => sequence,
+            DedupKey::SeqLen => {
+                let mut key = sequence.len().to_le_bytes().to_vec();
+                key.extend_from_slice(&sequence);
+                key
+            }
+            DedupKey::Canonical => {
+                let rc = reverse_complement_bytes(&sequence);
+                sequence.min(rc)
+            }
+        }
+    }
+}
+
+/// Retained records grouped by their trimmed sequence, so that one record can be written for
+/// each unique sequence, e.g., as deduplicated input for consensus or clustering tools.
+#[derive(Debug, Default)]
+pub struct CollapsedReads {
+    /// What makes two records share a group
+    key: DedupKey,
+
+    /// The position of each unique sequence's key in `groups`
+    positions: HashMap<Vec<u8>, usize>,
+
+    /// The first record seen with each unique sequence, with the highest quality score seen at
+    /// each position, and the number of records with that sequence, in the order first seen
+    groups: Vec<(FastqRecord, u64)>,
+}
+
+impl CollapsedReads {
+    /// Gather records into groups by `key` rather than by their exact sequence.
+    pub fn with_key(key: DedupKey) -> Self {
+        CollapsedReads {
+            key,
+            ..CollapsedReads::default()
+        }
+    }
+
+    /// Add a record to the group for its sequence.
+    pub fn add(&mut self, record: FastqRecord) {
+        let key = self.key.key(record.sequence());
+        match self.positions.get(&key) {
+            Some(&position) => {
+                let (representative, count) = &mut self.groups[position];
+
+                // a record on the other strand from its group's first record lines up with it
+                // only once its quality scores are reversed
+                let mut scores = record.quality_scores().to_vec();
+                if !record
+                    .sequence()
+                    .eq_ignore_ascii_case(representative.sequence())
+                {
+                    scores.reverse();
+                }
+                for (best, score) in representative.quality_scores_mut().iter_mut().zip(scores) {
+                    *best = (*best).max(score);
+                }
+                *count += 1;
+            }
+            None => {
+                self.positions.insert(key, self.groups.len());
+                self.groups.push((record, 1));
+            }
+        }
+    }
+
+    /// The number of unique sequences gathered so far.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Whether no records have been gathered.
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Take one record for each unique sequence, in the order the sequences were first seen,
+    /// leaving the groups empty. Each record keeps the name of the first record with its
+    /// sequence, with the number of records sharing it appended as `;size=N`, and carries the
+    /// highest quality score seen at each position.
+    pub fn drain(&mut self) -> Vec<FastqRecord> {
+        self.positions.clear();
+        self.groups
+            .drain(..)
+            .map(|(mut record, count)| {
+                record
+                    .name_mut()
+                    .extend_from_slice(format!(";size={count}").as_bytes());
+                record
+            })
+            .collect()
+    }
+}
+
+/// Retained records grouped by the amplicon they were trimmed to, so that each amplicon's
+/// records can be written contiguously, e.g., for better locality and compression downstream.
+#[derive(Debug, Default)]
+pub struct GroupedReads {
+    /// The records trimmed to each amplicon, in the order they were trimmed
+    groups: HashMap<String, Vec<FastqRecord>>,
+}
+
+impl GroupedReads {
+    /// Add a record to the group for the amplicon it was trimmed to.
+    pub fn add(&mut self, amplicon: &str, record: FastqRecord) {
+        self.groups
+            .entry(amplicon.to_string())
+            .or_default()
+            .push(record);
+    }
+
+    /// The number of records gathered so far.
+    pub fn len(&self) -> usize {
+        self.groups.values().map(Vec::len).sum()
+    }
+
+    /// Whether no records have been gathered.
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Take every record, leaving the groups empty. Records come out one amplicon at a time, in
+    /// the order the amplicons appear in `scheme`, followed by any other amplicons, such as
+    /// those joined from concatemers, in name order. Within an amplicon, records keep the order
+    /// they were trimmed in.
+    pub fn drain(&mut self, scheme: &AmpliconScheme) -> Vec<FastqRecord> {
+        let mut groups = std::mem::take(&mut self.groups);
+        let mut records = Vec::with_capacity(groups.values().map(Vec::len).sum());
+        for pair in &scheme.scheme {
+            if let Some(group) = groups.remove(&pair.amplicon) {
+                records.extend(group);
+            }
+        }
+        let mut others: Vec<_> = groups.into_iter().collect();
+        others.sort_by(|(left, _), (right, _)| left.cmp(right));
+        for (_, group) in others {
+            records.extend(group);
+        }
+        records
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike the hasher in the standard library, its output is
+/// fixed by its definition, so anything chosen by it is chosen the same way by every build.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// A TSV of the amplicon, name, insert length, and GC fraction of retained records, e.g., for
+/// plotting length against GC content per amplicon to spot off-target products. Rows can be
+/// capped to a sample of the records, chosen by the 64-bit FNV-1a hash of their names so that
+/// the same records are sampled from run to run, on every platform and with every release.
+#[derive(Debug)]
+pub struct GcLengthReport {
+    /// The buffered file the rows are written to
+    writer: BufWriter<File>,
+
+    /// The most rows to write, if the rows are sampled
+    sample: Option<usize>,
+
+    /// The number of records added so far
+    seen: u64,
+
+    /// When sampling, the rows with the lowest name hashes so far, each with its hash and the
+    /// order it was added in
+    sampled: BinaryHeap<(u64, u64, String)>,
+}
+
+impl GcLengthReport {
+    /// Create the file that the rows will be written to, starting with its header, keeping at
+    /// most `sample` rows if a sample size is given.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be created.
+    pub fn create(path: &Path, sample: Option<usize>) -> Result<Self> {
+        let mut writer = File::create(path).map(BufWriter::new).map_err(|err| {
+            eyre!(
+                "Could not create the GC and length report {:?}: {}",
+                path,
+                err
+            )
+        })?;
+        writeln!(writer, "amplicon\tread_id\tinsert_len\tgc_fraction")?;
+        Ok(GcLengthReport {
+            writer,
+            sample,
+            seen: 0,
+            sampled: BinaryHeap::new(),
+        })
+    }
+
+    /// Add a row for a retained record trimmed to `amplicon`. Without sampling, the row is
+    /// written right away.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the row cannot be written.
+    pub fn add(&mut self, amplicon: &str, record: &FastqRecord) -> Result<()> {
+        let read_id = String::from_utf8_lossy(record.name());
+        let row = format!(
+            "{}\t{}\t{}\t{:.4}",
+            amplicon,
+            read_id,
+            record.sequence().len(),
+            gc_content(record.sequence())
+        );
+        self.seen += 1;
+        let Some(sample) = self.sample else {
+            writeln!(self.writer, "{row}")?;
+            return Ok(());
+        };
+
+        self.sampled.push((fnv1a(record.name()), self.seen, row));
+        if self.sampled.len() > sample {
+            self.sampled.pop();
+        }
+        Ok(())
+    }
+
+    /// Flush any buffered rows to the file. Sampled rows are held until [`GcLengthReport::finish`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be written.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Write out the sampled rows, if the rows are sampled, in the order their records were
+    /// added, and flush the file. Call this once every input has been trimmed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be written.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut sampled = std::mem::take(&mut self.sampled).into_vec();
+        sampled.sort_unstable_by_key(|(_, order, _)| *order);
+        for (_, _, row) in sampled {
+            writeln!(self.writer, "{row}")?;
+        }
+        self.flush()
+    }
+}
+
+/// A FASTQ file of the regions trimmed off of retained records, e.g., for adapter, UMI, or
+/// barcode analysis. Each retained record gets two records here, its 5' region up to and
+/// including the first primer and its 3' region from the second primer onward, both in the
+/// orientation of the input record, whether or not written records are reverse complemented.
+#[derive(Debug)]
+pub struct PrimerRegions {
+    /// The buffered file the regions are written to
+    writer: BufWriter<File>,
+}
+
+impl PrimerRegions {
+    /// Create the file that primer regions will be written to.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be created.
+    pub fn create(path: &Path) -> Result<Self> {
+        let writer = File::create(path).map(BufWriter::new).map_err(|err| {
+            eyre!(
+                "Could not create the primer region output {:?}: {}",
+                path,
+                err
+            )
+        })?;
+        Ok(PrimerRegions { writer })
+    }
+
+    /// Write the regions on either side of `insert` in an untrimmed record.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the insert does not lie within the record or the
+    /// regions cannot be written.
+    pub fn write(&mut self, original: &FastqRecord, insert: &Range<usize>) -> Result<()> {
+        let (five_prime, three_prime) = primer_regions(original, insert)?;
+        let mut writer = noodles::fastq::io::Writer::new(&mut self.writer);
+        writer.write_record(&five_prime)?;
+        writer.write_record(&three_prime)?;
+        Ok(())
+    }
+
+    /// Flush any buffered regions to the file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be written.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The demultiplexing key for retained records whose names carry no barcode.
+pub const UNCLASSIFIED_BARCODE: &str = "unclassified";
+
+/// One FASTQ file of retained records per barcode, e.g., for pooled samples whose barcodes were
+/// written into read names upstream, as in `@read barcode=BC07`. Each record's barcode is found
+/// in the name and description of its input record with a regular expression, and records
+/// without one are written to an [`UNCLASSIFIED_BARCODE`] file. Files are created as each
+/// barcode, or barcode and amplicon, is first seen.
+#[derive(Debug)]
+pub struct BarcodeDemux {
+    /// Finds the barcode in a record's header, as its first capture group if it has one or as
+    /// the whole match otherwise
+    pattern: Regex,
+
+    /// Whether each barcode's records are split further by amplicon
+    by_amplicon: bool,
+
+    /// The output path, without its FASTQ and compression extensions, that each key is added to
+    stem: String,
+
+    /// The buffered file for each key, whether a barcode alone or a barcode and an amplicon
+    writers: BTreeMap<String, BufWriter<File>>,
+}
+
+impl BarcodeDemux {
+    /// Prepare to split records by the barcodes `pattern` finds in their headers, into
+    /// uncompressed FASTQ files named after `output_path` plus each key, e.g.
+    /// `trimmed.BC07.fastq`, or `trimmed.BC07.amplicon_1.fastq` when also splitting by
+    /// amplicon.
+    pub fn new(pattern: Regex, by_amplicon: bool, output_path: &Path) -> Self {
+        let path = output_path.to_string_lossy();
+        let path = [".gz", ".bgz"]
+            .iter()
+            .find_map(|extension| path.strip_suffix(extension))
+            .unwrap_or(&path);
+        let stem = [".fastq", ".fq"]
+            .iter()
+            .find_map(|extension| path.strip_suffix(extension))
+            .unwrap_or(path);
+
+        BarcodeDemux {
+            pattern,
+            by_amplicon,
+            stem: stem.to_string(),
+            writers: BTreeMap::new(),
+        }
+    }
+
+    /// The barcode found in a record's header, i.e., its name and description separated by a
+    /// space, if there is one.
+    pub fn barcode<'h>(&self, header: &'h str) -> Option<&'h str> {
+        let captures = self.pattern.captures(header)?;
+        captures
+            .get(1)
+            .or_else(|| captures.get(0))
+            .map(|found| found.as_str())
+            .filter(|barcode| !barcode.is_empty())
+    }
+
+    /// The file that records with `key` are written to.
+    pub fn path_for(&self, key: &str) -> PathBuf {
+        PathBuf::from(format!("{}.{key}.fastq", self.stem))
+    }
+
+    /// Write a retained record from `amplicon` to the file for the barcode in `header`, the
+    /// name and description of the input record it was trimmed from.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file for the record's key cannot be created
+    /// or written.
+    pub fn write(&mut self, header: &str, amplicon: &str, record: &FastqRecord) -> Result<()> {
+        // barcodes become part of file names, so they can't be allowed to reach other directories
+        let key = match self.barcode(header) {
+            Some(barcode) if self.by_amplicon => {
+                format!("{}.{amplicon}", barcode.replace(['/', '\\'], "_"))
+            }
+            Some(barcode) => barcode.replace(['/', '\\'], "_"),
+            None => UNCLASSIFIED_BARCODE.to_string(),
+        };
+        let path = self.path_for(&key);
+        let writer = match self.writers.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::create(&path).map(BufWriter::new).map_err(|err| {
+                    eyre!(
+                        "Could not create the demultiplexed output {:?}: {}",
+                        path,
+                        err
+                    )
+                })?;
+                entry.insert(file)
+            }
+        };
+        noodles::fastq::io::Writer::new(writer).write_record(record)?;
+        Ok(())
+    }
+
+    /// Flush any buffered records to their files.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file cannot be written.
+    pub fn flush(&mut self) -> Result<()> {
+        for writer in self.writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to one input record over the course of trimming, as written to a
+/// [`ReadReport`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOutcome {
+    /// The name of the input record
+    pub read_id: String,
+
+    /// Whether exactly one complete or partial amplicon was found and trimmed to
+    pub matched: bool,
+
+    /// The amplicon the record matched, if any
+    pub amplicon: Option<String>,
+
+    /// Where the trimmed insert starts in the input record, if the record matched
+    pub start: Option<usize>,
+
+    /// Where the trimmed insert stops in the input record, exclusive, if the record matched
+    pub stop: Option<usize>,
+
+    /// The length of the trimmed record, if the record matched
+    pub trimmed_len: Option<usize>,
+
+    /// Whether the record passed every filter and was kept
+    pub pass_filter: bool,
+
+    /// Why the record was dropped, if it was, as tagged on rejected records
+    pub rejection_reason: Option<String>,
+}
+
+/// A newline-delimited JSON report with one [`ReadOutcome`] per input record, e.g., for
+/// streaming QC dashboards. Lines are written as records are processed, alongside the trimmed
+/// output, to a file or, given [`STDIN_PATH`], to standard output.
+pub struct ReadReport {
+    /// The buffered destination that lines are written to
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for ReadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadReport").finish_non_exhaustive()
+    }
+}
+
+impl ReadReport {
+    /// Create the file that the report will be written to, or write it to standard output if
+    /// `path` is [`STDIN_PATH`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be created.
+    pub fn create(path: &Path) -> Result<Self> {
+        let destination: Box<dyn Write + Send> = if path == Path::new(STDIN_PATH) {
+            Box::new(std::io::stdout())
+        } else {
+            let file = File::create(path)
+                .map_err(|err| eyre!("Could not create the per-read report {:?}: {}", path, err))?;
+            Box::new(file)
+        };
+        Ok(ReadReport {
+            writer: BufWriter::new(destination),
+        })
+    }
+
+    /// Write one record's outcome as a line of JSON.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the line cannot be written.
+    pub fn write(&mut self, outcome: &ReadOutcome) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, outcome)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Flush any buffered lines to the destination.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the destination cannot be written.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Write out the collapsed records gathered for `settings`, if there are any, to each of
+/// `writers`.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be written.
+async fn write_collapsed<W: ReadSink>(settings: &TrimSettings, writers: &mut [W]) -> Result<()> {
+    let Some(collapsed) = &settings.collapse_output else {
+        return Ok(());
+    };
+    let records = collapsed
+        .lock()
+        .map_err(|_| eyre!("Collapsing output was poisoned by a panic."))?
+        .drain();
+    for writer in writers {
+        for record in &records {
+            writer.write_read(record).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Write out the records gathered by amplicon for `settings`, if there are any, to each of
+/// `writers`, one amplicon after another in the order of `scheme`.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be written.
+async fn write_grouped<W: ReadSink>(
+    settings: &TrimSettings,
+    scheme: &AmpliconScheme,
+    writers: &mut [W],
+) -> Result<()> {
+    let Some(grouped) = &settings.group_by_amplicon else {
+        return Ok(());
+    };
+    let records = grouped
+        .lock()
+        .map_err(|_| eyre!("Grouping output by amplicon was poisoned by a panic."))?
+        .drain(scheme);
+    for writer in writers {
+        for record in &records {
+            writer.write_read(record).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodic reports of how many records a run has processed and how quickly, along with an
+/// estimate of the time left when the total number of records is known up front. The current
+/// time is always passed in, rather than read from the system clock, so that reporting can be
+/// tested without waiting.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// The least time between two reports
+    interval: Duration,
+
+    /// The total number of records to process, if it is known
+    total: Option<u64>,
+
+    /// The number of records processed so far
+    processed: u64,
+
+    /// When processing started
+    started: Instant,
+
+    /// When the last report was made, or when processing started if there hasn't been one
+    last_report: Instant,
+}
+
+impl Progress {
+    /// Start tracking progress as of `now`, reporting at most once per `interval`.
+    pub fn new(interval: Duration, total: Option<u64>, now: Instant) -> Self {
+        Self {
+            interval,
+            total,
+            processed: 0,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    /// Count one more processed record as of `now`, returning a report if at least one interval
+    /// has passed since the last report.
+    pub fn advance(&mut self, now: Instant) -> Option<String> {
+        self.processed += 1;
+        if now.saturating_duration_since(self.last_report) < self.interval {
+            return None;
+        }
+        self.last_report = now;
+        Some(self.report(now))
+    }
+
+    /// Describe the progress as of `now`, with an ETA when the total is known and throughput
+    /// only when it isn't.
+    pub fn report(&self, now: Instant) -> String {
+        let elapsed = now.saturating_duration_since(self.started).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.processed as f64 / elapsed
+        } else {
+            0.0
+        };
+        match self.total {
+            Some(total) if total > 0 => {
+                let remaining = total.saturating_sub(self.processed);
+                let eta = if rate > 0.0 {
+                    format!("{:.0}s", remaining as f64 / rate)
+                } else {
+                    "unknown".to_string()
+                };
+                format!(
+                    "Processed {} of {} reads ({:.1}%), {:.1} reads/s, ETA {}",
+                    self.processed,
+                    total,
+                    self.processed as f64 / total as f64 * 100.0,
+                    rate,
+                    eta
+                )
+            }
+            _ => format!("Processed {} reads, {:.1} reads/s", self.processed, rate),
+        }
+    }
+}
+
+/// Count the records across all of the provided inputs, e.g., to give progress reports an ETA.
+/// Counting takes a full pass over each input, and standard input can only be read once, so
+/// `None` is returned if any input is standard input.
+///
+/// # Errors
+///
+/// This function will return an error if any input has an unsupported format or cannot be
+/// parsed.
+pub async fn count_records(inputs: &[PathBuf]) -> Result<Option<u64>> {
+    let mut total = 0;
+    for input in inputs {
+        if is_stdin(input) {
+            return Ok(None);
+        }
+        total += match io_selector(input).await? {
+            InputType::FASTQGZ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                reader
+                    .records()
+                    .try_fold(0, |count, _| async move { Ok(count + 1) })
+                    .await?
+            }
+            InputType::FASTQ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                reader
+                    .records()
+                    .try_fold(0, |count, _| async move { Ok(count + 1) })
+                    .await?
+            }
+            InputType::BAM(_) => {
+                return Err(eyre!(
+                    "Unaligned BAM inputs are not yet supported but will be soon! Found {:?}.",
+                    input
+                ))
+            }
+        };
+    }
+
+    Ok(Some(total))
+}
+
+/// The number of reads carrying one primer of a scheme, in either orientation, from a
+/// [`PrimerScan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrimerCount {
+    /// The name of the amplicon the primer belongs to
+    pub amplicon: String,
+
+    /// Which of the amplicon's primers it is, either `forward` or `reverse`
+    pub primer: String,
+
+    /// The primer's sequence, 5' to 3'
+    pub sequence: String,
+
+    /// The number of reads carrying the primer or its reverse complement
+    pub reads: u64,
+}
+
+/// How often each primer of a scheme turns up in a set of reads, each searched for on its own
+/// rather than paired into amplicons, e.g., to spot primers that never bind because of an
+/// error in their sequence or coordinates.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrimerScan {
+    /// The number of reads scanned
+    pub total: u64,
+
+    /// The count for each primer, in the order of the scheme
+    pub primers: Vec<PrimerCount>,
+}
+
+impl PrimerScan {
+    /// Write one line per primer as TSV, under a header: its amplicon, whether it is the
+    /// forward or reverse primer, its sequence, the number of reads carrying it, and the
+    /// fraction of all scanned reads that is.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the TSV cannot be written.
+    pub fn write_tsv<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "amplicon\tprimer\tsequence\treads\tfrequency")?;
+        for count in &self.primers {
+            let frequency = if self.total == 0 {
+                0.0
+            } else {
+                count.reads as f64 / self.total as f64
+            };
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{frequency:.4}",
+                count.amplicon, count.primer, count.sequence, count.reads
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Count the reads in `records` that carry each primer in `scan`, adding to its counts.
+async fn scan_records<S, M>(
+    mut records: S,
+    scan: &mut PrimerScan,
+    primers_rc: &[String],
+    matcher: &M,
+) -> Result<()>
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+    M: PrimerMatcher + ?Sized,
+{
+    let mut records_read = 0;
+    while let Some(record) = records
+        .try_next()
+        .await
+        .map_err(|error| read_error(error, records_read))?
+    {
+        records_read += 1;
+        for (count, primer_rc) in scan.primers.iter_mut().zip(primers_rc) {
+            let found = [count.sequence.as_str(), primer_rc.as_str()]
+                .into_iter()
+                .any(|primer| find_primer_match(record.sequence(), primer, matcher).is_some());
+            if found {
+                count.reads += 1;
+            }
+        }
+    }
+    scan.total += records_read;
+
+    Ok(())
+}
+
+/// Scan every read across the provided inputs for each primer in the scheme, in either
+/// orientation, using the provided matcher. Each primer is found independently of every other,
+/// with [`find_primer_match`], so reads are counted for a primer whether or not they hold a
+/// complete amplicon.
+///
+/// # Errors
+///
+/// This function will return an error if any input has an unsupported format or cannot be
+/// parsed.
+pub async fn count_primers<M: PrimerMatcher + ?Sized>(
+    inputs: &[PathBuf],
+    scheme: &AmpliconScheme,
+    matcher: &M,
+) -> Result<PrimerScan> {
+    // amplicons may be listed more than once, but each primer is only counted once
+    let mut scan = PrimerScan::default();
+    let mut primers_rc = Vec::new();
+    for pair in &scheme.scheme {
+        for (primer, sequence, sequence_rc) in [
+            ("forward", &pair.fwd, &pair.fwd_rc),
+            ("reverse", &pair.rev, &pair.rev_rc),
+        ] {
+            let listed = scan
+                .primers
+                .iter()
+                .any(|count| count.amplicon == pair.amplicon && count.primer == primer);
+            if listed {
+                continue;
+            }
+            scan.primers.push(PrimerCount {
+                amplicon: pair.amplicon.clone(),
+                primer: primer.to_string(),
+                sequence: sequence.clone(),
+                reads: 0,
+            });
+            primers_rc.push(sequence_rc.clone());
+        }
+    }
+
+    for input in inputs {
+        if is_stdin(input) {
+            let mut reader = read_stdin_reads().await?;
+            scan_records(reader.records(), &mut scan, &primers_rc, matcher).await?;
+            continue;
+        }
+        match io_selector(input).await? {
+            InputType::FASTQGZ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                scan_records(reader.records(), &mut scan, &primers_rc, matcher).await?;
+            }
+            InputType::FASTQ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                scan_records(reader.records(), &mut scan, &primers_rc, matcher).await?;
+            }
+            InputType::BAM(_) => {
+                return Err(eyre!(
+                    "Unaligned BAM inputs are not yet supported but will be soon! Found {:?}.",
+                    input
+                ))
+            }
+        }
+    }
+
+    Ok(scan)
+}
+
+/// The writer for rejected records, which are always written as uncompressed FASTQ.
+pub type RejectedWriter = <Fastq as SeqWriter>::Writer;
+
+/// Open a writer for rejected records if the settings request one.
+async fn open_rejected(settings: &TrimSettings) -> Result<Option<RejectedWriter>> {
+    match &settings.emit_rejected {
+        Some(path) => Ok(Some(Fastq.read_writer(path).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Flush the writer for rejected records, if there is one.
+async fn finalize_rejected(rejected: Option<RejectedWriter>) -> Result<()> {
+    match rejected {
+        Some(writer) => Fastq.finalize_write(writer).await,
+        None => Ok(()),
+    }
+}
+
+/// Counts accumulated over the course of a trimming run.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrimStats {
+    /// The name of the sample the records came from, if one was given
+    #[serde(default)]
+    pub sample_name: Option<String>,
+
+    /// The number of records read from the input
+    pub total: u64,
+
+    /// The number of records containing exactly one complete amplicon, or when partial
+    /// amplicons are allowed, exactly one partial amplicon, that could be trimmed
+    pub matched: u64,
+
+    /// The number of matched records that only held a partial amplicon
+    pub partial: u64,
+
+    /// The number of records without a single complete amplicon
+    pub unmatched: u64,
+
+    /// The number of records rejected by the [`LengthGate`] without being searched for primers
+    pub length_gated: u64,
+
+    /// The number of records with a forward primer from one amplicon and a reverse primer from
+    /// another, which are dropped as likely PCR chimeras
+    pub chimeric: u64,
+
+    /// The number of records whose primers left no insert, or one shorter than the minimum
+    /// insert length, between them
+    pub too_short: u64,
+
+    /// The number of matched records whose inserts strayed too far from their amplicon's
+    /// expected length, whether or not they were dropped
+    #[serde(default)]
+    pub implausible_insert: u64,
+
+    /// The number of matched records removed by frequency or length filters
+    pub filtered: u64,
+
+    /// The number of records dropped because their output failed validation, which indicates a
+    /// bug in trimming or in a record transform
+    pub invalid: u64,
+
+    /// The number of input records skipped because they had a different number of bases and
+    /// quality scores
+    #[serde(default)]
+    pub malformed: u64,
+
+    /// The number of reads that concatemers were split into, each of which is then trimmed,
+    /// filtered, and counted like a record of its own, but not counted among the input records
+    #[serde(default)]
+    pub concatemer_pieces: u64,
+
+    /// The number of records that passed all filters, whether or not they were written
+    pub retained: u64,
+
+    /// The number of retained records for each amplicon, keyed by amplicon name
+    pub per_amplicon: BTreeMap<String, u64>,
+
+    /// When timing is enabled, the nanoseconds spent searching for primers in the records that
+    /// matched each amplicon, keyed by amplicon name
+    pub matching_nanos: BTreeMap<String, u64>,
+
+    /// When timing is enabled, the nanoseconds spent searching for primers in records that did
+    /// not match a single amplicon
+    pub unmatched_matching_nanos: u64,
+}
+
+impl TrimStats {
+    /// Add the counts from another run, e.g., over another input file, into these counts.
+    pub fn absorb(&mut self, other: TrimStats) {
+        if self.sample_name.is_none() {
+            self.sample_name = other.sample_name;
+        }
+        self.total += other.total;
+        self.matched += other.matched;
+        self.partial += other.partial;
+        self.unmatched += other.unmatched;
+        self.length_gated += other.length_gated;
+        self.chimeric += other.chimeric;
+        self.too_short += other.too_short;
+        self.implausible_insert += other.implausible_insert;
+        self.filtered += other.filtered;
+        self.invalid += other.invalid;
+        self.malformed += other.malformed;
+        self.concatemer_pieces += other.concatemer_pieces;
+        self.retained += other.retained;
+        for (amplicon, count) in other.per_amplicon {
+            *self.per_amplicon.entry(amplicon).or_insert(0) += count;
+        }
+        for (amplicon, nanos) in other.matching_nanos {
+            *self.matching_nanos.entry(amplicon).or_insert(0) += nanos;
+        }
+        self.unmatched_matching_nanos += other.unmatched_matching_nanos;
+    }
+
+    /// Count a dropped record under the tally for the reason it was dropped.
+    fn count_rejection(&mut self, reason: &RejectReason) {
+        match reason {
+            RejectReason::LengthGate => self.length_gated += 1,
+            RejectReason::Chimera => self.chimeric += 1,
+            RejectReason::NoMatch | RejectReason::MultiMatch | RejectReason::LowQualityPrimer => {
+                self.unmatched += 1
+            }
+            RejectReason::TooShort => self.too_short += 1,
+            RejectReason::LowFreq
+            | RejectReason::LenFilter
+            | RejectReason::ImplausibleInsert
+            | RejectReason::AmpliconCount => self.filtered += 1,
+            RejectReason::InvalidOutput => self.invalid += 1,
+            RejectReason::MalformedInput => self.malformed += 1,
+        }
+    }
+}
+
+impl fmt::Display for TrimStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(sample_name) = &self.sample_name {
+            writeln!(f, "Sample:             {sample_name}")?;
+        }
+        writeln!(f, "Total reads:        {}", self.total)?;
+        if self.concatemer_pieces > 0 {
+            writeln!(f, "  split into: {} pieces", self.concatemer_pieces)?;
+        }
+        writeln!(f, "Matched reads:      {}", self.matched)?;
+        if self.partial > 0 {
+            writeln!(f, "  partial: {}", self.partial)?;
+        }
+        writeln!(f, "Unmatched reads:    {}", self.unmatched)?;
+        writeln!(f, "Length-gated reads: {}", self.length_gated)?;
+        writeln!(f, "Chimeric reads:     {}", self.chimeric)?;
+        writeln!(f, "Too-short inserts:  {}", self.too_short)?;
+        if self.implausible_insert > 0 {
+            writeln!(f, "Suspicious inserts: {}", self.implausible_insert)?;
+        }
+        writeln!(f, "Filtered reads:     {}", self.filtered)?;
+        if self.invalid > 0 {
+            writeln!(f, "Invalid reads:      {}", self.invalid)?;
+        }
+        if self.malformed > 0 {
+            writeln!(f, "Malformed reads:    {}", self.malformed)?;
+        }
+        writeln!(f, "Retained reads:     {}", self.retained)?;
+        for (amplicon, count) in &self.per_amplicon {
+            writeln!(f, "  {amplicon}: {count}")?;
+        }
+        if !self.matching_nanos.is_empty() || self.unmatched_matching_nanos > 0 {
+            writeln!(f, "Primer matching time (ms):")?;
+            for (amplicon, nanos) in &self.matching_nanos {
+                writeln!(f, "  {amplicon}: {:.3}", *nanos as f64 / 1e6)?;
+            }
+            writeln!(
+                f,
+                "  unmatched: {:.3}",
+                self.unmatched_matching_nanos as f64 / 1e6
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub trait Trimming: SupportedFormat {
+    type Record;
+    fn trim<T: RecordTransform>(
+        self,
+        input_path: &Path,
+        output_path: &Path,
+        scheme: AmpliconScheme,
+        _filters: Option<FilterSettings>,
+        settings: &TrimSettings,
+        transform: &T,
+    ) -> impl Future<Output = Result<TrimStats>>;
+}
+
+/// Whether a record about to be written could have been trimmed from a record of
+/// `original_len` bases: it must be no longer than the original, with one quality score per
+/// base.
+fn is_valid_output(original_len: usize, record: &FastqRecord) -> bool {
+    record.sequence().len() <= original_len
+        && record.sequence().len() == record.quality_scores().len()
+}
+
+/// Explain a failure to read the next record after `records_read` records. An input that ends
+/// partway through, e.g., a gzip stream without its trailer after an interrupted transfer, is
+/// called out as truncated, so that it isn't mistaken for a parsing bug or a complete input.
+fn read_error(error: std::io::Error, records_read: u64) -> color_eyre::eyre::Report {
+    if is_truncation(&error) {
+        eyre!(
+            "The input appears to be truncated: {records_read} reads were read before it ended unexpectedly ({error}). Check that the file was downloaded or copied completely."
+        )
+    } else {
+        error.into()
+    }
+}
+
+/// Check that the primers of the one complete amplicon in a read were matched over bases with a
+/// mean Phred score of at least `min_quality`, going by the spans in `hit` the read was
+/// classified with, returning the reason to reject the read if not. Quality scores that can't be
+/// decoded with the configured offset make the read malformed, which is handled according to
+/// the `on_error` policy like any other malformed record.
+///
+/// # Errors
+///
+/// This function will return an error if the quality scores under a primer can't be decoded
+/// and malformed records stop trimming.
+fn check_primer_quality(
+    record: &FastqRecord,
+    record_number: u64,
+    hit: &AmpliconHit,
+    min_quality: u8,
+    settings: &TrimSettings,
+) -> Result<Option<RejectReason>> {
+    for span in [hit.fwd, hit.rev] {
+        match is_confident_span(
+            record.quality_scores(),
+            span,
+            min_quality,
+            settings.quality_offset,
+        ) {
+            Ok(true) => {}
+            Ok(false) => return Ok(Some(RejectReason::LowQualityPrimer)),
+            Err(error) => {
+                let message = format!(
+                    "Read {} (record {} of the input) has quality scores that can't be decoded: {}",
+                    String::from_utf8_lossy(record.name()),
+                    record_number,
+                    error
+                );
+                match settings.on_error {
+                    OnError::Stop => return Err(eyre!("{message}")),
+                    OnError::SkipWarn => eprintln!("Warning: {message} Skipping it."),
+                }
+                return Ok(Some(RejectReason::MalformedInput));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// A record trimmed down to the amplicon it was matched to, before it is filtered.
+struct TrimmedRead {
+    /// The amplicon the record was matched to, or several joined by `+` for a concatemer
+    amplicon: String,
+
+    /// Whether the record holds only part of its amplicon
+    partial: bool,
+
+    /// Where the trimmed bases lie in the untrimmed record, when that is needed
+    insert: Option<Range<usize>>,
+
+    /// The trimmed record, unless nothing was left of it
+    record: Option<FastqRecord>,
+}
+
+/// Classify a record by the primers it contains, going through the match cache when there is
+/// one, along with where the primers of its amplicon lie when it holds exactly one complete
+/// amplicon. Reads without an amplicon may still be kept as partial amplicons when they read far
+/// enough past a forward primer, and the time spent matching is tallied into `stats` when
+/// matching is timed.
+///
+/// # Errors
+///
+/// This function will return an error if the match cache cannot be used.
+async fn classify_record(
+    record: &FastqRecord,
+    matcher: &AmpliconMatcher,
+    scheme: &AmpliconScheme,
+    settings: &TrimSettings,
+    stats: &mut TrimStats,
+) -> Result<(AmpliconMatch, Option<AmpliconHit>)> {
+    let started = settings.time_matching.then(Instant::now);
+    let (mut classified, hit) = match &settings.match_cache {
+        Some(cache) => cache.classify_hit(matcher, record.sequence())?,
+        None => matcher.classify_hit(record.sequence()),
+    };
+
+    // short reads that stop before the reverse primer may still be kept as partial amplicons
+    // if they read far enough past the forward primer
+    if let (AmpliconMatch::NoMatch, Some(min_extension)) = (&classified, settings.min_extension) {
+        classified = record
+            .find_partial_amplicon(&scheme.scheme, min_extension)
+            .await;
+    }
+
+    if let Some(started) = started {
+        let elapsed = started.elapsed().as_nanos() as u64;
+        match &classified {
+            AmpliconMatch::Single(PrimerPair { amplicon, .. })
+            | AmpliconMatch::Partial(PartialAmplicon { amplicon, .. }) => {
+                *stats.matching_nanos.entry(amplicon.clone()).or_insert(0) += elapsed
+            }
+            _ => stats.unmatched_matching_nanos += elapsed,
+        }
+    }
+
+    Ok((classified, hit))
+}
+
+/// Trim a classified record down to its amplicon, or return the reason it can't be kept.
+/// Concatemers kept on purpose, i.e. with more than one amplicon in `all_hits`, are trimmed to
+/// their outermost primers, a single amplicon is trimmed to the primers in the `hit` it was
+/// classified with, and one whose primers failed the quality gate is rejected with
+/// `primer_rejection`.
+///
+/// # Errors
+///
+/// This function will return an error if the record cannot be trimmed.
+async fn trim_classified(
+    record: FastqRecord,
+    classified: AmpliconMatch,
+    hit: Option<AmpliconHit>,
+    all_hits: Option<&[AmpliconHit]>,
+    primer_rejection: Option<RejectReason>,
+    matcher: &AmpliconMatcher,
+    settings: &TrimSettings,
+) -> Result<std::result::Result<TrimmedRead, RejectReason>> {
+    let trimmed = match (all_hits, classified, primer_rejection) {
+        // concatemers kept on purpose are trimmed to their outermost primers, and credited to
+        // every amplicon they hold, e.g. amplicon_1+amplicon_2
+        (Some(hits), _, _) if hits.len() > 1 => {
+            let amplicon = hits
+                .iter()
+                .map(|hit| hit.amplicon.as_str())
+                .collect::<Vec<_>>()
+                .join("+");
+            let insert = hits[0].insert().start..hits[hits.len() - 1].insert().end;
+            TrimmedRead {
+                amplicon,
+                partial: false,
+                insert: Some(insert),
+                record: matcher.trim_to_hits(record, hits),
+            }
+        }
+        (_, AmpliconMatch::Single(_), Some(reason)) => return Ok(Err(reason)),
+        (_, AmpliconMatch::Single(pair), None) => {
+            let insert = hit.as_ref().map(AmpliconHit::insert).filter(|_| {
+                settings.primer_regions.is_some()
+                    || settings.read_report.is_some()
+                    || settings.keep_original_in_header
+            });
+            TrimmedRead {
+                amplicon: pair.amplicon,
+                partial: false,
+                insert,
+                record: hit.and_then(|hit| matcher.trim_to_hit(record, &hit)),
+            }
+        }
+        (_, AmpliconMatch::Partial(hit), _) => {
+            let amplicon = hit.amplicon.clone();
+            let insert = hit.start..hit.end;
+            TrimmedRead {
+                amplicon,
+                partial: true,
+                insert: Some(insert),
+                record: record.trim_to_partial_amplicon(hit).await?,
+            }
+        }
+        (_, AmpliconMatch::Chimera { .. }, _) => return Ok(Err(RejectReason::Chimera)),
+        (_, AmpliconMatch::NoMatch, _) => return Ok(Err(RejectReason::NoMatch)),
+        (_, AmpliconMatch::MultiMatch, _) => return Ok(Err(RejectReason::MultiMatch)),
+    };
+
+    Ok(Ok(trimmed))
+}
+
+/// Make the changes `settings` asks for to a trimmed record that passed every filter, e.g.,
+/// rescaling its quality scores or renaming it, and then pass it through `transform`.
+/// `insert` is where its bases lie in the untrimmed record of `original_len` bases.
+///
+/// # Errors
+///
+/// This function will return an error if the record's quality scores cannot be rescaled.
+fn prepare_output<T: RecordTransform>(
+    record: &mut FastqRecord,
+    original_len: usize,
+    insert: Option<&Range<usize>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<()> {
+    if settings.rescale_quality {
+        *record.quality_scores_mut() = settings
+            .quality_offset
+            .rescale(record.quality_scores(), QualityOffset::Phred33)?;
+    }
+
+    if settings.uppercase_output {
+        record.sequence_mut().make_ascii_uppercase();
+    }
+
+    if settings.rc_output {
+        *record.sequence_mut() = reverse_complement_bytes(record.sequence());
+        record.quality_scores_mut().reverse();
+    }
+
+    if let (true, Some(sample_name)) = (settings.prefix_sample_name, &settings.sample_name) {
+        record
+            .name_mut()
+            .splice(0..0, format!("{sample_name}:").bytes());
+    }
+
+    if settings.uniquify_names {
+        let index = settings.name_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        record
+            .name_mut()
+            .extend_from_slice(format!("_{index}").as_bytes());
+    }
+
+    if settings.annotate_metrics {
+        annotate_metrics(record);
+    }
+
+    if let (true, Some(insert)) = (settings.keep_original_in_header, insert) {
+        annotate_origin(record, original_len, insert);
+    }
+
+    transform.transform(record);
+
+    Ok(())
+}
+
+/// Send a retained record to its output: collapsed with its duplicates, grouped by amplicon,
+/// demultiplexed by the barcode in its input `header`, written to its length bin's writer, or
+/// otherwise written to every one of `writers`. The primer regions trimmed off of the
+/// `original` record and its GC content and length are recorded too, when they are reported.
+///
+/// # Errors
+///
+/// This function will return an error if an output was poisoned by a panic or cannot be
+/// written.
+async fn route_output<W: ReadSink>(
+    amplicon: &str,
+    record: FastqRecord,
+    writers: &mut [W],
+    settings: &TrimSettings,
+    header: Option<&str>,
+    original: Option<&FastqRecord>,
+    insert: Option<&Range<usize>>,
+) -> Result<()> {
+    if let (Some(regions), Some(original), Some(insert)) =
+        (&settings.primer_regions, original, insert)
+    {
+        regions
+            .lock()
+            .map_err(|_| eyre!("Primer region output was poisoned by a panic."))?
+            .write(original, insert)?;
+    }
+
+    if let Some(report) = &settings.gc_length_report {
+        report
+            .lock()
+            .map_err(|_| eyre!("GC and length reporting was poisoned by a panic."))?
+            .add(amplicon, &record)?;
+    }
+
+    if let Some(collapsed) = &settings.collapse_output {
+        collapsed
+            .lock()
+            .map_err(|_| eyre!("Collapsing output was poisoned by a panic."))?
+            .add(record);
+    } else if let Some(grouped) = &settings.group_by_amplicon {
+        grouped
+            .lock()
+            .map_err(|_| eyre!("Grouping output by amplicon was poisoned by a panic."))?
+            .add(amplicon, record);
+    } else if let (Some(demux), Some(header)) = (&settings.barcode_demux, header) {
+        demux
+            .lock()
+            .map_err(|_| eyre!("Barcode demultiplexing was poisoned by a panic."))?
+            .write(header, amplicon, &record)?;
+    } else if let Some(bins) = &settings.length_bins {
+        // each bin has its own writer, in the order of its label
+        let bin = bins.bin(record.sequence().len());
+        if let Some(writer) = writers.get_mut(bin) {
+            writer.write_read(&record).await?;
+        }
+    } else {
+        for writer in writers.iter_mut() {
+            writer.write_read(&record).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A well-formed record trimmed down to its amplicon and refined, before it is filtered.
+struct TrimmedCandidate {
+    /// The amplicon the record was matched to, or several joined by `+` for a concatemer
+    amplicon: String,
+
+    /// Whether the trimmed insert is implausibly long or short for its amplicon
+    implausible: bool,
+
+    /// Where the trimmed bases lie in the untrimmed record, when that is needed
+    insert: Option<Range<usize>>,
+
+    /// The trimmed record
+    record: FastqRecord,
+}
+
+/// Build the matcher records are trimmed with from the matching options in `settings`.
+fn trim_matcher(scheme: &AmpliconScheme, settings: &TrimSettings) -> AmpliconMatcher {
+    AmpliconMatcher::from_scheme(
+        scheme,
+        MatcherOptions {
+            match_algorithm: settings.match_algorithm,
+            max_primer_errors: settings.max_primer_errors,
+            min_insert_len: settings.min_insert_len,
+            anchor_window: None,
+            scheme_kind: settings.scheme_kind,
+        },
+    )
+}
+
+/// Build the length gate records must pass before they are matched, widened to admit partial
+/// amplicons when `settings` keeps them.
+fn trim_gate(
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+) -> LengthGate {
+    let gate = LengthGate::new(scheme, filters);
+    match settings.min_extension {
+        Some(min_extension) => gate.allow_partial(scheme, min_extension),
+        None => gate,
+    }
+}
+
+/// Pair each record with its index among the pieces of a concatemer, if it is one. When
+/// `settings` splits concatemers, they are split into one read per amplicon before anything
+/// else, so that each piece is trimmed, filtered, and counted like any other read, while input
+/// records are still counted once. Malformed records are left whole to be caught downstream.
+fn split_records<'a, S>(
+    records: S,
+    matcher: &'a AmpliconMatcher,
+    settings: &TrimSettings,
+) -> impl Stream<Item = std::io::Result<(FastqRecord, Option<usize>)>> + Unpin + 'a
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin + 'a,
+{
+    if settings.split_concatemers {
+        records
+            .map_ok(|record| {
+                let pieces = if record.sequence().len() == record.quality_scores().len() {
+                    matcher.split_concatemer(record)
+                } else {
+                    vec![record]
+                };
+                let split = pieces.len() > 1;
+                futures::stream::iter(
+                    pieces
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(i, piece)| Ok((piece, split.then_some(i)))),
+                )
+            })
+            .try_flatten()
+            .left_stream()
+    } else {
+        records.map_ok(|record| (record, None)).right_stream()
+    }
+}
+
+/// Trim a well-formed record down to its amplicon, as every pass over the reads does, or return
+/// the reason it can't be kept. The record must pass the length gate and any count of the
+/// amplicons in it, its primers must pass the quality gate, and what is left of it once trimmed
+/// has its boundaries refined when `settings` asks for it. Matched, partial, and implausible
+/// records are tallied into `stats`.
+///
+/// # Errors
+///
+/// This function will return an error if the record cannot be classified, trimmed, or refined.
+async fn trim_candidate(
+    record: FastqRecord,
+    gate: &LengthGate,
+    matcher: &AmpliconMatcher,
+    scheme: &AmpliconScheme,
+    settings: &TrimSettings,
+    stats: &mut TrimStats,
+) -> Result<std::result::Result<TrimmedCandidate, RejectReason>> {
+    if !gate.admits(record.sequence().len()) {
+        return Ok(Err(RejectReason::LengthGate));
+    }
+
+    // counting every amplicon in a read takes a scan of its own, so it is only done when
+    // reads are filtered by that count
+    let all_hits = settings
+        .amplicons_per_read
+        .map(|range| (range, matcher.find_all_amplicons(record.sequence())));
+    if all_hits
+        .as_ref()
+        .is_some_and(|(range, hits)| !range.contains(hits.len()))
+    {
+        return Ok(Err(RejectReason::AmpliconCount));
+    }
+
+    let (classified, hit) = classify_record(&record, matcher, scheme, settings, stats).await?;
+
+    // primers matched only over low-quality bases aren't trusted
+    let primer_rejection = match (&hit, settings.min_primer_quality) {
+        (Some(hit), Some(min_quality)) => {
+            check_primer_quality(&record, stats.total, hit, min_quality, settings)?
+        }
+        _ => None,
+    };
+
+    let all_hits = all_hits.as_ref().map(|(_, hits)| hits.as_slice());
+    let trimmed = trim_classified(
+        record,
+        classified,
+        hit,
+        all_hits,
+        primer_rejection,
+        matcher,
+        settings,
+    )
+    .await?;
+    let (amplicon, partial, mut insert, mut trimmed_record) = match trimmed {
+        Err(reason) => return Ok(Err(reason)),
+        Ok(TrimmedRead { record: None, .. }) => return Ok(Err(RejectReason::TooShort)),
+        Ok(TrimmedRead {
+            amplicon,
+            partial,
+            insert,
+            record: Some(record),
+        }) => (amplicon, partial, insert, record),
+    };
+    stats.matched += 1;
+    if partial {
+        stats.partial += 1;
+    }
+
+    // partial amplicons stop short by design, so only complete ones are checked
+    let implausible = match &settings.insert_len_check {
+        Some(check) if !partial => !check.is_plausible(&amplicon, trimmed_record.sequence().len()),
+        _ => false,
+    };
+    if implausible {
+        stats.implausible_insert += 1;
+    }
+    if let Some(refinement) = &settings.refine_boundary {
+        let kept = refine_boundaries(&mut trimmed_record, settings.quality_offset, refinement)?;
+        insert = insert.map(|insert| insert.start + kept.start..insert.start + kept.end);
+    }
+
+    Ok(Ok(TrimmedCandidate {
+        amplicon,
+        implausible,
+        insert,
+        record: trimmed_record,
+    }))
+}
+
+/// Trim records as [`trim_records`] would, with the same matcher, gates, and boundary
+/// refinement, and count each record that is trimmed into `index` without filtering or writing
+/// it. This is the first pass of trimming with frequencies computed from the inputs themselves,
+/// so that the sequences counted are exactly those the second pass filters. Malformed records
+/// are skipped here and reported by the second pass.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed or trimmed, or if the index
+/// grows past the guard's limit.
+pub async fn index_trimmed_records<S>(
+    records: S,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    index: &mut IndexFormat,
+    guard: &mut MemoryGuard,
+) -> Result<()>
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+{
+    let gate = trim_gate(scheme, filters, settings);
+    let matcher = trim_matcher(scheme, settings);
+    let mut stats = TrimStats::default();
+
+    let mut records = split_records(records, &matcher, settings);
+    // an interrupt stops the first pass too, before another record is read
+    while !settings.interrupt.load(Ordering::Relaxed) {
+        let Some((record, piece)) = records
+            .try_next()
+            .await
+            .map_err(|error| read_error(error, stats.total))?
+        else {
+            break;
+        };
+        if piece.is_none_or(|i| i == 0) {
+            stats.total += 1;
+        }
+        if record.quality_scores().len() != record.sequence().len() {
+            continue;
+        }
+
+        if let Ok(candidate) =
+            trim_candidate(record, &gate, &matcher, scheme, settings, &mut stats).await?
+        {
+            guard.charge(index.add(&candidate.amplicon, &candidate.record), "index")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Iterate through records asynchronously, find amplicon hits, and trim them down to exclude
+/// primers and anything that extends beyond them. Records that pass the provided filters are
+/// passed through `transform` and written to each of `writers`, and the outcome for
+/// every record is tallied into the returned [`TrimStats`]. Dropped records are written,
+/// untrimmed and tagged with the reason they were dropped, to `rejected`, if there is one.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed, trimmed, or written.
+pub async fn trim_records<S, W, T>(
+    records: S,
+    writers: &mut [W],
+    mut rejected: Option<&mut RejectedWriter>,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<TrimStats>
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+    W: ReadSink,
+    T: RecordTransform,
+{
+    let gate = trim_gate(scheme, filters, settings);
+    let matcher = trim_matcher(scheme, settings);
+    let mut stats = TrimStats {
+        sample_name: settings.sample_name.clone(),
+        ..TrimStats::default()
+    };
 
-use crate::{
-    io::{Fastq, FastqGz, Init, SeqWriter, SupportedFormat},
-    primers::AmpliconScheme,
-    record::FindAmplicons,
-};
-use color_eyre::eyre::Result;
+    let mut records = split_records(records, &matcher, settings);
+    // on an interrupt, stop before reading another record, so that none is read and then
+    // dropped without being counted
+    while !settings.interrupt.load(Ordering::Relaxed) {
+        let Some((record, piece)) = records
+            .try_next()
+            .await
+            .map_err(|error| read_error(error, stats.total))?
+        else {
+            break;
+        };
 
-pub struct FilterSettings<'a, 'b> {
-    pub min_freq: &'a f64,
-    pub max_len: &'a usize,
-    pub unique_seqs: &'b HashMap<Vec<u8>, f64>,
+        // every piece of a concatemer after the first comes from an input record that has
+        // already been counted
+        if piece.is_some() {
+            stats.concatemer_pieces += 1;
+        }
+        if piece.is_none_or(|i| i == 0) {
+            stats.total += 1;
+            if let Some(progress) = &settings.progress {
+                let report = progress
+                    .lock()
+                    .map_err(|_| eyre!("Progress reporting was poisoned by a panic."))?
+                    .advance(Instant::now());
+                if let Some(report) = report {
+                    eprintln!("{report}");
+                }
+            }
+        }
+
+        // hold on to the untrimmed record in case it needs to be written out as rejected or
+        // split into the primer regions trimmed off of it
+        let original =
+            (rejected.is_some() || settings.primer_regions.is_some()).then(|| record.clone());
+        let original_len = record.sequence().len();
+
+        // malformed records are caught as soon as they are read, before anything slices their
+        // quality scores by positions in their sequence
+        let malformed = record.quality_scores().len() != original_len;
+        if malformed {
+            let message = format!(
+                "Read {} (record {} of the input) has {} bases but {} quality scores.",
+                String::from_utf8_lossy(record.name()),
+                stats.total,
+                original_len,
+                record.quality_scores().len()
+            );
+            match settings.on_error {
+                OnError::Stop => return Err(eyre!("{message}")),
+                OnError::SkipWarn => eprintln!("Warning: {message} Skipping it."),
+            }
+        }
+
+        // barcodes are found in the input record's header, before anything is added to it
+        let header = settings.barcode_demux.as_ref().map(|_| {
+            let mut header = String::from_utf8_lossy(record.name()).into_owned();
+            if !record.description().is_empty() {
+                header.push(' ');
+                header.push_str(&String::from_utf8_lossy(record.description()));
+            }
+            header
+        });
+        let mut outcome = settings.read_report.as_ref().map(|_| ReadOutcome {
+            read_id: String::from_utf8_lossy(record.name()).into_owned(),
+            ..ReadOutcome::default()
+        });
+
+        let reason = if malformed {
+            Some(RejectReason::MalformedInput)
+        } else {
+            match trim_candidate(record, &gate, &matcher, scheme, settings, &mut stats).await? {
+                Err(reason) => Some(reason),
+                Ok(TrimmedCandidate {
+                    amplicon,
+                    implausible,
+                    insert,
+                    record: mut trimmed_record,
+                }) => {
+                    if let Some(outcome) = outcome.as_mut() {
+                        outcome.matched = true;
+                        outcome.amplicon = Some(amplicon.clone());
+                        outcome.start = insert.as_ref().map(|insert| insert.start);
+                        outcome.stop = insert.as_ref().map(|insert| insert.end);
+                        outcome.trimmed_len = Some(trimmed_record.sequence().len());
+                    }
+                    let filtered = match &settings.insert_len_check {
+                        Some(check) if implausible && check.drop => {
+                            Some(RejectReason::ImplausibleInsert)
+                        }
+                        _ => trimmed_record.rejection_reason(&amplicon, filters).await,
+                    };
+                    if filtered.is_some() {
+                        filtered
+                    } else {
+                        prepare_output(
+                            &mut trimmed_record,
+                            original_len,
+                            insert.as_ref(),
+                            settings,
+                            transform,
+                        )?;
+                        if settings.validate_output
+                            && !is_valid_output(original_len, &trimmed_record)
+                        {
+                            Some(RejectReason::InvalidOutput)
+                        } else {
+                            stats.retained += 1;
+                            *stats.per_amplicon.entry(amplicon.clone()).or_insert(0) += 1;
+                            route_output(
+                                &amplicon,
+                                trimmed_record,
+                                writers,
+                                settings,
+                                header.as_deref(),
+                                original.as_ref(),
+                                insert.as_ref(),
+                            )
+                            .await?;
+                            None
+                        }
+                    }
+                }
+            }
+        };
+
+        if let (Some(report), Some(mut outcome)) = (&settings.read_report, outcome) {
+            outcome.pass_filter = reason.is_none();
+            outcome.rejection_reason = reason.as_ref().map(|reason| reason.as_str().to_string());
+            report
+                .lock()
+                .map_err(|_| eyre!("Per-read reporting was poisoned by a panic."))?
+                .write(&outcome)?;
+        }
+
+        let Some(reason) = reason else {
+            continue;
+        };
+        stats.count_rejection(&reason);
+
+        if let (Some(rejected), Some(mut original)) = (rejected.as_mut(), original) {
+            reason.tag(&mut original);
+            rejected.write_record(&original).await?;
+        }
+    }
+
+    flush_side_outputs(settings)?;
+
+    Ok(stats)
 }
 
-impl<'a, 'b> FilterSettings<'a, 'b> {
-    pub fn new(
-        min_freq: &'a Option<f64>,
-        max_len: &'a Option<usize>,
-        unique_seqs: &'b Option<HashMap<Vec<u8>, f64>>,
-    ) -> Option<FilterSettings<'a, 'b>> {
-        match (min_freq, max_len, unique_seqs) {
-            (Some(min_freq), Some(max_len), Some(unique_seqs)) => Some(FilterSettings {
-                min_freq,
-                max_len,
-                unique_seqs,
-            }),
-            (Some(min_freq), None, Some(unique_seqs)) => Some(FilterSettings {
-                min_freq,
-                max_len: &123456789,
-                unique_seqs,
-            }),
-            (None, Some(max_len), Some(unique_seqs)) => Some(FilterSettings {
-                min_freq: &0.0,
-                max_len,
-                unique_seqs,
-            }),
-            (_, _, _) => None,
+/// Flush every output besides the trimmed reads themselves that `settings` writes to as reads
+/// are trimmed, e.g. the primer regions or the per-read report.
+///
+/// # Errors
+///
+/// This function will return an error if an output was poisoned by a panic or cannot be
+/// flushed.
+fn flush_side_outputs(settings: &TrimSettings) -> Result<()> {
+    if let Some(regions) = &settings.primer_regions {
+        regions
+            .lock()
+            .map_err(|_| eyre!("Primer region output was poisoned by a panic."))?
+            .flush()?;
+    }
+    if let Some(demux) = &settings.barcode_demux {
+        demux
+            .lock()
+            .map_err(|_| eyre!("Barcode demultiplexing was poisoned by a panic."))?
+            .flush()?;
+    }
+    if let Some(report) = &settings.read_report {
+        report
+            .lock()
+            .map_err(|_| eyre!("Per-read reporting was poisoned by a panic."))?
+            .flush()?;
+    }
+    if let Some(report) = &settings.gc_length_report {
+        report
+            .lock()
+            .map_err(|_| eyre!("GC and length reporting was poisoned by a panic."))?
+            .flush()?;
+    }
+
+    Ok(())
+}
+
+/// What to do on meeting a malformed input record, e.g., one with a different number of bases
+/// and quality scores.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnError {
+    /// Stop with an error naming the record
+    #[default]
+    Stop,
+
+    /// Skip the record with a warning, counting it as malformed, and carry on
+    SkipWarn,
+}
+
+/// How the trimmed records from several inputs are written out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+    /// Merge the records from every input into a single output
+    #[default]
+    Merge,
+
+    /// Write the records from each input to its own file, named after the input, in an output
+    /// directory
+    PerInput,
+}
+
+/// Trim each of the provided input files in turn, merging all retained records into a single
+/// output. The output is written in the same format as the first input, unless the settings
+/// request other output formats.
+///
+/// # Errors
+///
+/// This function will return an error if no inputs are provided, if any input has an unsupported
+/// format, or if trimming any input fails.
+pub async fn trim_inputs<T: RecordTransform>(
+    inputs: &[PathBuf],
+    output_path: &Path,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<TrimStats> {
+    let mut rejected = open_rejected(settings).await?;
+    let stats = trim_inputs_to(
+        inputs,
+        output_path,
+        rejected.as_mut(),
+        scheme,
+        filters,
+        settings,
+        transform,
+    )
+    .await?;
+    finalize_rejected(rejected).await?;
+
+    Ok(stats)
+}
+
+/// The name of the file that the trimmed records from `input` are written to in
+/// [`OutputMode::PerInput`]: the input's file name with its extension swapped for that of
+/// `input_type`, or `stdin` plus that extension for standard input. A compression extension
+/// is dropped along with the rest, e.g. for a plain-text input misnamed as `.fastq.gz`.
+pub fn per_input_file_name(input: &Path, input_type: &InputType) -> Result<String> {
+    let extension = input_type.extension();
+    if is_stdin(input) {
+        return Ok(format!("stdin{extension}"));
+    }
+
+    let file_name = input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| eyre!("Could not determine a file name for the input {:?}.", input))?;
+    let stem = file_name
+        .strip_suffix(extension.as_str())
+        .unwrap_or_else(|| {
+            let uncompressed = [".gz", ".bgz"]
+                .iter()
+                .find_map(|compression| file_name.strip_suffix(compression))
+                .unwrap_or(file_name);
+            Path::new(uncompressed)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(uncompressed)
+        });
+
+    Ok(format!("{stem}{extension}"))
+}
+
+/// The sample name to record for reads from `input` when none is given: the input's file name
+/// without its sequence and compression extensions, e.g. `sample1` for `sample1.fastq.gz`, or
+/// `stdin` for standard input.
+pub fn sample_name_for_input(input: &Path) -> String {
+    if is_stdin(input) {
+        return "stdin".to_string();
+    }
+
+    let file_name = input
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let uncompressed = [".gz", ".bgz"]
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+        .unwrap_or(&file_name);
+    [".fastq", ".fq", ".bam"]
+        .iter()
+        .find_map(|extension| uncompressed.strip_suffix(extension))
+        .unwrap_or(uncompressed)
+        .to_string()
+}
+
+/// Trim each of the provided input files in turn, writing the retained records from each to its
+/// own file in `output_dir`, which is created if needed. Each output is named after its input by
+/// [`per_input_file_name`] and written in the same format as its input, unless the settings
+/// request other output formats. Rejected records from
+/// every input are still written to the one file in the settings.
+///
+/// # Errors
+///
+/// This function will return an error if no inputs are provided, if two inputs would be written
+/// to the same file, if the output directory cannot be created, if any input has an unsupported
+/// format, or if trimming any input fails.
+pub async fn trim_inputs_per_input<T: RecordTransform>(
+    inputs: &[PathBuf],
+    output_dir: &Path,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<TrimStats> {
+    if inputs.is_empty() {
+        return Err(eyre!("No input files were provided for trimming."));
+    }
+
+    // work out every output path up front so that colliding names fail before any trimming
+    let mut output_paths = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let file_name = per_input_file_name(input, &io_selector(input).await?)?;
+        let output_path = output_dir.join(file_name);
+        if output_paths.contains(&output_path) {
+            return Err(eyre!(
+                "More than one input would be written to {:?}. Inputs must have distinct names to be written per input.",
+                output_path
+            ));
         }
+        output_paths.push(output_path);
     }
+    if !settings.count_only {
+        tokio::fs::create_dir_all(output_dir).await?;
+    }
+
+    let mut rejected = open_rejected(settings).await?;
+    let mut stats = TrimStats::default();
+    for (input, output_path) in inputs.iter().zip(&output_paths) {
+        if settings.interrupt.load(Ordering::Relaxed) {
+            break;
+        }
+        let input_stats = trim_inputs_to(
+            std::slice::from_ref(input),
+            output_path,
+            rejected.as_mut(),
+            scheme,
+            filters,
+            settings,
+            transform,
+        )
+        .await?;
+        stats.absorb(input_stats);
+    }
+    finalize_rejected(rejected).await?;
+
+    Ok(stats)
 }
 
-pub trait Trimming: SupportedFormat {
-    type Record;
-    fn trim(
-        self,
-        input_path: &Path,
-        output_path: &Path,
-        scheme: AmpliconScheme,
-        _filters: Option<FilterSettings>,
-    ) -> impl Future<Output = Result<()>>;
+/// The path that an output in `format` is written to in place of `output_path`, which carries
+/// the extension of `input_type`: the same path with that extension swapped for the format's.
+pub fn output_path_for_format(
+    output_path: &Path,
+    input_type: &InputType,
+    format: &OutputType,
+) -> PathBuf {
+    let path = output_path.to_string_lossy();
+    let stem = path
+        .strip_suffix(input_type.extension().as_str())
+        .unwrap_or(&path);
+    PathBuf::from(format!("{stem}{}", format.extension()))
+}
+
+/// The path that the records in the length bin labeled `label` are written to in place of
+/// `output_path`, which carries the extension of `input_type`: the same path with the label
+/// inserted before that extension, e.g. `trimmed.200-400.fastq`.
+pub fn output_path_for_bin(output_path: &Path, input_type: &InputType, label: &str) -> PathBuf {
+    let extension = input_type.extension();
+    let path = output_path.to_string_lossy();
+    let stem = path.strip_suffix(extension.as_str()).unwrap_or(&path);
+    PathBuf::from(format!("{stem}.{label}{extension}"))
+}
+
+/// Trim the provided inputs into one output, written in the same format as the first input
+/// unless the settings request other output formats or length bins, sending rejected records
+/// to `rejected`.
+async fn trim_inputs_to<T: RecordTransform>(
+    inputs: &[PathBuf],
+    output_path: &Path,
+    mut rejected: Option<&mut RejectedWriter>,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<TrimStats> {
+    let Some(first_input) = inputs.first() else {
+        return Err(eyre!("No input files were provided for trimming."));
+    };
+
+    // written quality scores keep the input's encoding unless they are rescaled to Phred+33
+    let output_offset = if settings.rescale_quality {
+        QualityOffset::Phred33
+    } else {
+        settings.quality_offset
+    };
+
+    // when only counting, or when records are demultiplexed by barcode into files of their
+    // own, skip creating the writer entirely
+    let skip_writer = settings.count_only || settings.barcode_demux.is_some();
+    let stats = match (skip_writer, io_selector(first_input).await?) {
+        (true, _) => {
+            trim_inputs_into::<FastqWriter<tokio::io::Sink>, _>(
+                inputs,
+                &mut [],
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?
+        }
+
+        // when length bins are requested, route each record to the output for its bin
+        (false, input_type) if settings.length_bins.is_some() => {
+            let format = OutputType::from_name(input_type.name()).ok_or_else(|| {
+                eyre!(
+                    "Records can't be split by length into {} outputs.",
+                    input_type.name()
+                )
+            })?;
+            let labels = settings
+                .length_bins
+                .as_ref()
+                .map(LengthBins::labels)
+                .unwrap_or_default();
+            let mut writers = Vec::with_capacity(labels.len());
+            for label in &labels {
+                let path = output_path_for_bin(output_path, &input_type, label);
+                writers.push(format.output_writer(&path, output_offset).await?);
+            }
+            let stats = trim_inputs_into(
+                inputs,
+                &mut writers,
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            for writer in writers {
+                finalize_output(writer).await?;
+            }
+            stats
+        }
+
+        // when outputs are limited to a number of records each, roll over to a new numbered
+        // output whenever the current one fills up
+        (false, input_type) if settings.reads_per_file.is_some() => {
+            let format = OutputType::from_name(input_type.name()).ok_or_else(|| {
+                eyre!(
+                    "Records can't be split across {} outputs.",
+                    input_type.name()
+                )
+            })?;
+            let path = output_path.to_string_lossy();
+            let stem = path
+                .strip_suffix(input_type.extension().as_str())
+                .unwrap_or(&path);
+            let mut writer = FastqWriter::new(ChunkedWriter::new(
+                Path::new(stem),
+                format,
+                settings.reads_per_file.unwrap_or_default(),
+            )?);
+            let stats = trim_inputs_into(
+                inputs,
+                std::slice::from_mut(&mut writer),
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            writer.into_inner().shutdown().await?;
+            stats
+        }
+
+        // when the output is compressed only if it turns out to be large, hold records back
+        // until it is clear which it will be
+        (false, input_type) if settings.uncompressed_threshold.is_some() => {
+            let path = output_path.to_string_lossy();
+            let stem = path
+                .strip_suffix(input_type.extension().as_str())
+                .unwrap_or(&path);
+            let mut writer = FastqWriter::new(AutoCompressWriter::new(
+                Path::new(stem),
+                settings.uncompressed_threshold.unwrap_or_default(),
+            ));
+            let stats = trim_inputs_into(
+                inputs,
+                std::slice::from_mut(&mut writer),
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            writer.into_inner().shutdown().await?;
+            stats
+        }
+
+        // when output formats are requested, fan each record out to one output per format
+        (false, input_type) if !settings.output_formats.is_empty() => {
+            let mut writers = Vec::with_capacity(settings.output_formats.len());
+            for format in &settings.output_formats {
+                let path = output_path_for_format(output_path, &input_type, format);
+                writers.push(format.output_writer(&path, output_offset).await?);
+            }
+            let stats = trim_inputs_into(
+                inputs,
+                &mut writers,
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            for writer in writers {
+                finalize_output(writer).await?;
+            }
+            stats
+        }
+        (false, InputType::FASTQGZ(format)) => {
+            let mut writer = format.read_writer(output_path).await?;
+            let stats = trim_inputs_into(
+                inputs,
+                std::slice::from_mut(&mut writer),
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            format.finalize_write(writer).await?;
+            stats
+        }
+        (false, InputType::FASTQ(format)) => {
+            let mut writer = format.read_writer(output_path).await?;
+            let stats = trim_inputs_into(
+                inputs,
+                std::slice::from_mut(&mut writer),
+                rejected,
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            format.finalize_write(writer).await?;
+            stats
+        }
+        (false, InputType::BAM(_)) => {
+            return Err(eyre!(
+                "Unaligned BAM inputs are not yet supported but will be soon!"
+            ))
+        }
+    };
+
+    Ok(stats)
+}
+
+/// Stream the records from each input through the trimming loop into the same writers.
+async fn trim_inputs_into<W, T>(
+    inputs: &[PathBuf],
+    writers: &mut [W],
+    mut rejected: Option<&mut RejectedWriter>,
+    scheme: &AmpliconScheme,
+    filters: &Option<FilterSettings<'_, '_>>,
+    settings: &TrimSettings,
+    transform: &T,
+) -> Result<TrimStats>
+where
+    W: ReadSink,
+    T: RecordTransform,
+{
+    let mut stats = TrimStats::default();
+    for input in inputs {
+        if is_stdin(input) {
+            let mut reader = read_stdin_reads().await?;
+            let records = reader.records();
+            let input_stats = trim_records(
+                records,
+                &mut *writers,
+                rejected.as_deref_mut(),
+                scheme,
+                filters,
+                settings,
+                transform,
+            )
+            .await?;
+            stats.absorb(input_stats);
+            continue;
+        }
+
+        let input_stats = match io_selector(input).await? {
+            InputType::FASTQGZ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                let records = reader.records();
+                trim_records(
+                    records,
+                    &mut *writers,
+                    rejected.as_deref_mut(),
+                    scheme,
+                    filters,
+                    settings,
+                    transform,
+                )
+                .await?
+            }
+            InputType::FASTQ(format) => {
+                let mut reader = format.read_reads(input).await?;
+                let records = reader.records();
+                trim_records(
+                    records,
+                    &mut *writers,
+                    rejected.as_deref_mut(),
+                    scheme,
+                    filters,
+                    settings,
+                    transform,
+                )
+                .await?
+            }
+            InputType::BAM(_) => {
+                return Err(eyre!(
+                    "Unaligned BAM inputs are not yet supported but will be soon! Found {:?}.",
+                    input
+                ))
+            }
+        };
+        stats.absorb(input_stats);
+    }
+
+    write_collapsed(settings, writers).await?;
+    write_grouped(settings, scheme, writers).await?;
+
+    Ok(stats)
 }
 
 impl Trimming for Fastq {
     type Record = FastqRecord;
-    async fn trim(
+    async fn trim<T: RecordTransform>(
         self,
         input_path: &Path,
         output_path: &Path,
         scheme: AmpliconScheme,
         filters: Option<FilterSettings<'_, '_>>,
-    ) -> Result<()> {
+        settings: &TrimSettings,
+        transform: &T,
+    ) -> Result<TrimStats> {
         let (mut reader, format) = self.init(input_path).await?;
-        let mut records = reader.records();
-        let mut writer = format.read_writer(output_path).await?;
+        let records = reader.parse_records();
+        let mut rejected = open_rejected(settings).await?;
 
-        // iterate through records asynchronously, find amplicon hits, and trim them down to
-        // exclude primers and anything that extends beyond them
-        while let Some(record) = records.try_next().await? {
-            let amplicon_hit = record.find_amplicon(&scheme.scheme).await;
-            if let Some(hit) = amplicon_hit {
-                let trimmed = record.trim_to_amplicon(hit).await?;
-                match trimmed {
-                    Some(trimmed_record) => match trimmed_record.whether_to_write(&filters).await {
-                        true => writer.write_record(&trimmed_record).await?,
-                        false => continue,
-                    },
-                    _ => continue,
-                }
-            } else {
-                continue;
-            }
+        // when only counting, skip creating the writer entirely
+        if settings.count_only {
+            let stats = trim_records::<_, FastqWriter<tokio::io::Sink>, _>(
+                records,
+                &mut [],
+                rejected.as_mut(),
+                &scheme,
+                &filters,
+                settings,
+                transform,
+            )
+            .await?;
+            write_collapsed::<FastqWriter<tokio::io::Sink>>(settings, &mut []).await?;
+            write_grouped::<FastqWriter<tokio::io::Sink>>(settings, &scheme, &mut []).await?;
+            finalize_rejected(rejected).await?;
+            return Ok(stats);
         }
 
+        let mut writer = format.read_writer(output_path).await?;
+        let stats = trim_records(
+            records,
+            std::slice::from_mut(&mut writer),
+            rejected.as_mut(),
+            &scheme,
+            &filters,
+            settings,
+            transform,
+        )
+        .await?;
+        write_collapsed(settings, std::slice::from_mut(&mut writer)).await?;
+        write_grouped(settings, &scheme, std::slice::from_mut(&mut writer)).await?;
+
         // Finalize the written contents to make sure the file is not corrupted
         format.finalize_write(writer).await?;
+        finalize_rejected(rejected).await?;
 
-        Ok(())
+        Ok(stats)
     }
 }
 
 impl Trimming for FastqGz {
     type Record = FastqRecord;
-    async fn trim(
+    async fn trim<T: RecordTransform>(
         self,
         input_path: &Path,
         output_path: &Path,
         scheme: AmpliconScheme,
         filters: Option<FilterSettings<'_, '_>>,
-    ) -> Result<()> {
+        settings: &TrimSettings,
+        transform: &T,
+    ) -> Result<TrimStats> {
         let (mut reader, format) = self.init(input_path).await?;
-        let mut records = reader.records();
-        let mut writer = format.read_writer(output_path).await?;
+        let records = reader.parse_records();
+        let mut rejected = open_rejected(settings).await?;
 
-        // iterate through records asynchronously, find amplicon hits, and trim them down to
-        // exclude primers and anything that extends beyond them
-        while let Some(record) = records.try_next().await? {
-            let amplicon_hit = record.find_amplicon(&scheme.scheme).await;
-            if let Some(hit) = amplicon_hit {
-                let trimmed = record.trim_to_amplicon(hit).await?;
-                match trimmed {
-                    Some(record) => match record.whether_to_write(&filters).await {
-                        true => writer.write_record(&record).await?,
-                        false => continue,
-                    },
-                    _ => continue,
-                }
-            } else {
-                continue;
-            }
+        // when only counting, skip creating the writer entirely
+        if settings.count_only {
+            let stats = trim_records::<_, FastqWriter<tokio::io::Sink>, _>(
+                records,
+                &mut [],
+                rejected.as_mut(),
+                &scheme,
+                &filters,
+                settings,
+                transform,
+            )
+            .await?;
+            write_collapsed::<FastqWriter<tokio::io::Sink>>(settings, &mut []).await?;
+            write_grouped::<FastqWriter<tokio::io::Sink>>(settings, &scheme, &mut []).await?;
+            finalize_rejected(rejected).await?;
+            return Ok(stats);
         }
 
+        let mut writer = format.read_writer(output_path).await?;
+        let stats = trim_records(
+            records,
+            std::slice::from_mut(&mut writer),
+            rejected.as_mut(),
+            &scheme,
+            &filters,
+            settings,
+            transform,
+        )
+        .await?;
+        write_collapsed(settings, std::slice::from_mut(&mut writer)).await?;
+        write_grouped(settings, &scheme, std::slice::from_mut(&mut writer)).await?;
+
         // Finalize the written contents to make sure the file is not corrupted
         format.finalize_write(writer).await?;
+        finalize_rejected(rejected).await?;
+
+        Ok(stats)
+    }
+}
+
+/// Counts accumulated over the course of merging paired-end reads.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeStats {
+    /// The number of read pairs read from the inputs
+    pub pairs: u64,
+
+    /// The number of pairs that overlapped enough to be merged
+    pub merged: u64,
+
+    /// The number of pairs that could not be merged
+    pub unmerged: u64,
 
+    /// The number of pairs skipped because a mate had a different number of bases and quality
+    /// scores
+    #[serde(default)]
+    pub malformed: u64,
+
+    /// The number of overlapping bases, across all merged pairs, where the mates disagreed
+    pub conflicts: u64,
+}
+
+impl fmt::Display for MergeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Read pairs:        {}", self.pairs)?;
+        writeln!(f, "Merged pairs:      {}", self.merged)?;
+        writeln!(f, "Unmerged pairs:    {}", self.unmerged)?;
+        if self.malformed > 0 {
+            writeln!(f, "Malformed pairs:   {}", self.malformed)?;
+        }
+        writeln!(f, "Conflicting bases: {}", self.conflicts)?;
         Ok(())
     }
 }
 
+/// Strip a trailing `/1` or `/2` mate suffix from a read name.
+fn mate_name(name: &[u8]) -> &[u8] {
+    match name {
+        [rest @ .., b'/', b'1' | b'2'] => rest,
+        _ => name,
+    }
+}
+
+/// Walk two streams of mates in lockstep, merging each overlapping pair into a single read and
+/// writing it to `writer`. Pairs that cannot be merged are dropped, or written as two separate
+/// reads if the settings ask to keep them. Pairs with a malformed mate are handled according to
+/// the `on_error` policy, as in trimming.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed or written, if the mates'
+/// names disagree, if one input runs out of records before the other, or if a mate is
+/// malformed and malformed records stop merging.
+pub async fn merge_records<S1, S2, W>(
+    mut r1_records: S1,
+    mut r2_records: S2,
+    writer: &mut FastqWriter<W>,
+    settings: &MergeSettings,
+) -> Result<MergeStats>
+where
+    S1: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+    S2: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut stats = MergeStats::default();
+    loop {
+        let (r1, r2) = match (r1_records.try_next().await?, r2_records.try_next().await?) {
+            (Some(r1), Some(r2)) => (r1, r2),
+            (None, None) => break,
+            _ => {
+                return Err(eyre!(
+                    "The paired inputs have different numbers of reads. Only {} complete pairs were found.",
+                    stats.pairs
+                ))
+            }
+        };
+        stats.pairs += 1;
+
+        if mate_name(r1.name()) != mate_name(r2.name()) {
+            return Err(eyre!(
+                "Mates in pair {} have different names: {} and {}.",
+                stats.pairs,
+                String::from_utf8_lossy(r1.name()),
+                String::from_utf8_lossy(r2.name())
+            ));
+        }
+
+        // malformed mates are caught before anything slices their quality scores by positions
+        // in their sequence
+        if let Some(mate) = [&r1, &r2]
+            .into_iter()
+            .find(|mate| mate.quality_scores().len() != mate.sequence().len())
+        {
+            let message = format!(
+                "Read {} (pair {} of the inputs) has {} bases but {} quality scores.",
+                String::from_utf8_lossy(mate.name()),
+                stats.pairs,
+                mate.sequence().len(),
+                mate.quality_scores().len()
+            );
+            match settings.on_error {
+                OnError::Stop => return Err(eyre!("{message}")),
+                OnError::SkipWarn => eprintln!("Warning: {message} Skipping the pair."),
+            }
+            stats.malformed += 1;
+            continue;
+        }
+
+        match merge_pair(&r1, &r2, settings) {
+            Some(merged) => {
+                stats.merged += 1;
+                stats.conflicts += merged.conflicts as u64;
+                writer.write_record(&merged.record).await?;
+            }
+            None => {
+                stats.unmerged += 1;
+                if settings.keep_unmerged {
+                    writer.write_record(&r1).await?;
+                    writer.write_record(&r2).await?;
+                }
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Merge overlapping pairs from two paired-end FASTQ files, which may each be compressed or
+/// not, into one uncompressed FASTQ of full-length reads.
+///
+/// # Errors
+///
+/// This function will return an error if either input has an unsupported format or if merging
+/// fails.
+pub async fn merge_paired_inputs(
+    r1_path: &Path,
+    r2_path: &Path,
+    output_path: &Path,
+    settings: &MergeSettings,
+) -> Result<MergeStats> {
+    let mut writer = Fastq.read_writer(output_path).await?;
+    let stats = match io_selector(r1_path).await? {
+        InputType::FASTQGZ(format) => {
+            let mut reader = format.read_reads(r1_path).await?;
+            merge_with_mates(reader.records(), r2_path, &mut writer, settings).await?
+        }
+        InputType::FASTQ(format) => {
+            let mut reader = format.read_reads(r1_path).await?;
+            merge_with_mates(reader.records(), r2_path, &mut writer, settings).await?
+        }
+        InputType::BAM(_) => {
+            return Err(eyre!(
+                "Unaligned BAM inputs are not yet supported but will be soon!"
+            ))
+        }
+    };
+    Fastq.finalize_write(writer).await?;
+
+    Ok(stats)
+}
+
+/// Open the second mates' file and merge its records with the provided first mates.
+async fn merge_with_mates<S1, W>(
+    r1_records: S1,
+    r2_path: &Path,
+    writer: &mut FastqWriter<W>,
+    settings: &MergeSettings,
+) -> Result<MergeStats>
+where
+    S1: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match io_selector(r2_path).await? {
+        InputType::FASTQGZ(format) => {
+            let mut reader = format.read_reads(r2_path).await?;
+            merge_records(r1_records, reader.records(), writer, settings).await
+        }
+        InputType::FASTQ(format) => {
+            let mut reader = format.read_reads(r2_path).await?;
+            merge_records(r1_records, reader.records(), writer, settings).await
+        }
+        InputType::BAM(_) => Err(eyre!(
+            "Unaligned BAM inputs are not yet supported but will be soon!"
+        )),
+    }
+}
+
 pub trait Sorting: SupportedFormat {
     fn sort_reads(self) -> impl Future<Output = Result<Self>>
     where
         Self: std::marker::Sized;
 }
 
-pub async fn sync_trimming<I>(reads: I, scheme: &AmpliconScheme) -> Result<Vec<FastqRecord>>
+/// The number of records to search for primers at once when no concurrency is requested, e.g.,
+/// for [`sort_records`]: the parallelism the system reports as available, independent of how
+/// many workers the async runtime happens to run. When the system can't report it, one record
+/// is searched at a time rather than panicking.
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|parallelism| parallelism.get())
+        .unwrap_or(1)
+}
+
+/// The records trimmed for sorting, each with the amplicon it was trimmed to, or `None` for
+/// records without one.
+type SortedStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<(String, FastqRecord)>>> + 'a>>;
+
+/// Trim records and sort them into one uncompressed FASTQ file per amplicon, named
+/// `<amplicon>.fastq`, in `output_dir`, returning the number of records written for each
+/// amplicon. Up to `concurrency` records are searched for primers at once, or
+/// [`default_concurrency`] records when it is `None`. Without
+/// `keep_order`, records are written as soon as they are trimmed, so the order within each
+/// file may vary from run to run; with it, trimmed records are buffered until every record
+/// before them has been written, so each file keeps the order of the input.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed or trimmed, or if an
+/// output file cannot be written.
+pub async fn sort_records<S>(
+    records: S,
+    output_dir: &Path,
+    scheme: &AmpliconScheme,
+    concurrency: Option<usize>,
+    keep_order: bool,
+) -> Result<BTreeMap<String, u64>>
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+{
+    let trimming = records
+        .map_err(color_eyre::Report::from)
+        .map_ok(|record| async move {
+            match record.find_amplicon(&scheme.scheme).await {
+                Some(hit) => {
+                    let amplicon = hit.amplicon.clone();
+                    let trimmed = record.trim_to_amplicon(hit).await?;
+                    Ok(trimmed.map(|record| (amplicon, record)))
+                }
+                None => Ok(None),
+            }
+        });
+    let concurrency = concurrency.unwrap_or_else(default_concurrency).max(1);
+    let mut trimmed: SortedStream<'_> = if keep_order {
+        Box::pin(trimming.try_buffered(concurrency))
+    } else {
+        Box::pin(trimming.try_buffer_unordered(concurrency))
+    };
+
+    let mut writers = AmpliconWriters::new(output_dir);
+    let mut counts = BTreeMap::new();
+    while let Some(hit) = trimmed.try_next().await? {
+        let Some((amplicon, record)) = hit else {
+            continue;
+        };
+        writers.write(&amplicon, &record).await?;
+        *counts.entry(amplicon).or_insert(0) += 1;
+    }
+    writers.finalize().await?;
+
+    Ok(counts)
+}
+
+/// Trim the records of a FASTQ file, which may be compressed or not, and sort them into one
+/// file per amplicon in `output_dir`, as in [`sort_records`].
+///
+/// # Errors
+///
+/// This function will return an error if the input has an unsupported format or if sorting
+/// fails.
+pub async fn sort_input(
+    input_path: &Path,
+    output_dir: &Path,
+    scheme: &AmpliconScheme,
+    concurrency: Option<usize>,
+    keep_order: bool,
+) -> Result<BTreeMap<String, u64>> {
+    match io_selector(input_path).await? {
+        InputType::FASTQGZ(format) => {
+            let mut reader = format.read_reads(input_path).await?;
+            sort_records(
+                reader.records(),
+                output_dir,
+                scheme,
+                concurrency,
+                keep_order,
+            )
+            .await
+        }
+        InputType::FASTQ(format) => {
+            let mut reader = format.read_reads(input_path).await?;
+            sort_records(
+                reader.records(),
+                output_dir,
+                scheme,
+                concurrency,
+                keep_order,
+            )
+            .await
+        }
+        InputType::BAM(_) => Err(eyre!(
+            "Unaligned BAM inputs are not yet supported but will be soon!"
+        )),
+    }
+}
+
+/// One uncompressed FASTQ writer per amplicon, each writing to `<amplicon>.fastq` in an output
+/// directory and created the first time a record from its amplicon is written.
+pub struct AmpliconWriters {
+    /// The directory every amplicon's file is written to
+    output_dir: PathBuf,
+
+    /// The writer for each amplicon written to so far
+    writers: HashMap<String, <Fastq as SeqWriter>::Writer>,
+}
+
+impl AmpliconWriters {
+    /// Prepare to write records into per-amplicon files in `output_dir`, which must exist.
+    pub fn new(output_dir: &Path) -> Self {
+        AmpliconWriters {
+            output_dir: output_dir.to_path_buf(),
+            writers: HashMap::new(),
+        }
+    }
+
+    /// Write a record to the file for its amplicon, creating the file if needed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be created or written to.
+    pub async fn write(&mut self, amplicon: &str, record: &FastqRecord) -> Result<()> {
+        if !self.writers.contains_key(amplicon) {
+            let path = self.output_dir.join(format!("{amplicon}.fastq"));
+            self.writers
+                .insert(amplicon.to_string(), Fastq.read_writer(&path).await?);
+        }
+        if let Some(writer) = self.writers.get_mut(amplicon) {
+            writer.write_record(record).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush every amplicon's file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any file cannot be flushed.
+    pub async fn finalize(self) -> Result<()> {
+        for writer in self.writers.into_values() {
+            Fastq.finalize_write(writer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The tag that [`annotate_bam`] gives each record with the name of the amplicon it came from,
+/// e.g. `am:Z:nCoV_42`. Lowercase tags are reserved for end users by the SAM specification.
+pub const AMPLICON_TAG: Tag = Tag::new(b'a', b'm');
+
+/// Counts accumulated while annotating a BAM file with amplicon assignments.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnotateStats {
+    /// The number of records read from the input
+    pub total: u64,
+
+    /// The number of records tagged with the amplicon they came from
+    pub tagged: u64,
+
+    /// The number of tagged records for each amplicon, keyed by amplicon name
+    pub per_amplicon: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for AnnotateStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Total records:  {}", self.total)?;
+        writeln!(f, "Tagged records: {}", self.tagged)?;
+        for (amplicon, count) in &self.per_amplicon {
+            writeln!(f, "  {amplicon}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Tag each record of an aligned BAM file with the amplicon it came from, under
+/// [`AMPLICON_TAG`], and write every record to `output_path` otherwise unchanged, alignment and
+/// all, e.g., for amplicon-aware variant calling. Records are assigned to amplicons by
+/// searching their sequences for primers as when trimming, and only records holding exactly one
+/// complete amplicon are tagged. Nothing is trimmed or dropped.
+///
+/// # Errors
+///
+/// This function will return an error if the input cannot be read or parsed as BAM, or if the
+/// output cannot be written.
+pub async fn annotate_bam(
+    input_path: &Path,
+    output_path: &Path,
+    scheme: &AmpliconScheme,
+    options: MatcherOptions,
+) -> Result<AnnotateStats> {
+    let mut reader = Bam.read_reads(input_path).await?;
+    let header = reader.read_header().await?;
+    let mut writer = Bam.read_writer(output_path).await?;
+    writer.write_header(&header).await?;
+
+    let matcher = AmpliconMatcher::from_scheme(scheme, options);
+    let mut stats = AnnotateStats::default();
+    let mut records = reader.records();
+    while let Some(record) = records.try_next().await? {
+        stats.total += 1;
+        let mut record = RecordBuf::try_from_alignment_record(&header, &record)?;
+        if let AmpliconMatch::Single(hit) = matcher.classify(record.sequence().as_ref()) {
+            stats.tagged += 1;
+            *stats.per_amplicon.entry(hit.amplicon.clone()).or_insert(0) += 1;
+            record
+                .data_mut()
+                .insert(AMPLICON_TAG, Value::String(hit.amplicon.into()));
+        }
+        writer.write_alignment_record(&header, &record).await?;
+    }
+    Bam.finalize_write(writer).await?;
+
+    Ok(stats)
+}
+
+/// Settings for calling a consensus for each amplicon with [`consensus_records`].
+#[derive(Debug, Default, Clone)]
+pub struct ConsensusSettings {
+    /// Also write the trimmed records behind each consensus to one FASTQ file per amplicon in
+    /// this directory, which must exist
+    pub reads_dir: Option<PathBuf>,
+
+    /// Stop with an error once the trimmed records held for consensus calling are estimated to
+    /// use more than this many bytes
+    pub max_memory: Option<u64>,
+
+    /// Whether to weight each record's vote at a position by the probability that its base
+    /// there was called correctly, rather than counting every record the same
+    pub quality_weighted: bool,
+
+    /// The ASCII offset that the input quality scores are encoded with
+    pub quality_offset: QualityOffset,
+
+    /// The reference to trim and pad each amplicon's consensus to, so that it spans exactly
+    /// the amplicon's insert in the reference
+    pub reference: Option<ConsensusReference>,
+
+    /// With a reference, stitch the placed consensus of every amplicon into one consensus per
+    /// reference sequence, as long as that sequence, and write them to this FASTA file
+    pub genome_output: Option<PathBuf>,
+}
+
+/// The reference that consensus sequences are placed onto with [`trim_to_reference`].
+#[derive(Debug, Default, Clone)]
+pub struct ConsensusReference {
+    /// The reference sequences, keyed by name
+    pub sequences: HashMap<Vec<u8>, Vec<u8>>,
+
+    /// Where each amplicon's insert lies in the reference, keyed by amplicon name
+    pub regions: HashMap<String, InsertRegion>,
+}
+
+impl ConsensusReference {
+    /// The reference bases of an amplicon's insert, along with where they lie.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the amplicon has no insert region or if its
+    /// region is not in the reference.
+    pub fn insert(&self, amplicon: &str) -> Result<(&InsertRegion, &[u8])> {
+        let region = self.regions.get(amplicon).ok_or_else(|| {
+            eyre!(
+                "The amplicon {} has no region in the reference to trim its consensus to.",
+                amplicon
+            )
+        })?;
+        let bases = self
+            .sequences
+            .get(region.ref_name.as_bytes())
+            .and_then(|sequence| sequence.get(region.start..region.start + region.len()))
+            .ok_or_else(|| {
+                eyre!(
+                    "Positions {} and {} for {} are not present in the reference sequence, {}.",
+                    region.start,
+                    region.stop,
+                    amplicon,
+                    region.ref_name
+                )
+            })?;
+        Ok((region, bases))
+    }
+}
+
+/// Trim records, group them by the amplicon they were trimmed to, and call a consensus for each
+/// amplicon, writing them to a FASTA file at `output_path` and returning the number of records
+/// each consensus was called from. Every record is aligned to its amplicon's most frequent
+/// sequence, as in [`IndexFormat::representatives`], and tallied into a
+/// [`ConsensusAccumulator`], so that positions most reads agree on are called and consistent
+/// deletions are left out. The records behind each consensus can also be written out for
+/// manual review, as in [`sort_records`], and each record's votes can be weighted by its
+/// quality scores, decoded with the configured offset, as set in `settings`. With a reference,
+/// each consensus is then trimmed and padded to span exactly its amplicon's insert in the
+/// reference, as in [`trim_to_reference`], so that consensus sequences share the reference's
+/// coordinates, and they can also be stitched into one consensus as long as each reference
+/// sequence, as in [`assemble_genome`]. Trimmed records are held in memory until every
+/// consensus is called, so with a memory limit, grouping stops with an error once they are
+/// estimated to use more than that.
+///
+/// # Errors
+///
+/// This function will return an error if a record cannot be parsed or trimmed, if the grouped
+/// records grow past the memory limit, if a record's quality scores cannot be decoded when
+/// weighting by quality, if an amplicon's consensus can't be placed onto the reference, if a
+/// whole-genome consensus is requested without a reference, or if the consensus FASTA, the
+/// whole-genome FASTA, or any per-amplicon file cannot be written.
+pub async fn consensus_records<S>(
+    mut records: S,
+    output_path: &Path,
+    scheme: &AmpliconScheme,
+    settings: &ConsensusSettings,
+) -> Result<BTreeMap<String, u64>>
+where
+    S: Stream<Item = std::io::Result<FastqRecord>> + Unpin,
+{
+    let mut writers = settings.reads_dir.as_deref().map(AmpliconWriters::new);
+    let mut guard = MemoryGuard::new(settings.max_memory);
+    let mut trimmed = Vec::new();
+    while let Some(record) = records.try_next().await? {
+        let Some(hit) = record.find_amplicon(&scheme.scheme).await else {
+            continue;
+        };
+        let amplicon = hit.amplicon.clone();
+        let Some(record) = record.trim_to_amplicon(hit).await? else {
+            continue;
+        };
+        if let Some(writers) = writers.as_mut() {
+            writers.write(&amplicon, &record).await?;
+        }
+        let held = amplicon.len()
+            + record.name().len()
+            + record.description().len()
+            + record.sequence().len()
+            + record.quality_scores().len();
+        guard.charge(
+            held as u64 + MAP_ENTRY_OVERHEAD,
+            "set of reads grouped for consensus calling",
+        )?;
+        trimmed.push((amplicon, record));
+    }
+    if let Some(writers) = writers {
+        writers.finalize().await?;
+    }
+
+    // group the records by amplicon in one pass rather than searching them all for each one
+    let index = IndexFormat::from_trimmed(scheme.hash_amplicon_scheme()?, &trimmed);
+    let mut groups: HashMap<&str, Vec<&FastqRecord>> = HashMap::new();
+    for (amplicon, record) in &trimmed {
+        groups.entry(amplicon.as_str()).or_default().push(record);
+    }
+
+    let mut fasta = File::create(output_path)
+        .map(BufWriter::new)
+        .map(noodles::fasta::io::Writer::new)?;
+    let mut counts = BTreeMap::new();
+    let mut placed: BTreeMap<String, Vec<PlacedConsensus>> = BTreeMap::new();
+    for (amplicon, representative) in index.representatives() {
+        let group = groups.remove(amplicon.as_str()).unwrap_or_default();
+        let mut accumulator = ConsensusAccumulator::new(representative.len());
+        for record in &group {
+            if settings.quality_weighted {
+                let phred_scores = settings.quality_offset.decode(record.quality_scores())?;
+                let (aligned, weights) =
+                    align_to_reference_weighted(record.sequence(), &phred_scores, &representative);
+                accumulator.add_weighted(&aligned, &weights);
+            } else {
+                accumulator.add(&align_to_reference(record.sequence(), &representative));
+            }
+        }
+        let mut consensus = accumulator.consensus(DEFAULT_MIN_DELETION_FREQ);
+        if let Some(reference) = &settings.reference {
+            let (region, bases) = reference.insert(&amplicon)?;
+            consensus = trim_to_reference(&consensus, bases);
+            if settings.genome_output.is_some() {
+                placed
+                    .entry(region.ref_name.clone())
+                    .or_default()
+                    .push(PlacedConsensus {
+                        start: region.start,
+                        sequence: consensus.clone(),
+                        depth: group.len() as u64,
+                    });
+            }
+        }
+        fasta.write_record(&noodles::fasta::Record::new(
+            Definition::new(amplicon.as_str(), None),
+            Sequence::from(consensus),
+        ))?;
+        counts.insert(amplicon, group.len() as u64);
+    }
+
+    // stitch the placed consensus sequences into one for each reference sequence they lie on
+    if let Some(genome_path) = &settings.genome_output {
+        let Some(reference) = &settings.reference else {
+            return Err(eyre!(
+                "A whole-genome consensus can only be assembled from consensus sequences trimmed to a reference."
+            ));
+        };
+        let mut genomes = File::create(genome_path)
+            .map(BufWriter::new)
+            .map(noodles::fasta::io::Writer::new)?;
+        for (ref_name, amplicons) in &placed {
+            let ref_len = reference
+                .sequences
+                .get(ref_name.as_bytes())
+                .map_or(0, Vec::len);
+            genomes.write_record(&noodles::fasta::Record::new(
+                Definition::new(ref_name.as_str(), None),
+                Sequence::from(assemble_genome(ref_len, amplicons)),
+            ))?;
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Call a consensus for each amplicon in a FASTQ file, which may be compressed or not, as in
+/// [`consensus_records`].
+///
+/// # Errors
+///
+/// This function will return an error if the input has an unsupported format or if consensus
+/// calling fails.
+pub async fn consensus_input(
+    input_path: &Path,
+    output_path: &Path,
+    scheme: &AmpliconScheme,
+    settings: &ConsensusSettings,
+) -> Result<BTreeMap<String, u64>> {
+    match io_selector(input_path).await? {
+        InputType::FASTQGZ(format) => {
+            let mut reader = format.read_reads(input_path).await?;
+            consensus_records(reader.records(), output_path, scheme, settings).await
+        }
+        InputType::FASTQ(format) => {
+            let mut reader = format.read_reads(input_path).await?;
+            consensus_records(reader.records(), output_path, scheme, settings).await
+        }
+        InputType::BAM(_) => Err(eyre!(
+            "Unaligned BAM inputs are not yet supported but will be soon!"
+        )),
+    }
+}
+
+/// Eagerly trim a collection of reads, returning each trimmed read alongside the name of the
+/// amplicon it was trimmed to.
+pub async fn sync_trimming<I>(
+    reads: I,
+    scheme: &AmpliconScheme,
+) -> Result<Vec<(String, FastqRecord)>>
 where
     I: IntoIterator<Item = FastqRecord>,
 {
     // trim them down based on the amplicon scheme
     let reads = reads.into_iter().map(|record| async move {
         if let Some(hit) = record.find_amplicon(&scheme.scheme).await {
+            let amplicon = hit.amplicon.clone();
             let trimmed_record = record.trim_to_amplicon(hit).await?;
-            Ok(trimmed_record)
+            Ok(trimmed_record.map(|record| (amplicon, record)))
         } else {
             Ok(None)
         }
@@ -157,7 +3448,7 @@ where
     let collected = join_all(reads)
         .await
         .into_iter()
-        .filter_map(|result: Result<Option<FastqRecord>>| result.ok().flatten())
+        .filter_map(|result: Result<Option<(String, FastqRecord)>>| result.ok().flatten())
         .collect();
 
     Ok(collected)
@@ -1,31 +1,830 @@
 // #![warn(missing_docs)]
 
-//!
+//! Module `record` implements amplicon-finding and trimming on individual sequencing records.
 
-use color_eyre::eyre::Result;
+use std::borrow::Cow;
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
 use itertools::Itertools;
+use memchr::memmem::Finder;
 use noodles::fastq::Record as FastqRecord;
 
 use crate::{
-    primers::{PossiblePrimers, PrimerPair},
-    reads::FilterSettings,
+    primers::{reverse_complement_bytes, AmpliconScheme, PossiblePrimers, PrimerPair},
+    reads::{FilterSettings, OnError},
 };
 
+/// The ASCII offset used to encode Phred quality scores in a FASTQ file. Modern data uses
+/// Phred+33, while some legacy Illumina data uses Phred+64.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QualityOffset {
+    /// Phred+33 (Sanger and Illumina 1.8+)
+    #[default]
+    #[value(name = "33")]
+    Phred33,
+
+    /// Phred+64 (Illumina 1.3 to 1.7)
+    #[value(name = "64")]
+    Phred64,
+}
+
+impl QualityOffset {
+    /// The ASCII value that represents a Phred score of zero.
+    pub fn value(&self) -> u8 {
+        match self {
+            QualityOffset::Phred33 => 33,
+            QualityOffset::Phred64 => 64,
+        }
+    }
+
+    /// Decode ASCII-encoded quality scores into raw Phred scores.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any character falls below the offset, which
+    /// usually means the wrong offset was chosen for the data.
+    pub fn decode(&self, quality_scores: &[u8]) -> Result<Vec<u8>> {
+        let offset = self.value();
+        quality_scores
+            .iter()
+            .map(|score| {
+                score.checked_sub(offset).ok_or_else(|| {
+                    eyre!(
+                        "Quality character '{}' is below the Phred+{} offset. Is the quality offset set correctly?",
+                        *score as char,
+                        offset
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Re-encode quality scores from this offset into the `target` offset.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the scores cannot be decoded with this offset.
+    pub fn rescale(&self, quality_scores: &[u8], target: QualityOffset) -> Result<Vec<u8>> {
+        let rescaled = self
+            .decode(quality_scores)?
+            .into_iter()
+            .map(|score| score.saturating_add(target.value()))
+            .collect();
+        Ok(rescaled)
+    }
+}
+
+/// A hook for modifying trimmed records just before they are written, e.g., to rename them or
+/// add tags to their descriptions. The default implementation leaves records unchanged.
+pub trait RecordTransform {
+    /// Modify a trimmed record in place.
+    fn transform(&self, _record: &mut FastqRecord) {}
+}
+
+/// The identity transform, which writes trimmed records as-is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdentityTransform;
+
+impl RecordTransform for IdentityTransform {}
+
+/// The outcome of searching a record for the primers in a scheme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmpliconMatch {
+    /// Both primers of exactly one amplicon were found
+    Single(PrimerPair),
+
+    /// No amplicon had both of its primers in the record
+    NoMatch,
+
+    /// More than one amplicon had both of its primers in the record
+    MultiMatch,
+
+    /// No amplicon had both of its primers in the record, but a forward primer from one
+    /// amplicon and a reverse primer from another were found, as in a PCR chimera
+    Chimera {
+        /// The amplicon the forward primer belongs to
+        fwd_amplicon: String,
+
+        /// The amplicon the reverse primer belongs to
+        rev_amplicon: String,
+    },
+
+    /// No amplicon had both of its primers in the record, but the record starts from the forward
+    /// primer of exactly one amplicon and reads far enough toward its reverse primer, as in a
+    /// short read that stops before the end of the amplicon
+    Partial(PartialAmplicon),
+}
+
+/// A forward primer found in a record too short to reach the reverse primer, along with where
+/// the part of the insert that the record does cover lies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialAmplicon {
+    /// The name of the matched amplicon
+    pub amplicon: String,
+
+    /// The forward primer sequence as it was found in the record
+    pub fwd: String,
+
+    /// The index in the record where the covered insert starts
+    pub start: usize,
+
+    /// The index in the record where the covered insert ends
+    pub end: usize,
+}
+
+/// Why a record was dropped by the trimming loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The record was too short or too long to hold any amplicon in the scheme
+    LengthGate,
+
+    /// No amplicon had both of its primers in the record
+    NoMatch,
+
+    /// More than one amplicon had both of its primers in the record
+    MultiMatch,
+
+    /// The record had primers from two different amplicons
+    Chimera,
+
+    /// The primers were found but left no insert between them, or one shorter than the minimum
+    /// insert length
+    TooShort,
+
+    /// The trimmed sequence was too rare in the index
+    LowFreq,
+
+    /// The trimmed sequence was longer than the expected amplicon length
+    LenFilter,
+
+    /// The record about to be written was longer than its input record or had mismatched
+    /// sequence and quality lengths
+    InvalidOutput,
+
+    /// The trimmed insert strayed further from the length the scheme's coordinates give its
+    /// amplicon than the allowed tolerance, suggesting a primer was matched in the wrong place
+    ImplausibleInsert,
+
+    /// A primer was only matched over bases whose mean quality fell below the minimum, so the
+    /// match was not trusted
+    LowQualityPrimer,
+
+    /// The record held a number of complete amplicons outside the requested range, e.g. a
+    /// concatemer when only single amplicons were wanted
+    AmpliconCount,
+
+    /// The record was malformed as read from the input, with a different number of bases and
+    /// quality scores
+    MalformedInput,
+}
+
+impl RejectReason {
+    /// The short tag used to label rejected records.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::LengthGate => "length_gate",
+            RejectReason::NoMatch => "no_match",
+            RejectReason::MultiMatch => "multi_match",
+            RejectReason::Chimera => "chimera",
+            RejectReason::TooShort => "too_short",
+            RejectReason::LowFreq => "low_freq",
+            RejectReason::LenFilter => "len_filter",
+            RejectReason::InvalidOutput => "invalid_output",
+            RejectReason::ImplausibleInsert => "implausible_insert",
+            RejectReason::LowQualityPrimer => "low_quality_primer",
+            RejectReason::AmpliconCount => "amplicon_count",
+            RejectReason::MalformedInput => "malformed_input",
+        }
+    }
+
+    /// Append this reason to a record's description, e.g. `rejected=no_match`.
+    pub fn tag(&self, record: &mut FastqRecord) {
+        append_to_description(record, &format!("rejected={}", self.as_str()));
+    }
+}
+
+/// Append a comment to a record's description, separated by a space from anything already
+/// there.
+fn append_to_description(record: &mut FastqRecord, comment: &str) {
+    let description = record.description_mut();
+    if !description.is_empty() {
+        description.push(b' ');
+    }
+    description.extend_from_slice(comment.as_bytes());
+}
+
+/// The fraction of bases in a sequence that are G or C, in either case. Empty sequences have a
+/// GC content of zero.
+pub fn gc_content(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let gc = seq
+        .iter()
+        .filter(|base| matches!(base, b'G' | b'C' | b'g' | b'c'))
+        .count();
+    gc as f64 / seq.len() as f64
+}
+
+/// Append the length and GC content of a record's sequence to its description, e.g.
+/// `len=19 gc=0.42`, keeping any description it already has.
+pub fn annotate_metrics(record: &mut FastqRecord) {
+    let metrics = format!(
+        "len={} gc={:.2}",
+        record.sequence().len(),
+        gc_content(record.sequence())
+    );
+    append_to_description(record, &metrics);
+}
+
+/// Append the length of the untrimmed record and where the trimmed insert lay within it to a
+/// record's description, e.g. `orig_len=80 trim_start=12 trim_end=24`, so that positions in the
+/// trimmed record can be mapped back to the original. Coordinates are 0-based and half-open, in
+/// the orientation of the untrimmed record.
+pub fn annotate_origin(record: &mut FastqRecord, original_len: usize, insert: &Range<usize>) {
+    let origin = format!(
+        "orig_len={} trim_start={} trim_end={}",
+        original_len, insert.start, insert.end
+    );
+    append_to_description(record, &origin);
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Settings for nudging the ends of a trimmed insert past low-quality bases, which basecallers
+/// often produce at primer junctions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundaryRefinement {
+    /// The lowest Phred score an end of the insert may have after refinement
+    pub min_quality: u8,
+
+    /// The most bases either end of the insert may be moved by
+    pub window: usize,
+}
+
+impl Default for BoundaryRefinement {
+    fn default() -> Self {
+        BoundaryRefinement {
+            min_quality: 10,
+            window: 2,
+        }
+    }
+}
+
+/// Move each end of a trimmed record inward to the nearest base with a quality of at least
+/// `min_quality`, looking no further than `window` bases in. Ends are only ever moved inward,
+/// since the bases just outside the insert belong to the primers. An end with no such base
+/// within the window is left where it is, as is a record too short to refine without removing
+/// it entirely. Returns the positions of the record, before refinement, that were kept.
+///
+/// # Errors
+///
+/// This function will return an error if the quality scores cannot be decoded with `offset`.
+pub fn refine_boundaries(
+    record: &mut FastqRecord,
+    offset: QualityOffset,
+    refinement: &BoundaryRefinement,
+) -> Result<Range<usize>> {
+    let scores = offset.decode(record.quality_scores())?;
+    let len = scores.len();
+    if len == 0 {
+        return Ok(0..0);
+    }
+    let passes = |idx: usize| scores[idx] >= refinement.min_quality;
+    let window = refinement.window.min(len - 1);
+
+    let start = (0..=window).find(|&i| passes(i)).unwrap_or(0);
+    let end = (0..=window)
+        .find(|&i| passes(len - 1 - i))
+        .map_or(len, |i| len - i);
+    if start >= end {
+        return Ok(0..len);
+    }
+
+    *record.sequence_mut() = subseq(record.sequence(), start, end)?.to_vec();
+    *record.quality_scores_mut() = subseq(record.quality_scores(), start, end)?.to_vec();
+
+    Ok(start..end)
+}
+
+/// Take the bases from `start` up to, but not including, `stop` from a sequence or its quality
+/// scores. All subranging of sequences goes through here so that bad coordinates surface as
+/// errors rather than panics.
 ///
+/// # Errors
+///
+/// This function will return an error if `start` is past `stop` or `stop` is past the end of
+/// the sequence.
+pub fn subseq(seq: &[u8], start: usize, stop: usize) -> Result<&[u8]> {
+    if start > stop {
+        return Err(eyre!(
+            "Cannot take bases {} to {} of a sequence because the start is past the stop.",
+            start,
+            stop
+        ));
+    }
+    seq.get(start..stop).ok_or_else(|| {
+        eyre!(
+            "Cannot take bases {} to {} of a sequence that is only {} bases long.",
+            start,
+            stop,
+            seq.len()
+        )
+    })
+}
+
+/// The shortest insert kept between a pair of primers unless another minimum is requested.
+/// Reads whose primers abut or nearly abut, as in adapter or primer dimers, would otherwise be
+/// trimmed down to a few bases that downstream tools cannot use.
+pub const DEFAULT_MIN_INSERT_LEN: usize = 10;
+
+/// The most mismatches or edits allowed in each primer when matching is not exact, unless
+/// another budget is requested.
+pub const DEFAULT_MAX_PRIMER_ERRORS: usize = 2;
+
+/// Where a primer was found in a record, from its first base up to, but not including, `end`.
+/// Alignment-based matching may place a primer over more or fewer bases than it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrimerSpan {
+    /// The position of the first base matched to the primer
+    pub start: usize,
+
+    /// The position just past the last base matched to the primer
+    pub end: usize,
+}
+
+impl PrimerSpan {
+    /// The number of bases of the record that the primer covers.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Whether the primer covers no bases at all.
+    pub fn is_empty(&self) -> bool {
+        self.end == self.start
+    }
+}
+
+/// A way of finding a primer in a record's sequence.
+pub trait PrimerMatcher: Send + Sync {
+    /// Return where `primer` best matches in `seq`, if it matches anywhere.
+    fn find(&self, seq: &[u8], primer: &[u8]) -> Option<PrimerSpan>;
+}
+
+/// Matches primers exactly, as a plain substring of the record.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExactMatcher;
+
+impl PrimerMatcher for ExactMatcher {
+    fn find(&self, seq: &[u8], primer: &[u8]) -> Option<PrimerSpan> {
+        memchr::memmem::find(seq, primer).map(|start| PrimerSpan {
+            start,
+            end: start + primer.len(),
+        })
+    }
+}
+
+/// Matches primers with up to `max_mismatches` substituted bases, but no insertions or
+/// deletions. The position with the fewest mismatches wins, with ties going to the earliest.
+#[derive(Debug, Clone, Copy)]
+pub struct HammingMatcher {
+    /// The most bases that may differ between the primer and the record
+    pub max_mismatches: usize,
+}
+
+impl PrimerMatcher for HammingMatcher {
+    fn find(&self, seq: &[u8], primer: &[u8]) -> Option<PrimerSpan> {
+        if primer.is_empty() {
+            return None;
+        }
+        let mut best: Option<(usize, usize)> = None;
+        for (start, window) in seq.windows(primer.len()).enumerate() {
+            let mismatches = window
+                .iter()
+                .zip(primer)
+                .filter(|(read_base, primer_base)| read_base != primer_base)
+                .count();
+            if mismatches <= self.max_mismatches
+                && best.is_none_or(|(fewest, _)| mismatches < fewest)
+            {
+                best = Some((mismatches, start));
+                if mismatches == 0 {
+                    break;
+                }
+            }
+        }
+        best.map(|(_, start)| PrimerSpan {
+            start,
+            end: start + primer.len(),
+        })
+    }
+}
+
+/// Matches primers with up to `max_edits` substitutions, insertions, and deletions, which
+/// tolerates the indels common in nanopore reads. The whole primer is aligned to any stretch of
+/// the record, computing only the band of the alignment that can still finish within the edit
+/// budget. The alignment with the fewest edits wins, with ties going to the one that ends
+/// earliest.
+#[derive(Debug, Clone, Copy)]
+pub struct AlignMatcher {
+    /// The most edits allowed in aligning the primer to the record
+    pub max_edits: usize,
+}
+
+impl PrimerMatcher for AlignMatcher {
+    fn find(&self, seq: &[u8], primer: &[u8]) -> Option<PrimerSpan> {
+        let rows = primer.len();
+        if rows == 0 {
+            return None;
+        }
+        let budget = self.max_edits;
+        let over_budget = budget + 1;
+
+        // for each prefix of the primer, the fewest edits of an alignment ending at the current
+        // base of the record, and where in the record that alignment starts. Every row past
+        // `last` is over budget
+        let mut column: Vec<(usize, usize)> =
+            (0..=rows).map(|row| (row.min(over_budget), 0)).collect();
+        let mut last = budget.min(rows);
+        let mut best: Option<(usize, PrimerSpan)> = None;
+        for (col, base) in seq.iter().enumerate() {
+            let mut diagonal = column[0];
+            column[0] = (0, col + 1);
+            let band = (last + 1).min(rows);
+            for row in 1..=band {
+                let left = column[row];
+                let above = column[row - 1];
+                let mut cell = (
+                    diagonal.0 + usize::from(primer[row - 1] != *base),
+                    diagonal.1,
+                );
+                if above.0 + 1 < cell.0 {
+                    cell = (above.0 + 1, above.1);
+                }
+                if left.0 + 1 < cell.0 {
+                    cell = (left.0 + 1, left.1);
+                }
+                column[row] = (cell.0.min(over_budget), cell.1);
+                diagonal = left;
+            }
+
+            last = band;
+            while last > 0 && column[last].0 > budget {
+                last -= 1;
+            }
+            let (edits, start) = column[rows];
+            if last == rows && start <= col && best.is_none_or(|(fewest, _)| edits < fewest) {
+                best = Some((
+                    edits,
+                    PrimerSpan {
+                        start,
+                        end: col + 1,
+                    },
+                ));
+                if edits == 0 {
+                    break;
+                }
+            }
+        }
+        best.map(|(_, span)| span)
+    }
+}
+
+/// The algorithm used to find primers in records.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MatchAlgorithm {
+    /// Primers must appear exactly
+    #[default]
+    Exact,
+
+    /// Primers may have substituted bases, up to a mismatch budget
+    Hamming,
+
+    /// Primers may have substitutions, insertions, and deletions, up to an edit budget
+    Align,
+}
+
+/// How the amplicons of a scheme relate to one another, which decides how much of the scheme
+/// must be searched before a read can be classified.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SchemeKind {
+    /// Amplicons may overlap, as in tiled schemes, so every amplicon is searched for and reads
+    /// holding more than one complete amplicon are reported as multiple matches
+    #[default]
+    Tiled,
+
+    /// Amplicons never overlap, so a read can only hold one of them, and searching stops at the
+    /// first amplicon whose primers are both found. Reads are classified just as with
+    /// [`SchemeKind::Tiled`], except for artifacts that join several complete amplicons, which
+    /// are taken to be the first of them
+    NonOverlapping,
+}
+
+impl MatchAlgorithm {
+    /// Build the matcher for this algorithm, allowing up to `max_errors` mismatches or edits in
+    /// each primer when matching is not exact.
+    pub fn matcher(self, max_errors: usize) -> Box<dyn PrimerMatcher> {
+        match self {
+            MatchAlgorithm::Exact => Box::new(ExactMatcher),
+            MatchAlgorithm::Hamming => Box::new(HammingMatcher {
+                max_mismatches: max_errors,
+            }),
+            MatchAlgorithm::Align => Box::new(AlignMatcher {
+                max_edits: max_errors,
+            }),
+        }
+    }
+}
+
+/// The bases of a read as primers are searched for in them: uppercased, so that reads with
+/// lowercase bases still match primers, which are always uppercase, but borrowed unchanged when
+/// there is nothing to uppercase. Positions in the result are positions in the read, so the
+/// record itself never needs to be changed.
+pub fn canonical_bases(seq: &[u8]) -> Cow<'_, [u8]> {
+    if seq.iter().any(u8::is_ascii_lowercase) {
+        Cow::Owned(seq.to_ascii_uppercase())
+    } else {
+        Cow::Borrowed(seq)
+    }
+}
+
+/// Return where `primer` is found in `seq` using the provided matcher, whatever the case of the
+/// bases in `seq`.
+pub fn find_primer_match<M: PrimerMatcher + ?Sized>(
+    seq: &[u8],
+    primer: &str,
+    matcher: &M,
+) -> Option<PrimerSpan> {
+    matcher.find(&canonical_bases(seq), primer.as_bytes())
+}
+
+/// Return where `primer` is found in `seq` as in [`find_primer_match`], but only if the read's
+/// bases under the match have a mean Phred score of at least `min_quality`, going by its
+/// `quality_scores` encoded with `offset`. A primer matched within a stretch of low-quality
+/// bases is more likely to be a false positive than a real primer.
+///
+/// # Errors
+///
+/// This function will return an error if the quality scores under the match cannot be decoded
+/// with `offset` or do not cover it.
+pub fn find_confident_primer_match<M: PrimerMatcher + ?Sized>(
+    seq: &[u8],
+    quality_scores: &[u8],
+    primer: &str,
+    matcher: &M,
+    min_quality: u8,
+    offset: QualityOffset,
+) -> Result<Option<PrimerSpan>> {
+    let Some(span) = find_primer_match(seq, primer, matcher) else {
+        return Ok(None);
+    };
+
+    Ok(is_confident_span(quality_scores, span, min_quality, offset)?.then_some(span))
+}
+
+/// Whether the bases of a read at `span`, e.g. where one of its primers was matched, have a
+/// mean Phred score of at least `min_quality`, going by its `quality_scores` encoded with
+/// `offset`. An empty span is always confident.
+///
+/// # Errors
+///
+/// This function will return an error if the quality scores under the span cannot be decoded
+/// with `offset` or do not cover it.
+pub fn is_confident_span(
+    quality_scores: &[u8],
+    span: PrimerSpan,
+    min_quality: u8,
+    offset: QualityOffset,
+) -> Result<bool> {
+    let scores = offset.decode(subseq(quality_scores, span.start, span.end)?)?;
+    if scores.is_empty() {
+        return Ok(true);
+    }
+    let mean = scores.iter().map(|score| f64::from(*score)).sum::<f64>() / scores.len() as f64;
+
+    Ok(mean >= f64::from(min_quality))
+}
+
+/// Return where `primer` is last found in `seq` using the provided matcher, starting past the
+/// end of `first`, e.g., to find the reverse primer of an amplicon whose primers coincide
+/// after the occurrence taken for its forward primer. `seq` is expected to be canonical, as
+/// from [`canonical_bases`].
+pub fn last_match_after<M: PrimerMatcher + ?Sized>(
+    seq: &[u8],
+    primer: &[u8],
+    first: &PrimerSpan,
+    matcher: &M,
+) -> Option<PrimerSpan> {
+    let mut last = None;
+    let mut from = first.end;
+    while from < seq.len() {
+        let Some(span) = matcher.find(&seq[from..], primer) else {
+            break;
+        };
+        let span = PrimerSpan {
+            start: span.start + from,
+            end: span.end + from,
+        };
+        from = span.start + 1;
+        last = Some(span);
+    }
+    last
+}
+
+/// Find an amplicon's primers in a read, in whichever orientation each occurs, when the
+/// primers coincide as in [`PossiblePrimers::primers_coincide`]. They are told apart by
+/// position: the forward primer is the first occurrence, and a reverse primer with the same
+/// sequence only counts if it occurs again after it.
+fn coinciding_primers<'p, M: PrimerMatcher + ?Sized>(
+    seq: &[u8],
+    pair: &'p PossiblePrimers,
+    matcher: &M,
+) -> (Option<&'p str>, Option<&'p str>) {
+    let fwd = [pair.fwd.as_str(), pair.fwd_rc.as_str()]
+        .into_iter()
+        .find_map(|candidate| {
+            matcher
+                .find(seq, candidate.as_bytes())
+                .map(|span| (candidate, span))
+        });
+    let rev = [pair.rev.as_str(), pair.rev_rc.as_str()]
+        .into_iter()
+        .find(|candidate| match fwd {
+            Some((fwd_seq, fwd_span)) if fwd_seq == *candidate => {
+                last_match_after(seq, candidate.as_bytes(), &fwd_span, matcher).is_some()
+            }
+            _ => matcher.find(seq, candidate.as_bytes()).is_some(),
+        });
+
+    (fwd.map(|(primer, _)| primer), rev)
+}
+
+/// Return the positions of the insert between a record's primers, found with the provided
+/// matcher, as long as that insert is at least `min_insert_len` bases long once both primers'
+/// footprints are excluded. When the primers found have the same sequence, as for amplicons
+/// whose primers coincide, the first occurrence is taken as the forward primer and the last as
+/// the reverse primer.
+pub fn amplicon_bounds<M: PrimerMatcher + ?Sized>(
+    seq: &[u8],
+    primers: &PrimerPair,
+    min_insert_len: usize,
+    matcher: &M,
+) -> Option<Range<usize>> {
+    let seq = canonical_bases(seq);
+    let fwd = find_primer_match(&seq, &primers.fwd, matcher)?;
+    let rev = if primers.fwd == primers.rev {
+        last_match_after(&seq, primers.rev.as_bytes(), &fwd, matcher)?
+    } else {
+        find_primer_match(&seq, &primers.rev, matcher)?
+    };
+
+    // reads from the reverse strand carry the reverse primer first and the reverse complement
+    // of the forward primer last, so the insert always starts after whichever primer comes
+    // first and ends where the other one begins
+    let (first, second) = if fwd.start <= rev.start {
+        (fwd, rev)
+    } else {
+        (rev, fwd)
+    };
+
+    // the insert is whatever the span from the start of the first primer to the end of the
+    // second holds beyond both primers' footprints, which is nothing when the primers overlap,
+    // as in a primer dimer
+    let span = second.end.saturating_sub(first.start);
+    let insert_len = span.saturating_sub(first.len() + second.len());
+    if insert_len < min_insert_len.max(1) {
+        return None;
+    }
+
+    Some(first.end..first.end + insert_len)
+}
+
+/// Split an untrimmed record into the regions on either side of its insert: the 5' region up
+/// to and including the first primer, and the 3' region from the second primer onward. Each
+/// region keeps the record's name and is labeled in its description, e.g. `region=5prime`,
+/// so that concatenating the 5' region, the insert, and the 3' region gives back the record.
+///
+/// # Errors
+///
+/// This function will return an error if the insert does not lie within the record.
+pub fn primer_regions(
+    record: &FastqRecord,
+    insert: &Range<usize>,
+) -> Result<(FastqRecord, FastqRecord)> {
+    let len = record.sequence().len();
+    let region = |start: usize, stop: usize, label: &str| -> Result<FastqRecord> {
+        let mut region = record.clone();
+        *region.sequence_mut() = subseq(record.sequence(), start, stop)?.to_vec();
+        *region.quality_scores_mut() = subseq(record.quality_scores(), start, stop)?.to_vec();
+        append_to_description(&mut region, &format!("region={label}"));
+        Ok(region)
+    };
+
+    Ok((
+        region(0, insert.start, "5prime")?,
+        region(insert.end, len, "3prime")?,
+    ))
+}
+
+/// Sort the primers found in each amplicon's orientations into an [`AmpliconMatch`].
+fn classify_hits(hits: &[(&str, Option<&str>, Option<&str>)]) -> AmpliconMatch {
+    let mut amplicon_match: Vec<PrimerPair> = hits
+        .iter()
+        .filter_map(
+            |(amplicon, maybe_fwd, maybe_rev)| match (maybe_fwd, maybe_rev) {
+                (Some(fwd), Some(rev)) => Some(PrimerPair {
+                    amplicon: amplicon.to_string(),
+                    fwd: fwd.to_string(),
+                    rev: rev.to_string(),
+                }),
+                _ => None,
+            },
+        )
+        .unique_by(|hit| (hit.fwd.clone(), hit.rev.clone()))
+        .collect();
+
+    match (amplicon_match.len(), amplicon_match.pop()) {
+        (1, Some(success)) => return AmpliconMatch::Single(success),
+        (0, _) => (),
+        _ => return AmpliconMatch::MultiMatch,
+    }
+
+    // without a complete amplicon, look for a forward primer from one amplicon alongside a
+    // reverse primer from another
+    let fwd_hit = hits.iter().find(|(_, fwd, _)| fwd.is_some());
+    let rev_hit = hits.iter().find(|(amplicon, _, rev)| {
+        rev.is_some() && fwd_hit.is_some_and(|(fwd_amplicon, _, _)| fwd_amplicon != amplicon)
+    });
+    match (fwd_hit, rev_hit) {
+        (Some((fwd_amplicon, _, _)), Some((rev_amplicon, _, _))) => AmpliconMatch::Chimera {
+            fwd_amplicon: fwd_amplicon.to_string(),
+            rev_amplicon: rev_amplicon.to_string(),
+        },
+        _ => AmpliconMatch::NoMatch,
+    }
+}
+
+/// Methods for finding and trimming to a complete amplicon within a record.
 pub trait FindAmplicons<'a, 'b> {
-    ///
+    /// Return the forward primer, in whichever orientation it occurs, if it is in the record.
     fn forward_match(&'a self, pair: &'b PossiblePrimers) -> Option<&'b str>;
 
-    ///
+    /// Return the reverse primer, in whichever orientation it occurs, if it is in the record.
     fn reverse_match(&'a self, pair: &'b PossiblePrimers) -> Option<&'b str>;
 
-    /// .
+    /// Classify the record by the primers it contains, distinguishing records with one complete
+    /// amplicon from those with none, several, or primers from different amplicons.
+    fn classify_amplicon(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+    ) -> impl futures::Future<Output = AmpliconMatch>;
+
+    /// Classify the record as in [`FindAmplicons::classify_amplicon`], but find each primer
+    /// with the provided matcher, e.g., to tolerate sequencing errors in the primers.
+    fn classify_amplicon_with<M: PrimerMatcher + ?Sized>(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        matcher: &M,
+    ) -> impl futures::Future<Output = AmpliconMatch>;
+
+    /// Return the primers found in the record if they identify exactly one amplicon.
     fn find_amplicon(
         &'a self,
         primerpairs: &'b [PossiblePrimers],
     ) -> impl futures::Future<Output = Option<PrimerPair>>;
 
-    ///
+    /// Return the primers found in the record as in [`FindAmplicons::find_amplicon`], but for
+    /// a [`SchemeKind::NonOverlapping`] scheme, return the first amplicon whose primers are both
+    /// found without searching for the rest.
+    fn find_amplicon_in(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        kind: SchemeKind,
+    ) -> impl futures::Future<Output = Option<PrimerPair>>;
+
+    /// For a record without a complete amplicon, look for the forward primer of exactly one
+    /// amplicon followed, in the direction of the reverse primer, by at least `min_extension`
+    /// bases. Reads of the forward strand must extend past the end of the forward primer, while
+    /// reads of the reverse strand must extend before the start of its reverse complement.
+    fn find_partial_amplicon(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        min_extension: usize,
+    ) -> impl futures::Future<Output = AmpliconMatch>;
+
+    /// Trim the record down to the sequence between its primers.
     fn trim_to_amplicon(
         self,
         primers: PrimerPair,
@@ -33,26 +832,59 @@ pub trait FindAmplicons<'a, 'b> {
     where
         Self: Sized;
 
-    ///
+    /// Trim the record down to the sequence between its primers, as long as that insert is at
+    /// least `min_insert_len` bases long once both primers' footprints are excluded. Inserts
+    /// that are shorter, e.g. from primer dimers, are dropped.
+    fn trim_to_amplicon_with_min_insert(
+        self,
+        primers: PrimerPair,
+        min_insert_len: usize,
+    ) -> impl futures::Future<Output = Result<Option<Self>>>
+    where
+        Self: Sized;
+
+    /// Trim the record as in [`FindAmplicons::trim_to_amplicon_with_min_insert`], but find the
+    /// primers with the provided matcher, which should be the one they were classified with.
+    fn trim_to_matched_amplicon<M: PrimerMatcher + ?Sized>(
+        self,
+        primers: PrimerPair,
+        min_insert_len: usize,
+        matcher: &M,
+    ) -> impl futures::Future<Output = Result<Option<Self>>>
+    where
+        Self: Sized;
+
+    /// Trim the record down to the part of the insert it covers past its forward primer.
+    fn trim_to_partial_amplicon(
+        self,
+        hit: PartialAmplicon,
+    ) -> impl futures::Future<Output = Result<Option<Self>>>
+    where
+        Self: Sized;
+
+    /// Return the reason a trimmed record from the named amplicon fails the provided filters,
+    /// if it fails any.
+    fn rejection_reason(
+        &'a self,
+        amplicon: &str,
+        filters: &'b Option<FilterSettings>,
+    ) -> impl futures::Future<Output = Option<RejectReason>>;
+
+    /// Decide whether a trimmed record from the named amplicon passes the provided filters.
     fn whether_to_write(
         &'a self,
+        amplicon: &str,
         filters: &'b Option<FilterSettings>,
     ) -> impl futures::Future<Output = bool>;
 }
 
 impl<'a, 'b> FindAmplicons<'a, 'b> for FastqRecord {
     fn forward_match(&'a self, pair: &'b PossiblePrimers) -> Option<&'b str> {
-        if self
-            .sequence()
-            .windows(pair.fwd.len())
-            .any(|window| window.eq(pair.fwd.as_bytes()))
-        {
+        let finders = pair.finders();
+        let seq = canonical_bases(self.sequence());
+        if finders.fwd.find(&seq).is_some() {
             Some(&pair.fwd)
-        } else if self
-            .sequence()
-            .windows(pair.fwd.len())
-            .any(|window| window.eq(pair.fwd_rc.as_bytes()))
-        {
+        } else if finders.fwd_rc.find(&seq).is_some() {
             Some(&pair.fwd_rc)
         } else {
             None
@@ -60,78 +892,1028 @@ impl<'a, 'b> FindAmplicons<'a, 'b> for FastqRecord {
     }
 
     fn reverse_match(&'a self, pair: &'b PossiblePrimers) -> Option<&'b str> {
-        if self
-            .sequence()
-            .windows(pair.rev.len())
-            .any(|window| window.eq(pair.rev.as_bytes()))
-        {
+        let finders = pair.finders();
+        let seq = canonical_bases(self.sequence());
+        if finders.rev.find(&seq).is_some() {
             Some(&pair.rev)
-        } else if self
-            .sequence()
-            .windows(pair.rev.len())
-            .any(|window| window.eq(pair.rev_rc.as_bytes()))
-        {
+        } else if finders.rev_rc.find(&seq).is_some() {
             Some(&pair.rev_rc)
         } else {
             None
         }
     }
 
-    async fn find_amplicon(&'a self, primerpairs: &'b [PossiblePrimers]) -> Option<PrimerPair> {
-        let mut amplicon_match: Vec<PrimerPair> = primerpairs
+    async fn classify_amplicon(&'a self, primerpairs: &'b [PossiblePrimers]) -> AmpliconMatch {
+        let seq = canonical_bases(self.sequence());
+        let hits: Vec<(&str, Option<&str>, Option<&str>)> = primerpairs
             .iter()
-            .filter_map(|pair| {
-                let maybe_fwd = self.forward_match(pair);
-                let maybe_rev = self.reverse_match(pair);
-
-                match (maybe_fwd, maybe_rev) {
-                    (Some(fwd), Some(rev)) => Some(PrimerPair {
-                        fwd: fwd.to_string(),
-                        rev: rev.to_string(),
-                    }),
-                    _ => None,
+            .map(|pair| {
+                if pair.primers_coincide() {
+                    let (fwd, rev) = coinciding_primers(&seq, pair, &ExactMatcher);
+                    return (pair.amplicon.as_str(), fwd, rev);
                 }
+                (
+                    pair.amplicon.as_str(),
+                    self.forward_match(pair),
+                    self.reverse_match(pair),
+                )
             })
-            .unique()
             .collect();
 
-        match (amplicon_match.len(), amplicon_match.pop()) {
-            (1, Some(success)) => Some(success),
+        classify_hits(&hits)
+    }
+
+    async fn classify_amplicon_with<M: PrimerMatcher + ?Sized>(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        matcher: &M,
+    ) -> AmpliconMatch {
+        let seq = canonical_bases(self.sequence());
+        let matched = |primer: &'b str, primer_rc: &'b str| {
+            [primer, primer_rc]
+                .into_iter()
+                .find(|candidate| find_primer_match(&seq, candidate, matcher).is_some())
+        };
+        let hits: Vec<(&str, Option<&str>, Option<&str>)> = primerpairs
+            .iter()
+            .map(|pair| {
+                if pair.primers_coincide() {
+                    let (fwd, rev) = coinciding_primers(&seq, pair, matcher);
+                    return (pair.amplicon.as_str(), fwd, rev);
+                }
+                (
+                    pair.amplicon.as_str(),
+                    matched(pair.fwd.as_str(), pair.fwd_rc.as_str()),
+                    matched(pair.rev.as_str(), pair.rev_rc.as_str()),
+                )
+            })
+            .collect();
+
+        classify_hits(&hits)
+    }
+
+    async fn find_amplicon(&'a self, primerpairs: &'b [PossiblePrimers]) -> Option<PrimerPair> {
+        match self.classify_amplicon(primerpairs).await {
+            AmpliconMatch::Single(success) => Some(success),
             _ => None,
         }
     }
 
-    async fn trim_to_amplicon(mut self, primers: PrimerPair) -> Result<Option<Self>> {
-        let seq_str = std::str::from_utf8(self.sequence())?;
-        match (&seq_str.find(&primers.fwd), &seq_str.find(&primers.rev)) {
-            (Some(fwd_idx), Some(rev_idx)) => {
-                let new_start = fwd_idx + primers.fwd.len();
-                let new_end = rev_idx;
+    async fn find_amplicon_in(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        kind: SchemeKind,
+    ) -> Option<PrimerPair> {
+        if kind == SchemeKind::Tiled {
+            return self.find_amplicon(primerpairs).await;
+        }
+
+        let seq = canonical_bases(self.sequence());
+        primerpairs.iter().find_map(|pair| {
+            if pair.primers_coincide() {
+                let (Some(fwd), Some(rev)) = coinciding_primers(&seq, pair, &ExactMatcher) else {
+                    return None;
+                };
+                return Some(PrimerPair {
+                    amplicon: pair.amplicon.clone(),
+                    fwd: fwd.to_string(),
+                    rev: rev.to_string(),
+                });
+            }
+            let finders = pair.finders();
+            let fwd = if finders.fwd.find(&seq).is_some() {
+                &pair.fwd
+            } else if finders.fwd_rc.find(&seq).is_some() {
+                &pair.fwd_rc
+            } else {
+                return None;
+            };
+            let rev = if finders.rev.find(&seq).is_some() {
+                &pair.rev
+            } else if finders.rev_rc.find(&seq).is_some() {
+                &pair.rev_rc
+            } else {
+                return None;
+            };
+            Some(PrimerPair {
+                amplicon: pair.amplicon.clone(),
+                fwd: fwd.clone(),
+                rev: rev.clone(),
+            })
+        })
+    }
+
+    async fn find_partial_amplicon(
+        &'a self,
+        primerpairs: &'b [PossiblePrimers],
+        min_extension: usize,
+    ) -> AmpliconMatch {
+        let seq = canonical_bases(self.sequence());
+        let mut hits: Vec<PartialAmplicon> = primerpairs
+            .iter()
+            .filter_map(|pair| {
+                let finders = pair.finders();
+                let (fwd, start, end) = if let Some(idx) = finders.fwd.find(&seq) {
+                    (&pair.fwd, idx + pair.fwd.len(), seq.len())
+                } else if let Some(idx) = finders.fwd_rc.find(&seq) {
+                    (&pair.fwd_rc, 0, idx)
+                } else {
+                    return None;
+                };
+                (end.saturating_sub(start) >= min_extension).then(|| PartialAmplicon {
+                    amplicon: pair.amplicon.clone(),
+                    fwd: fwd.to_string(),
+                    start,
+                    end,
+                })
+            })
+            .unique_by(|hit| hit.fwd.clone())
+            .collect();
+
+        match (hits.len(), hits.pop()) {
+            (1, Some(hit)) => AmpliconMatch::Partial(hit),
+            (0, _) => AmpliconMatch::NoMatch,
+            _ => AmpliconMatch::MultiMatch,
+        }
+    }
+
+    async fn trim_to_amplicon(self, primers: PrimerPair) -> Result<Option<Self>> {
+        self.trim_to_amplicon_with_min_insert(primers, DEFAULT_MIN_INSERT_LEN)
+            .await
+    }
+
+    async fn trim_to_amplicon_with_min_insert(
+        self,
+        primers: PrimerPair,
+        min_insert_len: usize,
+    ) -> Result<Option<Self>> {
+        self.trim_to_matched_amplicon(primers, min_insert_len, &ExactMatcher)
+            .await
+    }
+
+    async fn trim_to_matched_amplicon<M: PrimerMatcher + ?Sized>(
+        mut self,
+        primers: PrimerPair,
+        min_insert_len: usize,
+        matcher: &M,
+    ) -> Result<Option<Self>> {
+        let Some(insert) = amplicon_bounds(self.sequence(), &primers, min_insert_len, matcher)
+        else {
+            return Ok(None);
+        };
+
+        *self.sequence_mut() = subseq(self.sequence(), insert.start, insert.end)?.to_vec();
+        *self.quality_scores_mut() =
+            subseq(self.quality_scores(), insert.start, insert.end)?.to_vec();
+
+        Ok(Some(self))
+    }
+
+    async fn trim_to_partial_amplicon(mut self, hit: PartialAmplicon) -> Result<Option<Self>> {
+        if hit.start >= hit.end {
+            return Ok(None);
+        }
+
+        *self.sequence_mut() = subseq(self.sequence(), hit.start, hit.end)?.to_vec();
+        *self.quality_scores_mut() = subseq(self.quality_scores(), hit.start, hit.end)?.to_vec();
+
+        Ok(Some(self))
+    }
+
+    async fn rejection_reason(
+        &'a self,
+        amplicon: &str,
+        filters: &'b Option<FilterSettings<'_, '_>>,
+    ) -> Option<RejectReason> {
+        let Some(filters) = filters else {
+            return None;
+        };
+
+        // amplicon-specific length bounds take precedence over the global bound
+        let max_len = filters
+            .amplicon_max_lens
+            .and_then(|lens| lens.get(amplicon))
+            .unwrap_or(filters.max_len);
+        if self.sequence().len() > *max_len {
+            return Some(RejectReason::LenFilter);
+        }
 
-                if &new_start >= new_end {
-                    return Ok(None);
+        let passes_index = match filters.index {
+            Some(index) => match (
+                index.frequency(self.sequence()),
+                index.count(self.sequence()),
+            ) {
+                (Some(freq), Some(count)) => {
+                    freq >= *filters.min_freq && count >= *filters.min_count
                 }
+                _ => false,
+            },
+            None => true,
+        };
+        (!passes_index).then_some(RejectReason::LowFreq)
+    }
+
+    async fn whether_to_write(
+        &'a self,
+        amplicon: &str,
+        filters: &'b Option<FilterSettings<'_, '_>>,
+    ) -> bool {
+        self.rejection_reason(amplicon, filters).await.is_none()
+    }
+}
+
+/// Options for compiling an [`AmpliconMatcher`], each of which falls back to the same default
+/// as the trimming loop when left unset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MatcherOptions {
+    /// How primers are found in each read
+    pub match_algorithm: MatchAlgorithm,
+
+    /// The most mismatches or edits allowed in each primer when matching is not exact, which
+    /// defaults to [`DEFAULT_MAX_PRIMER_ERRORS`]
+    pub max_primer_errors: Option<usize>,
+
+    /// The fewest bases that must separate a read's primers for it to be trimmed, which defaults
+    /// to [`DEFAULT_MIN_INSERT_LEN`]
+    pub min_insert_len: Option<usize>,
+
+    /// When set, primers read 5' to 3' must start within this many bases of the start of a
+    /// read, and reverse complemented primers must end within this many bases of its end,
+    /// rather than anywhere in the read
+    pub anchor_window: Option<usize>,
+
+    /// Whether the scheme's amplicons may overlap, and so whether every amplicon must be
+    /// searched for in each read
+    pub scheme_kind: SchemeKind,
+}
+
+/// The complete amplicon found in a read by an [`AmpliconMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmpliconHit {
+    /// The name of the matched amplicon
+    pub amplicon: String,
+
+    /// Where the forward primer, in whichever orientation it occurs, was found
+    pub fwd: PrimerSpan,
+
+    /// Where the reverse primer, in whichever orientation it occurs, was found
+    pub rev: PrimerSpan,
+}
+
+impl AmpliconHit {
+    /// The positions of the insert between the primers, which starts after whichever primer
+    /// comes first and is empty when the primers overlap, as in a primer dimer.
+    pub fn insert(&self) -> Range<usize> {
+        let (first, second) = if self.fwd.start <= self.rev.start {
+            (self.fwd, self.rev)
+        } else {
+            (self.rev, self.fwd)
+        };
+        let span = second.end.saturating_sub(first.start);
+        let insert_len = span.saturating_sub(first.len() + second.len());
+
+        first.end..first.end + insert_len
+    }
+
+    /// The positions of the whole amplicon, from the start of whichever primer comes first to
+    /// the end of the other.
+    pub fn span(&self) -> Range<usize> {
+        self.fwd.start.min(self.rev.start)..self.fwd.end.max(self.rev.end)
+    }
+}
+
+/// Choose as many amplicons as possible from `candidates` without any two overlapping, e.g.,
+/// to pick out the amplicons of a concatemer, returning them in order along the read. The
+/// choice is made greedily from left to right: the candidate that ends first is taken, then
+/// the one that ends first among those starting at or after its end, and so on, with ties going
+/// to the candidate that starts first.
+pub fn select_non_overlapping(mut candidates: Vec<AmpliconHit>) -> Vec<AmpliconHit> {
+    candidates.sort_by_key(|hit| (hit.span().end, hit.span().start));
+    let mut selected: Vec<AmpliconHit> = Vec::with_capacity(candidates.len());
+    for hit in candidates {
+        let clear = selected
+            .last()
+            .is_none_or(|last| hit.span().start >= last.span().end);
+        if clear {
+            selected.push(hit);
+        }
+    }
+    selected
+}
+
+/// The end of a read that a primer orientation is expected near.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Anchor {
+    /// Primers read 5' to 3', which start a read of either strand
+    Start,
+
+    /// Reverse complemented primers, which end a read of either strand
+    End,
+}
+
+/// One orientation of a primer, with its finder compiled for exact matching.
+#[derive(Debug)]
+struct CompiledPrimer {
+    /// The primer sequence in this orientation
+    seq: String,
+
+    /// Finds the primer sequence exactly
+    finder: Finder<'static>,
+
+    /// The end of the read the primer is anchored to
+    anchor: Anchor,
+}
+
+impl CompiledPrimer {
+    fn new(seq: &str, anchor: Anchor) -> Self {
+        CompiledPrimer {
+            seq: seq.to_string(),
+            finder: Finder::new(seq.as_bytes()).into_owned(),
+            anchor,
+        }
+    }
+}
+
+/// Every orientation of one amplicon's primers, in the order they are searched for.
+#[derive(Debug)]
+struct CompiledAmplicon {
+    /// The name of the amplicon
+    amplicon: String,
+
+    /// The forward primer and its reverse complement
+    fwd: [CompiledPrimer; 2],
+
+    /// The reverse primer and its reverse complement
+    rev: [CompiledPrimer; 2],
+
+    /// Whether the primers coincide, so that they are told apart by position
+    coincide: bool,
+}
+
+/// The primers of one amplicon as they were found in a read, if they were found at all.
+#[derive(Debug, Clone, Copy)]
+struct LocatedPrimers<'m> {
+    /// The name of the amplicon
+    amplicon: &'m str,
+
+    /// The orientation of the forward primer found and where it was found
+    fwd: Option<(&'m str, PrimerSpan)>,
+
+    /// The orientation of the reverse primer found and where it was found
+    rev: Option<(&'m str, PrimerSpan)>,
+}
+
+/// The shortest k-mer worth prefiltering reads with. Shorter k-mers turn up in nearly every
+/// read by chance, so checking them would only cost time.
+const MIN_PREFILTER_K: usize = 6;
+
+/// The longest k-mer used to prefilter reads.
+const MAX_PREFILTER_K: usize = 16;
+
+/// The k-mers of every primer in a scheme. A primer with `e` errors still has one of `e + 1`
+/// disjoint pieces intact, so as long as `k` is no longer than those pieces, a read without
+/// any of these k-mers cannot hold a primer and need not be searched.
+#[derive(Debug, Clone)]
+struct KmerPrefilter {
+    k: usize,
+    kmers: HashSet<Vec<u8>>,
+}
+
+impl KmerPrefilter {
+    /// Collect the k-mers of the provided primers, or return `None` if the primers are too short
+    /// for k-mers long enough to be worth checking.
+    fn new<'p>(primers: impl Iterator<Item = &'p [u8]> + Clone, max_errors: usize) -> Option<Self> {
+        let k = primers
+            .clone()
+            .map(|primer| primer.len() / (max_errors + 1))
+            .min()?
+            .min(MAX_PREFILTER_K);
+        if k < MIN_PREFILTER_K {
+            return None;
+        }
+        let kmers = primers
+            .flat_map(|primer| primer.windows(k).map(<[u8]>::to_vec))
+            .collect();
+
+        Some(KmerPrefilter { k, kmers })
+    }
+
+    /// Whether the sequence shares at least one k-mer with the primers.
+    fn admits(&self, seq: &[u8]) -> bool {
+        seq.windows(self.k).any(|kmer| self.kmers.contains(kmer))
+    }
+}
+
+/// A primer scheme compiled once for matching and trimming any number of reads, e.g., by
+/// embedders and language bindings. Everything that does not depend on the read, i.e., the
+/// primers in each orientation, their exact-match finders, a k-mer prefilter, and which end of
+/// the read each primer is anchored to, is prepared up front, and the matcher is immutable
+/// afterward, so it can be shared freely across threads.
+pub struct AmpliconMatcher {
+    /// The primers of each amplicon in the scheme
+    amplicons: Vec<CompiledAmplicon>,
+
+    /// Finds primers when matching is not exact
+    matcher: Box<dyn PrimerMatcher>,
+
+    /// The options the matcher was compiled with
+    options: MatcherOptions,
+
+    /// The most mismatches or edits allowed in each primer
+    max_errors: usize,
+
+    /// Skips reads that cannot hold any primer, when the primers are long enough to tell
+    prefilter: Option<KmerPrefilter>,
+}
+
+impl AmpliconMatcher {
+    /// Compile the primers of a scheme for matching reads with the provided options.
+    pub fn from_scheme(scheme: &AmpliconScheme, options: MatcherOptions) -> Self {
+        let max_errors = match options.match_algorithm {
+            MatchAlgorithm::Exact => 0,
+            _ => options
+                .max_primer_errors
+                .unwrap_or(DEFAULT_MAX_PRIMER_ERRORS),
+        };
+        let amplicons: Vec<CompiledAmplicon> = scheme
+            .scheme
+            .iter()
+            .map(|pair| CompiledAmplicon {
+                amplicon: pair.amplicon.clone(),
+                fwd: [
+                    CompiledPrimer::new(&pair.fwd, Anchor::Start),
+                    CompiledPrimer::new(&pair.fwd_rc, Anchor::End),
+                ],
+                rev: [
+                    CompiledPrimer::new(&pair.rev, Anchor::Start),
+                    CompiledPrimer::new(&pair.rev_rc, Anchor::End),
+                ],
+                coincide: pair.primers_coincide(),
+            })
+            .collect();
+        let prefilter = KmerPrefilter::new(
+            amplicons
+                .iter()
+                .flat_map(|amplicon| amplicon.fwd.iter().chain(&amplicon.rev))
+                .map(|primer| primer.seq.as_bytes()),
+            max_errors,
+        );
+
+        AmpliconMatcher {
+            amplicons,
+            matcher: options.match_algorithm.matcher(max_errors),
+            options,
+            max_errors,
+            prefilter,
+        }
+    }
 
-                *self.sequence_mut() = self.sequence()[new_start..*new_end].to_vec();
-                *self.quality_scores_mut() = self.quality_scores()[new_start..*new_end].to_vec();
+    /// The matcher used to find primers, which also finds them when trimming records classified
+    /// by [`AmpliconMatcher::classify`].
+    pub fn primer_matcher(&self) -> &dyn PrimerMatcher {
+        self.matcher.as_ref()
+    }
+
+    /// Find one orientation of a primer in a read, within its anchoring window if there is one.
+    fn find(&self, seq: &[u8], primer: &CompiledPrimer) -> Option<PrimerSpan> {
+        // an anchored primer may reach past its window by as many bases as it has edits
+        let (offset, end) = match (self.options.anchor_window, primer.anchor) {
+            (None, _) => (0, seq.len()),
+            (Some(window), Anchor::Start) => (
+                0,
+                seq.len().min(window + primer.seq.len() + self.max_errors),
+            ),
+            (Some(window), Anchor::End) => (
+                seq.len()
+                    .saturating_sub(window + primer.seq.len() + self.max_errors),
+                seq.len(),
+            ),
+        };
+        let span = self.find_anywhere(&seq[offset..end], primer)?;
+
+        Some(PrimerSpan {
+            start: span.start + offset,
+            end: span.end + offset,
+        })
+    }
 
-                Ok(Some(self))
+    /// Find one orientation of a primer anywhere in a sequence, ignoring its anchoring window.
+    fn find_anywhere(&self, seq: &[u8], primer: &CompiledPrimer) -> Option<PrimerSpan> {
+        match self.options.match_algorithm {
+            MatchAlgorithm::Exact => primer.finder.find(seq).map(|start| PrimerSpan {
+                start,
+                end: start + primer.seq.len(),
+            }),
+            _ => self.matcher.find(seq, primer.seq.as_bytes()),
+        }
+    }
+
+    /// Find the earliest occurrence of either orientation of a primer in `seq` at or after
+    /// `from`.
+    fn earliest(
+        &self,
+        seq: &[u8],
+        primers: &[CompiledPrimer; 2],
+        from: usize,
+    ) -> Option<PrimerSpan> {
+        primers
+            .iter()
+            .filter_map(|primer| self.find_anywhere(&seq[from..], primer))
+            .min_by_key(|span| (span.start, span.end))
+            .map(|span| PrimerSpan {
+                start: span.start + from,
+                end: span.end + from,
+            })
+    }
+
+    /// Find the first orientation of a primer that occurs in `seq`, along with where it occurs.
+    fn first_match<'m>(
+        &self,
+        seq: &[u8],
+        primers: &'m [CompiledPrimer; 2],
+    ) -> Option<(&'m str, PrimerSpan)> {
+        primers.iter().find_map(|primer| {
+            self.find(seq, primer)
+                .map(|span| (primer.seq.as_str(), span))
+        })
+    }
+
+    /// Find each amplicon's primers in a read, taking the first orientation found for each.
+    fn locate(&self, seq: &[u8]) -> Vec<LocatedPrimers<'_>> {
+        let seq = canonical_bases(seq);
+        let seq = seq.as_ref();
+        if self
+            .prefilter
+            .as_ref()
+            .is_some_and(|prefilter| !prefilter.admits(seq))
+        {
+            return Vec::new();
+        }
+        // amplicons of a non-overlapping scheme can't share a read, so the first complete one
+        // settles it
+        let mut located = Vec::with_capacity(self.amplicons.len());
+        for amplicon in &self.amplicons {
+            let fwd = self.first_match(seq, &amplicon.fwd);
+
+            // when an amplicon's primers coincide, a reverse primer with the same sequence as
+            // the forward primer found is its last occurrence after it
+            let rev = match fwd {
+                Some((fwd_seq, fwd_span)) if amplicon.coincide => {
+                    amplicon.rev.iter().find_map(|primer| {
+                        if primer.seq == fwd_seq {
+                            last_match_after(
+                                seq,
+                                primer.seq.as_bytes(),
+                                &fwd_span,
+                                self.matcher.as_ref(),
+                            )
+                        } else {
+                            self.find(seq, primer)
+                        }
+                        .map(|span| (primer.seq.as_str(), span))
+                    })
+                }
+                _ => self.first_match(seq, &amplicon.rev),
+            };
+            let primers = LocatedPrimers {
+                amplicon: &amplicon.amplicon,
+                fwd,
+                rev,
+            };
+            let complete = primers.fwd.is_some() && primers.rev.is_some();
+            located.push(primers);
+            if complete && self.options.scheme_kind == SchemeKind::NonOverlapping {
+                break;
             }
-            _ => Ok(None),
         }
+        located
     }
 
-    async fn whether_to_write(&'a self, filters: &'b Option<FilterSettings<'_, '_>>) -> bool {
-        if let Some(filters) = filters {
-            let seq = self.sequence().to_vec();
-            let seq_len = seq.len();
-            if let Some(freq) = filters.unique_seqs.get(&seq) {
-                freq >= filters.min_freq && &seq_len <= filters.max_len
-            } else {
-                false
+    /// Sort the primers located in a read into an [`AmpliconMatch`].
+    fn classify_located(located: &[LocatedPrimers]) -> AmpliconMatch {
+        let hits: Vec<(&str, Option<&str>, Option<&str>)> = located
+            .iter()
+            .map(|primers| {
+                (
+                    primers.amplicon,
+                    primers.fwd.map(|(primer, _)| primer),
+                    primers.rev.map(|(primer, _)| primer),
+                )
+            })
+            .collect();
+
+        classify_hits(&hits)
+    }
+
+    /// Classify a read by the primers it contains, as in [`FindAmplicons::classify_amplicon`].
+    pub fn classify(&self, seq: &[u8]) -> AmpliconMatch {
+        Self::classify_located(&self.locate(seq))
+    }
+
+    /// Return where the primers of the one amplicon found in a read lie, if the read holds
+    /// exactly one complete amplicon.
+    pub fn match_read(&self, seq: &[u8]) -> Option<AmpliconHit> {
+        self.classify_hit(seq).1
+    }
+
+    /// Classify a read as in [`AmpliconMatcher::classify`], along with where the primers of
+    /// its amplicon lie when it holds exactly one complete amplicon, as in
+    /// [`AmpliconMatcher::match_read`], so that both come from a single search of the read.
+    pub fn classify_hit(&self, seq: &[u8]) -> (AmpliconMatch, Option<AmpliconHit>) {
+        let located = self.locate(seq);
+        let classified = Self::classify_located(&located);
+        let hit = match &classified {
+            AmpliconMatch::Single(pair) => {
+                located
+                    .into_iter()
+                    .find_map(|primers| match (primers.fwd, primers.rev) {
+                        (Some((_, fwd)), Some((_, rev))) if primers.amplicon == pair.amplicon => {
+                            Some(AmpliconHit {
+                                amplicon: pair.amplicon.clone(),
+                                fwd,
+                                rev,
+                            })
+                        }
+                        _ => None,
+                    })
             }
-        } else {
-            true
+            _ => None,
+        };
+
+        (classified, hit)
+    }
+
+    /// The next complete amplicon in a read at or after `from`, pairing the earliest occurrence
+    /// of either orientation of its forward primer with the earliest of its reverse primer.
+    fn next_bracket(
+        &self,
+        seq: &[u8],
+        amplicon: &CompiledAmplicon,
+        from: usize,
+    ) -> Option<AmpliconHit> {
+        let fwd = self.earliest(seq, &amplicon.fwd, from)?;
+
+        // coinciding primers are told apart by position, so the reverse primer is the next
+        // occurrence after the forward one
+        let rev_from = if amplicon.coincide { fwd.end } else { from };
+        let rev = self.earliest(seq, &amplicon.rev, rev_from)?;
+
+        Some(AmpliconHit {
+            amplicon: amplicon.amplicon.clone(),
+            fwd,
+            rev,
+        })
+    }
+
+    /// Return every complete amplicon in a read that doesn't overlap another, in order from its
+    /// start, e.g., to count or split the amplicons joined end to end in a nanopore concatemer.
+    /// Each amplicon's primers are paired up along the read, and the candidates from every
+    /// amplicon are narrowed down with [`select_non_overlapping`]. Anchoring windows are
+    /// ignored, since the amplicons inside a concatemer lie away from its ends, and candidates
+    /// whose insert is shorter than the minimum insert length, e.g. primer dimers, are left out.
+    pub fn find_all_amplicons(&self, seq: &[u8]) -> Vec<AmpliconHit> {
+        let seq = canonical_bases(seq);
+        let seq = seq.as_ref();
+        if self
+            .prefilter
+            .as_ref()
+            .is_some_and(|prefilter| !prefilter.admits(seq))
+        {
+            return Vec::new();
         }
+        let min_insert_len = self
+            .options
+            .min_insert_len
+            .unwrap_or(DEFAULT_MIN_INSERT_LEN);
+
+        let mut candidates = Vec::new();
+        for amplicon in &self.amplicons {
+            let mut from = 0;
+            while let Some(hit) = self.next_bracket(seq, amplicon, from) {
+                let end = hit.span().end;
+                if hit.insert().len() >= min_insert_len.max(1) {
+                    candidates.push(hit);
+                }
+                if end <= from {
+                    break;
+                }
+                from = end;
+            }
+        }
+
+        select_non_overlapping(candidates)
+    }
+
+    /// Split a record holding several complete amplicons, as found by
+    /// [`AmpliconMatcher::find_all_amplicons`], into one untrimmed record per amplicon, each
+    /// spanning just that amplicon's primers and what lies between them, with its quality
+    /// scores sliced to match. The pieces are named after the record with their place in it,
+    /// e.g. `read1_1` and `read1_2`, and keep its description. Records holding fewer than two
+    /// amplicons are returned as they are.
+    pub fn split_concatemer(&self, record: FastqRecord) -> Vec<FastqRecord> {
+        let hits = self.find_all_amplicons(record.sequence());
+        if hits.len() < 2 {
+            return vec![record];
+        }
+
+        hits.iter()
+            .enumerate()
+            .filter_map(|(i, hit)| {
+                let mut piece = trim_record_to(record.clone(), hit.span())?;
+                piece
+                    .name_mut()
+                    .extend_from_slice(format!("_{}", i + 1).as_bytes());
+                Some(piece)
+            })
+            .collect()
     }
+
+    /// Trim a record down to the span from the insert of the first of `hits` to the insert of
+    /// the last, as found in it by [`AmpliconMatcher::find_all_amplicons`], so that a
+    /// concatemer loses its outermost primers but keeps the amplicons joined within it. Records
+    /// without any hits are dropped.
+    pub fn trim_to_hits(&self, record: FastqRecord, hits: &[AmpliconHit]) -> Option<FastqRecord> {
+        let (first, last) = (hits.first()?, hits.last()?);
+        trim_record_to(record, first.insert().start..last.insert().end)
+    }
+
+    /// Trim a record down to the insert between the primers of the one amplicon it holds.
+    /// Records without exactly one complete amplicon, or whose insert is shorter than the
+    /// minimum insert length, are dropped. Primers are matched regardless of case, but the
+    /// insert is cut from the record's own bases, so soft-masked lowercase bases stay lowercase.
+    pub fn trim(&self, record: FastqRecord) -> Option<FastqRecord> {
+        let hit = self.match_read(record.sequence())?;
+        self.trim_to_hit(record, &hit)
+    }
+
+    /// Trim a record down to the insert between the primers of `hit`, as found in it by
+    /// [`AmpliconMatcher::match_read`]. Records whose insert is shorter than the minimum insert
+    /// length are dropped.
+    pub fn trim_to_hit(&self, record: FastqRecord, hit: &AmpliconHit) -> Option<FastqRecord> {
+        let insert = hit.insert();
+        let min_insert_len = self
+            .options
+            .min_insert_len
+            .unwrap_or(DEFAULT_MIN_INSERT_LEN);
+        if insert.len() < min_insert_len.max(1) {
+            return None;
+        }
+
+        trim_record_to(record, insert)
+    }
+}
+
+/// Trim a record down to the bases in `range`, or drop it if they are not all in the record.
+fn trim_record_to(mut record: FastqRecord, range: Range<usize>) -> Option<FastqRecord> {
+    let sequence = subseq(record.sequence(), range.start, range.end)
+        .ok()?
+        .to_vec();
+    let quality_scores = subseq(record.quality_scores(), range.start, range.end)
+        .ok()?
+        .to_vec();
+    *record.sequence_mut() = sequence;
+    *record.quality_scores_mut() = quality_scores;
+
+    Some(record)
+}
+
+/// The number of independently locked shards a [`MatchCache`] is split into, so that workers
+/// looking up different reads rarely wait on one another.
+const MATCH_CACHE_SHARDS: usize = 16;
+
+/// A read's classification, along with where its amplicon's primers lie when it holds exactly
+/// one, as returned by [`AmpliconMatcher::classify_hit`].
+type ClassifiedHit = (AmpliconMatch, Option<AmpliconHit>);
+
+/// One shard of a [`MatchCache`].
+#[derive(Debug, Default)]
+struct MatchCacheShard {
+    /// The classification of each cached read sequence and where its amplicon's primers lie,
+    /// along with when it was last used
+    entries: HashMap<Vec<u8>, (ClassifiedHit, u64)>,
+
+    /// Counts up with every lookup, giving the order in which entries were last used
+    clock: u64,
+}
+
+/// A bounded cache of read classifications from an [`AmpliconMatcher`], keyed by the read
+/// sequence, so that byte-identical reads, which are common in deep amplicon data, are only
+/// searched for primers once. Entries are spread across separately locked shards by the hash
+/// of their sequence, and when a shard fills up, its least recently used half is evicted.
+/// Lookups compare the whole sequence, so cached results are always identical to matching
+/// afresh. A cache must only be used with one matcher, since it does not know which matcher
+/// produced its entries.
+#[derive(Debug)]
+pub struct MatchCache {
+    /// The cached classifications, split into shards by sequence hash
+    shards: Vec<Mutex<MatchCacheShard>>,
+
+    /// The most entries each shard holds before evicting
+    shard_capacity: usize,
+
+    /// The number of lookups answered from the cache
+    hits: AtomicU64,
+
+    /// The number of lookups that had to match the read
+    misses: AtomicU64,
+}
+
+impl MatchCache {
+    /// Create a cache holding the classifications of about `capacity` distinct read sequences.
+    pub fn new(capacity: usize) -> Self {
+        MatchCache {
+            shards: (0..MATCH_CACHE_SHARDS)
+                .map(|_| Mutex::new(MatchCacheShard::default()))
+                .collect(),
+            shard_capacity: capacity.div_ceil(MATCH_CACHE_SHARDS).max(1),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Classify a read with `matcher`, as in [`AmpliconMatcher::classify`], reusing the cached
+    /// classification of an identical sequence if there is one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the cache was poisoned by a panic.
+    pub fn classify(&self, matcher: &AmpliconMatcher, seq: &[u8]) -> Result<AmpliconMatch> {
+        self.classify_hit(matcher, seq)
+            .map(|(classified, _)| classified)
+    }
+
+    /// Classify a read with `matcher` and find where its amplicon's primers lie, as in
+    /// [`AmpliconMatcher::classify_hit`], reusing the cached results for an identical sequence
+    /// if there are any.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the cache was poisoned by a panic.
+    pub fn classify_hit(
+        &self,
+        matcher: &AmpliconMatcher,
+        seq: &[u8],
+    ) -> Result<(AmpliconMatch, Option<AmpliconHit>)> {
+        let mut hasher = DefaultHasher::new();
+        seq.hash(&mut hasher);
+        let shard = &self.shards[hasher.finish() as usize % self.shards.len()];
+
+        // look the read up and release the shard before matching, so that other workers are
+        // not held up by the primer search
+        let cached = {
+            let mut shard = shard
+                .lock()
+                .map_err(|_| eyre!("The match cache was poisoned by a panic."))?;
+            shard.clock += 1;
+            let now = shard.clock;
+            shard.entries.get_mut(seq).map(|(classified, last_used)| {
+                *last_used = now;
+                classified.clone()
+            })
+        };
+        if let Some(classified) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(classified);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let classified = matcher.classify_hit(seq);
+        let mut shard = shard
+            .lock()
+            .map_err(|_| eyre!("The match cache was poisoned by a panic."))?;
+        if shard.entries.len() >= self.shard_capacity {
+            // every lookup and insertion ticks the clock and touches at most one entry, so no
+            // two entries share a last use and exactly the most recent half are kept
+            let keep = shard.entries.len() / 2;
+            let mut last_used: Vec<u64> = shard.entries.values().map(|(_, used)| *used).collect();
+            let nth = last_used.len() - keep;
+            let cutoff = match keep {
+                0 => u64::MAX,
+                _ => *last_used.select_nth_unstable(nth).1,
+            };
+            shard.entries.retain(|_, (_, used)| *used >= cutoff);
+        }
+        shard.clock += 1;
+        let now = shard.clock;
+        shard
+            .entries
+            .insert(seq.to_vec(), (classified.clone(), now));
+
+        Ok(classified)
+    }
+
+    /// The number of lookups answered from the cache so far.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups that had to match the read so far.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Settings for merging overlapping paired-end reads into single reads.
+#[derive(Debug, Clone)]
+pub struct MergeSettings {
+    /// The fewest bases the mates must overlap by to be merged
+    pub min_overlap: usize,
+
+    /// The largest fraction of overlapping bases allowed to disagree between the mates
+    pub max_mismatch_rate: f64,
+
+    /// Whether to write mates that could not be merged as separate reads rather than dropping
+    /// them
+    pub keep_unmerged: bool,
+
+    /// What to do with pairs where a mate has a different number of bases and quality scores
+    pub on_error: OnError,
+}
+
+impl Default for MergeSettings {
+    fn default() -> Self {
+        MergeSettings {
+            min_overlap: 10,
+            max_mismatch_rate: 0.1,
+            keep_unmerged: false,
+            on_error: OnError::Stop,
+        }
+    }
+}
+
+/// A read merged from a pair of overlapping mates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedPair {
+    /// The merged read, named after the first mate
+    pub record: FastqRecord,
+
+    /// The number of overlapping bases
+    pub overlap: usize,
+
+    /// The number of overlapping bases where the mates disagreed and the higher-quality base
+    /// was kept
+    pub conflicts: usize,
+}
+
+/// Merge a pair of mates that read the same molecule from opposite ends into one read spanning
+/// both. The second mate is reverse complemented and the longest overlap between the end of
+/// the first mate and the start of the second is found, allowing a fraction of mismatches.
+/// Where the mates disagree in the overlap, the base with the higher quality score is kept.
+/// Returns `None` if the mates do not overlap enough to be merged, or if a mate's quality
+/// scores don't cover its bases.
+pub fn merge_pair(
+    r1: &FastqRecord,
+    r2: &FastqRecord,
+    settings: &MergeSettings,
+) -> Option<MergedPair> {
+    let (seq1, qual1) = (r1.sequence(), r1.quality_scores());
+    let seq2 = reverse_complement_bytes(r2.sequence());
+    let qual2: Vec<u8> = r2.quality_scores().iter().rev().copied().collect();
+
+    let max_overlap = seq1.len().min(seq2.len());
+    let overlap = (settings.min_overlap.max(1)..=max_overlap)
+        .rev()
+        .find(|overlap| {
+            let offset = seq1.len() - overlap;
+            let mismatches = seq1[offset..]
+                .iter()
+                .zip(&seq2[..*overlap])
+                .filter(|(base1, base2)| base1 != base2)
+                .count();
+            mismatches as f64 <= (*overlap as f64) * settings.max_mismatch_rate
+        })?;
+
+    let offset = seq1.len() - overlap;
+    let mut sequence = subseq(seq1, 0, offset).ok()?.to_vec();
+    let mut quality_scores = subseq(qual1, 0, offset).ok()?.to_vec();
+    let overlap1 = subseq(seq1, offset, seq1.len())
+        .ok()?
+        .iter()
+        .zip(subseq(qual1, offset, seq1.len()).ok()?);
+    let overlap2 = subseq(&seq2, 0, overlap)
+        .ok()?
+        .iter()
+        .zip(subseq(&qual2, 0, overlap).ok()?);
+    let mut conflicts = 0;
+    for ((&base1, &score1), (&base2, &score2)) in overlap1.zip(overlap2) {
+        if base1 != base2 {
+            conflicts += 1;
+        }
+        let (base, score) = if score2 > score1 {
+            (base2, score2)
+        } else {
+            (base1, score1)
+        };
+        sequence.push(base);
+        quality_scores.push(score);
+    }
+    sequence.extend_from_slice(subseq(&seq2, overlap, seq2.len()).ok()?);
+    quality_scores.extend_from_slice(subseq(&qual2, overlap, seq2.len()).ok()?);
+
+    let record = FastqRecord::new(r1.definition().clone(), sequence, quality_scores);
+    Some(MergedPair {
+        record,
+        overlap,
+        conflicts,
+    })
 }
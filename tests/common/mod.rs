@@ -0,0 +1,83 @@
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use amplicon_tk::primers::{AmpliconScheme, PossiblePrimers};
+use color_eyre::eyre::Result;
+use noodles::fastq::{record::Definition, Record as FastqRecord};
+
+/// Reverse complement an uppercase DNA sequence.
+pub fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'T' => b'A',
+            b'G' => b'C',
+            b'C' => b'G',
+            other => *other,
+        })
+        .collect()
+}
+
+/// Build the primers for an amplicon from its forward and reverse primer sequences.
+pub fn primers(amplicon: &str, fwd: &str, rev: &str) -> PossiblePrimers {
+    PossiblePrimers::new(
+        amplicon.to_string(),
+        fwd.to_string(),
+        String::from_utf8(revcomp(fwd.as_bytes())).unwrap(),
+        rev.to_string(),
+        String::from_utf8(revcomp(rev.as_bytes())).unwrap(),
+    )
+}
+
+/// A two-amplicon scheme matching the reads in [`FASTQ`].
+pub fn test_scheme() -> AmpliconScheme {
+    AmpliconScheme {
+        scheme: vec![
+            primers("amplicon_1", "TGGAGGAT", "CCATAGTA"),
+            primers("amplicon_2", "CCCCAAAA", "AAAAACGT"),
+        ],
+    }
+}
+
+/// Build a forward-oriented read containing `insert` between the primers of `pair`.
+pub fn amplicon_read(name: &str, pair: &PossiblePrimers, insert: &str) -> FastqRecord {
+    let seq = format!("GGGG{}{}{}CCCC", pair.fwd, insert, pair.rev_rc);
+    let qual = "I".repeat(seq.len());
+    FastqRecord::new(Definition::new(name, ""), seq, qual)
+}
+
+/// Serialize records into FASTQ text.
+pub fn to_fastq(records: &[FastqRecord]) -> String {
+    records
+        .iter()
+        .map(|record| {
+            format!(
+                "@{}\n{}\n+\n{}\n",
+                String::from_utf8_lossy(record.name()),
+                String::from_utf8_lossy(record.sequence()),
+                String::from_utf8_lossy(record.quality_scores())
+            )
+        })
+        .collect()
+}
+
+/// Read all records from an uncompressed FASTQ file.
+pub fn read_output(path: &Path) -> Result<Vec<FastqRecord>> {
+    let mut reader = std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .map(noodles::fastq::Reader::new)?;
+    let records = reader.records().collect::<std::io::Result<Vec<_>>>()?;
+    Ok(records)
+}
+
+/// Open an uncompressed FASTQ file with the synchronous reader used for indexing.
+pub fn sync_reader(
+    path: &Path,
+) -> Result<noodles::fastq::Reader<std::io::BufReader<std::fs::File>>> {
+    let reader = std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .map(noodles::fastq::Reader::new)?;
+    Ok(reader)
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use amplicon_tk::{
+    cli::{log_directive, parse_interval, parse_length_bins, parse_memory_size, Cli, Commands},
+    io::{list_formats, InputType, OutputType},
+};
+use clap::{CommandFactory, Parser};
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+
+#[test]
+fn test_verbosity_maps_to_log_level() {
+    let directive = |verbose: u8, quiet: u8, rust_log: Option<&str>| {
+        log_directive(&Verbosity::<InfoLevel>::new(verbose, quiet), rust_log)
+    };
+
+    // info by default, with each flag stepping one level
+    assert_eq!(directive(0, 0, None), "info");
+    assert_eq!(directive(1, 0, None), "debug");
+    assert_eq!(directive(2, 0, None), "trace");
+    assert_eq!(directive(0, 1, None), "warn");
+    assert_eq!(directive(0, 2, None), "error");
+    assert_eq!(directive(0, 3, None), "off");
+
+    // RUST_LOG is honored unless a verbosity flag is given explicitly
+    assert_eq!(
+        directive(0, 0, Some("amplicon_tk=trace")),
+        "amplicon_tk=trace"
+    );
+    assert_eq!(directive(0, 1, Some("amplicon_tk=trace")), "warn");
+}
+
+#[test]
+fn test_parse_interval() {
+    assert_eq!(parse_interval("5s"), Ok(Duration::from_secs(5)));
+    assert_eq!(parse_interval("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_interval("2m"), Ok(Duration::from_secs(120)));
+    assert_eq!(parse_interval("10"), Ok(Duration::from_secs(10)));
+    assert!(parse_interval("0s").is_err());
+    assert!(parse_interval("fast").is_err());
+}
+
+#[test]
+fn test_parse_memory_size() {
+    assert_eq!(parse_memory_size("512"), Ok(512));
+    assert_eq!(parse_memory_size("64K"), Ok(64 * 1024));
+    assert_eq!(parse_memory_size("512M"), Ok(512 * 1024 * 1024));
+    assert_eq!(parse_memory_size("2gb"), Ok(2 * 1024 * 1024 * 1024));
+    assert!(parse_memory_size("0M").is_err());
+    assert!(parse_memory_size("lots").is_err());
+}
+
+#[test]
+fn test_parse_length_bins() {
+    let bins = parse_length_bins("200, 400,600").expect("valid boundaries");
+    assert_eq!(bins.labels(), ["200-400", "400-600", "other"]);
+    assert_eq!(bins.bin(199), 2);
+    assert_eq!(bins.bin(200), 0);
+    assert_eq!(bins.bin(400), 1);
+    assert_eq!(bins.bin(600), 2);
+    assert!(parse_length_bins("200").is_err());
+    assert!(parse_length_bins("400,200").is_err());
+    assert!(parse_length_bins("200,long").is_err());
+}
+
+#[test]
+fn test_cli_definition_is_valid() {
+    Cli::command().debug_assert();
+}
+
+#[test]
+fn test_list_formats_covers_every_format() {
+    let cli = Cli::try_parse_from(["amplicon-tk", "list-formats"]).expect("valid subcommand");
+    assert!(matches!(cli.command, Some(Commands::ListFormats)));
+
+    let listing = list_formats();
+    let (inputs, outputs) = listing
+        .split_once("Output formats:")
+        .expect("an output section");
+    let input_names: Vec<&str> = InputType::all().iter().map(InputType::name).collect();
+    assert_eq!(input_names, ["fastq.gz", "fastq", "bam"]);
+    for format in InputType::all() {
+        assert!(inputs.contains(format.name()));
+        for ext in format.detected_extensions() {
+            assert!(inputs.contains(&format!(".{ext}")));
+        }
+    }
+    for format in OutputType::all() {
+        assert!(outputs.contains(format.name()));
+        assert!(outputs.contains(format.extension()));
+    }
+    assert_eq!(
+        listing.lines().count(),
+        2 + InputType::all().len() + OutputType::all().len()
+    );
+}
+
+#[test]
+fn test_primer_table_orientation_flag_is_independent_of_rev_as_appears() {
+    let cli = Cli::try_parse_from([
+        "amplicon-tk",
+        "trim",
+        "-i",
+        "reads.fastq",
+        "--primer-table",
+        "pairs.tsv",
+        "--primer-fasta",
+        "primers.fasta",
+        "--reverse-complement-primers-in-table",
+    ])
+    .expect("valid arguments");
+    let Some(Commands::Trim {
+        rev_as_appears,
+        reverse_complement_primers_in_table,
+        ..
+    }) = cli.command
+    else {
+        panic!("expected the trim subcommand");
+    };
+    assert!(reverse_complement_primers_in_table);
+    assert!(!rev_as_appears);
+}
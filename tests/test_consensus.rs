@@ -0,0 +1,395 @@
+mod common;
+
+use amplicon_tk::{
+    consensus::{
+        align_to_reference, align_to_reference_weighted, assemble_genome, trim_to_reference,
+        ConsensusAccumulator, PlacedConsensus, DEFAULT_MIN_DELETION_FREQ, DELETION, REFERENCE_PAD,
+    },
+    primers::InsertRegion,
+    reads::{consensus_records, ConsensusReference, ConsensusSettings},
+    record::QualityOffset,
+};
+use color_eyre::eyre::Result;
+use common::{amplicon_read, read_output, test_scheme};
+
+#[test]
+fn test_trim_to_reference_drops_insertions() {
+    let reference = b"ACGTACGTTTGCAGGCATCAGT";
+
+    // a consensus identical to the reference is left alone
+    assert_eq!(trim_to_reference(reference, reference), reference.to_vec());
+
+    // an inserted base is dropped, while a substitution is kept at its reference position
+    let consensus = b"ACGTACGTTTGGCAGGCATGAGT";
+    let trimmed = trim_to_reference(consensus, reference);
+    assert_eq!(trimmed.len(), reference.len());
+    assert_eq!(trimmed, b"ACGTACGTTTGCAGGCATGAGT".to_vec());
+}
+
+#[test]
+fn test_trim_to_reference_pads_missing_bases() {
+    let reference = b"ACGTACGTTTGCAGGCATCAGT";
+
+    // a deleted base and an end the consensus never reached are both padded, with the
+    // deletion placed at the start of the homopolymer it falls in
+    let consensus = b"ACGTACGTTGCAGGCATC";
+    let trimmed = trim_to_reference(consensus, reference);
+    assert_eq!(trimmed.len(), reference.len());
+    assert_eq!(trimmed, b"ACGTACGNTTGCAGGCATCNNN".to_vec());
+    assert_eq!(
+        trimmed
+            .iter()
+            .filter(|base| **base == REFERENCE_PAD)
+            .count(),
+        4
+    );
+}
+
+#[test]
+fn test_overlapping_amplicons_assemble_into_one_consensus() {
+    let reference = b"ACGTACGTTTGCAGGCATCAGTCCATGA";
+
+    // two tiled amplicons overlap across positions 10 to 18 and disagree at position 14
+    let first = PlacedConsensus {
+        start: 0,
+        sequence: b"ACGTACGTTTGCAGTCATC".to_vec(),
+        depth: 10,
+    };
+    let second = PlacedConsensus {
+        start: 10,
+        sequence: trim_to_reference(b"GCAGGCATCAGTCCATGA", &reference[10..]),
+        depth: 50,
+    };
+
+    // the deeper amplicon wins the overlap, whichever order the amplicons come in
+    let genome = assemble_genome(reference.len(), &[first.clone(), second.clone()]);
+    assert_eq!(genome, reference.to_vec());
+    assert_eq!(
+        assemble_genome(reference.len(), &[second, first.clone()]),
+        genome
+    );
+
+    // positions no amplicon covers are padded
+    let genome = assemble_genome(reference.len(), &[first]);
+    assert_eq!(&genome[..19], b"ACGTACGTTTGCAGTCATC".as_slice());
+    assert!(genome[19..].iter().all(|base| *base == REFERENCE_PAD));
+}
+
+#[test]
+fn test_consistent_deletion_shortens_consensus() {
+    let reference = b"ACGTACGTTTGCAGGCATCAGTCCATGA";
+    let without = |positions: &[usize]| -> Vec<u8> {
+        reference
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| !positions.contains(position))
+            .map(|(_, base)| *base)
+            .collect()
+    };
+
+    // every read lacks the A at position 16, one also lacks the A at position 4, and one only
+    // covers the reference from position 5 onward
+    let reads = [
+        without(&[16]),
+        without(&[16]),
+        without(&[4, 16]),
+        without(&[16])[5..].to_vec(),
+    ];
+    let aligned: Vec<Vec<u8>> = reads
+        .iter()
+        .map(|read| align_to_reference(read, reference))
+        .collect();
+    assert_eq!(aligned[0][16], DELETION);
+    assert_eq!(aligned[2][4], DELETION);
+    assert!(aligned[3][..5].iter().all(|base| *base == REFERENCE_PAD));
+
+    let mut accumulator = ConsensusAccumulator::new(reference.len());
+    for read in &aligned {
+        accumulator.add(read);
+    }
+
+    // the shared deletion is left out, while the minority one is outvoted
+    let consensus = accumulator.consensus(DEFAULT_MIN_DELETION_FREQ);
+    assert_eq!(consensus.len(), reference.len() - 1);
+    assert_eq!(consensus, without(&[16]));
+}
+
+#[test]
+fn test_high_quality_minority_outweighs_low_quality_majority() {
+    let reference = b"ACGTACGTTTGCAGGCATCAGT";
+
+    // two reads call a C at position 10 with a Phred score of 2, while one calls the reference
+    // G there with a score of 40
+    let mut miscalled = reference.to_vec();
+    miscalled[10] = b'C';
+    let mut low_quality = vec![40; reference.len()];
+    low_quality[10] = 2;
+    let high_quality = vec![40; reference.len()];
+    let reads = [
+        (miscalled.as_slice(), &low_quality),
+        (miscalled.as_slice(), &low_quality),
+        (reference.as_slice(), &high_quality),
+    ];
+
+    // counted equally, the miscall wins
+    let mut unweighted = ConsensusAccumulator::new(reference.len());
+    for (read, _) in &reads {
+        unweighted.add(&align_to_reference(read, reference));
+    }
+    assert_eq!(unweighted.consensus(DEFAULT_MIN_DELETION_FREQ), miscalled);
+
+    // weighted by quality, the single confident call wins
+    let mut weighted = ConsensusAccumulator::new(reference.len());
+    for (read, quality) in &reads {
+        let (aligned, weights) = align_to_reference_weighted(read, quality, reference);
+        weighted.add_weighted(&aligned, &weights);
+    }
+    assert_eq!(
+        weighted.consensus(DEFAULT_MIN_DELETION_FREQ),
+        reference.to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_consensus_reads_dir_matches_consensus_depths() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // a minority substitution in the first amplicon and a read that matches nothing
+    let mut records = vec![
+        amplicon_read("a1", amp1, "GATCGATCGATC"),
+        amplicon_read("a2", amp1, "GATCGATCGATC"),
+        amplicon_read("a3", amp1, "GATCGTTCGATC"),
+        amplicon_read("b1", amp2, "TTGACCATTG"),
+        amplicon_read("b2", amp2, "TTGACCATTG"),
+    ];
+    let mut unmatched = amplicon_read("unmatched", amp1, "GATCGATCGATC");
+    *unmatched.sequence_mut() = vec![b'A'; unmatched.sequence().len()];
+    records.push(unmatched);
+
+    let output = dir.path().join("amplicons.fasta");
+    let reads_dir = dir.path().join("reads");
+    std::fs::create_dir(&reads_dir)?;
+    let stream = futures::stream::iter(records.into_iter().map(Ok));
+    let settings = ConsensusSettings {
+        reads_dir: Some(reads_dir.clone()),
+        ..ConsensusSettings::default()
+    };
+    let depths = consensus_records(stream, &output, &scheme, &settings).await?;
+    assert_eq!(depths.get("amplicon_1"), Some(&3));
+    assert_eq!(depths.get("amplicon_2"), Some(&2));
+
+    // one consensus per amplicon, with the minority substitution voted out
+    let mut reader = std::fs::File::open(&output)
+        .map(std::io::BufReader::new)
+        .map(noodles::fasta::io::Reader::new)?;
+    let consensus: Vec<(Vec<u8>, Vec<u8>)> = reader
+        .records()
+        .map(|record| {
+            record.map(|record| (record.name().to_vec(), record.sequence().as_ref().to_vec()))
+        })
+        .collect::<std::io::Result<_>>()?;
+    assert_eq!(
+        consensus,
+        vec![
+            (b"amplicon_1".to_vec(), b"GATCGATCGATC".to_vec()),
+            (b"amplicon_2".to_vec(), b"TTGACCATTG".to_vec()),
+        ]
+    );
+
+    // and one file of trimmed reads per amplicon, as deep as its consensus
+    for (amplicon, depth) in &depths {
+        let reads = read_output(&reads_dir.join(format!("{amplicon}.fastq")))?;
+        assert_eq!(reads.len() as u64, *depth);
+    }
+
+    Ok(())
+}
+
+/// Read back the name and sequence of every record in a FASTA file.
+fn read_fasta(path: &std::path::Path) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut reader = std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .map(noodles::fasta::io::Reader::new)?;
+    let records = reader
+        .records()
+        .map(|record| {
+            record.map(|record| (record.name().to_vec(), record.sequence().as_ref().to_vec()))
+        })
+        .collect::<std::io::Result<_>>()?;
+    Ok(records)
+}
+
+#[tokio::test]
+async fn test_quality_weighted_consensus_decodes_with_quality_offset() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+
+    // encoded as Phred+64, two reads call a T at one position with a score of 2 while one
+    // calls a C there with a score of 40. Read as Phred+33, every score is 33 or more
+    let mut records = Vec::new();
+    for (name, insert, quality) in [
+        ("low1", "GATCGTTCGATC", b'B'),
+        ("low2", "GATCGTTCGATC", b'B'),
+        ("high", "GATCGCTCGATC", b'h'),
+    ] {
+        let mut record = amplicon_read(name, amp1, insert);
+        record.quality_scores_mut().fill(quality);
+        records.push(record);
+    }
+
+    for (quality_offset, expected) in [
+        (QualityOffset::Phred64, b"GATCGCTCGATC"),
+        (QualityOffset::Phred33, b"GATCGTTCGATC"),
+    ] {
+        let output = dir.path().join("amplicons.fasta");
+        let settings = ConsensusSettings {
+            quality_weighted: true,
+            quality_offset,
+            ..ConsensusSettings::default()
+        };
+        let stream = futures::stream::iter(records.clone().into_iter().map(Ok));
+        let depths = consensus_records(stream, &output, &scheme, &settings).await?;
+        assert_eq!(depths.get("amplicon_1"), Some(&3));
+        assert_eq!(
+            read_fasta(&output)?,
+            vec![(b"amplicon_1".to_vec(), expected.to_vec())]
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consensus_trimmed_to_reference_drops_insertion() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+
+    // every read carries an extra A relative to the reference insert
+    let insert = "GATCGATCGATC";
+    let records: Vec<_> = (0..3)
+        .map(|i| amplicon_read(&format!("read{i}"), amp1, "GATCGAATCGATC"))
+        .collect();
+    let reference = format!("GGGG{}{insert}{}CCCC", amp1.fwd, amp1.rev_rc);
+    let settings = ConsensusSettings {
+        reference: Some(ConsensusReference {
+            sequences: [(b"ref".to_vec(), reference.into_bytes())].into(),
+            regions: [(
+                "amplicon_1".to_string(),
+                InsertRegion {
+                    ref_name: "ref".to_string(),
+                    start: 4 + amp1.fwd.len(),
+                    stop: 4 + amp1.fwd.len() + insert.len(),
+                },
+            )]
+            .into(),
+        }),
+        ..ConsensusSettings::default()
+    };
+
+    // the consensus keeps the insertion unless it is placed onto the reference
+    let output = dir.path().join("amplicons.fasta");
+    for (settings, expected) in [
+        (ConsensusSettings::default(), "GATCGAATCGATC"),
+        (settings, insert),
+    ] {
+        let stream = futures::stream::iter(records.clone().into_iter().map(Ok));
+        consensus_records(stream, &output, &scheme, &settings).await?;
+        assert_eq!(
+            read_fasta(&output)?,
+            vec![(b"amplicon_1".to_vec(), expected.as_bytes().to_vec())]
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_assembled_genome_prefers_deeper_overlapping_amplicon() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // the first amplicon's insert spans 4..20 of the reference and the second's 14..32, and
+    // the deeper first amplicon disagrees with the reference at position 16
+    let reference = "ACGTTGCAAGCTTACGGATCTGCATCGGATACCGTTAGCAGTAC";
+    let mut first = reference[4..20].to_string();
+    first.replace_range(12..13, "T");
+    let mut records: Vec<_> = (0..3)
+        .map(|i| amplicon_read(&format!("a{i}"), amp1, &first))
+        .collect();
+    records.push(amplicon_read("b0", amp2, &reference[14..32]));
+
+    let region = |start: usize, stop: usize| InsertRegion {
+        ref_name: "ref".to_string(),
+        start,
+        stop,
+    };
+    let genome_path = dir.path().join("genome.fasta");
+    let settings = ConsensusSettings {
+        reference: Some(ConsensusReference {
+            sequences: [(b"ref".to_vec(), reference.as_bytes().to_vec())].into(),
+            regions: [
+                ("amplicon_1".to_string(), region(4, 20)),
+                ("amplicon_2".to_string(), region(14, 32)),
+            ]
+            .into(),
+        }),
+        genome_output: Some(genome_path.clone()),
+        ..ConsensusSettings::default()
+    };
+    let output = dir.path().join("amplicons.fasta");
+    let stream = futures::stream::iter(records.into_iter().map(Ok));
+    let depths = consensus_records(stream, &output, &scheme, &settings).await?;
+    assert_eq!(depths.get("amplicon_1"), Some(&3));
+    assert_eq!(depths.get("amplicon_2"), Some(&1));
+
+    // one contiguous consensus across both amplicons, padded where neither reaches
+    let expected = format!(
+        "NNNN{}T{}{}",
+        &reference[4..16],
+        &reference[17..32],
+        "N".repeat(reference.len() - 32)
+    );
+    assert_eq!(
+        read_fasta(&genome_path)?,
+        vec![(b"ref".to_vec(), expected.into_bytes())]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_memory_stops_grouping_reads_for_consensus() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let records: Vec<_> = (0..50)
+        .map(|i| amplicon_read(&format!("read{i}"), &scheme.scheme[0], "GATCGATCGATC"))
+        .collect();
+    let output = dir.path().join("amplicons.fasta");
+
+    // every read is held until the consensus is called, so a tiny limit is soon passed
+    let settings = ConsensusSettings {
+        max_memory: Some(1024),
+        ..ConsensusSettings::default()
+    };
+    let stream = futures::stream::iter(records.clone().into_iter().map(Ok));
+    let err = consensus_records(stream, &output, &scheme, &settings)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("--max-memory"), "{err}");
+
+    // while a roomy one is fine
+    let settings = ConsensusSettings {
+        max_memory: Some(1 << 20),
+        ..ConsensusSettings::default()
+    };
+    let stream = futures::stream::iter(records.into_iter().map(Ok));
+    let depths = consensus_records(stream, &output, &scheme, &settings).await?;
+    assert_eq!(depths.get("amplicon_1"), Some(&50));
+
+    Ok(())
+}
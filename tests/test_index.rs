@@ -0,0 +1,468 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use amplicon_tk::{
+    index::{index_inputs, merge_indices, Index, IndexFormat, IndexOptions},
+    io::{dry_count_records, Fastq},
+    reads::{sync_trimming, FilterSettings, TrimSettings, Trimming},
+    record::{FindAmplicons, IdentityTransform},
+};
+use color_eyre::eyre::Result;
+use common::{amplicon_read, read_output, sync_reader, test_scheme, to_fastq};
+use noodles::fastq::Record as FastqRecord;
+
+#[tokio::test]
+async fn test_representatives_are_most_frequent() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACACACAC"),
+        amplicon_read("r3", amp1, "ACACACACAC"),
+        amplicon_read("r4", amp1, "ACACTCACAC"),
+        amplicon_read("r5", amp2, "GTGTGTGTGT"),
+        amplicon_read("r6", amp2, "GTGTGAGTGT"),
+        amplicon_read("r7", amp2, "GTGTGAGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let index = Fastq.index(sync_reader(&input)?, scheme, &input).await?;
+    assert!(dir.path().join("reads.fastq.ampidx").exists());
+
+    let representatives = index.representatives();
+    assert_eq!(representatives.len(), 2);
+    assert_eq!(representatives["amplicon_1"], b"ACACACACAC".to_vec());
+    assert_eq!(representatives["amplicon_2"], b"GTGTGAGTGT".to_vec());
+
+    let fasta_path = dir.path().join("representatives.fasta");
+    index.write_representatives(&fasta_path)?;
+    assert_eq!(
+        std::fs::read_to_string(&fasta_path)?,
+        ">amplicon_1\nACACACACAC\n>amplicon_2\nGTGTGAGTGT\n"
+    );
+
+    Ok(())
+}
+
+fn with_quality(mut record: FastqRecord, score: u8) -> FastqRecord {
+    let len = record.quality_scores().len();
+    *record.quality_scores_mut() = vec![score; len];
+    record
+}
+
+#[tokio::test]
+async fn test_representative_ties_are_deterministic() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // amplicon_1 has two equally frequent sequences, where the lexicographically larger one has
+    // the higher quality, while amplicon_2's equally frequent sequences have equal quality
+    let reads = vec![
+        with_quality(amplicon_read("r1", amp1, "AAAAAAAAAC"), b'5'),
+        with_quality(amplicon_read("r2", amp1, "TTTTTTTTTC"), b'I'),
+        with_quality(amplicon_read("r3", amp2, "TGTGTGTGTG"), b'I'),
+        with_quality(amplicon_read("r4", amp2, "GTGTGTGTGT"), b'I'),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    for _ in 0..5 {
+        let index = Fastq
+            .index(sync_reader(&input)?, test_scheme(), &input)
+            .await?;
+        let representatives = index.representatives();
+        assert_eq!(representatives["amplicon_1"], b"TTTTTTTTTC".to_vec());
+        assert_eq!(representatives["amplicon_2"], b"GTGTGTGTGT".to_vec());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_count_filters_by_absolute_count() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACACACAC"),
+        amplicon_read("r3", amp1, "ACACACACAC"),
+        amplicon_read("r4", amp1, "ACACTCACAC"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let built = Fastq
+        .index(sync_reader(&input)?, test_scheme(), &input)
+        .await?;
+    assert_eq!(built.total, 4);
+    assert_eq!(built.count(b"ACACACACAC"), Some(3));
+    assert_eq!(built.count(b"ACACTCACAC"), Some(1));
+
+    let index = Fastq.load_index(&input, &test_scheme().hash_amplicon_scheme()?)?;
+    assert!(index.is_some());
+    let filters = FilterSettings::new(&None, &Some(2), &None, &index, &None);
+
+    let trimmed = sync_trimming(reads, &scheme).await?;
+    let mut kept = Vec::new();
+    for (amplicon, record) in &trimmed {
+        if record.whether_to_write(amplicon, &filters).await {
+            kept.push(record.name().to_vec());
+        }
+    }
+    assert_eq!(kept, vec![b"r1".to_vec(), b"r2".to_vec(), b"r3".to_vec()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frequency_computed_from_stored_counts() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACACACAC"),
+        amplicon_read("r3", amp1, "ACACACACAC"),
+        amplicon_read("r4", amp1, "ACACTCACAC"),
+        amplicon_read("r5", amp2, "GTGTGTGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    Fastq
+        .index(sync_reader(&input)?, test_scheme(), &input)
+        .await?;
+    let index = Fastq
+        .load_index(&input, &test_scheme().hash_amplicon_scheme()?)?
+        .expect("index round trip");
+
+    // the raw counts survive serialization
+    assert_eq!(index.total, 5);
+    assert_eq!(index.unique_seqs[b"ACACACACAC".as_slice()], 3);
+    assert_eq!(index.amplicon_totals["amplicon_1"], 4);
+    assert_eq!(index.amplicon_totals["amplicon_2"], 1);
+
+    // frequencies match the fractions of all trimmed reads that were previously stored
+    assert_eq!(index.frequency(b"ACACACACAC"), Some(3.0 / 5.0));
+    assert_eq!(index.frequency(b"ACACTCACAC"), Some(1.0 / 5.0));
+    assert_eq!(index.frequency(b"GTGTGTGTGT"), Some(1.0 / 5.0));
+    assert_eq!(index.frequency(b"TTTTTTTTTT"), None);
+
+    // per-amplicon frequencies use each amplicon's own total
+    assert_eq!(
+        index.amplicon_frequency("amplicon_1", b"ACACACACAC"),
+        Some(3.0 / 4.0)
+    );
+    assert_eq!(
+        index.amplicon_frequency("amplicon_2", b"GTGTGTGTGT"),
+        Some(1.0)
+    );
+    assert_eq!(index.amplicon_frequency("amplicon_2", b"ACACACACAC"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_indices_sums_counts() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let sample_a = dir.path().join("a.fastq");
+    std::fs::write(
+        &sample_a,
+        to_fastq(&[
+            amplicon_read("a1", amp1, "ACACACACAC"),
+            amplicon_read("a2", amp1, "ACACACACAC"),
+            amplicon_read("a3", amp2, "GTGTGTGTGT"),
+        ]),
+    )?;
+    let sample_b = dir.path().join("b.fastq");
+    std::fs::write(
+        &sample_b,
+        to_fastq(&[
+            amplicon_read("b1", amp1, "ACACACACAC"),
+            amplicon_read("b2", amp1, "ACACTCACAC"),
+        ]),
+    )?;
+    Fastq
+        .index(sync_reader(&sample_a)?, test_scheme(), &sample_a)
+        .await?;
+    Fastq
+        .index(sync_reader(&sample_b)?, test_scheme(), &sample_b)
+        .await?;
+
+    let index_paths = vec![
+        dir.path().join("a.fastq.ampidx"),
+        dir.path().join("b.fastq.ampidx"),
+    ];
+    let merged = merge_indices(&index_paths)?;
+    let merged_path = dir.path().join("merged.ampidx");
+    merged.write_to(&merged_path)?;
+    let merged = IndexFormat::read(&merged_path)?;
+
+    assert_eq!(merged.hash(), test_scheme().hash_amplicon_scheme()?);
+    assert_eq!(merged.total, 5);
+    assert_eq!(merged.count(b"ACACACACAC"), Some(3));
+    assert_eq!(merged.count(b"ACACTCACAC"), Some(1));
+    assert_eq!(merged.count(b"GTGTGTGTGT"), Some(1));
+    assert_eq!(merged.amplicon_totals["amplicon_1"], 4);
+    assert_eq!(merged.amplicon_totals["amplicon_2"], 1);
+
+    // indices built with a different primer scheme cannot be merged in
+    let mut other_scheme = test_scheme();
+    other_scheme.scheme.truncate(1);
+    let sample_c = dir.path().join("c.fastq");
+    std::fs::write(
+        &sample_c,
+        to_fastq(&[amplicon_read("c1", amp1, "ACACACACAC")]),
+    )?;
+    Fastq
+        .index(sync_reader(&sample_c)?, other_scheme, &sample_c)
+        .await?;
+    let mismatched = vec![
+        dir.path().join("a.fastq.ampidx"),
+        dir.path().join("c.fastq.ampidx"),
+    ];
+    assert!(merge_indices(&mismatched).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_two_pass_frequencies_match_index_filtering() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACACACAC"),
+        amplicon_read("r3", amp1, "ACACACACAC"),
+        amplicon_read("r4", amp1, "ACACTCACAC"),
+        amplicon_read("r5", amp2, "GTGTGTGTGT"),
+        amplicon_read("r6", amp2, "GTGTGAGTGT"),
+        amplicon_read("r7", amp2, "GTGTGAGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    // counting in a first pass gives the same index as a separate indexing run
+    let indexed = Some(
+        Fastq
+            .index(sync_reader(&input)?, test_scheme(), &input)
+            .await?,
+    );
+    let settings = TrimSettings::default();
+    let two_pass = Some(
+        index_inputs(
+            std::slice::from_ref(&input),
+            &scheme,
+            &None,
+            &settings,
+            None,
+        )
+        .await?,
+    );
+    assert_eq!(two_pass, indexed);
+
+    // the first pass trims with the same settings as the second, so inserts too short to be
+    // kept aren't counted either
+    let strict = TrimSettings {
+        min_insert_len: Some(11),
+        ..TrimSettings::default()
+    };
+    let too_short =
+        index_inputs(std::slice::from_ref(&input), &scheme, &None, &strict, None).await?;
+    assert_eq!(too_short.total, 0);
+
+    // and an interrupt stops the first pass before it reads another record
+    let interrupted = TrimSettings::default();
+    interrupted.interrupt.store(true, Ordering::Relaxed);
+    let stopped = index_inputs(
+        std::slice::from_ref(&input),
+        &scheme,
+        &None,
+        &interrupted,
+        None,
+    )
+    .await?;
+    assert_eq!(stopped.total, 0);
+
+    // and so filters the same reads out in the second pass
+    let mut outputs = Vec::new();
+    for (label, index) in [("indexed", &indexed), ("two_pass", &two_pass)] {
+        let output = dir.path().join(format!("{label}.fastq"));
+        let filters = FilterSettings::new(&Some(0.2), &Some(2), &None, index, &None);
+        Fastq
+            .trim(
+                &input,
+                &output,
+                test_scheme(),
+                filters,
+                &TrimSettings::default(),
+                &IdentityTransform,
+            )
+            .await?;
+        outputs.push(read_output(&output)?);
+    }
+    let names: Vec<String> = outputs[1]
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect();
+    assert_eq!(names, ["r1", "r2", "r3", "r6", "r7"]);
+    assert_eq!(outputs[0], outputs[1]);
+
+    // standard input cannot be read a second time
+    let stdin = index_inputs(&[PathBuf::from("-")], &scheme, &None, &settings, None)
+        .await
+        .unwrap_err();
+    assert!(stdin.to_string().contains("standard input"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_index_round_trips_through_load_index() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACTCACAC"),
+        amplicon_read("r3", amp2, "GTGTGTGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+    let hash = scheme.hash_amplicon_scheme()?;
+
+    let index = Fastq
+        .index_with(
+            sync_reader(&input)?,
+            test_scheme(),
+            &input,
+            IndexOptions {
+                compress: true,
+                ..IndexOptions::default()
+            },
+        )
+        .await?;
+    let sidecar = std::fs::read(dir.path().join("reads.fastq.ampidx"))?;
+    assert_eq!(&sidecar[..2], &[0x1f, 0x8b]);
+
+    // the compressed sidecar is decompressed transparently when loaded
+    let loaded = Fastq.load_index(&input, &hash)?;
+    assert_eq!(loaded.as_ref(), Some(&index));
+
+    // and compressed and uncompressed copies of an index read back identically
+    let plain = dir.path().join("plain.ampidx");
+    let compressed = dir.path().join("compressed.ampidx");
+    index.write_to(&plain)?;
+    index.write_compressed_to(&compressed)?;
+    assert_eq!(IndexFormat::read(&plain)?, IndexFormat::read(&compressed)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_memory_stops_indexing_diverse_inputs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // every read carries its own insert, so the index grows with every read
+    let reads: Vec<FastqRecord> = (0..200)
+        .map(|i| {
+            let insert: String = format!("{i:012b}")
+                .chars()
+                .map(|bit| if bit == '0' { 'A' } else { 'C' })
+                .collect();
+            amplicon_read(&format!("r{i}"), &scheme.scheme[0], &insert)
+        })
+        .collect();
+    let input = dir.path().join("diverse.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let options = IndexOptions {
+        max_memory: Some(1024),
+        ..IndexOptions::default()
+    };
+    let err = Fastq
+        .index_with(sync_reader(&input)?, test_scheme(), &input, options)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("--max-memory"), "{err}");
+    assert!(!dir.path().join("diverse.fastq.ampidx").exists());
+
+    // the same guard applies to counting frequencies across inputs, and a roomy limit is fine
+    let settings = TrimSettings::default();
+    assert!(index_inputs(
+        std::slice::from_ref(&input),
+        &scheme,
+        &None,
+        &settings,
+        Some(1024)
+    )
+    .await
+    .is_err());
+    let index = index_inputs(&[input], &scheme, &None, &settings, Some(1 << 20)).await?;
+    assert_eq!(index.total, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dry_count_matches_processed_records() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let reads = vec![
+        amplicon_read("r1", amp1, "ACACACACAC"),
+        amplicon_read("r2", amp1, "ACACTCACAC"),
+        amplicon_read("r3", amp2, "GTGTGTGTGT"),
+        amplicon_read("r4", amp2, "GTGTGTGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    // a gzipped copy, missing its final newline, counts the same
+    let gzipped = dir.path().join("reads.fastq.gz");
+    let mut encoder = flate2::write::GzEncoder::new(
+        std::fs::File::create(&gzipped)?,
+        flate2::Compression::default(),
+    );
+    std::io::Write::write_all(&mut encoder, to_fastq(&reads).trim_end().as_bytes())?;
+    encoder.finish()?;
+
+    let expected_records = dry_count_records(&[input.clone(), gzipped]).await?;
+    assert_eq!(expected_records, Some(2 * reads.len() as u64));
+
+    // pre-sizing the index doesn't change what it counts
+    let options = IndexOptions {
+        expected_records: dry_count_records(std::slice::from_ref(&input)).await?,
+        ..IndexOptions::default()
+    };
+    let presized = Fastq
+        .index_with(sync_reader(&input)?, test_scheme(), &input, options)
+        .await?;
+    assert_eq!(Some(presized.total), options.expected_records);
+    let index = Fastq
+        .index(sync_reader(&input)?, test_scheme(), &input)
+        .await?;
+    assert_eq!(presized, index);
+
+    // standard input can't be read twice, so it is never counted ahead of time
+    assert_eq!(dry_count_records(&[PathBuf::from("-")]).await?, None);
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+
+use amplicon_tk::{
+    io::{Bed, Fasta, PrimerReader, RefReader},
+    lint::{lint_scheme, wallace_tm, LintIssue},
+    primers::{get_reverse_complement, ref_to_dict},
+};
+use color_eyre::eyre::Result;
+
+/// A forward and a reverse primer, 5' to 3', with the same estimated melting temperature.
+const FWD: &str = "ACGTTGCAAGCTTACGGATC";
+const REV: &str = "TGCATCGGATACCGTTAGCA";
+
+/// A reference with one amplicon, its forward primer at 20..40 and its reverse primer at
+/// 140..160, and nothing else that either primer could bind to.
+fn reference() -> String {
+    format!(
+        "{}{FWD}{}{}{}",
+        "A".repeat(20),
+        "A".repeat(100),
+        get_reverse_complement(REV),
+        "A".repeat(20)
+    )
+}
+
+const PRIMERS: [(usize, usize, &str); 2] = [(20, 40, "amp1_LEFT"), (140, 160, "amp1_RIGHT")];
+
+/// Write out a reference and BED file and lint the scheme they describe.
+async fn lint(
+    dir: &Path,
+    reference: &str,
+    primers: &[(usize, usize, &str)],
+) -> Result<Vec<LintIssue>> {
+    let ref_path = dir.join("ref.fasta");
+    std::fs::write(&ref_path, format!(">ref\n{reference}\n"))?;
+    let bed_path = dir.join("primers.bed");
+    let bed: String = primers
+        .iter()
+        .map(|(start, stop, name)| format!("ref\t{start}\t{stop}\t{name}\n"))
+        .collect();
+    std::fs::write(&bed_path, bed)?;
+
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    lint_scheme(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT")
+}
+
+#[tokio::test]
+async fn test_clean_scheme_has_no_issues() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    assert_eq!(wallace_tm(FWD.as_bytes()), wallace_tm(REV.as_bytes()));
+    assert!(lint(dir.path(), &reference(), &PRIMERS).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lint_finds_duplicate_primer_names() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let primers = [PRIMERS[0], PRIMERS[1], (20, 40, "amp1_LEFT")];
+    let issues = lint(dir.path(), &reference(), &primers).await?;
+    assert_eq!(
+        issues,
+        [LintIssue::DuplicateName {
+            primer_name: "amp1_LEFT".to_string(),
+            count: 2,
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lint_finds_complementary_primers() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // read as a forward primer, the reverse primer's site gives the reverse complement of the
+    // reverse primer
+    let primers = [PRIMERS[0], PRIMERS[1], (140, 160, "amp2_LEFT")];
+    let issues = lint(dir.path(), &reference(), &primers).await?;
+    assert_eq!(
+        issues,
+        [LintIssue::ComplementaryPrimers {
+            first: "amp1_RIGHT".to_string(),
+            second: "amp2_LEFT".to_string(),
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lint_finds_secondary_binding_sites() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // the last 12 bases of the forward primer turn up again inside the amplicon
+    let reference = format!(
+        "{}{FWD}{}{}{}{}{}",
+        "A".repeat(20),
+        "A".repeat(40),
+        &FWD[FWD.len() - 12..],
+        "A".repeat(48),
+        get_reverse_complement(REV),
+        "A".repeat(20)
+    );
+    let issues = lint(dir.path(), &reference, &PRIMERS).await?;
+    assert_eq!(
+        issues,
+        [LintIssue::SecondaryBindingSite {
+            primer_name: "amp1_LEFT".to_string(),
+            ref_name: "ref".to_string(),
+            position: 80,
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lint_finds_amplicons_without_an_insert() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // the reverse primer's site starts right where the forward primer's ends
+    let reference = format!(
+        "{}{FWD}{}{}",
+        "A".repeat(20),
+        get_reverse_complement(REV),
+        "A".repeat(20)
+    );
+    let primers = [(20, 40, "amp1_LEFT"), (40, 60, "amp1_RIGHT")];
+    let issues = lint(dir.path(), &reference, &primers).await?;
+    assert_eq!(
+        issues,
+        [LintIssue::EmptySpan {
+            amplicon: "amp1".to_string(),
+            span: 0,
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lint_finds_mismatched_melting_temperatures() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // a second amplicon pairs the forward primer with an AT-rich reverse primer
+    let at_rich = "TTATCATTATTAATTATCAT";
+    let reference = format!("{}{at_rich}{}", reference(), "A".repeat(20));
+    let primers = [
+        PRIMERS[0],
+        PRIMERS[1],
+        (20, 40, "amp2_LEFT"),
+        (180, 200, "amp2_RIGHT"),
+    ];
+    let issues = lint(dir.path(), &reference, &primers).await?;
+    assert_eq!(
+        issues,
+        [LintIssue::TmMismatch {
+            amplicon: "amp2".to_string(),
+            fwd_tm: 60,
+            rev_tm: 44,
+        }]
+    );
+
+    // issues are reported as tagged JSON objects
+    let json = serde_json::to_value(&issues[0])?;
+    assert_eq!(json["kind"], "tm_mismatch");
+    assert_eq!(json["rev_tm"], 44);
+
+    Ok(())
+}
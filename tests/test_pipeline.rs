@@ -0,0 +1,111 @@
+use amplicon_tk::{
+    index::IndexFormat,
+    pipeline::RecordStream,
+    reads::{DedupKey, FilterSettings},
+};
+use color_eyre::eyre::Result;
+use futures::TryStreamExt;
+use noodles::fastq::{record::Definition, Record as FastqRecord};
+
+/// A trimmed record named `name` that carries `seq`.
+fn trimmed(name: &str, seq: &str) -> FastqRecord {
+    FastqRecord::new(Definition::new(name, ""), seq, "I".repeat(seq.len()))
+}
+
+/// Run records through [`RecordStream::run_filters`] and return the names of those kept.
+async fn kept(
+    records: Vec<FastqRecord>,
+    filters: &Option<FilterSettings<'_, '_>>,
+) -> Result<Vec<String>> {
+    let kept: Vec<FastqRecord> =
+        RecordStream::from_records(futures::stream::iter(records.into_iter().map(Ok)))
+            .run_filters(filters)
+            .try_collect()
+            .await?;
+    Ok(kept
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect())
+}
+
+#[tokio::test]
+async fn test_run_filters_removes_rare_and_overlong_records() -> Result<()> {
+    let (common_seq, rare_seq) = ("ACGTACGTAC", "TTGCATGCAA");
+    let long_seq = "GA".repeat(15);
+
+    // of the seven reads indexed, only one carries the rare sequence
+    let indexed: Vec<(String, FastqRecord)> =
+        [(common_seq, 3), (rare_seq, 1), (long_seq.as_str(), 3)]
+            .into_iter()
+            .flat_map(|(seq, copies)| {
+                std::iter::repeat_n(("amplicon_1".to_string(), trimmed("indexed", seq)), copies)
+            })
+            .collect();
+    let index = Some(IndexFormat::from_trimmed("scheme".to_string(), &indexed));
+    let records = || {
+        vec![
+            trimmed("common", common_seq),
+            trimmed("rare", rare_seq),
+            trimmed("long", &long_seq),
+            trimmed("unindexed", "CCCCCCCCCC"),
+        ]
+    };
+
+    // without filters, every record is kept
+    assert_eq!(
+        kept(records(), &None).await?,
+        ["common", "rare", "long", "unindexed"]
+    );
+
+    // sequences below the minimum frequency, or missing from the index, are removed
+    let by_freq = FilterSettings::new(&Some(0.2), &None, &None, &index, &None);
+    assert_eq!(kept(records(), &by_freq).await?, ["common", "long"]);
+
+    // as are records longer than the maximum length, which needs no index
+    let by_len = FilterSettings::new(&None, &None, &Some(20), &None, &None);
+    assert_eq!(
+        kept(records(), &by_len).await?,
+        ["common", "rare", "unindexed"]
+    );
+
+    let both = FilterSettings::new(&Some(0.2), &None, &Some(20), &index, &None);
+    assert_eq!(kept(records(), &both).await?, ["common"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_canonical_dedup_treats_reverse_complements_as_one() -> Result<()> {
+    let records = || {
+        futures::stream::iter(
+            [
+                trimmed("fwd", "AACCGGTTAG"),
+                trimmed("rev", "CTAACCGGTT"),
+                trimmed("dup", "AACCGGTTAG"),
+            ]
+            .into_iter()
+            .map(Ok),
+        )
+    };
+    let names = |kept: Vec<FastqRecord>| -> Vec<String> {
+        kept.iter()
+            .map(|record| String::from_utf8_lossy(record.name()).to_string())
+            .collect()
+    };
+
+    // by plain sequence, a sequence and its reverse complement are two uniques
+    let plain = RecordStream::from_records(records())
+        .dedup_by(DedupKey::Seq)
+        .try_collect()
+        .await?;
+    assert_eq!(names(plain), ["fwd", "rev"]);
+
+    // canonically, they are one
+    let canonical = RecordStream::from_records(records())
+        .dedup_by(DedupKey::Canonical)
+        .try_collect()
+        .await?;
+    assert_eq!(names(canonical), ["fwd"]);
+
+    Ok(())
+}
@@ -0,0 +1,850 @@
+mod common;
+
+use std::collections::HashMap;
+
+use amplicon_tk::{
+    io::{Bed, Fasta, PrimerReader, RefReader},
+    primers::{
+        check_bed_coordinates, define_amplicons, define_amplicons_checked,
+        define_amplicons_from_table, define_amplicons_with_overrides, get_reverse_complement,
+        get_reverse_complement_with, indexed_refs_to_windows, read_primer_fasta, ref_name_key,
+        ref_to_dict, refs_to_dict, refs_to_windows, reverse_complement_bytes,
+        reverse_complement_bytes_with, AmpliconScheme, CoordinateMismatch, PossiblePrimers,
+        PrimerCollision, RefLookup, UnknownBases,
+    },
+    reads::FilterSettings,
+    record::{AmpliconMatcher, FindAmplicons, MatcherOptions},
+};
+use biotest::{values::Nucleotides, Format};
+use color_eyre::eyre::Result;
+use common::{amplicon_read, primers, test_scheme};
+
+const PRIMER_FASTA: &str = "\
+>amp1_LEFT
+TGGAGGAT
+>amp1_RIGHT
+CCATAGTA
+>amp2_LEFT
+CCCCAAAA
+>amp2_RIGHT
+AAAAACGT
+";
+
+#[tokio::test]
+async fn test_primer_table_expected_lengths() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fasta_path = dir.path().join("primers.fasta");
+    std::fs::write(&fasta_path, PRIMER_FASTA)?;
+    let table_path = dir.path().join("pairs.tsv");
+    std::fs::write(
+        &table_path,
+        "# fwd\trev\texpected_len\namp1_LEFT\tamp1_RIGHT\t20\namp2_LEFT\tamp2_RIGHT\t100\n",
+    )?;
+
+    let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+    let table = define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", false)?;
+    assert_eq!(table.scheme.scheme.len(), 2);
+    assert_eq!(table.scheme.scheme[0].amplicon, "amp1");
+    assert_eq!(table.scheme.scheme[0].rev_rc, "TACTATGG");
+    assert_eq!(table.expected_lens.get("amp1"), Some(&20));
+    assert_eq!(table.expected_lens.get("amp2"), Some(&100));
+
+    // a 50-base insert is too long for the first amplicon but fine for the second
+    let insert = "GATC".repeat(12) + "GA";
+    let amplicon_max_lens = Some(table.expected_lens);
+    let filters = FilterSettings::new(&None, &None, &None, &None, &amplicon_max_lens);
+    for (pair, expected) in table.scheme.scheme.iter().zip([false, true]) {
+        let read = amplicon_read("read", pair, &insert);
+        let hit = read.find_amplicon(&table.scheme.scheme).await.unwrap();
+        let amplicon = hit.amplicon.clone();
+        let trimmed = read.trim_to_amplicon(hit).await?.unwrap();
+        assert_eq!(trimmed.sequence().len(), 50);
+        assert_eq!(
+            trimmed.whether_to_write(&amplicon, &filters).await,
+            expected,
+            "Unexpected filtering result for {amplicon}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_primer_table_rejects_malformed_rows() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fasta_path = dir.path().join("primers.fasta");
+    std::fs::write(&fasta_path, PRIMER_FASTA)?;
+    let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+
+    for table in [
+        "amp1_LEFT\n",
+        "amp1_LEFT\tamp1_RIGHT\tamp1\tlong\n",
+        "amp1_LEFT\tamp1_RIGHT\t\t20\n",
+        "amp1_LEFT\tamp1_RIGHT\tamp1\t20\textra\n",
+        "amp1_LEFT\tamp3_RIGHT\n",
+    ] {
+        let table_path = dir.path().join("pairs.tsv");
+        std::fs::write(&table_path, table)?;
+        assert!(define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", false).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_lowercase_primer_fasta_is_uppercased() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fasta_path = dir.path().join("primers.fasta");
+    let lowercase: String = PRIMER_FASTA
+        .lines()
+        .map(|line| match line.starts_with('>') {
+            true => format!("{line}\n"),
+            false => format!("{}\n", line.to_ascii_lowercase()),
+        })
+        .collect();
+    std::fs::write(&fasta_path, lowercase)?;
+    let table_path = dir.path().join("pairs.tsv");
+    std::fs::write(&table_path, "amp1_LEFT\tamp1_RIGHT\n")?;
+
+    let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+    assert_eq!(primer_seqs["amp1_LEFT"], "TGGAGGAT");
+    let table = define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", false)?;
+    assert_eq!(table.scheme.scheme[0].fwd, "TGGAGGAT");
+    assert_eq!(table.scheme.scheme[0].rev_rc, "TACTATGG");
+
+    // so that reads, which are matched in uppercase, still match primers given in lowercase
+    let read = amplicon_read("read", &test_scheme().scheme[0], "GATCGATCGA");
+    let matcher = AmpliconMatcher::from_scheme(&table.scheme, MatcherOptions::default());
+    assert!(matcher.match_read(read.sequence()).is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_primer_table_amplicon_names() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fasta_path = dir.path().join("primers.fasta");
+    std::fs::write(&fasta_path, PRIMER_FASTA.replace("amp2_LEFT", "amp2_alt_F"))?;
+    let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+    let table_path = dir.path().join("pairs.tsv");
+    let amplicons = |table: &str| -> Result<Vec<String>> {
+        std::fs::write(&table_path, table)?;
+        let table = define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", false)?;
+        Ok(table
+            .scheme
+            .scheme
+            .into_iter()
+            .map(|pair| pair.amplicon)
+            .collect())
+    };
+
+    // with two columns, irregular primer names give irregular amplicon names
+    assert_eq!(
+        amplicons("amp1_LEFT\tamp1_RIGHT\namp2_alt_F\tamp2_RIGHT\n")?,
+        ["amp1", "amp2_alt_F"]
+    );
+
+    // a third column names the amplicon outright
+    assert_eq!(
+        amplicons("amp1_LEFT\tamp1_RIGHT\ttile_1\namp2_alt_F\tamp2_RIGHT\ttile_2\n")?,
+        ["tile_1", "tile_2"]
+    );
+
+    // expected lengths follow the amplicon name and are keyed by it
+    std::fs::write(&table_path, "amp2_alt_F\tamp2_RIGHT\ttile_2\t100\n")?;
+    let table = define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", false)?;
+    assert_eq!(table.scheme.scheme[0].amplicon, "tile_2");
+    assert_eq!(table.expected_lens.get("tile_2"), Some(&100));
+
+    Ok(())
+}
+
+#[test]
+fn test_reverse_complement_handles_iupac_codes() {
+    // every IUPAC symbol, plus U for RNA
+    let primer = "ACGTURYSWKMBDHVN";
+    let rc = get_reverse_complement(primer);
+    assert_eq!(rc.len(), primer.len());
+    assert_eq!(rc, "NBDHVKMWSRYAACGT");
+
+    // degenerate primers round-trip, apart from U, which comes back as T
+    assert_eq!(get_reverse_complement(&rc), primer.replace('U', "T"));
+
+    // case is kept, and the byte-level version agrees
+    assert_eq!(
+        get_reverse_complement(&primer.to_ascii_lowercase()),
+        rc.to_ascii_lowercase()
+    );
+    assert_eq!(reverse_complement_bytes(primer.as_bytes()), rc.as_bytes());
+}
+
+#[test]
+fn test_reverse_complement_keeps_length_with_unknown_bases() {
+    // soft-masked bases and Ns are complemented, keeping their case
+    let rc = get_reverse_complement("acgtN");
+    assert_eq!(rc, "Nacgt");
+    assert_eq!(rc.len(), "acgtN".len());
+    assert_eq!(
+        get_reverse_complement_with("acgtN", UnknownBases::MaskAsN),
+        rc
+    );
+
+    // bytes that aren't bases are kept, or masked as N when asked
+    let gapped = "ac-gt.N";
+    let kept = get_reverse_complement_with(gapped, UnknownBases::Keep);
+    assert_eq!(kept, "N.ac-gt");
+    let masked = get_reverse_complement_with(gapped, UnknownBases::MaskAsN);
+    assert_eq!(masked, "NNacNgt");
+    assert_eq!(kept.len(), gapped.len());
+    assert_eq!(masked.len(), gapped.len());
+    assert_eq!(
+        reverse_complement_bytes_with(gapped.as_bytes(), UnknownBases::MaskAsN),
+        masked.as_bytes()
+    );
+}
+
+#[tokio::test]
+async fn test_primer_fasta_overrides_reference_sequences() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // the BED coordinates are shifted a base to the right of where the primers really are
+    let reference = format!(
+        ">ref\n{}{}{}{}{}\n",
+        "A".repeat(8),
+        "TGGAGGAT",
+        "GATCGATCGATC",
+        "TACTATGG",
+        "A".repeat(8)
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, reference)?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t9\t17\tamp1_LEFT\nref\t29\t37\tamp1_RIGHT\n",
+    )?;
+    let fasta_path = dir.path().join("primers.fasta");
+    std::fs::write(&fasta_path, PRIMER_FASTA)?;
+
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let overrides = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+    let read = amplicon_read(
+        "read",
+        &common::primers("amp1", "TGGAGGAT", "CCATAGTA"),
+        "GATCGATCGATC",
+    );
+
+    // sequences pulled from the wrong coordinates do not match the read
+    let from_ref =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    assert_eq!(from_ref.scheme[0].amplicon, "amp1");
+    assert!(read.find_amplicon(&from_ref.scheme).await.is_none());
+
+    // the overriding sequences do, while the BED still determines which amplicons exist
+    let overridden = define_amplicons_with_overrides(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &overrides,
+        "_LEFT",
+        "_RIGHT",
+    )
+    .await?;
+    assert_eq!(overridden.scheme[0].amplicon, "amp1");
+    assert_eq!(overridden.scheme[0].fwd, "TGGAGGAT");
+    assert_eq!(overridden.scheme[0].rev, "CCATAGTA");
+    let hit = read
+        .find_amplicon(&overridden.scheme)
+        .await
+        .expect("override hit");
+    let trimmed = read.trim_to_amplicon(hit).await?.expect("trimmed read");
+    assert_eq!(trimmed.sequence(), b"GATCGATCGATC");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_soft_masked_reference_primers_are_uppercased() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // lowercase, soft-masked bases overlap both primers
+    let reference = format!(
+        ">ref\n{}{}{}{}{}\n",
+        "AAAAAaaa", "tggaggat", "GATCGATCGATC", "TACTATgg", "aaAAAAAA"
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, &reference)?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\n",
+    )?;
+
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let scheme =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    let pair = &scheme.scheme[0];
+    assert!(!pair.fwd.is_empty() && !pair.rev.is_empty());
+    for primer in [&pair.fwd, &pair.fwd_rc, &pair.rev, &pair.rev_rc] {
+        assert_eq!(primer, &primer.to_uppercase());
+    }
+    assert_eq!(pair.fwd.len(), pair.fwd_rc.len());
+
+    // a read of the unmasked molecule matches the primers from the masked reference
+    let molecule = reference.lines().nth(1).unwrap().to_uppercase();
+    let read = noodles::fastq::Record::new(
+        noodles::fastq::record::Definition::new("read", ""),
+        molecule.as_bytes().to_vec(),
+        vec![b'I'; molecule.len()],
+    );
+    assert!(read.find_amplicon(&scheme.scheme).await.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_byte_and_str_reverse_complements_agree() -> Result<()> {
+    assert_eq!(reverse_complement_bytes(b"AACGTTU"), b"AAACGTT".to_vec());
+    assert_eq!(get_reverse_complement("AACGTTU"), "AAACGTT");
+    assert_eq!(reverse_complement_bytes(b"acgTN"), b"NAcgt".to_vec());
+    assert!(reverse_complement_bytes(b"").is_empty());
+
+    let mut rng = biotest::seeded_rand(42);
+    for len in [1, 8, 20, 150] {
+        for nucleotides in [
+            Nucleotides::Dna,
+            Nucleotides::DnaUpper,
+            Nucleotides::DnaLower,
+        ] {
+            let generator = biotest::Sequence::builder()
+                .sequence(nucleotides)
+                .sequence_len(len)
+                .build()?;
+            let mut seq = Vec::new();
+            generator.record(&mut seq, &mut rng)?;
+            let seq_str = String::from_utf8(seq.clone())?;
+
+            let from_bytes = reverse_complement_bytes(&seq);
+            let from_str = get_reverse_complement(&seq_str);
+            assert_eq!(from_bytes, from_str.as_bytes());
+            assert_eq!(from_bytes.len(), seq.len());
+            assert_eq!(reverse_complement_bytes(&from_bytes), seq);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shifted_bed_coordinates_are_diagnosed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let reference = format!(
+        ">ref\n{}{}{}{}{}\n",
+        "A".repeat(8),
+        "TGGAGGAT",
+        "GATCGATCGATC",
+        "TACTATGG",
+        "A".repeat(8)
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, reference)?;
+    let fasta_path = dir.path().join("primers.fasta");
+    std::fs::write(&fasta_path, PRIMER_FASTA)?;
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+
+    // correct coordinates pass, with the reverse primer given in its own orientation
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\n",
+    )?;
+    let mismatches = check_bed_coordinates(Bed.read_primers(&bed_path)?, &ref_dict, &primer_seqs)?;
+    assert!(mismatches.is_empty());
+
+    // coordinates shifted a base to the right, as with a 1-based BED file, are caught and the
+    // correction is suggested
+    std::fs::write(
+        &bed_path,
+        "ref\t9\t17\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\n",
+    )?;
+    let mismatches = check_bed_coordinates(Bed.read_primers(&bed_path)?, &ref_dict, &primer_seqs)?;
+    assert_eq!(
+        mismatches,
+        vec![CoordinateMismatch {
+            primer_name: "amp1_LEFT".to_string(),
+            start: 9,
+            stop: 17,
+            shift: Some(-1),
+        }]
+    );
+    let message = mismatches[0].to_string();
+    assert!(message.contains("off by -1"), "{message}");
+    assert!(message.contains("try positions 8 and 16"), "{message}");
+
+    // coordinates nowhere near the primer have no suggested correction
+    std::fs::write(&bed_path, "ref\t16\t24\tamp1_LEFT\n")?;
+    let mismatches = check_bed_coordinates(Bed.read_primers(&bed_path)?, &ref_dict, &primer_seqs)?;
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].shift, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiple_reference_fastas_are_merged() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let segment_a = dir.path().join("segment_a.fasta");
+    std::fs::write(&segment_a, ">seg_a\nAAAATGGAGGATGATC\n")?;
+    let segment_b = dir.path().join("segment_b.fasta");
+    std::fs::write(&segment_b, ">seg_b\nGATCTACTATGGAAAA\n")?;
+
+    let mut fastas = vec![Fasta.read_ref(&segment_a)?, Fasta.read_ref(&segment_b)?];
+    let ref_dict = refs_to_dict(&mut fastas).await?;
+    assert_eq!(ref_dict.len(), 2);
+    assert_eq!(
+        ref_dict.get(b"seg_a".as_slice()),
+        Some(&b"AAAATGGAGGATGATC".to_vec())
+    );
+    assert_eq!(
+        ref_dict.get(b"seg_b".as_slice()),
+        Some(&b"GATCTACTATGGAAAA".to_vec())
+    );
+
+    // the same contig in two references is ambiguous
+    let mut fastas = vec![Fasta.read_ref(&segment_a)?, Fasta.read_ref(&segment_a)?];
+    let duplicate = refs_to_dict(&mut fastas).await.unwrap_err();
+    assert!(duplicate.to_string().contains("seg_a"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reference_windows_match_whole_reference() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // primers sit at the very start of one contig and the very end of another, with long,
+    // soft-masked stretches that no primer touches
+    let segment_a = format!(
+        ">seg_a\n{}{}{}\n",
+        "TGGAGGAT",
+        "GATCGATCGATC",
+        "acgt".repeat(250)
+    );
+    let segment_b = format!(
+        ">seg_b\n{}{}{}{}\n",
+        "acgt".repeat(250),
+        "CCCCAAAA",
+        "GATCGATCGATC",
+        "ACGTTTTT"
+    );
+    let path_a = dir.path().join("segment_a.fasta");
+    std::fs::write(&path_a, segment_a)?;
+    let path_b = dir.path().join("segment_b.fasta");
+    std::fs::write(&path_b, segment_b)?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "seg_a\t0\t8\tamp1_LEFT\nseg_a\t20\t28\tamp1_RIGHT\nseg_b\t1000\t1008\tamp2_LEFT\nseg_b\t1020\t1028\tamp2_RIGHT\n",
+    )?;
+
+    let ref_dict = refs_to_dict(&mut [Fasta.read_ref(&path_a)?, Fasta.read_ref(&path_b)?]).await?;
+    let windows = refs_to_windows(
+        &mut [Fasta.read_ref(&path_a)?, Fasta.read_ref(&path_b)?],
+        Bed.read_primers(&bed_path)?,
+    )
+    .await?;
+
+    // the windows hold nothing far from the primers
+    assert!(windows.has_sequence(b"seg_b"));
+    assert!(windows.lookup(b"seg_b", 400, 408).is_none());
+    assert_eq!(
+        windows.lookup(b"seg_b", 1000, 1008),
+        ref_dict.lookup(b"seg_b", 1000, 1008)
+    );
+
+    let eager =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    let lazy = define_amplicons(Bed.read_primers(&bed_path)?, &windows, "_LEFT", "_RIGHT").await?;
+    assert_eq!(eager.scheme.len(), 2);
+    assert_eq!(eager, lazy);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_indexed_reference_fetches_primer_windows() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // a large, line-wrapped reference with the amplicon buried deep in its second sequence
+    let mut state: u32 = 42;
+    let mut filler = |len: usize| -> String {
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"ACGT"[(state >> 16) as usize % 4] as char
+            })
+            .collect()
+    };
+    let chr1 = filler(200_000);
+    let chr2 = format!(
+        "{}{}{}{}{}",
+        filler(150_000),
+        "TGGAGGAT",
+        "GATCGATCGATC",
+        "TACTATGG",
+        filler(50_000)
+    );
+    let wrap = |seq: &str| -> String {
+        seq.as_bytes()
+            .chunks(60)
+            .map(|line| format!("{}\n", String::from_utf8_lossy(line)))
+            .collect()
+    };
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(
+        &ref_path,
+        format!(">chr1\n{}>chr2\n{}", wrap(&chr1), wrap(&chr2)),
+    )?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "chr2\t150000\t150008\tamp1_LEFT\nchr2\t150020\t150028\tamp1_RIGHT\n",
+    )?;
+
+    // without an index, and without asking for one, there is nothing to read by coordinate
+    assert!(Fasta.read_indexed_ref(&ref_path, false)?.is_none());
+
+    let indexed = Fasta.read_indexed_ref(&ref_path, true)?;
+    assert!(dir.path().join("ref.fasta.fai").exists());
+    let mut indexed = vec![indexed.expect("the index should have been created")];
+    let windows = indexed_refs_to_windows(&mut indexed, Bed.read_primers(&bed_path)?)?;
+
+    assert!(!windows.has_sequence(b"chr1"));
+    assert_eq!(
+        windows.lookup(b"chr2", 150_000, 150_008),
+        Some(b"TGGAGGAT".as_slice())
+    );
+    assert_eq!(
+        windows.lookup(b"chr2", 150_020, 150_028),
+        Some(b"TACTATGG".as_slice())
+    );
+
+    // the index written above is picked up on its own next time, and fetches the same windows
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let mut reopened = vec![Fasta
+        .read_indexed_ref(&ref_path, false)?
+        .expect("the index should be reused")];
+    let reopened = indexed_refs_to_windows(&mut reopened, Bed.read_primers(&bed_path)?)?;
+    assert_eq!(reopened, windows);
+    let eager =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    let lazy = define_amplicons(Bed.read_primers(&bed_path)?, &windows, "_LEFT", "_RIGHT").await?;
+    assert_eq!(eager, lazy);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unpaired_primers_produce_an_empty_scheme_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let reference = format!(
+        ">ref\n{}{}{}{}{}\n",
+        "A".repeat(8),
+        "TGGAGGAT",
+        "GATCGATCGATC",
+        "TACTATGG",
+        "A".repeat(8)
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, reference)?;
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+
+    // the reverse primer uses a suffix other than the expected one, so nothing pairs
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(&bed_path, "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_R\n")?;
+    let scheme =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    assert!(scheme.scheme.is_empty());
+    let error = scheme.ensure_not_empty().unwrap_err();
+    assert!(error.to_string().contains("produced no amplicons"));
+
+    // a scheme that pairs passes the check
+    std::fs::write(
+        &bed_path,
+        "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\n",
+    )?;
+    let scheme =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    assert!(scheme.ensure_not_empty().is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hard_masked_primer_regions_are_reported() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // an assembly gap covers the forward primer
+    let reference = format!(
+        ">ref\n{}{}{}{}{}\n",
+        "A".repeat(8),
+        "N".repeat(8),
+        "GATCGATCGATC",
+        "TACTATGG",
+        "A".repeat(8)
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, reference)?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\n",
+    )?;
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let no_overrides = HashMap::new();
+
+    // by default, the masked primer is only warned about
+    let lenient = define_amplicons_checked(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &no_overrides,
+        "_LEFT",
+        "_RIGHT",
+        false,
+    )
+    .await?;
+    assert_eq!(lenient.scheme.len(), 1);
+
+    // strictly, it stops the run and points to the primer FASTA
+    let error = define_amplicons_checked(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &no_overrides,
+        "_LEFT",
+        "_RIGHT",
+        true,
+    )
+    .await
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("amp1_LEFT"));
+    assert!(!error.contains("amp1_RIGHT"));
+    assert!(error.contains("--primer-fasta"));
+
+    // an override for the masked primer satisfies even the strict check
+    let overrides = HashMap::from([("amp1_LEFT".to_string(), "TGGAGGAT".to_string())]);
+    let overridden = define_amplicons_checked(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &overrides,
+        "_LEFT",
+        "_RIGHT",
+        true,
+    )
+    .await?;
+    assert_eq!(overridden.scheme.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reverse_complement_collisions_are_reported() -> Result<()> {
+    assert!(test_scheme().reverse_complement_collisions().is_empty());
+
+    // the second amplicon's forward primer is the reverse complement of the first amplicon's
+    // reverse primer, so each is found wherever the other is
+    let colliding = AmpliconScheme {
+        scheme: vec![
+            primers("amplicon_1", "TGGAGGAT", "CCATAGTA"),
+            primers("amplicon_2", "TACTATGG", "GCAAACGT"),
+        ],
+    };
+    assert_eq!(
+        colliding.reverse_complement_collisions(),
+        [PrimerCollision {
+            first: "amplicon_1 reverse".to_string(),
+            second: "amplicon_2 forward".to_string(),
+        }]
+    );
+
+    // the same collision in a scheme defined from a BED file and its reference
+    let dir = tempfile::tempdir()?;
+    let reference = format!(
+        ">ref\n{}TGGAGGAT{}TACTATGG{}CCATAGTA{}ACGTTTGC{}\n",
+        "A".repeat(8),
+        "GATCGATCGATC",
+        "A".repeat(8),
+        "GATCGATCGATC",
+        "A".repeat(8)
+    );
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(&ref_path, reference)?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t8\t16\tamp1_LEFT\nref\t28\t36\tamp1_RIGHT\nref\t44\t52\tamp2_LEFT\nref\t64\t72\tamp2_RIGHT\n",
+    )?;
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    let no_overrides = HashMap::new();
+
+    // by default, the collision is only warned about
+    let lenient = define_amplicons_checked(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &no_overrides,
+        "_LEFT",
+        "_RIGHT",
+        false,
+    )
+    .await?;
+    assert!(!lenient.reverse_complement_collisions().is_empty());
+
+    // strictly, it stops the run and names both primers
+    let error = define_amplicons_checked(
+        Bed.read_primers(&bed_path)?,
+        &ref_dict,
+        &no_overrides,
+        "_LEFT",
+        "_RIGHT",
+        true,
+    )
+    .await
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("amp1 reverse"));
+    assert!(error.contains("amp2 forward"));
+    assert!(!error.contains("amp1 forward"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fasta_descriptions_do_not_break_bed_lookups() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // the FASTA headers carry descriptions, separated by a space and by a tab, while the BED
+    // file uses the bare names
+    let ref_path = dir.path().join("ref.fasta");
+    std::fs::write(
+        &ref_path,
+        ">seg_a Influenza A virus segment 4\nAAAATGGAGGATGATCGATCGATCTACTATGGAAAA\n>seg_b\tsegment 6\nAAAACCCCAAAAGATCGATCGATCACGTTTTTAAAA\n",
+    )?;
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "seg_a\t4\t12\tamp1_LEFT\nseg_a\t24\t32\tamp1_RIGHT\nseg_b\t4\t12\tamp2_LEFT\nseg_b\t24\t32\tamp2_RIGHT\n",
+    )?;
+    assert_eq!(ref_name_key(b"seg_a Influenza A virus"), b"seg_a");
+    assert_eq!(ref_name_key(b"seg_b\tsegment 6"), b"seg_b");
+
+    let ref_dict = ref_to_dict(&mut Fasta.read_ref(&ref_path)?).await?;
+    assert!(ref_dict.has_sequence(b"seg_a"));
+    assert!(ref_dict.has_sequence(b"seg_b"));
+    assert_eq!(
+        ref_dict.lookup(b"seg_a", 4, 12),
+        Some(b"TGGAGGAT".as_slice())
+    );
+
+    // whole references and primer windows both find every primer under its bare name
+    let windows = refs_to_windows(
+        &mut [Fasta.read_ref(&ref_path)?],
+        Bed.read_primers(&bed_path)?,
+    )
+    .await?;
+    let eager =
+        define_amplicons(Bed.read_primers(&bed_path)?, &ref_dict, "_LEFT", "_RIGHT").await?;
+    let lazy = define_amplicons(Bed.read_primers(&bed_path)?, &windows, "_LEFT", "_RIGHT").await?;
+    assert_eq!(eager.scheme.len(), 2);
+    assert_eq!(eager, lazy);
+    let amp2 = eager
+        .scheme
+        .iter()
+        .find(|pair| pair.amplicon == "amp2")
+        .expect("amp2 in the scheme");
+    assert_eq!(amp2.fwd, "CCCCAAAA");
+    assert_eq!(amp2.rev, "ACGTTTTT");
+
+    Ok(())
+}
+
+#[test]
+fn test_primer_table_reverse_primer_conventions() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let table_path = dir.path().join("pairs.tsv");
+    std::fs::write(
+        &table_path,
+        "amp1_LEFT\tamp1_RIGHT\namp2_LEFT\tamp2_RIGHT\n",
+    )?;
+    let table_scheme = |fasta: &str, rev_as_appears: bool| -> Result<AmpliconScheme> {
+        let fasta_path = dir.path().join("primers.fasta");
+        std::fs::write(&fasta_path, fasta)?;
+        let primer_seqs = read_primer_fasta(&mut Fasta.read_ref(&fasta_path)?)?;
+        Ok(define_amplicons_from_table(&table_path, &primer_seqs, "_LEFT", rev_as_appears)?.scheme)
+    };
+
+    // reverse primers written 5' to 3' are taken as they are
+    let five_to_three = table_scheme(PRIMER_FASTA, false)?;
+    assert_eq!(five_to_three.scheme[0].rev, "CCATAGTA");
+    assert_eq!(five_to_three.scheme[0].rev_rc, "TACTATGG");
+
+    // reverse primers stored as they appear in reads are only reverse complemented on request
+    let as_appears = PRIMER_FASTA
+        .replace("CCATAGTA", "TACTATGG")
+        .replace("AAAAACGT", "ACGTTTTT");
+    let unconverted = table_scheme(&as_appears, false)?;
+    assert_eq!(unconverted.scheme[0].rev, "TACTATGG");
+    assert_ne!(unconverted, five_to_three);
+    let converted = table_scheme(&as_appears, true)?;
+    assert_eq!(converted.scheme[0].rev, "CCATAGTA");
+    assert_eq!(converted.scheme[1].rev, "AAAAACGT");
+    assert_eq!(converted, five_to_three);
+    assert_eq!(
+        converted.hash_amplicon_scheme()?,
+        five_to_three.hash_amplicon_scheme()?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rev_as_appears_matches_five_to_three_scheme() -> Result<()> {
+    // the same scheme with its reverse primers written as they appear in forward-strand reads
+    let as_appears = || AmpliconScheme {
+        scheme: test_scheme()
+            .scheme
+            .into_iter()
+            .map(|pair| {
+                PossiblePrimers::new(pair.amplicon, pair.fwd, pair.fwd_rc, pair.rev_rc, pair.rev)
+            })
+            .collect(),
+    };
+    let five_to_three = test_scheme();
+    let converted = as_appears().with_rev_as_appears();
+    assert_eq!(converted, five_to_three);
+    assert_eq!(
+        converted.hash_amplicon_scheme()?,
+        five_to_three.hash_amplicon_scheme()?
+    );
+
+    // both conventions find and trim the same amplicon in the same read, even when primers
+    // must sit at the ends of the read they are expected at
+    let read = amplicon_read("read", &five_to_three.scheme[0], "GATCGATCGATC");
+    let anchored = MatcherOptions {
+        anchor_window: Some(4),
+        ..MatcherOptions::default()
+    };
+    for scheme in [&five_to_three, &converted] {
+        let hit = read.find_amplicon(&scheme.scheme).await.unwrap();
+        assert_eq!(hit.rev, "TACTATGG");
+        let trimmed = read.clone().trim_to_amplicon(hit).await?.unwrap();
+        assert_eq!(trimmed.sequence(), b"GATCGATCGATC");
+        let matcher = AmpliconMatcher::from_scheme(scheme, anchored);
+        assert!(matcher.match_read(read.sequence()).is_some());
+    }
+
+    // left unconverted, the reverse primer is expected at the wrong end of the read
+    let matcher = AmpliconMatcher::from_scheme(&as_appears(), anchored);
+    assert!(matcher.match_read(read.sequence()).is_none());
+
+    Ok(())
+}
@@ -0,0 +1,2642 @@
+mod common;
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use amplicon_tk::{
+    index::Index,
+    io::{
+        expand_input_glob, io_selector, Bed, Fastq, FastqGz, InputType, OutputType, PrimerReader,
+        Sam, ScratchDir,
+    },
+    primers::expected_insert_lens,
+    reads::{
+        annotate_bam, count_primers, count_records, default_concurrency, merge_paired_inputs,
+        per_input_file_name, sample_name_for_input, sort_input, sort_records, trim_inputs,
+        trim_inputs_per_input, BarcodeDemux, CollapsedReads, DedupKey, FilterSettings,
+        GcLengthReport, GroupedReads, InsertLenCheck, LengthBins, LengthGate, OnError,
+        PrimerRegions, Progress, ReadOutcome, ReadReport, TrimSettings, Trimming, AMPLICON_TAG,
+        LENGTH_GATE_FLANK,
+    },
+    record::{
+        find_confident_primer_match, BoundaryRefinement, ExactMatcher, IdentityTransform,
+        MatchAlgorithm, MatchCache, MatcherOptions, MergeSettings, QualityOffset, RecordTransform,
+        DEFAULT_MIN_INSERT_LEN,
+    },
+};
+use color_eyre::eyre::Result;
+use common::{amplicon_read, read_output, revcomp, sync_reader, test_scheme, to_fastq};
+use flate2::{write::GzEncoder, Compression};
+use futures::TryStreamExt;
+use noodles::fastq::Record as FastqRecord;
+use noodles::sam::alignment::RecordBuf;
+use regex::Regex;
+
+const FASTQ: &str = "\
+@matched_1
+TGTTTCCACTGGAGGATACTCACCCCTCTTGCACTCAAGTTAAACAGTTTCCAAAGCGTACTATGGTTAAGCCACAGCCT
++
+445656:11DHHGJPSHFDCDDOMIBD@?@DDD><<<<FFLDFGIJCIKJIKFGSOSCC=;98782-,-..112299:B=
+@unmatched_1
+AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+@matched_2
+GGGGCCCCAAAATTTTACGTACGTACGTGCAACGTTTTTGGAC
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+";
+
+fn write_input(dir: &Path) -> Result<std::path::PathBuf> {
+    let input = dir.join("reads.fastq");
+    std::fs::write(&input, FASTQ)?;
+    Ok(input)
+}
+
+#[tokio::test]
+async fn test_count_only_matches_trim_report() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    let trimmed_path = dir.path().join("trimmed.fastq");
+    let trim_stats = Fastq
+        .trim(
+            &input,
+            &trimmed_path,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+
+    let counted_path = dir.path().join("counted.fastq");
+    let count_settings = TrimSettings {
+        count_only: true,
+        ..TrimSettings::default()
+    };
+    let count_stats = Fastq
+        .trim(
+            &input,
+            &counted_path,
+            test_scheme(),
+            None,
+            &count_settings,
+            &IdentityTransform,
+        )
+        .await?;
+
+    assert_eq!(trim_stats, count_stats);
+    assert_eq!(count_stats.total, 3);
+    assert_eq!(count_stats.matched, 2);
+    assert_eq!(count_stats.unmatched, 1);
+    assert_eq!(count_stats.per_amplicon.get("amplicon_1"), Some(&1));
+    assert_eq!(count_stats.per_amplicon.get("amplicon_2"), Some(&1));
+    assert!(trimmed_path.exists());
+    assert!(
+        !counted_path.exists(),
+        "Count-only mode should not create an output file."
+    );
+
+    Ok(())
+}
+
+struct UppercaseNames;
+
+impl RecordTransform for UppercaseNames {
+    fn transform(&self, record: &mut FastqRecord) {
+        record.name_mut().make_ascii_uppercase();
+    }
+}
+
+#[tokio::test]
+async fn test_record_transform_applied_before_write() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+    let output = dir.path().join("trimmed.fastq");
+
+    Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &UppercaseNames,
+        )
+        .await?;
+
+    let names: Vec<Vec<u8>> = read_output(&output)?
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"MATCHED_1".to_vec(), b"MATCHED_2".to_vec()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_input_glob_merges_matching_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    for sample in ["c", "a", "b"] {
+        std::fs::write(dir.path().join(format!("{sample}.fastq")), FASTQ)?;
+    }
+    std::fs::write(dir.path().join("ignored.txt"), "not reads")?;
+
+    let pattern = format!("{}/*.fastq", dir.path().display());
+    let inputs = expand_input_glob(&pattern)?;
+    let names: Vec<_> = inputs.iter().filter_map(|path| path.file_name()).collect();
+    assert_eq!(names, vec!["a.fastq", "b.fastq", "c.fastq"]);
+
+    let output = dir.path().join("merged.fastq");
+    let stats = trim_inputs(
+        &inputs,
+        &output,
+        &test_scheme(),
+        &None,
+        &TrimSettings::default(),
+        &IdentityTransform,
+    )
+    .await?;
+
+    assert_eq!(stats.total, 9);
+    assert_eq!(stats.retained, 6);
+    assert_eq!(read_output(&output)?.len(), 6);
+
+    let empty_pattern = format!("{}/*.fastq.gz", dir.path().display());
+    assert!(expand_input_glob(&empty_pattern).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_formats_fan_out_identical_records() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    let settings = TrimSettings {
+        output_formats: vec![OutputType::FASTQ(Fastq), OutputType::FASTQGZ(FastqGz)],
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &test_scheme(),
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 2);
+
+    let plain = read_output(&dir.path().join("trimmed.fastq"))?;
+    let decoder =
+        flate2::read::GzDecoder::new(std::fs::File::open(dir.path().join("trimmed.fastq.gz"))?);
+    let mut reader = noodles::fastq::Reader::new(std::io::BufReader::new(decoder));
+    let compressed = reader.records().collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(plain.len(), 2);
+    assert_eq!(plain, compressed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sam_output_parses_back_as_unaligned_records() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    let settings = TrimSettings {
+        output_formats: vec![OutputType::FASTQ(Fastq), OutputType::SAM(Sam)],
+        ..TrimSettings::default()
+    };
+    trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &test_scheme(),
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    let fastq = read_output(&dir.path().join("trimmed.fastq"))?;
+
+    let mut reader = tokio::fs::File::open(dir.path().join("trimmed.sam"))
+        .await
+        .map(tokio::io::BufReader::new)
+        .map(noodles::sam::AsyncReader::new)?;
+    let header = reader.read_header().await?;
+    assert!(header.reference_sequences().is_empty());
+    let records: Vec<RecordBuf> = reader.record_bufs(&header).try_collect().await?;
+    assert_eq!(records.len(), fastq.len());
+    for (sam, fastq) in records.iter().zip(&fastq) {
+        assert!(sam.flags().is_unmapped());
+        assert_eq!(
+            sam.name()
+                .map(|name| String::from_utf8_lossy(name.as_ref()).into_owned()),
+            Some(String::from_utf8_lossy(fastq.name()).into_owned())
+        );
+        assert_eq!(sam.sequence().as_ref(), fastq.sequence());
+
+        // SAM holds raw Phred scores rather than their Phred+33 characters
+        let phred: Vec<u8> = fastq
+            .quality_scores()
+            .iter()
+            .map(|score| score - 33)
+            .collect();
+        assert_eq!(sam.quality_scores().as_ref(), phred.as_slice());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sam_output_decodes_quality_offset_and_keeps_descriptions() -> Result<()> {
+    use noodles::sam::alignment::{record::data::field::Tag, record_buf::data::field::Value};
+
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // a Phred+64 read, scoring 40 at every base, with a description after its name
+    let template = amplicon_read("read_1", &scheme.scheme[0], "GATCGATCGATC");
+    let read = FastqRecord::new(
+        noodles::fastq::record::Definition::new("read_1", "sample=a barcode=07"),
+        template.sequence().to_vec(),
+        vec![b'h'; template.sequence().len()],
+    );
+    let input = dir.path().join("reads.fastq");
+    let mut writer = noodles::fastq::io::Writer::new(std::fs::File::create(&input)?);
+    writer.write_record(&read)?;
+    drop(writer);
+
+    let settings = TrimSettings {
+        output_formats: vec![OutputType::SAM(Sam)],
+        quality_offset: QualityOffset::Phred64,
+        ..TrimSettings::default()
+    };
+    trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+
+    let mut reader = tokio::fs::File::open(dir.path().join("trimmed.sam"))
+        .await
+        .map(tokio::io::BufReader::new)
+        .map(noodles::sam::AsyncReader::new)?;
+    let header = reader.read_header().await?;
+    let records: Vec<RecordBuf> = reader.record_bufs(&header).try_collect().await?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].sequence().as_ref(), b"GATCGATCGATC");
+    assert_eq!(records[0].quality_scores().as_ref(), [40; 12].as_slice());
+    assert_eq!(
+        records[0].data().get(&Tag::COMMENT),
+        Some(&Value::String("sample=a barcode=07".into()))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_small_outputs_are_written_uncompressed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    let settings = TrimSettings {
+        uncompressed_threshold: Some(2),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &test_scheme(),
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 2);
+
+    assert_eq!(read_output(&dir.path().join("trimmed.fastq"))?.len(), 2);
+    assert!(!dir.path().join("trimmed.fastq.gz").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_outputs_switch_to_gzip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    // trim once uncompressed to compare against
+    let plain_path = dir.path().join("plain.fastq");
+    trim_inputs(
+        std::slice::from_ref(&input),
+        &plain_path,
+        &test_scheme(),
+        &None,
+        &TrimSettings::default(),
+        &IdentityTransform,
+    )
+    .await?;
+
+    let settings = TrimSettings {
+        uncompressed_threshold: Some(1),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &test_scheme(),
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 2);
+    assert!(!dir.path().join("trimmed.fastq").exists());
+
+    // the records buffered before the switch are compressed along with the rest
+    let decoder =
+        flate2::read::GzDecoder::new(std::fs::File::open(dir.path().join("trimmed.fastq.gz"))?);
+    let mut reader = noodles::fastq::Reader::new(std::io::BufReader::new(decoder));
+    let compressed = reader.records().collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(compressed.len(), 2);
+    assert_eq!(compressed, read_output(&plain_path)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reads_per_file_rolls_over_to_numbered_chunks() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let reads: Vec<FastqRecord> = (0..25)
+        .map(|i| amplicon_read(&format!("read_{i}"), &scheme.scheme[0], "ACGTACGTACGT"))
+        .collect();
+    let input = dir.path().join("reads.fastq.gz");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(to_fastq(&reads).as_bytes())?;
+    std::fs::write(&input, encoder.finish()?)?;
+
+    let settings = TrimSettings {
+        reads_per_file: Some(10),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq.gz"),
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 25);
+    assert!(!dir.path().join("trimmed.fastq.gz").exists());
+    assert!(!dir.path().join("trimmed.003.fastq.gz").exists());
+
+    // every chunk is a complete gzip stream, and the reads stay in order across them
+    let mut names = Vec::new();
+    for (chunk, expected) in [(0, 10), (1, 10), (2, 5)] {
+        let path = dir.path().join(format!("trimmed.{chunk:03}.fastq.gz"));
+        let decoder = flate2::read::GzDecoder::new(std::fs::File::open(path)?);
+        let mut reader = noodles::fastq::Reader::new(std::io::BufReader::new(decoder));
+        let records = reader.records().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(records.len(), expected);
+        names.extend(records.iter().map(|record| record.name().to_vec()));
+    }
+    let expected: Vec<Vec<u8>> = reads.iter().map(|read| read.name().to_vec()).collect();
+    assert_eq!(names, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_split_by_length_bins_trimmed_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let reads: Vec<FastqRecord> = [150, 300, 500]
+        .into_iter()
+        .map(|len| {
+            amplicon_read(
+                &format!("len_{len}"),
+                &scheme.scheme[0],
+                &"GA".repeat(len / 2),
+            )
+        })
+        .collect();
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let settings = TrimSettings {
+        length_bins: Some(LengthBins::new(vec![200, 400, 600])?),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &dir.path().join("trimmed.fastq"),
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 3);
+
+    // every read lands in the one file for its bin, with nothing left in a single output
+    let binned = |label: &str| -> Result<Vec<String>> {
+        Ok(
+            read_output(&dir.path().join(format!("trimmed.{label}.fastq")))?
+                .iter()
+                .map(|record| String::from_utf8_lossy(record.name()).to_string())
+                .collect(),
+        )
+    };
+    assert_eq!(binned("200-400")?, ["len_300"]);
+    assert_eq!(binned("400-600")?, ["len_500"]);
+    assert_eq!(binned("other")?, ["len_150"]);
+    assert!(!dir.path().join("trimmed.fastq").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_barcodes_in_read_names_demultiplex_output() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let reads = [
+        ("r1", "barcode=BC07", 0),
+        ("r2", "barcode=BC12", 1),
+        ("r3", "barcode=BC07", 1),
+        ("r4", "runid=1", 0),
+    ];
+    let fastq: String = reads
+        .iter()
+        .map(|(name, description, amplicon)| {
+            let read = amplicon_read(name, &scheme.scheme[*amplicon], "GATCGATCGATCGA");
+            format!(
+                "@{name} {description}\n{}\n+\n{}\n",
+                String::from_utf8_lossy(read.sequence()),
+                String::from_utf8_lossy(read.quality_scores())
+            )
+        })
+        .collect();
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, fastq)?;
+
+    let barcode = Regex::new(r"barcode=(\S+)")?;
+    for (output, by_amplicon) in [("trimmed.fastq", false), ("by_amplicon.fastq", true)] {
+        let output = dir.path().join(output);
+        let settings = TrimSettings {
+            barcode_demux: Some(Arc::new(Mutex::new(BarcodeDemux::new(
+                barcode.clone(),
+                by_amplicon,
+                &output,
+            )))),
+            ..TrimSettings::default()
+        };
+        let stats = trim_inputs(
+            std::slice::from_ref(&input),
+            &output,
+            &scheme,
+            &None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+        assert_eq!(stats.retained, 4);
+        assert!(!output.exists());
+    }
+
+    // each barcode gets its own file, and reads without one are unclassified
+    let demuxed = |file: &str| -> Result<Vec<String>> {
+        Ok(read_output(&dir.path().join(file))?
+            .iter()
+            .map(|record| String::from_utf8_lossy(record.name()).to_string())
+            .collect())
+    };
+    assert_eq!(demuxed("trimmed.BC07.fastq")?, ["r1", "r3"]);
+    assert_eq!(demuxed("trimmed.BC12.fastq")?, ["r2"]);
+    assert_eq!(demuxed("trimmed.unclassified.fastq")?, ["r4"]);
+    assert_eq!(demuxed("by_amplicon.BC07.amplicon_1.fastq")?, ["r1"]);
+    assert_eq!(demuxed("by_amplicon.BC07.amplicon_2.fastq")?, ["r3"]);
+    assert_eq!(demuxed("by_amplicon.BC12.amplicon_2.fastq")?, ["r2"]);
+    assert_eq!(demuxed("by_amplicon.unclassified.fastq")?, ["r4"]);
+
+    Ok(())
+}
+
+fn write_primer_files(dir: &Path) -> Result<(PathBuf, PathBuf)> {
+    let table = dir.join("primers.tsv");
+    std::fs::write(
+        &table,
+        "amplicon_1_LEFT\tamplicon_1_RIGHT\namplicon_2_LEFT\tamplicon_2_RIGHT\n",
+    )?;
+    let primer_fasta = dir.join("primers.fasta");
+    std::fs::write(
+        &primer_fasta,
+        ">amplicon_1_LEFT\nTGGAGGAT\n>amplicon_1_RIGHT\nCCATAGTA\n>amplicon_2_LEFT\nCCCCAAAA\n>amplicon_2_RIGHT\nAAAAACGT\n",
+    )?;
+    Ok((table, primer_fasta))
+}
+
+fn trim_from_stdin(
+    dir: &Path,
+    stdin_bytes: &[u8],
+    extra_args: &[&str],
+) -> Result<std::process::Output> {
+    let (table, primer_fasta) = write_primer_files(dir)?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amplicon-tk"))
+        .current_dir(dir)
+        .args(["trim", "-i", "-", "--primer-table"])
+        .arg(&table)
+        .arg("--primer-fasta")
+        .arg(&primer_fasta)
+        .args(["-o", "from_stdin"])
+        .args(extra_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("piped stdin")
+        .write_all(stdin_bytes)?;
+
+    Ok(child.wait_with_output()?)
+}
+
+#[test]
+fn test_trim_reads_from_stdin() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let output = trim_from_stdin(dir.path(), FASTQ.as_bytes(), &[])?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let names: Vec<Vec<u8>> = read_output(&dir.path().join("from_stdin.fastq"))?
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"matched_1".to_vec(), b"matched_2".to_vec()]);
+
+    // gzip-compressed input on stdin is detected from its magic bytes
+    let gz_dir = tempfile::tempdir()?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(FASTQ.as_bytes())?;
+    let output = trim_from_stdin(gz_dir.path(), &encoder.finish()?, &[])?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        read_output(&gz_dir.path().join("from_stdin.fastq"))?.len(),
+        2
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_scratch_dir_lives_in_temp_dir_until_dropped() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let scratch = ScratchDir::create(Some(temp_dir.path()))?;
+    let scratch_path = scratch.path().to_path_buf();
+    assert_eq!(scratch_path.parent(), Some(temp_dir.path()));
+
+    // a second scratch directory never collides with the first
+    let other = ScratchDir::create(Some(temp_dir.path()))?;
+    assert_ne!(other.path(), scratch_path);
+
+    std::fs::write(scratch_path.join("intermediate.fastq"), FASTQ)?;
+    drop(scratch);
+    assert!(!scratch_path.exists());
+    assert!(temp_dir.path().exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_two_pass_freq_spools_stdin_to_temp_dir() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let temp_dir = dir.path().join("scratch");
+    let output = trim_from_stdin(
+        dir.path(),
+        FASTQ.as_bytes(),
+        &["--two-pass-freq", "--temp-dir", temp_dir.to_str().unwrap()],
+    )?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // both passes read the spooled copy, which was removed once the run succeeded
+    assert_eq!(read_output(&dir.path().join("from_stdin.fastq"))?.len(), 2);
+    assert!(temp_dir.exists());
+    assert_eq!(std::fs::read_dir(&temp_dir)?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_no_index_trims_without_reading_an_index() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (table, primer_fasta) = write_primer_files(dir.path())?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // a rare variant alongside a common one, and an index next to the input that can't be read
+    let reads = vec![
+        amplicon_read("common_1", amp1, "ACACACACAC"),
+        amplicon_read("common_2", amp1, "ACACACACAC"),
+        amplicon_read("common_3", amp1, "ACACACACAC"),
+        amplicon_read("rare_1", amp1, "ACACTCACAC"),
+        amplicon_read("other_1", amp2, "GTGTGTGTGT"),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+    std::fs::write(dir.path().join("reads.fastq.ampidx"), "not an index")?;
+
+    let trim = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_amplicon-tk"))
+            .current_dir(dir.path())
+            .args(["trim", "-i"])
+            .arg(&input)
+            .arg("--primer-table")
+            .arg(&table)
+            .arg("--primer-fasta")
+            .arg(&primer_fasta)
+            .args(["-o", "trimmed"])
+            .args(extra_args)
+            .output()
+    };
+
+    // the index alongside the input is normally read, and fails to load
+    assert!(!trim(&[])?.status.success());
+
+    let output = trim(&["--no-index"])?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let names: Vec<String> = read_output(&dir.path().join("trimmed.fastq"))?
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect();
+    assert_eq!(
+        names,
+        ["common_1", "common_2", "common_3", "rare_1", "other_1"]
+    );
+
+    // frequency filters make no sense without an index
+    assert!(!trim(&["--no-index", "--min-freq", "0.5"])?.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chimeric_reads_are_counted_and_dropped() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+    let seq = format!("GGGG{}GATCGATCGATC{}CCCC", amp1.fwd, amp2.rev_rc);
+    let chimera = format!("@chimera\n{}\n+\n{}\n", seq, "I".repeat(seq.len()));
+
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, format!("{FASTQ}{chimera}"))?;
+    let output = dir.path().join("trimmed.fastq");
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+
+    assert_eq!(stats.total, 4);
+    assert_eq!(stats.chimeric, 1);
+    assert_eq!(stats.unmatched, 1);
+    assert_eq!(stats.retained, 2);
+    assert_eq!(read_output(&output)?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_short_inserts_are_counted_and_dropped() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+
+    // primers that abut, as in a dimer, primers around a single base, and an insert of exactly
+    // the default minimum length
+    let threshold = "GATCGATCGA";
+    assert_eq!(threshold.len(), DEFAULT_MIN_INSERT_LEN);
+    let reads = vec![
+        amplicon_read("empty", amp1, ""),
+        amplicon_read("one", amp1, "G"),
+        amplicon_read("threshold", amp1, threshold),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    // even the smallest minimum still drops reads with no insert at all
+    for (min_insert_len, kept) in [
+        (None, vec!["threshold"]),
+        (Some(1), vec!["one", "threshold"]),
+    ] {
+        let output = dir.path().join(format!("trimmed_{min_insert_len:?}.fastq"));
+        let settings = TrimSettings {
+            min_insert_len,
+            ..TrimSettings::default()
+        };
+        let stats = Fastq
+            .trim(
+                &input,
+                &output,
+                test_scheme(),
+                None,
+                &settings,
+                &IdentityTransform,
+            )
+            .await?;
+        assert_eq!(stats.too_short, (reads.len() - kept.len()) as u64);
+        assert_eq!(stats.unmatched, 0);
+        assert_eq!(stats.retained, kept.len() as u64);
+
+        let names: Vec<String> = read_output(&output)?
+            .iter()
+            .map(|record| String::from_utf8_lossy(record.name()).to_string())
+            .collect();
+        assert_eq!(names, kept);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_primer_regions_and_insert_reconstruct_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // a forward read, a reverse read, a read whose insert starts with a low-quality base that
+    // boundary refinement trims away, and a read that is dropped
+    let forward = amplicon_read("forward", amp1, "GATCGATCGATC");
+    let reverse = raw_read(
+        "reverse",
+        &String::from_utf8(common::revcomp(
+            amplicon_read("reverse", amp2, "TTGACCATTGAC").sequence(),
+        ))?,
+    );
+    let mut refined = amplicon_read("refined", amp1, "CATCGATCGATC");
+    refined.quality_scores_mut()[12] = b'#';
+    let reads = vec![
+        forward,
+        reverse,
+        refined,
+        raw_read("dropped", &"A".repeat(40)),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let regions_path = dir.path().join("ends.fastq");
+    let settings = TrimSettings {
+        refine_boundary: Some(BoundaryRefinement::default()),
+        primer_regions: Some(Arc::new(Mutex::new(PrimerRegions::create(&regions_path)?))),
+        ..TrimSettings::default()
+    };
+    let output = dir.path().join("trimmed.fastq");
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.retained, 3);
+
+    // two regions per retained read, which put back around the insert give the original read
+    let trimmed = read_output(&output)?;
+    let regions = read_output(&regions_path)?;
+    assert_eq!(regions.len(), 2 * trimmed.len());
+    assert_eq!(trimmed[2].sequence(), b"ATCGATCGATC");
+    for ((insert, ends), original) in trimmed.iter().zip(regions.chunks(2)).zip(&reads) {
+        assert_eq!(ends[0].name(), original.name());
+        assert_eq!(ends[0].description(), b"region=5prime");
+        assert_eq!(ends[1].description(), b"region=3prime");
+        let sequence = [ends[0].sequence(), insert.sequence(), ends[1].sequence()].concat();
+        assert_eq!(sequence, original.sequence());
+        let quality_scores = [
+            ends[0].quality_scores(),
+            insert.quality_scores(),
+            ends[1].quality_scores(),
+        ]
+        .concat();
+        assert_eq!(quality_scores, original.quality_scores());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_per_read_report_describes_every_read() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // a kept read, a read too long for the length filter, a chimera, and a read without primers
+    let chimera = format!("GGGG{}GATCGATCGATC{}CCCC", amp1.fwd, amp2.rev_rc);
+    let reads = vec![
+        amplicon_read("kept", amp1, "GATCGATCGATC"),
+        amplicon_read("long", amp2, &"GATC".repeat(8)),
+        raw_read("chimera", &chimera),
+        raw_read("unmatched", &"A".repeat(40)),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let report_path = dir.path().join("reads.ndjson");
+    let settings = TrimSettings {
+        read_report: Some(Arc::new(Mutex::new(ReadReport::create(&report_path)?))),
+        ..TrimSettings::default()
+    };
+    let max_len = Some(20);
+    let filters = FilterSettings::new(&None, &None, &max_len, &None, &None);
+    let stats = Fastq
+        .trim(
+            &input,
+            &dir.path().join("trimmed.fastq"),
+            test_scheme(),
+            filters,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.retained, 1);
+
+    let outcomes = std::fs::read_to_string(&report_path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<ReadOutcome>, _>>()?;
+    let expected = vec![
+        ReadOutcome {
+            read_id: "kept".to_string(),
+            matched: true,
+            amplicon: Some("amplicon_1".to_string()),
+            start: Some(12),
+            stop: Some(24),
+            trimmed_len: Some(12),
+            pass_filter: true,
+            rejection_reason: None,
+        },
+        ReadOutcome {
+            read_id: "long".to_string(),
+            matched: true,
+            amplicon: Some("amplicon_2".to_string()),
+            start: Some(12),
+            stop: Some(44),
+            trimmed_len: Some(32),
+            pass_filter: false,
+            rejection_reason: Some("len_filter".to_string()),
+        },
+        ReadOutcome {
+            read_id: "chimera".to_string(),
+            rejection_reason: Some("chimera".to_string()),
+            ..ReadOutcome::default()
+        },
+        ReadOutcome {
+            read_id: "unmatched".to_string(),
+            rejection_reason: Some("no_match".to_string()),
+            ..ReadOutcome::default()
+        },
+    ];
+    assert_eq!(outcomes, expected);
+
+    Ok(())
+}
+
+fn raw_read(name: &str, seq: &str) -> FastqRecord {
+    FastqRecord::new(
+        noodles::fastq::record::Definition::new(name, ""),
+        seq.as_bytes().to_vec(),
+        "I".repeat(seq.len()).into_bytes(),
+    )
+}
+
+#[tokio::test]
+async fn test_rejected_reads_are_tagged_with_reason() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    let complete_1 = amplicon_read("multi", amp1, "GATCGATCGATC");
+    let complete_2 = amplicon_read("multi", amp2, "GATCGATCGATC");
+    let reads = vec![
+        amplicon_read("keep_1", amp1, "GATCGATCGATC"),
+        amplicon_read("keep_2", amp1, "GATCGATCGATC"),
+        amplicon_read("rare", amp1, "GATCGATCGATA"),
+        amplicon_read("long", amp2, &"GATC".repeat(10)),
+        raw_read("too_short", &format!("GGGG{}{}CCCC", amp1.fwd, amp1.rev_rc)),
+        raw_read("no_match", &"A".repeat(40)),
+        raw_read(
+            "multi",
+            &format!(
+                "{}{}",
+                String::from_utf8_lossy(complete_1.sequence()),
+                String::from_utf8_lossy(complete_2.sequence())
+            ),
+        ),
+        raw_read(
+            "chimera",
+            &format!("GGGG{}GATCGATCGATC{}CCCC", amp1.fwd, amp2.rev_rc),
+        ),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let index = Some(
+        Fastq
+            .index(sync_reader(&input)?, test_scheme(), &input)
+            .await?,
+    );
+    let filters = FilterSettings::new(&None, &Some(2), &Some(20), &index, &None);
+
+    let rejected_path = dir.path().join("rejected.fastq");
+    let settings = TrimSettings {
+        emit_rejected: Some(rejected_path.clone()),
+        ..TrimSettings::default()
+    };
+    let output = dir.path().join("trimmed.fastq");
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            filters,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.retained, 2);
+
+    let tags: Vec<(String, String)> = read_output(&rejected_path)?
+        .iter()
+        .map(|record| {
+            (
+                String::from_utf8_lossy(record.name()).to_string(),
+                String::from_utf8_lossy(record.description()).to_string(),
+            )
+        })
+        .collect();
+    let expected = [
+        ("rare", "low_freq"),
+        ("long", "len_filter"),
+        ("too_short", "too_short"),
+        ("no_match", "no_match"),
+        ("multi", "multi_match"),
+        ("chimera", "chimera"),
+    ];
+    assert_eq!(tags.len(), expected.len());
+    for ((name, description), (expected_name, reason)) in tags.iter().zip(expected) {
+        assert_eq!(name, expected_name);
+        assert_eq!(description, &format!("rejected={reason}"));
+    }
+
+    // rejected reads are written untrimmed
+    let rejected = read_output(&rejected_path)?;
+    assert_eq!(rejected[0].sequence(), reads[2].sequence());
+
+    Ok(())
+}
+
+/// Simulates a Ctrl-C arriving while the first retained record is being processed.
+struct InterruptAfterFirst(Arc<AtomicBool>);
+
+impl RecordTransform for InterruptAfterFirst {
+    fn transform(&self, _record: &mut FastqRecord) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[tokio::test]
+async fn test_interrupted_trim_finalizes_output() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("reads.fastq.gz");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(FASTQ.as_bytes())?;
+    std::fs::write(&input, encoder.finish()?)?;
+
+    let settings = TrimSettings::default();
+    let output = dir.path().join("trimmed.fastq.gz");
+    let stats = FastqGz
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &InterruptAfterFirst(Arc::clone(&settings.interrupt)),
+        )
+        .await?;
+    assert_eq!(stats.total, 1);
+    assert_eq!(stats.retained, 1);
+
+    // the truncated output is still a complete gzip stream of valid FASTQ records
+    let decoder = flate2::read::GzDecoder::new(std::fs::File::open(&output)?);
+    let mut reader = noodles::fastq::Reader::new(std::io::BufReader::new(decoder));
+    let records = reader.records().collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].name(), b"matched_1");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concatenated_gzip_members_are_all_read() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // as if made with `cat a.fastq.gz b.fastq.gz > reads.fastq.gz`
+    let mut concatenated = Vec::new();
+    for _ in 0..2 {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(FASTQ.as_bytes())?;
+        concatenated.extend(encoder.finish()?);
+    }
+    let input = dir.path().join("reads.fastq.gz");
+    std::fs::write(&input, concatenated)?;
+    let per_member = FASTQ.lines().count() as u64 / 4;
+
+    assert_eq!(
+        count_records(std::slice::from_ref(&input)).await?,
+        Some(2 * per_member)
+    );
+
+    let output = dir.path().join("trimmed.fastq");
+    let stats = FastqGz
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.total, 2 * per_member);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_truncated_gzip_input_is_reported() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // cut a gzip stream off partway through, as an interrupted transfer would
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(FASTQ.repeat(50).as_bytes())?;
+    let compressed = encoder.finish()?;
+    let input = dir.path().join("reads.fastq.gz");
+    std::fs::write(&input, &compressed[..compressed.len() / 2])?;
+
+    let error = FastqGz
+        .trim(
+            &input,
+            &dir.path().join("trimmed.fastq"),
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("appears to be truncated"),
+        "Unexpected error: {error}"
+    );
+    assert!(error.contains("reads were read before"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_length_gate_rejects_before_matching() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+
+    // both test amplicons have 16 bases of primers, and inserts are expected to be at most 50
+    let filters = FilterSettings::new(&None, &None, &Some(50), &None, &None);
+    let gate = LengthGate::new(&scheme, &filters);
+    assert_eq!(gate.min_len, 17);
+    assert_eq!(gate.max_len, Some(50 + 16 + LENGTH_GATE_FLANK));
+    assert_eq!(LengthGate::new(&scheme, &None).max_len, None);
+
+    let reads = vec![
+        amplicon_read("kept", amp1, "GATCGATCGATC"),
+        raw_read("short_junk", "GATCGATCGA"),
+        raw_read("long_junk", &"GATC".repeat(100)),
+        raw_read("plausible_junk", &"A".repeat(40)),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    let stats = Fastq
+        .trim(
+            &input,
+            &dir.path().join("trimmed.fastq"),
+            test_scheme(),
+            filters,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.total, 4);
+    assert_eq!(stats.length_gated, 2);
+    assert_eq!(stats.unmatched, 1);
+    assert_eq!(stats.retained, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_matching_time_is_recorded_per_amplicon() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+    let output = dir.path().join("trimmed.fastq");
+
+    let untimed = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert!(untimed.matching_nanos.is_empty());
+    assert_eq!(untimed.unmatched_matching_nanos, 0);
+
+    let settings = TrimSettings {
+        time_matching: true,
+        ..TrimSettings::default()
+    };
+    let timed = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    let timed_amplicons: Vec<&String> = timed.matching_nanos.keys().collect();
+    assert_eq!(timed_amplicons, vec!["amplicon_1", "amplicon_2"]);
+    assert!(timed.matching_nanos.values().all(|nanos| *nanos > 0));
+    assert!(timed.unmatched_matching_nanos > 0);
+    assert!(timed.to_string().contains("Primer matching time"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rc_output_reverse_complements_trimmed_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+
+    let plain_path = dir.path().join("plain.fastq");
+    Fastq
+        .trim(
+            &input,
+            &plain_path,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+
+    let rc_path = dir.path().join("rc.fastq");
+    let settings = TrimSettings {
+        rc_output: true,
+        ..TrimSettings::default()
+    };
+    Fastq
+        .trim(
+            &input,
+            &rc_path,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+
+    let plain = read_output(&plain_path)?;
+    let rc = read_output(&rc_path)?;
+    assert_eq!(plain.len(), rc.len());
+    for (plain, rc) in plain.iter().zip(&rc) {
+        assert_eq!(plain.name(), rc.name());
+        assert_eq!(rc.sequence(), common::revcomp(plain.sequence()).as_slice());
+        let reversed: Vec<u8> = plain.quality_scores().iter().rev().copied().collect();
+        assert_eq!(rc.quality_scores(), reversed.as_slice());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uniquify_names_across_duplicate_inputs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let inputs: Vec<_> = ["a", "b"]
+        .iter()
+        .map(|sample| {
+            let input = dir.path().join(format!("{sample}.fastq"));
+            std::fs::write(&input, FASTQ).map(|_| input)
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    let output = dir.path().join("merged.fastq");
+    let settings = TrimSettings {
+        uniquify_names: true,
+        ..TrimSettings::default()
+    };
+    trim_inputs(
+        &inputs,
+        &output,
+        &test_scheme(),
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+
+    let names: Vec<Vec<u8>> = read_output(&output)?
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            b"matched_1_1".to_vec(),
+            b"matched_2_2".to_vec(),
+            b"matched_1_3".to_vec(),
+            b"matched_2_4".to_vec(),
+        ]
+    );
+
+    Ok(())
+}
+
+/// Simulates a bounds bug that re-extends the first matched read past the ends of the input.
+struct OverextendFirstMatch;
+
+impl RecordTransform for OverextendFirstMatch {
+    fn transform(&self, record: &mut FastqRecord) {
+        if record.name() == b"matched_1" {
+            record.sequence_mut().extend_from_slice(&[b'N'; 100]);
+            record.quality_scores_mut().extend_from_slice(&[b'!'; 100]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_validate_output_drops_overextended_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+    let output = dir.path().join("trimmed.fastq");
+    let rejected_path = dir.path().join("rejected.fastq");
+
+    let settings = TrimSettings {
+        validate_output: true,
+        emit_rejected: Some(rejected_path.clone()),
+        ..TrimSettings::default()
+    };
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &OverextendFirstMatch,
+        )
+        .await?;
+
+    assert_eq!(stats.matched, 2);
+    assert_eq!(stats.invalid, 1);
+    assert_eq!(stats.retained, 1);
+    assert!(stats.to_string().contains("Invalid reads:      1"));
+
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].name(), b"matched_2");
+
+    let rejected = read_output(&rejected_path)?;
+    let invalid = rejected
+        .iter()
+        .find(|record| record.name() == b"matched_1")
+        .expect("the overextended read is rejected");
+    assert_eq!(invalid.description(), b"rejected=invalid_output");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_per_input_output_writes_one_file_per_input() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let inputs: Vec<_> = ["sample_a", "sample_b"]
+        .iter()
+        .map(|sample| {
+            let input = dir.path().join(format!("{sample}.fastq"));
+            std::fs::write(&input, FASTQ).map(|_| input)
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    let output_dir = dir.path().join("trimmed");
+    let stats = trim_inputs_per_input(
+        &inputs,
+        &output_dir,
+        &test_scheme(),
+        &None,
+        &TrimSettings::default(),
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.total, 6);
+    assert_eq!(stats.retained, 4);
+
+    for sample in ["sample_a", "sample_b"] {
+        let output = output_dir.join(format!("{sample}.fastq"));
+        let names: Vec<Vec<u8>> = read_output(&output)?
+            .iter()
+            .map(|record| record.name().to_vec())
+            .collect();
+        assert_eq!(names, vec![b"matched_1".to_vec(), b"matched_2".to_vec()]);
+    }
+    assert_eq!(std::fs::read_dir(&output_dir)?.count(), 2);
+
+    let gz_name = per_input_file_name(
+        Path::new("runs/sample_c.fastq.gz"),
+        &InputType::FASTQGZ(FastqGz),
+    )?;
+    assert_eq!(gz_name, "sample_c.fastq.gz");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_primers_matched_over_low_quality_bases_are_rejected() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let confident = amplicon_read("confident", &scheme.scheme[0], "ACGTACGTACGT");
+
+    // the same read, but with Phred 2 calls under its forward primer
+    let mut noisy = amplicon_read("noisy", &scheme.scheme[0], "ACGTACGTACGT");
+    noisy.quality_scores_mut()[4..12].fill(b'#');
+    assert!(find_confident_primer_match(
+        noisy.sequence(),
+        noisy.quality_scores(),
+        &scheme.scheme[0].fwd,
+        &ExactMatcher,
+        20,
+        QualityOffset::Phred33,
+    )?
+    .is_none());
+    assert!(find_confident_primer_match(
+        confident.sequence(),
+        confident.quality_scores(),
+        &scheme.scheme[0].fwd,
+        &ExactMatcher,
+        20,
+        QualityOffset::Phred33,
+    )?
+    .is_some());
+
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&[noisy, confident]))?;
+    let output = dir.path().join("trimmed.fastq");
+    let settings = TrimSettings {
+        min_primer_quality: Some(20),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.retained, 1);
+    assert_eq!(stats.unmatched, 1);
+    let names: Vec<Vec<u8>> = read_output(&output)?
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"confident".to_vec()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_split_concatemers_writes_each_amplicon_as_its_own_read() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let first = amplicon_read("first", &scheme.scheme[0], "ACGTACGTACGT");
+    let second = amplicon_read("second", &scheme.scheme[1], "TTGACCATTGCA");
+
+    // the two amplicons joined end to end, with a quality score of its own for every base
+    let sequence = [first.sequence(), second.sequence()].concat();
+    let quality: Vec<u8> = (0..sequence.len()).map(|i| b'!' + (i % 40) as u8).collect();
+    let concatemer = FastqRecord::new(
+        noodles::fastq::record::Definition::new("read1", ""),
+        sequence.clone(),
+        quality.clone(),
+    );
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&[concatemer]))?;
+
+    let output = dir.path().join("trimmed.fastq");
+    let settings = TrimSettings {
+        split_concatemers: true,
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        &[input],
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+
+    // the one input record is counted once, with its pieces counted separately
+    assert_eq!(stats.total, 1);
+    assert_eq!(stats.concatemer_pieces, 2);
+    assert_eq!(stats.retained, 2);
+
+    let records = read_output(&output)?;
+    let names: Vec<&[u8]> = records.iter().map(|record| record.name()).collect();
+    assert_eq!(names, [b"read1_1".as_slice(), b"read1_2".as_slice()]);
+    assert_eq!(records[0].sequence(), b"ACGTACGTACGT");
+    assert_eq!(records[1].sequence(), b"TTGACCATTGCA");
+
+    // each piece keeps the quality scores of its own bases
+    let second_insert = first.sequence().len() + 12;
+    assert_eq!(records[0].quality_scores(), &quality[12..24]);
+    assert_eq!(
+        records[1].quality_scores(),
+        &quality[second_insert..second_insert + 12]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_plain_text_named_as_gzip_is_read_uncompressed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("reads.fastq.gz");
+    std::fs::write(&input, FASTQ)?;
+    assert!(matches!(io_selector(&input).await?, InputType::FASTQ(_)));
+    assert_eq!(
+        per_input_file_name(&input, &InputType::FASTQ(Fastq))?,
+        "reads.fastq"
+    );
+
+    // the records are trimmed as if the input had been named as plain FASTQ
+    let output = dir.path().join("trimmed.fastq");
+    let stats = trim_inputs(
+        &[input],
+        &output,
+        &test_scheme(),
+        &None,
+        &TrimSettings::default(),
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.total, 3);
+    assert_eq!(stats.retained, 2);
+    assert_eq!(read_output(&output)?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_annotate_metrics_appends_length_and_gc() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, FASTQ.replace("@matched_2", "@matched_2 sample=a"))?;
+    let output = dir.path().join("trimmed.fastq");
+
+    let settings = TrimSettings {
+        annotate_metrics: true,
+        ..TrimSettings::default()
+    };
+    Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 2);
+    assert_eq!(written[0].description(), b"len=41 gc=0.46");
+
+    // the trimmed insert is TTTTACGTACGTACGTGCA, with 8 of its 19 bases G or C, and the
+    // existing comment is kept
+    assert_eq!(written[1].sequence(), b"TTTTACGTACGTACGTGCA");
+    assert_eq!(written[1].description(), b"sample=a len=19 gc=0.42");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keep_original_in_header_records_trim_offsets() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // a forward read and a read from the reverse strand, both 36 bases with a 12-base insert
+    let forward = amplicon_read("forward", &scheme.scheme[0], "GATCGATCGATC");
+    let reverse = raw_read(
+        "reverse",
+        &String::from_utf8(common::revcomp(
+            amplicon_read("reverse", &scheme.scheme[1], "TTGACCATTGAC").sequence(),
+        ))?,
+    );
+    let reads = vec![forward, reverse];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+    let output = dir.path().join("trimmed.fastq");
+
+    let settings = TrimSettings {
+        keep_original_in_header: true,
+        ..TrimSettings::default()
+    };
+    Fastq
+        .trim(&input, &output, scheme, None, &settings, &IdentityTransform)
+        .await?;
+
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 2);
+    assert_eq!(
+        written[0].description(),
+        b"orig_len=36 trim_start=12 trim_end=24"
+    );
+    assert_eq!(
+        written[1].description(),
+        b"orig_len=36 trim_start=12 trim_end=24"
+    );
+
+    // the recorded offsets pick the insert back out of the untrimmed read
+    for (trimmed, original) in written.iter().zip(&reads) {
+        let insert = &original.sequence()[12..24];
+        assert!(trimmed.sequence() == insert || trimmed.sequence() == common::revcomp(insert));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sample_name_is_reported_and_prefixed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // without a name, the sample is named after its input
+    let input = dir.path().join("patient_07.fastq");
+    std::fs::write(
+        &input,
+        to_fastq(&[amplicon_read("read_a", &scheme.scheme[0], "GATCGATCGATC")]),
+    )?;
+    let sample_name = sample_name_for_input(&input);
+    assert_eq!(sample_name, "patient_07");
+    assert_eq!(
+        sample_name_for_input(Path::new("runs/patient_07.fq.gz")),
+        "patient_07"
+    );
+    assert_eq!(sample_name_for_input(Path::new("-")), "stdin");
+
+    let output = dir.path().join("trimmed.fastq");
+    let settings = TrimSettings {
+        sample_name: Some(sample_name),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        std::slice::from_ref(&input),
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+
+    // the sample name lands in the summary and the stats JSON, but not in read names by default
+    assert_eq!(stats.sample_name.as_deref(), Some("patient_07"));
+    assert!(stats
+        .to_string()
+        .starts_with("Sample:             patient_07\n"));
+    let json = serde_json::to_value(&stats)?;
+    assert_eq!(json["sample_name"], "patient_07");
+    assert_eq!(read_output(&output)?[0].name(), b"read_a");
+
+    // with the flag, it is prepended to every written read name
+    let settings = TrimSettings {
+        sample_name: Some("cohort_s1".to_string()),
+        prefix_sample_name: true,
+        ..TrimSettings::default()
+    };
+    trim_inputs(
+        std::slice::from_ref(&input),
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(read_output(&output)?[0].name(), b"cohort_s1:read_a");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_implausible_inserts_are_counted_or_dropped() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // the BED coordinates leave 40 bases between the first amplicon's primers
+    let bed_path = dir.path().join("primers.bed");
+    std::fs::write(
+        &bed_path,
+        "ref\t10\t18\tamplicon_1_LEFT\nref\t58\t66\tamplicon_1_RIGHT\n",
+    )?;
+    let expected = expected_insert_lens(Bed.read_primers(&bed_path)?, "_LEFT", "_RIGHT")?;
+    assert_eq!(expected.get("amplicon_1"), Some(&40));
+
+    // the second read carries a copy of the reverse primer 12 bases into its insert, which
+    // is matched in place of the real one and leaves an implausibly short insert
+    let true_insert = "ACGTACGTACGT".to_string() + "GATC".repeat(7).as_str();
+    let spurious_insert = "ACGTACGTACGT".to_string() + "TACTATGG" + "GATC".repeat(5).as_str();
+    let reads = [
+        amplicon_read("plausible", &scheme.scheme[0], &true_insert),
+        amplicon_read("spurious", &scheme.scheme[0], &spurious_insert),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+    let output = dir.path().join("trimmed.fastq");
+
+    // by default, suspicious inserts are only counted
+    let check = InsertLenCheck {
+        expected,
+        tolerance: 5,
+        drop: false,
+    };
+    let settings = TrimSettings {
+        insert_len_check: Some(check.clone()),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        std::slice::from_ref(&input),
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.implausible_insert, 1);
+    assert_eq!(stats.retained, 2);
+    assert!(stats.to_string().contains("Suspicious inserts: 1"));
+
+    // when dropping, only the plausible read is written
+    let settings = TrimSettings {
+        insert_len_check: Some(InsertLenCheck {
+            drop: true,
+            ..check
+        }),
+        ..TrimSettings::default()
+    };
+    let stats = trim_inputs(
+        std::slice::from_ref(&input),
+        &output,
+        &scheme,
+        &None,
+        &settings,
+        &IdentityTransform,
+    )
+    .await?;
+    assert_eq!(stats.implausible_insert, 1);
+    assert_eq!(stats.filtered, 1);
+    assert_eq!(stats.retained, 1);
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].name(), b"plausible");
+    assert_eq!(written[0].sequence().len(), 40);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_only_amplicon_restricts_output() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+    let output = dir.path().join("trimmed.fastq");
+
+    let scheme = test_scheme().restrict_to_amplicon("amplicon_2")?;
+    assert_eq!(scheme.scheme.len(), 1);
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            scheme,
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+
+    assert_eq!(stats.retained, 1);
+    assert_eq!(
+        stats.per_amplicon.keys().collect::<Vec<_>>(),
+        vec!["amplicon_2"]
+    );
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].name(), b"matched_2");
+
+    assert!(test_scheme().restrict_to_amplicon("amplicon_3").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_progress_reports_once_per_interval() {
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+
+    // nothing is reported until a full interval has passed since the last report
+    let mut progress = Progress::new(Duration::from_secs(5), Some(100), start);
+    assert!(progress.advance(at(1)).is_none());
+    assert!(progress.advance(at(4)).is_none());
+    assert_eq!(
+        progress.advance(at(5)).as_deref(),
+        Some("Processed 3 of 100 reads (3.0%), 0.6 reads/s, ETA 162s")
+    );
+    assert!(progress.advance(at(6)).is_none());
+    assert!(progress.advance(at(9)).is_none());
+    assert!(progress.advance(at(10)).is_some());
+
+    // without a total, only throughput is reported
+    let mut progress = Progress::new(Duration::from_secs(5), None, start);
+    assert!(progress.advance(at(2)).is_none());
+    assert_eq!(
+        progress.advance(at(5)).as_deref(),
+        Some("Processed 2 reads, 0.4 reads/s")
+    );
+}
+
+#[tokio::test]
+async fn test_count_primers_counts_each_primer_on_its_own() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let forward = amplicon_read("forward", &scheme.scheme[0], "ACGTACGTACGTAC");
+    let reverse = raw_read("reverse", &String::from_utf8(revcomp(forward.sequence()))?);
+    let reads = [
+        forward,
+        reverse,
+        raw_read("lone_primer", "TTTTTTCCCCAAAATTTTTT"),
+        raw_read("no_primers", &"A".repeat(30)),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    // primers are counted in either orientation, whether or not their partner is found
+    let scan = count_primers(&[input], &scheme, &ExactMatcher).await?;
+    assert_eq!(scan.total, 4);
+    let counts: Vec<(&str, &str, u64)> = scan
+        .primers
+        .iter()
+        .map(|count| (count.amplicon.as_str(), count.primer.as_str(), count.reads))
+        .collect();
+    assert_eq!(
+        counts,
+        [
+            ("amplicon_1", "forward", 2),
+            ("amplicon_1", "reverse", 2),
+            ("amplicon_2", "forward", 1),
+            ("amplicon_2", "reverse", 0),
+        ]
+    );
+
+    let mut tsv = Vec::new();
+    scan.write_tsv(&mut tsv)?;
+    let tsv = String::from_utf8(tsv)?;
+    let lines: Vec<&str> = tsv.lines().collect();
+    assert_eq!(lines[0], "amplicon\tprimer\tsequence\treads\tfrequency");
+    assert_eq!(lines[1], "amplicon_1\tforward\tTGGAGGAT\t2\t0.5000");
+    assert_eq!(lines[4], "amplicon_2\treverse\tAAAAACGT\t0\t0.0000");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_count_records() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = write_input(dir.path())?;
+    let output = dir.path().join("trimmed.fastq");
+
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(
+        count_records(&[input.clone(), input]).await?,
+        Some(2 * stats.total)
+    );
+
+    // standard input can't be read twice, so it is never counted ahead of time
+    assert_eq!(count_records(&[PathBuf::from("-")]).await?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_default_concurrency_is_always_positive() -> Result<()> {
+    // the default doesn't depend on being inside a runtime, or on how many workers it has
+    let outside = default_concurrency();
+    assert!(outside >= 1);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let scheme = test_scheme();
+    let records: Vec<FastqRecord> = (0..4)
+        .map(|i| amplicon_read(&format!("read_{i}"), &scheme.scheme[0], "GATCGATCGATC"))
+        .collect();
+    let dir = tempfile::tempdir()?;
+    let counts = runtime.block_on(async {
+        assert_eq!(default_concurrency(), outside);
+        let stream = futures::stream::iter(records.into_iter().map(Ok));
+        sort_records(stream, dir.path(), &scheme, None, false).await
+    })?;
+    assert_eq!(counts.get("amplicon_1"), Some(&4));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sort_keeps_input_order_within_amplicons() -> Result<()> {
+    let scheme = test_scheme();
+    let records: Vec<FastqRecord> = (0..40)
+        .map(|i| {
+            let pair = &scheme.scheme[i % 2];
+            amplicon_read(&format!("read_{i}"), pair, "GATCGATCGATC")
+        })
+        .collect();
+
+    let mut runs = Vec::new();
+    for _ in 0..3 {
+        let dir = tempfile::tempdir()?;
+        let stream = futures::stream::iter(records.clone().into_iter().map(Ok));
+        let counts = sort_records(stream, dir.path(), &scheme, Some(8), true).await?;
+        assert_eq!(counts.get("amplicon_1"), Some(&20));
+        assert_eq!(counts.get("amplicon_2"), Some(&20));
+
+        let names = |amplicon: &str| -> Result<Vec<String>> {
+            Ok(read_output(&dir.path().join(format!("{amplicon}.fastq")))?
+                .iter()
+                .map(|record| String::from_utf8_lossy(record.name()).to_string())
+                .collect())
+        };
+        runs.push((names("amplicon_1")?, names("amplicon_2")?));
+    }
+
+    // each file follows the input order, on every run
+    let expected = |parity: usize| -> Vec<String> {
+        (0..40)
+            .filter(|i| i % 2 == parity)
+            .map(|i| format!("read_{i}"))
+            .collect()
+    };
+    for run in &runs {
+        assert_eq!(run, &(expected(0), expected(1)));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sort_input_reads_compressed_fastq_in_order() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let records: Vec<FastqRecord> = (0..10)
+        .map(|i| amplicon_read(&format!("read_{i}"), &scheme.scheme[i % 2], "GATCGATCGATC"))
+        .collect();
+    let input = dir.path().join("reads.fastq.gz");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(to_fastq(&records).as_bytes())?;
+    std::fs::write(&input, encoder.finish()?)?;
+
+    let output_dir = dir.path().join("sorted");
+    std::fs::create_dir(&output_dir)?;
+    let counts = sort_input(&input, &output_dir, &scheme, Some(4), true).await?;
+    assert_eq!(counts.get("amplicon_1"), Some(&5));
+    assert_eq!(counts.get("amplicon_2"), Some(&5));
+
+    let names: Vec<String> = read_output(&output_dir.join("amplicon_2.fastq"))?
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect();
+    assert_eq!(names, ["read_1", "read_3", "read_5", "read_7", "read_9"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_align_matching_trims_primers_with_indels() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let insert = "GATCGATCGATC";
+
+    // nanopore-style reads of amplicon_1 with a single deleted or inserted base in a primer, on
+    // both strands
+    let forward_reads = [
+        ("fwd_deletion", "TGGGGAT", "TACTATGG"),
+        ("rev_insertion", "TGGAGGAT", "TACTTATGG"),
+        ("fwd_insertion_rev_deletion", "TGGAGTGAT", "TACTAGG"),
+    ]
+    .map(|(name, fwd, rev_rc)| {
+        let seq = format!("GGGG{fwd}{insert}{rev_rc}CCCC");
+        let qual = "I".repeat(seq.len());
+        FastqRecord::new(noodles::fastq::record::Definition::new(name, ""), seq, qual)
+    });
+    let reverse_reads = forward_reads.clone().map(|record| {
+        let name = format!("{}_reverse", String::from_utf8_lossy(record.name()));
+        let seq = common::revcomp(record.sequence());
+        let qual = record.quality_scores().to_vec();
+        FastqRecord::new(noodles::fastq::record::Definition::new(name, ""), seq, qual)
+    });
+    let input = dir.path().join("indels.fastq");
+    std::fs::write(
+        &input,
+        to_fastq(&[forward_reads.as_slice(), reverse_reads.as_slice()].concat()),
+    )?;
+
+    // exact matching misses every read
+    let output = dir.path().join("exact.fastq");
+    let exact = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(exact.retained, 0);
+
+    // alignment trims each read at the aligned ends of its primers, leaving exactly the insert
+    let output = dir.path().join("aligned.fastq");
+    let settings = TrimSettings {
+        match_algorithm: MatchAlgorithm::Align,
+        max_primer_errors: Some(1),
+        ..TrimSettings::default()
+    };
+    let aligned = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(aligned.retained, 6);
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 6);
+    assert!(written
+        .iter()
+        .all(|record| record.sequence() == insert.as_bytes()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mismatched_quality_length_on_input_follows_on_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let good = amplicon_read("good", &scheme.scheme[0], "GATCGATCGATC");
+    let bad = amplicon_read("bad", &scheme.scheme[1], "GATCGATCGATC");
+
+    // the second read's quality line is a score short of its sequence
+    let fastq = format!(
+        "{}@bad\n{}\n+\n{}\n",
+        to_fastq(&[good]),
+        String::from_utf8_lossy(bad.sequence()),
+        "I".repeat(bad.sequence().len() - 1)
+    );
+    let input = dir.path().join("malformed.fastq");
+    std::fs::write(&input, fastq)?;
+    let output = dir.path().join("trimmed.fastq");
+    let trim = |on_error| {
+        let settings = TrimSettings {
+            on_error,
+            ..TrimSettings::default()
+        };
+        let (input, output) = (input.clone(), output.clone());
+        async move {
+            Fastq
+                .trim(
+                    &input,
+                    &output,
+                    test_scheme(),
+                    None,
+                    &settings,
+                    &IdentityTransform,
+                )
+                .await
+        }
+    };
+
+    // by default, the run stops at the malformed read rather than trimming it
+    let err = trim(OnError::Stop).await.unwrap_err();
+    assert!(err.to_string().contains("bad"), "{err}");
+    assert!(err.to_string().contains("quality scores"), "{err}");
+
+    // or it is skipped and counted, and the rest of the input is trimmed
+    let stats = trim(OnError::SkipWarn).await?;
+    assert_eq!(stats.total, 2);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.retained, 1);
+    assert_eq!(read_output(&output)?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mismatched_quality_length_on_mate_follows_on_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // two pairs reading a 60-base molecule from both ends, the second with a second mate whose
+    // quality line is ten scores short of its sequence
+    let molecule = b"GATTACACCGGTTAAGCTAGCTTCGAACGTACGGATCCATGCAAGTTCGAGCTTAAGCGT";
+    let (r1_seq, r2_seq) = (
+        String::from_utf8_lossy(&molecule[..40]).into_owned(),
+        String::from_utf8_lossy(&revcomp(&molecule[20..])).into_owned(),
+    );
+    let r1_path = dir.path().join("reads_R1.fastq");
+    let r2_path = dir.path().join("reads_R2.fastq");
+    std::fs::write(
+        &r1_path,
+        format!(
+            "@good/1\n{r1_seq}\n+\n{}\n@bad/1\n{r1_seq}\n+\n{}\n",
+            "I".repeat(40),
+            "I".repeat(40)
+        ),
+    )?;
+    std::fs::write(
+        &r2_path,
+        format!(
+            "@good/2\n{r2_seq}\n+\n{}\n@bad/2\n{r2_seq}\n+\n{}\n",
+            "I".repeat(40),
+            "I".repeat(30)
+        ),
+    )?;
+    let output = dir.path().join("merged.fastq");
+    let merge = |on_error| {
+        let settings = MergeSettings {
+            on_error,
+            ..MergeSettings::default()
+        };
+        let (r1_path, r2_path, output) = (r1_path.clone(), r2_path.clone(), output.clone());
+        async move { merge_paired_inputs(&r1_path, &r2_path, &output, &settings).await }
+    };
+
+    // by default, the run stops at the malformed mate rather than merging it
+    let err = merge(OnError::Stop).await.unwrap_err();
+    assert!(err.to_string().contains("bad/2"), "{err}");
+    assert!(err.to_string().contains("quality scores"), "{err}");
+
+    // or the pair is skipped and counted, and the rest of the pairs are merged
+    let stats = merge(OnError::SkipWarn).await?;
+    assert_eq!(stats.pairs, 2);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.merged, 1);
+    let merged = read_output(&output)?;
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].sequence(), molecule);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_undecodable_primer_quality_follows_on_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // Phred+64 reads, the second with characters below the offset under its forward primer
+    let mut good = amplicon_read("good", &scheme.scheme[0], "GATCGATCGATC");
+    good.quality_scores_mut().fill(b'h');
+    let mut bad = amplicon_read("bad", &scheme.scheme[1], "GATCGATCGATC");
+    bad.quality_scores_mut().fill(b'h');
+    bad.quality_scores_mut()[4..12].fill(b'#');
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&[good, bad]))?;
+    let output = dir.path().join("trimmed.fastq");
+    let trim = |on_error| {
+        let settings = TrimSettings {
+            min_primer_quality: Some(20),
+            quality_offset: QualityOffset::Phred64,
+            on_error,
+            ..TrimSettings::default()
+        };
+        let (input, output) = (input.clone(), output.clone());
+        async move {
+            Fastq
+                .trim(
+                    &input,
+                    &output,
+                    test_scheme(),
+                    None,
+                    &settings,
+                    &IdentityTransform,
+                )
+                .await
+        }
+    };
+
+    // by default, the run stops at the read whose primer quality can't be judged
+    let err = trim(OnError::Stop).await.unwrap_err();
+    assert!(err.to_string().contains("bad"), "{err}");
+    assert!(err.to_string().contains("Phred+64"), "{err}");
+
+    // or it is skipped and counted as malformed, and the rest of the input is trimmed
+    let stats = trim(OnError::SkipWarn).await?;
+    assert_eq!(stats.total, 2);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.unmatched, 0);
+    assert_eq!(stats.retained, 1);
+    assert_eq!(read_output(&output)?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_canonical_dedup_key_collapses_reverse_complements() {
+    let record = |name: &str, seq: &str, qual: &str| {
+        FastqRecord::new(noodles::fastq::record::Definition::new(name, ""), seq, qual)
+    };
+    let reads = || {
+        [
+            record("fwd", "AACCGGTTAG", "IIIIIIIII5"),
+            record("rev", "CTAACCGGTT", "JIIIIIIIII"),
+        ]
+    };
+
+    // by plain sequence, or sequence and length, the two strands are two uniques
+    for key in [DedupKey::Seq, DedupKey::SeqLen] {
+        let mut collapsed = CollapsedReads::with_key(key);
+        reads().into_iter().for_each(|read| collapsed.add(read));
+        assert_eq!(collapsed.len(), 2, "{key:?}");
+    }
+
+    // canonically, they are one, keeping the first read's orientation and lining up the
+    // second read's qualities with it
+    let mut collapsed = CollapsedReads::with_key(DedupKey::Canonical);
+    reads().into_iter().for_each(|read| collapsed.add(read));
+    let drained = collapsed.drain();
+    assert_eq!(drained.len(), 1);
+    assert_eq!(drained[0].name(), b"fwd;size=2");
+    assert_eq!(drained[0].sequence(), b"AACCGGTTAG");
+    assert_eq!(drained[0].quality_scores(), b"IIIIIIIIIJ");
+}
+
+#[test]
+fn test_every_dedup_key_ignores_case() {
+    let record = |name: &str, seq: &str| {
+        FastqRecord::new(
+            noodles::fastq::record::Definition::new(name, ""),
+            seq,
+            "I".repeat(seq.len()),
+        )
+    };
+
+    // the same read, soft-masked in part, collapses with its uppercase copy under every key
+    for key in [DedupKey::Seq, DedupKey::SeqLen, DedupKey::Canonical] {
+        assert_eq!(key.key(b"AACCggttAG"), key.key(b"AACCGGTTAG"), "{key:?}");
+        let mut collapsed = CollapsedReads::with_key(key);
+        collapsed.add(record("upper", "AACCGGTTAG"));
+        collapsed.add(record("mixed", "AACCggttAG"));
+        assert_eq!(collapsed.len(), 1, "{key:?}");
+    }
+
+    // a mixed-case reverse complement still only collapses canonically
+    assert_eq!(
+        DedupKey::Canonical.key(b"ctaaccGGTT"),
+        DedupKey::Canonical.key(b"AACCGGTTAG")
+    );
+    assert_ne!(
+        DedupKey::Seq.key(b"ctaaccGGTT"),
+        DedupKey::Seq.key(b"AACCGGTTAG")
+    );
+}
+
+#[tokio::test]
+async fn test_gc_length_report_rows() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let records = vec![
+        amplicon_read("r1", &scheme.scheme[0], "GGCCGGCCGGCC"),
+        amplicon_read("r2", &scheme.scheme[1], "AATTGGCCAATT"),
+        amplicon_read("r3", &scheme.scheme[0], "ATATATATATATAT"),
+        FastqRecord::new(
+            noodles::fastq::record::Definition::new("unmatched", ""),
+            "A".repeat(30),
+            "I".repeat(30),
+        ),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&records))?;
+
+    let report_rows = |sample: Option<usize>| {
+        let (input, dir) = (input.clone(), dir.path().to_path_buf());
+        async move {
+            let report_path = dir.join("gc_length.tsv");
+            let settings = TrimSettings {
+                gc_length_report: Some(Arc::new(Mutex::new(GcLengthReport::create(
+                    &report_path,
+                    sample,
+                )?))),
+                ..TrimSettings::default()
+            };
+            Fastq
+                .trim(
+                    &input,
+                    &dir.join("trimmed.fastq"),
+                    test_scheme(),
+                    None,
+                    &settings,
+                    &IdentityTransform,
+                )
+                .await?;
+            if let Some(report) = &settings.gc_length_report {
+                report.lock().unwrap().finish()?;
+            }
+            let tsv = std::fs::read_to_string(&report_path)?;
+            Ok::<_, color_eyre::Report>(tsv.lines().map(str::to_string).collect::<Vec<_>>())
+        }
+    };
+
+    // one row per retained read, in the order they were trimmed
+    let rows = report_rows(None).await?;
+    assert_eq!(
+        rows,
+        [
+            "amplicon\tread_id\tinsert_len\tgc_fraction",
+            "amplicon_1\tr1\t12\t1.0000",
+            "amplicon_2\tr2\t12\t0.3333",
+            "amplicon_1\tr3\t14\t0.0000",
+        ]
+    );
+
+    // a sample keeps the header and the same subset of rows every time, still in order
+    let sampled = report_rows(Some(2)).await?;
+    assert_eq!(sampled.len(), 3);
+    assert_eq!(sampled[0], rows[0]);
+    assert!(sampled[1..].iter().all(|row| rows.contains(row)));
+    assert!(
+        rows.iter().position(|row| *row == sampled[1])
+            < rows.iter().position(|row| *row == sampled[2])
+    );
+    assert_eq!(report_rows(Some(2)).await?, sampled);
+
+    // the rows kept are those with the lowest FNV-1a hashes of their names, which never change
+    assert_eq!(sampled[1..], [rows[1].clone(), rows[3].clone()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_group_by_amplicon_writes_each_amplicons_reads_contiguously() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // reads of the two amplicons arrive interleaved, starting with the second
+    let mut records = Vec::new();
+    for (name, pair) in [("r1", 1), ("r2", 0), ("r3", 1), ("r4", 0), ("r5", 0)] {
+        records.push(amplicon_read(name, &scheme.scheme[pair], "GATCGATCGATC"));
+    }
+    let input = dir.path().join("interleaved.fastq");
+    std::fs::write(&input, to_fastq(&records))?;
+    let output = dir.path().join("grouped.fastq");
+
+    let settings = TrimSettings {
+        group_by_amplicon: Some(Arc::new(Mutex::new(GroupedReads::default()))),
+        ..TrimSettings::default()
+    };
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.retained, 5);
+
+    // the first amplicon's reads come first, in the order they were read, then the second's
+    let names: Vec<String> = read_output(&output)?
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect();
+    assert_eq!(names, vec!["r2", "r4", "r5", "r1", "r3"]);
+    assert!(settings
+        .group_by_amplicon
+        .as_ref()
+        .is_some_and(|grouped| grouped.lock().unwrap().is_empty()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_collapse_output_writes_one_read_per_unique_sequence() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // three copies of one insert, with different qualities, two of another, and one of a third
+    let mut records = Vec::new();
+    for (name, pair, insert) in [
+        ("a1", 0, "GATCGATCGATC"),
+        ("b1", 0, "GATCGATCAATC"),
+        ("a2", 0, "GATCGATCGATC"),
+        ("c1", 1, "TTGACCATTG"),
+        ("b2", 0, "GATCGATCAATC"),
+        ("a3", 0, "GATCGATCGATC"),
+    ] {
+        records.push(amplicon_read(name, &scheme.scheme[pair], insert));
+    }
+    *records[2].quality_scores_mut() = {
+        let mut qual = records[2].quality_scores().to_vec();
+        qual[16] = b'5';
+        qual[17] = b'J';
+        qual
+    };
+    let input = dir.path().join("dups.fastq");
+    std::fs::write(&input, to_fastq(&records))?;
+    let output = dir.path().join("collapsed.fastq");
+
+    let settings = TrimSettings {
+        collapse_output: Some(Arc::new(Mutex::new(CollapsedReads::default()))),
+        ..TrimSettings::default()
+    };
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &settings,
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!(stats.retained, 6);
+
+    // one read per unique sequence, in the order the sequences were first seen
+    let written = read_output(&output)?;
+    assert_eq!(written.len(), 3);
+    let names: Vec<String> = written
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.name()).to_string())
+        .collect();
+    assert_eq!(names, vec!["a1;size=3", "b1;size=2", "c1;size=1"]);
+    assert_eq!(written[0].sequence(), b"GATCGATCGATC");
+
+    // qualities are the highest seen at each position across the duplicates
+    let mut expected_qual = vec![b'I'; 12];
+    expected_qual[5] = b'J';
+    assert_eq!(written[0].quality_scores(), expected_qual.as_slice());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_match_cache_gives_identical_output_on_duplicated_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // many copies of a few distinct reads, including ones that match nothing
+    let mut records = Vec::new();
+    for copy in 0..50 {
+        let (pair, insert) =
+            [(0, "GATCGATCGATC"), (1, "TTGACCATTG"), (0, "GATCGATCAATC")][copy % 3];
+        records.push(amplicon_read(
+            &format!("read_{copy}"),
+            &scheme.scheme[pair],
+            insert,
+        ));
+        if copy % 5 == 0 {
+            let mut unmatched = amplicon_read(&format!("unmatched_{copy}"), &scheme.scheme[0], "");
+            *unmatched.sequence_mut() = vec![b'A'; unmatched.sequence().len()];
+            records.push(unmatched);
+        }
+    }
+    let input = dir.path().join("dups.fastq");
+    std::fs::write(&input, to_fastq(&records))?;
+
+    let uncached_output = dir.path().join("uncached.fastq");
+    let uncached = Fastq
+        .trim(
+            &input,
+            &uncached_output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+
+    // a roomy cache and one small enough to evict constantly both match the uncached run
+    for capacity in [1024, 1] {
+        let cache = Arc::new(MatchCache::new(capacity));
+        let settings = TrimSettings {
+            match_cache: Some(Arc::clone(&cache)),
+            ..TrimSettings::default()
+        };
+        let cached_output = dir.path().join(format!("cached_{capacity}.fastq"));
+        let cached = Fastq
+            .trim(
+                &input,
+                &cached_output,
+                test_scheme(),
+                None,
+                &settings,
+                &IdentityTransform,
+            )
+            .await?;
+        assert_eq!(cached, uncached);
+        assert_eq!(read_output(&cached_output)?, read_output(&uncached_output)?);
+        assert_eq!(cache.hits() + cache.misses(), records.len() as u64);
+        if capacity == 1024 {
+            assert_eq!(cache.misses(), 4);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lowercase_bases_still_match_primers() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+
+    // lowercase bases spanning the end of the forward primer and the start of the insert
+    let mut record = amplicon_read("mixed_case", &scheme.scheme[0], "GATCGATCGATC");
+    record.sequence_mut()[8..16].make_ascii_lowercase();
+    let input = dir.path().join("mixed_case.fastq");
+    std::fs::write(&input, to_fastq(&[record]))?;
+
+    for (uppercase_output, expected) in [(false, "gatcGATCGATC"), (true, "GATCGATCGATC")] {
+        let output = dir.path().join(format!("trimmed_{uppercase_output}.fastq"));
+        let settings = TrimSettings {
+            uppercase_output,
+            ..TrimSettings::default()
+        };
+        let stats = Fastq
+            .trim(
+                &input,
+                &output,
+                test_scheme(),
+                None,
+                &settings,
+                &IdentityTransform,
+            )
+            .await?;
+        assert_eq!(stats.retained, 1);
+
+        let written = read_output(&output)?;
+        assert_eq!(written[0].sequence(), expected.as_bytes());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_annotate_bam_tags_records_with_their_amplicon() -> Result<()> {
+    use noodles::{
+        bam,
+        core::Position,
+        sam::{
+            self,
+            alignment::{
+                io::Write as _,
+                record::{
+                    cigar::{op::Kind, Op},
+                    Flags,
+                },
+                record_buf::{data::field::Value, Cigar, Name, QualityScores, Sequence},
+                RecordBuf,
+            },
+            header::record::value::{map::ReferenceSequence, Map},
+        },
+    };
+    use std::num::NonZeroUsize;
+
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let header = sam::Header::builder()
+        .add_reference_sequence(
+            "ref",
+            Map::<ReferenceSequence>::new(NonZeroUsize::try_from(1000)?),
+        )
+        .build();
+
+    // one aligned read from the first amplicon and one from no amplicon at all
+    let reads = [
+        amplicon_read("amplicon_read", &scheme.scheme[0], "GATCGATCGATC"),
+        raw_read("stray_read", &"A".repeat(36)),
+    ];
+    let input = dir.path().join("aligned.bam");
+    let mut writer = bam::io::Writer::new(std::fs::File::create(&input)?);
+    writer.write_header(&header)?;
+    for (i, read) in reads.iter().enumerate() {
+        let len = read.sequence().len();
+        let record = RecordBuf::builder()
+            .set_name(Name::from(read.name()))
+            .set_flags(Flags::empty())
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(100 * (i + 1))?)
+            .set_cigar(Cigar::from(vec![Op::new(Kind::Match, len)]))
+            .set_sequence(Sequence::from(read.sequence().to_vec()))
+            .set_quality_scores(QualityScores::from(vec![40; len]))
+            .build();
+        writer.write_alignment_record(&header, &record)?;
+    }
+    writer.try_finish()?;
+
+    let output = dir.path().join("annotated.bam");
+    let stats = annotate_bam(&input, &output, &scheme, MatcherOptions::default()).await?;
+    assert_eq!(stats.total, 2);
+    assert_eq!(stats.tagged, 1);
+
+    // only the amplicon read is tagged, and both keep their alignments
+    let mut reader = bam::io::Reader::new(std::fs::File::open(&output)?);
+    let header = reader.read_header()?;
+    let records = reader
+        .record_bufs(&header)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        records[0].data().get(&AMPLICON_TAG),
+        Some(&Value::String("amplicon_1".into()))
+    );
+    assert_eq!(records[0].alignment_start(), Some(Position::try_from(100)?));
+    assert_eq!(records[1].data().get(&AMPLICON_TAG), None);
+    assert_eq!(records[1].alignment_start(), Some(Position::try_from(200)?));
+
+    Ok(())
+}
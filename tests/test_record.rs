@@ -0,0 +1,804 @@
+mod common;
+
+use amplicon_tk::{
+    primers::{AmpliconScheme, PossiblePrimers},
+    record::{
+        find_primer_match, merge_pair, refine_boundaries, select_non_overlapping, subseq,
+        AmpliconHit, AmpliconMatch, AmpliconMatcher, BoundaryRefinement, ExactMatcher,
+        FindAmplicons, MatchAlgorithm, MatcherOptions, MergeSettings, PrimerSpan, QualityOffset,
+        SchemeKind,
+    },
+};
+use biotest::{values::Nucleotides, Format};
+use color_eyre::eyre::Result;
+use common::{amplicon_read, primers, revcomp, test_scheme};
+use noodles::fastq::{record::Definition, Record as FastqRecord};
+
+#[tokio::test]
+async fn test_reverse_read_trims_to_reverse_complement() -> Result<()> {
+    for seed in 0..200 {
+        let mut rng = biotest::seeded_rand(seed);
+        let mut random_seq = |len: usize| -> Result<Vec<u8>> {
+            let generator = biotest::Sequence::builder()
+                .sequence(Nucleotides::DnaUpper)
+                .sequence_len(len)
+                .build()?;
+            let mut seq = Vec::new();
+            generator.record(&mut seq, &mut rng)?;
+            Ok(seq)
+        };
+        let fwd = random_seq(20)?;
+        let rev = random_seq(20)?;
+        let insert = random_seq(40 + (seed as usize % 60))?;
+        let left_flank = random_seq(seed as usize % 15)?;
+        let right_flank = random_seq(seed as usize % 7)?;
+
+        let pair = PossiblePrimers::new(
+            format!("amplicon_{seed}"),
+            String::from_utf8(fwd.clone())?,
+            String::from_utf8(revcomp(&fwd))?,
+            String::from_utf8(rev.clone())?,
+            String::from_utf8(revcomp(&rev))?,
+        );
+
+        // the top strand of the molecule, followed by the same molecule read from the bottom
+        let molecule = [left_flank, fwd, insert.clone(), revcomp(&rev), right_flank].concat();
+        let quals: Vec<u8> = (0..molecule.len()).map(|i| b'!' + (i % 41) as u8).collect();
+        let fwd_read =
+            FastqRecord::new(Definition::new("fwd", ""), molecule.clone(), quals.clone());
+        let rev_read = FastqRecord::new(
+            Definition::new("rev", ""),
+            revcomp(&molecule),
+            quals.iter().rev().copied().collect::<Vec<u8>>(),
+        );
+
+        let scheme = [pair];
+        let fwd_hit = fwd_read.find_amplicon(&scheme).await.expect("forward hit");
+        let rev_hit = rev_read.find_amplicon(&scheme).await.expect("reverse hit");
+        let fwd_trimmed = fwd_read
+            .trim_to_amplicon(fwd_hit)
+            .await?
+            .expect("forward trim");
+        let rev_trimmed = rev_read
+            .trim_to_amplicon(rev_hit)
+            .await?
+            .expect("reverse trim");
+
+        assert_eq!(fwd_trimmed.sequence(), insert.as_slice(), "seed {seed}");
+        assert_eq!(
+            rev_trimmed.sequence(),
+            revcomp(fwd_trimmed.sequence()).as_slice(),
+            "Trimmed reverse read is not the reverse complement of the trimmed forward read (seed {seed})"
+        );
+        assert_eq!(
+            rev_trimmed.quality_scores(),
+            fwd_trimmed
+                .quality_scores()
+                .iter()
+                .rev()
+                .copied()
+                .collect::<Vec<u8>>()
+                .as_slice(),
+            "seed {seed}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_phred64_quality() -> Result<()> {
+    // 'h' is Phred 40 and '@' is Phred 0 under the legacy Phred+64 encoding
+    let decoded = QualityOffset::Phred64.decode(b"h@JT")?;
+    assert_eq!(decoded, vec![40, 0, 10, 20]);
+
+    // the same characters decode differently under Phred+33
+    let decoded_33 = QualityOffset::Phred33.decode(b"h@JT")?;
+    assert_eq!(decoded_33, vec![71, 31, 41, 51]);
+
+    // characters below the offset indicate the wrong encoding was chosen
+    assert!(QualityOffset::Phred64.decode(b"II5").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_rescale_phred64_to_phred33() -> Result<()> {
+    let rescaled = QualityOffset::Phred64.rescale(b"h@JT", QualityOffset::Phred33)?;
+    assert_eq!(rescaled, b"I!+5".to_vec());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cross_amplicon_read_is_chimeric() -> Result<()> {
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+
+    // amplicon_1's forward primer joined to amplicon_2's reverse primer
+    let seq = format!("GGGG{}GATCGATCGATC{}CCCC", amp1.fwd, amp2.rev_rc);
+    let qual = "I".repeat(seq.len());
+    let chimera = FastqRecord::new(Definition::new("chimera", ""), seq, qual);
+    assert_eq!(
+        chimera.classify_amplicon(&scheme.scheme).await,
+        AmpliconMatch::Chimera {
+            fwd_amplicon: "amplicon_1".to_string(),
+            rev_amplicon: "amplicon_2".to_string(),
+        }
+    );
+    assert!(chimera.find_amplicon(&scheme.scheme).await.is_none());
+
+    // a complete amplicon is still a single match, and a lone primer is not a chimera
+    let complete = amplicon_read("complete", amp1, "GATCGATCGATC");
+    assert!(matches!(
+        complete.classify_amplicon(&scheme.scheme).await,
+        AmpliconMatch::Single(hit) if hit.amplicon == "amplicon_1"
+    ));
+    let seq = format!("GGGG{}GATCGATCGATC", amp1.fwd);
+    let qual = "I".repeat(seq.len());
+    let lone = FastqRecord::new(Definition::new("lone", ""), seq, qual);
+    assert_eq!(
+        lone.classify_amplicon(&scheme.scheme).await,
+        AmpliconMatch::NoMatch
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_short_read_partial_amplicon_extension_threshold() -> Result<()> {
+    let scheme = test_scheme();
+    let amp1 = &scheme.scheme[0];
+    let insert = "GATCGATCGATC";
+
+    // a forward read that stops 12 bases past the forward primer, and the same read of the
+    // reverse strand
+    let forward = {
+        let seq = format!("GGGG{}{}", amp1.fwd, insert);
+        let qual = "I".repeat(seq.len());
+        FastqRecord::new(Definition::new("forward", ""), seq, qual)
+    };
+    let reverse = {
+        let insert_rc = String::from_utf8(revcomp(insert.as_bytes()))?;
+        let seq = format!("{}{}CCCC", insert_rc, amp1.fwd_rc);
+        let qual = "I".repeat(seq.len());
+        FastqRecord::new(Definition::new("reverse", ""), seq, qual)
+    };
+
+    for read in [forward, reverse] {
+        assert_eq!(
+            read.classify_amplicon(&scheme.scheme).await,
+            AmpliconMatch::NoMatch
+        );
+
+        // the read extends far enough toward the reverse primer to pass
+        let AmpliconMatch::Partial(hit) = read.find_partial_amplicon(&scheme.scheme, 12).await
+        else {
+            panic!("expected a partial amplicon in {:?}", read.name());
+        };
+        assert_eq!(hit.amplicon, "amplicon_1");
+        let trimmed = read
+            .clone()
+            .trim_to_partial_amplicon(hit)
+            .await?
+            .expect("trimmed read");
+        assert_eq!(trimmed.sequence().len(), insert.len());
+        assert_eq!(trimmed.quality_scores().len(), insert.len());
+
+        // but not far enough for a stricter threshold
+        assert_eq!(
+            read.find_partial_amplicon(&scheme.scheme, 13).await,
+            AmpliconMatch::NoMatch
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_insert_len_with_unequal_primer_lengths() -> Result<()> {
+    // a long forward primer ending in the start of a short reverse primer's reverse complement
+    let pair = common::primers("lopsided", "GATTACAGATTACAGATTACAGATTAAACC", "CCGGTT");
+    assert_eq!(pair.rev_rc, "AACCGG");
+    let scheme = [pair];
+    let read = |name: &str, seq: String| {
+        let qual = "I".repeat(seq.len());
+        FastqRecord::new(Definition::new(name, ""), seq, qual)
+    };
+
+    // three bases between the primers, read from either strand
+    let molecule = format!("TT{}TCA{}AA", scheme[0].fwd, scheme[0].rev_rc);
+    let molecule_rc = String::from_utf8(revcomp(molecule.as_bytes()))?;
+    for record in [read("fwd", molecule), read("rev", molecule_rc)] {
+        let hit = record.find_amplicon(&scheme).await.expect("amplicon hit");
+        let trimmed = record
+            .clone()
+            .trim_to_amplicon_with_min_insert(hit.clone(), 1)
+            .await?
+            .expect("one-base minimum insert");
+        assert_eq!(trimmed.sequence().len(), 3);
+        assert!(record
+            .clone()
+            .trim_to_amplicon_with_min_insert(hit.clone(), 3)
+            .await?
+            .is_some());
+        assert!(record
+            .trim_to_amplicon_with_min_insert(hit, 4)
+            .await?
+            .is_none());
+    }
+
+    // a primer dimer where the reverse primer overlaps the end of the forward primer has no
+    // insert at all
+    let dimer = read("dimer", format!("TT{}GGAA", scheme[0].fwd));
+    let hit = dimer.find_amplicon(&scheme).await.expect("dimer hit");
+    assert!(dimer.trim_to_amplicon(hit).await?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_subseq_validates_ranges() -> Result<()> {
+    let seq = b"GATTACA";
+    assert_eq!(subseq(seq, 1, 4)?, b"ATT");
+    assert_eq!(subseq(seq, 0, seq.len())?, seq);
+    assert!(subseq(seq, 3, 3)?.is_empty());
+
+    let reversed = subseq(seq, 4, 1).unwrap_err();
+    assert!(reversed.to_string().contains("start is past the stop"));
+    let out_of_range = subseq(seq, 5, 8).unwrap_err();
+    assert!(out_of_range.to_string().contains("only 7 bases long"));
+    assert!(subseq(seq, 8, 9).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_refine_boundaries_drops_low_quality_junction_bases() -> Result<()> {
+    let refinement = BoundaryRefinement::default();
+    let insert = |qual: &[u8]| {
+        let seq = b"ACGTACGTAC"[..qual.len()].to_vec();
+        FastqRecord::new(Definition::new("insert", ""), seq, qual.to_vec())
+    };
+
+    // one low-quality base at the start and two at the end are excluded
+    let mut record = insert(b"#IIIIIII##");
+    refine_boundaries(&mut record, QualityOffset::Phred33, &refinement)?;
+    assert_eq!(record.sequence(), b"CGTACGT");
+    assert_eq!(record.quality_scores(), b"IIIIIII");
+
+    // a high-quality insert is left alone
+    let mut record = insert(b"IIIIIIIIII");
+    refine_boundaries(&mut record, QualityOffset::Phred33, &refinement)?;
+    assert_eq!(record.sequence(), b"ACGTACGTAC");
+
+    // low-quality runs longer than the window leave the boundary where it was
+    let mut record = insert(b"###IIIIIII");
+    refine_boundaries(&mut record, QualityOffset::Phred33, &refinement)?;
+    assert_eq!(record.sequence(), b"ACGTACGTAC");
+
+    // an insert with no high-quality base is not refined away entirely
+    let mut record = insert(b"###");
+    refine_boundaries(&mut record, QualityOffset::Phred33, &refinement)?;
+    assert_eq!(record.sequence(), b"ACG");
+
+    Ok(())
+}
+
+const MOLECULE: &[u8] = b"GATTACACCGGTTAAGCTAGCTTCGAACGTACGGATCCATGCAAGTTCGAGCTTAAGCGT";
+
+/// Read the first 40 bases of [`MOLECULE`] from the top strand and the last 40 from the bottom.
+fn mates(r1_quals: Vec<u8>) -> (FastqRecord, FastqRecord) {
+    let r1 = FastqRecord::new(Definition::new("pair/1", ""), &MOLECULE[..40], r1_quals);
+    let r2 = FastqRecord::new(
+        Definition::new("pair/2", ""),
+        revcomp(&MOLECULE[20..]),
+        vec![b'5'; 40],
+    );
+    (r1, r2)
+}
+
+#[test]
+fn test_merge_cleanly_overlapping_pair() {
+    let (r1, r2) = mates(vec![b'I'; 40]);
+    let merged = merge_pair(&r1, &r2, &MergeSettings::default()).expect("merged pair");
+
+    assert_eq!(merged.overlap, 20);
+    assert_eq!(merged.conflicts, 0);
+    assert_eq!(merged.record.name(), b"pair/1");
+    assert_eq!(merged.record.sequence(), MOLECULE);
+    assert_eq!(
+        merged.record.quality_scores(),
+        [vec![b'I'; 40], vec![b'5'; 20]].concat().as_slice()
+    );
+
+    // mates that do not reach each other are not merged
+    let r2 = FastqRecord::new(
+        Definition::new("pair/2", ""),
+        revcomp(&MOLECULE[45..]),
+        vec![b'5'; 15],
+    );
+    assert!(merge_pair(&r1, &r2, &MergeSettings::default()).is_none());
+}
+
+#[test]
+fn test_merge_resolves_conflicts_by_quality() {
+    // a low-quality error at position 25 and a high-quality base at position 35 that disagree
+    // with the second mate
+    let mut quals = vec![b'5'; 40];
+    quals[25] = b'#';
+    quals[35] = b'I';
+    let (mut r1, r2) = mates(quals);
+    r1.sequence_mut()[25] = b'G';
+    r1.sequence_mut()[35] = b'G';
+    assert_ne!(MOLECULE[25], b'G');
+    assert_ne!(MOLECULE[35], b'G');
+
+    let merged = merge_pair(&r1, &r2, &MergeSettings::default()).expect("merged pair");
+    assert_eq!(merged.overlap, 20);
+    assert_eq!(merged.conflicts, 2);
+
+    // the second mate's higher-quality base wins at 25, and the first mate's at 35
+    let sequence = merged.record.sequence();
+    assert_eq!(sequence[25], MOLECULE[25]);
+    assert_eq!(sequence[35], b'G');
+    assert_eq!(merged.record.quality_scores()[25], b'5');
+    assert_eq!(merged.record.quality_scores()[35], b'I');
+    assert_eq!(sequence.len(), MOLECULE.len());
+}
+
+#[test]
+fn test_merge_leaves_mates_with_short_quality_unmerged() {
+    // quality scores that stop short of the bases, in either mate, leave nothing to merge by
+    let (r1, r2) = mates(vec![b'I'; 30]);
+    assert!(merge_pair(&r1, &r2, &MergeSettings::default()).is_none());
+
+    let (r1, mut r2) = mates(vec![b'I'; 40]);
+    r2.quality_scores_mut().truncate(10);
+    assert!(merge_pair(&r1, &r2, &MergeSettings::default()).is_none());
+}
+
+#[test]
+fn test_compiled_finders_match_window_scans() -> Result<()> {
+    let window_scan = |seq: &[u8], primer: &str| {
+        seq.windows(primer.len())
+            .any(|window| window.eq(primer.as_bytes()))
+    };
+
+    let scheme = test_scheme();
+    let hash_before = scheme.hash_amplicon_scheme()?;
+
+    let mut rng = biotest::seeded_rand(7);
+    let generator = biotest::Sequence::builder()
+        .sequence(Nucleotides::DnaUpper)
+        .sequence_len(12)
+        .build()?;
+    for i in 0..500 {
+        let mut insert = Vec::new();
+        generator.record(&mut insert, &mut rng)?;
+
+        // plant primers in some reads so that both outcomes are exercised
+        let pair = &scheme.scheme[i % 2];
+        let seq = match i % 4 {
+            0 => [pair.fwd.as_bytes(), &insert, pair.rev_rc.as_bytes()].concat(),
+            1 => [pair.rev.as_bytes(), &insert, pair.fwd_rc.as_bytes()].concat(),
+            2 => [&insert, pair.rev.as_bytes()].concat(),
+            _ => insert.clone(),
+        };
+        let read = FastqRecord::new(
+            Definition::new("read", ""),
+            seq.clone(),
+            vec![b'I'; seq.len()],
+        );
+
+        for pair in &scheme.scheme {
+            let expected_fwd = if window_scan(&seq, &pair.fwd) {
+                Some(pair.fwd.as_str())
+            } else if window_scan(&seq, &pair.fwd_rc) {
+                Some(pair.fwd_rc.as_str())
+            } else {
+                None
+            };
+            let expected_rev = if window_scan(&seq, &pair.rev) {
+                Some(pair.rev.as_str())
+            } else if window_scan(&seq, &pair.rev_rc) {
+                Some(pair.rev_rc.as_str())
+            } else {
+                None
+            };
+            assert_eq!(read.forward_match(pair), expected_fwd);
+            assert_eq!(read.reverse_match(pair), expected_rev);
+        }
+    }
+
+    // compiling finders does not change the identity of the scheme
+    assert_eq!(scheme.hash_amplicon_scheme()?, hash_before);
+    assert_eq!(scheme, test_scheme());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_match_algorithms_tolerate_primer_errors() -> Result<()> {
+    let scheme = test_scheme();
+    let insert = "GATCGATCGATC";
+
+    // the forward primer, TGGAGGAT, with a substitution, an insertion, and a deletion
+    let read_with = |fwd: &str| {
+        let seq = format!("GGGG{}{}{}CCCC", fwd, insert, "TACTATGG");
+        let qual = "I".repeat(seq.len());
+        FastqRecord::new(Definition::new("read", ""), seq, qual)
+    };
+    let substituted = read_with("TGGACGAT");
+    let inserted = read_with("TGGAGTGAT");
+    let deleted = read_with("TGGGGAT");
+
+    // each algorithm should find amplicon_1 in exactly these reads
+    let expectations = [
+        (MatchAlgorithm::Exact, [false, false, false]),
+        (MatchAlgorithm::Hamming, [true, false, false]),
+        (MatchAlgorithm::Align, [true, true, true]),
+    ];
+    for (algorithm, expected) in expectations {
+        let matcher = algorithm.matcher(1);
+        for (record, should_match) in [&substituted, &inserted, &deleted]
+            .into_iter()
+            .zip(expected)
+        {
+            let classified = record
+                .classify_amplicon_with(&scheme.scheme, matcher.as_ref())
+                .await;
+            let AmpliconMatch::Single(hit) = classified else {
+                assert!(!should_match, "{algorithm:?} missed {record:?}");
+                continue;
+            };
+            assert!(should_match, "{algorithm:?} matched {record:?}");
+            assert_eq!(hit.amplicon, "amplicon_1");
+
+            // trimming with the same matcher removes the whole erroneous primer
+            let trimmed = record
+                .clone()
+                .trim_to_matched_amplicon(hit, 1, matcher.as_ref())
+                .await?
+                .expect("the insert should be kept");
+            assert_eq!(trimmed.sequence(), insert.as_bytes());
+        }
+    }
+
+    // a primer over budget is not found, and alignment covers inserted bases
+    let matcher = MatchAlgorithm::Align.matcher(1);
+    assert_eq!(
+        find_primer_match(b"GGGGTGGAGTGATCC", "TGGAGGAT", matcher.as_ref()),
+        Some(PrimerSpan { start: 4, end: 13 })
+    );
+    assert!(find_primer_match(b"GGGGTGCAGTCATCC", "TGGAGGAT", matcher.as_ref()).is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compiled_matcher_agrees_with_record_matching() -> Result<()> {
+    fn shareable<T: Send + Sync>(_: &T) {}
+
+    // compile the scheme once and reuse it for every read
+    let scheme = test_scheme();
+    let matcher = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+    shareable(&matcher);
+
+    let mut rng = biotest::seeded_rand(11);
+    let generator = biotest::Sequence::builder()
+        .sequence(Nucleotides::DnaUpper)
+        .sequence_len(12)
+        .build()?;
+    let mut matched = 0;
+    for i in 0..500 {
+        let mut insert = Vec::new();
+        generator.record(&mut insert, &mut rng)?;
+
+        // plant complete amplicons, chimeras, and lone primers so that every outcome comes up
+        let pair = &scheme.scheme[i % 2];
+        let other = &scheme.scheme[(i + 1) % 2];
+        let seq = match i % 5 {
+            0 => [pair.fwd.as_bytes(), &insert, pair.rev_rc.as_bytes()].concat(),
+            1 => [pair.rev.as_bytes(), &insert, pair.fwd_rc.as_bytes()].concat(),
+            2 => [pair.fwd.as_bytes(), &insert, other.rev_rc.as_bytes()].concat(),
+            3 => [&insert, pair.rev.as_bytes()].concat(),
+            _ => insert.clone(),
+        };
+        let read = FastqRecord::new(
+            Definition::new("read", ""),
+            seq.clone(),
+            vec![b'I'; seq.len()],
+        );
+
+        let expected = read.classify_amplicon(&scheme.scheme).await;
+        assert_eq!(matcher.classify(&seq), expected);
+
+        let expected_trim = match expected {
+            AmpliconMatch::Single(hit) => {
+                matched += 1;
+                assert_eq!(
+                    matcher.match_read(&seq).map(|found| found.amplicon),
+                    Some(hit.amplicon.clone())
+                );
+                read.clone().trim_to_amplicon(hit).await?
+            }
+            _ => {
+                assert!(matcher.match_read(&seq).is_none());
+                None
+            }
+        };
+        assert_eq!(matcher.trim(read), expected_trim);
+    }
+    assert!(matched >= 150);
+
+    Ok(())
+}
+
+#[test]
+fn test_soft_masked_read_matches_and_keeps_its_case() {
+    let scheme = test_scheme();
+    let matcher = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+
+    // the whole read, primers included, is soft-masked
+    let mut read = amplicon_read("masked", &scheme.scheme[0], "GATCGATCGA");
+    read.sequence_mut().make_ascii_lowercase();
+
+    // primers are found as in the uppercase read
+    let upper = read.sequence().to_ascii_uppercase();
+    let hit = matcher.match_read(read.sequence());
+    assert!(hit.is_some());
+    assert_eq!(hit, matcher.match_read(&upper));
+    assert_eq!(
+        find_primer_match(read.sequence(), "TGGAGGAT", &ExactMatcher),
+        find_primer_match(&upper, "TGGAGGAT", &ExactMatcher)
+    );
+
+    // but the trimmed insert is cut from the read's own lowercase bases
+    let trimmed = matcher.trim(read).expect("the insert should be kept");
+    assert_eq!(trimmed.sequence(), b"gatcgatcga");
+}
+
+#[test]
+fn test_anchored_matcher_ignores_primers_away_from_read_ends() {
+    let scheme = test_scheme();
+    let insert = "ACGTACGTAC";
+    let near = amplicon_read("near", &scheme.scheme[0], insert);
+    let far = amplicon_read("far", &scheme.scheme[0], &"T".repeat(30))
+        .sequence()
+        .to_vec();
+    let far = [b"ACGTACGTACGTACGTACGT".as_slice(), &far].concat();
+
+    let anywhere = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+    let anchored = AmpliconMatcher::from_scheme(
+        &scheme,
+        MatcherOptions {
+            anchor_window: Some(4),
+            ..MatcherOptions::default()
+        },
+    );
+
+    // both primers of the near read lie within four bases of the read's ends
+    let expected = AmpliconHit {
+        amplicon: "amplicon_1".to_string(),
+        fwd: PrimerSpan { start: 4, end: 12 },
+        rev: PrimerSpan { start: 22, end: 30 },
+    };
+    assert_eq!(anywhere.match_read(near.sequence()), Some(expected.clone()));
+    assert_eq!(anchored.match_read(near.sequence()), Some(expected.clone()));
+    assert_eq!(expected.insert(), 12..22);
+    let trimmed = anchored.trim(near).expect("the insert should be kept");
+    assert_eq!(trimmed.sequence(), insert.as_bytes());
+
+    // the far read's forward primer starts 24 bases in
+    assert!(anywhere.match_read(&far).is_some());
+    assert!(anchored.match_read(&far).is_none());
+}
+
+#[tokio::test]
+async fn test_non_overlapping_fast_path_matches_full_search() -> Result<()> {
+    let scheme = test_scheme();
+    let full = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+    let fast = AmpliconMatcher::from_scheme(
+        &scheme,
+        MatcherOptions {
+            scheme_kind: SchemeKind::NonOverlapping,
+            ..MatcherOptions::default()
+        },
+    );
+
+    let mut rng = biotest::seeded_rand(17);
+    let generator = biotest::Sequence::builder()
+        .sequence(Nucleotides::DnaUpper)
+        .sequence_len(12)
+        .build()?;
+    for i in 0..500 {
+        let mut insert = Vec::new();
+        generator.record(&mut insert, &mut rng)?;
+
+        // the second amplicon's complete reads only turn up after the first has been searched
+        let pair = &scheme.scheme[i % 2];
+        let other = &scheme.scheme[(i + 1) % 2];
+        let seq = match i % 5 {
+            0 => [pair.fwd.as_bytes(), &insert, pair.rev_rc.as_bytes()].concat(),
+            1 => [pair.rev.as_bytes(), &insert, pair.fwd_rc.as_bytes()].concat(),
+            2 => [pair.fwd.as_bytes(), &insert, other.rev_rc.as_bytes()].concat(),
+            3 => [&insert, pair.rev.as_bytes()].concat(),
+            _ => insert.clone(),
+        };
+        let read = FastqRecord::new(
+            Definition::new("read", ""),
+            seq.clone(),
+            vec![b'I'; seq.len()],
+        );
+
+        assert_eq!(
+            read.find_amplicon_in(&scheme.scheme, SchemeKind::NonOverlapping)
+                .await,
+            read.find_amplicon(&scheme.scheme).await
+        );
+        assert_eq!(fast.classify(&seq), full.classify(&seq));
+        assert_eq!(fast.match_read(&seq), full.match_read(&seq));
+        assert_eq!(fast.trim(read.clone()), full.trim(read));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_coinciding_primers_are_matched_by_position() -> Result<()> {
+    let insert = "TTGACCATGTTGCA";
+    for pair in [
+        // a palindromic target, whose reverse primer is the reverse complement of its forward
+        // primer, so reads carry the same sequence on both sides of the insert
+        primers("palindromic", "ACGGATCC", "GGATCCGT"),
+        // both primers are the same sequence
+        primers("identical", "ACGGATCC", "ACGGATCC"),
+    ] {
+        assert!(pair.primers_coincide());
+        let scheme = AmpliconScheme { scheme: vec![pair] };
+        let pair = &scheme.scheme[0];
+        let matcher = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+
+        let fwd_read = amplicon_read("fwd", pair, insert);
+        let rev_read = FastqRecord::new(
+            Definition::new("rev", ""),
+            revcomp(fwd_read.sequence()),
+            fwd_read.quality_scores().to_vec(),
+        );
+        for (read, expected) in [
+            (fwd_read, insert.as_bytes().to_vec()),
+            (rev_read, revcomp(insert.as_bytes())),
+        ] {
+            let AmpliconMatch::Single(hit) = read.classify_amplicon(&scheme.scheme).await else {
+                panic!("{} is not a complete amplicon", pair.amplicon);
+            };
+            assert_eq!(
+                read.classify_amplicon_with(&scheme.scheme, &ExactMatcher)
+                    .await,
+                AmpliconMatch::Single(hit.clone())
+            );
+            assert_eq!(
+                read.find_amplicon_in(&scheme.scheme, SchemeKind::NonOverlapping)
+                    .await,
+                Some(hit.clone())
+            );
+
+            // the first occurrence ends the forward primer and the last starts the reverse one
+            let trimmed = read.clone().trim_to_amplicon(hit).await?.expect("trimmed");
+            assert_eq!(trimmed.sequence(), expected.as_slice());
+            assert_eq!(
+                matcher.trim(read).map(|record| record.sequence().to_vec()),
+                Some(expected)
+            );
+        }
+
+        // a single occurrence of the shared sequence is not a complete amplicon
+        let seq = format!("GGGG{}{insert}CCCC", pair.fwd);
+        let lone = FastqRecord::new(
+            Definition::new("lone", ""),
+            seq.clone(),
+            "I".repeat(seq.len()),
+        );
+        assert_eq!(
+            lone.classify_amplicon(&scheme.scheme).await,
+            AmpliconMatch::NoMatch
+        );
+        assert_eq!(matcher.classify(lone.sequence()), AmpliconMatch::NoMatch);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_find_all_amplicons_in_tandem_concatemer() {
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+    let matcher = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+
+    let first = amplicon_read("first", amp1, "ACGTACGTACGT");
+    let second = amplicon_read("second", amp2, "TTGACCATTGCA");
+    assert_eq!(matcher.find_all_amplicons(first.sequence()).len(), 1);
+
+    // two amplicons joined end to end, the second read from the reverse strand
+    let concatemer = [first.sequence(), revcomp(second.sequence()).as_slice()].concat();
+    let hits = matcher.find_all_amplicons(&concatemer);
+    let amplicons: Vec<&str> = hits.iter().map(|hit| hit.amplicon.as_str()).collect();
+    assert_eq!(amplicons, ["amplicon_1", "amplicon_2"]);
+    assert_eq!(&concatemer[hits[0].insert()], b"ACGTACGTACGT");
+    assert_eq!(
+        concatemer[hits[1].insert()].to_vec(),
+        revcomp(b"TTGACCATTGCA")
+    );
+    assert!(hits[0].insert().end < hits[1].insert().start);
+
+    // the same amplicon twice over is counted twice
+    let repeated = [first.sequence(), first.sequence()].concat();
+    let hits = matcher.find_all_amplicons(&repeated);
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits[1].insert().start, first.sequence().len() + 12);
+
+    // a concatemer is trimmed down to the span between its outermost primers
+    let record = FastqRecord::new(
+        Definition::new("concatemer", ""),
+        repeated.clone(),
+        vec![b'I'; repeated.len()],
+    );
+    let trimmed = matcher.trim_to_hits(record, &hits).unwrap();
+    assert_eq!(trimmed.sequence(), &repeated[12..repeated.len() - 12]);
+}
+
+#[test]
+fn test_find_all_amplicons_counts_zero_one_and_three() {
+    let scheme = test_scheme();
+    let (amp1, amp2) = (&scheme.scheme[0], &scheme.scheme[1]);
+    let matcher = AmpliconMatcher::from_scheme(&scheme, MatcherOptions::default());
+
+    // no primers at all
+    assert!(matcher.find_all_amplicons(&[b'A'; 60]).is_empty());
+
+    // a single amplicon
+    let single = amplicon_read("single", amp2, "TTGACCATTGCA");
+    let hits = matcher.find_all_amplicons(single.sequence());
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].amplicon, "amplicon_2");
+    assert_eq!(hits[0].span(), 4..single.sequence().len() - 4);
+
+    // three amplicons in tandem come back in order along the read, without overlapping
+    let tandem = [
+        amplicon_read("a", amp1, "ACGTACGTACGT").sequence(),
+        amplicon_read("b", amp2, "TTGACCATTGCA").sequence(),
+        amplicon_read("c", amp1, "GATCGATCGATC").sequence(),
+    ]
+    .concat();
+    let hits = matcher.find_all_amplicons(&tandem);
+    let amplicons: Vec<&str> = hits.iter().map(|hit| hit.amplicon.as_str()).collect();
+    assert_eq!(amplicons, ["amplicon_1", "amplicon_2", "amplicon_1"]);
+    assert!(hits
+        .windows(2)
+        .all(|pair| pair[0].span().end <= pair[1].span().start));
+    assert_eq!(&tandem[hits[2].insert()], b"GATCGATCGATC");
+}
+
+#[test]
+fn test_select_non_overlapping_prefers_earliest_ending() {
+    let hit = |amplicon: &str, start: usize, end: usize| AmpliconHit {
+        amplicon: amplicon.to_string(),
+        fwd: PrimerSpan {
+            start,
+            end: start + 8,
+        },
+        rev: PrimerSpan {
+            start: end - 8,
+            end,
+        },
+    };
+
+    // a long candidate spanning two short ones loses to both of them
+    let selected = select_non_overlapping(vec![
+        hit("long", 0, 100),
+        hit("second", 50, 90),
+        hit("first", 10, 40),
+    ]);
+    let amplicons: Vec<&str> = selected.iter().map(|hit| hit.amplicon.as_str()).collect();
+    assert_eq!(amplicons, ["first", "second"]);
+}
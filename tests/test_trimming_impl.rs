@@ -1,4 +1,14 @@
+mod common;
+
+use amplicon_tk::{
+    io::{Fastq, Init, RecordParser},
+    reads::{TrimSettings, Trimming},
+    record::IdentityTransform,
+};
 use color_eyre::eyre::Result;
+use common::{amplicon_read, read_output, test_scheme, to_fastq};
+use futures::TryStreamExt;
+use noodles::fastq::{record::Definition, Record as FastqRecord};
 
 #[test]
 fn test_with_primer_match() -> Result<()> {
@@ -110,3 +120,49 @@ fn test_with_no_primer_match() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_fastq_trim_reads_records_through_its_parser() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let scheme = test_scheme();
+    let reads = vec![
+        amplicon_read("amplicon_1", &scheme.scheme[0], "ACGTACGTACGTAC"),
+        amplicon_read("amplicon_2", &scheme.scheme[1], "TGCATGCATGCATG"),
+        FastqRecord::new(
+            Definition::new("unmatched", ""),
+            "A".repeat(40),
+            "I".repeat(40),
+        ),
+    ];
+    let input = dir.path().join("reads.fastq");
+    std::fs::write(&input, to_fastq(&reads))?;
+
+    // the reader's parser yields every record in the input, in order
+    let (mut reader, _) = Fastq.init(&input).await?;
+    let parsed: Vec<FastqRecord> = reader.parse_records().try_collect().await?;
+    assert_eq!(parsed, reads);
+
+    // and trimming consumes the same parser, keeping the reads with a complete amplicon
+    let output = dir.path().join("trimmed.fastq");
+    let stats = Fastq
+        .trim(
+            &input,
+            &output,
+            test_scheme(),
+            None,
+            &TrimSettings::default(),
+            &IdentityTransform,
+        )
+        .await?;
+    assert_eq!((stats.total, stats.retained), (3, 2));
+    let trimmed: Vec<Vec<u8>> = read_output(&output)?
+        .iter()
+        .map(|record| record.sequence().to_vec())
+        .collect();
+    assert_eq!(
+        trimmed,
+        [b"ACGTACGTACGTAC".to_vec(), b"TGCATGCATGCATG".to_vec()]
+    );
+
+    Ok(())
+}